@@ -19,7 +19,10 @@ set -eu
 
 # Start the server
 export RUST_LOG=tcpdump_controller=debug
-sudo -E tcpdump_controller &
+# The container has a single user, so the socket can live in /tmp
+# The socket is only writable by its owner and group, so give it to the user's group, or the
+# worker (which doesn't run as root) can't connect
+sudo -E tcpdump_controller --allow-world-writable-dir --socket-group "${DEFAULT_USER}" &
 
 # Get whicah config file we're using
 export CONFIG_PATH="$(pwd)/${CONFIG_FILENAME}"
@@ -20,6 +20,12 @@ import struct
 import typing
 from pathlib import Path
 
+# Flags of the start with options command, saying which options follow
+START_FLAG_ID = 0x01
+START_FLAG_FILTER = 0x02
+START_FLAG_INTERFACE = 0x04
+START_FLAG_SNAPLEN = 0x08
+
 
 class TcpDump():
     """
@@ -38,39 +44,97 @@ class TcpDump():
         self.tcpdump = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
         self.tcpdump.connect(socket_filename)
         self.tcpdump.settimeout(60)
+        # PIDs of the running captures, as reported by the daemon, keyed by
+        # capture id
+        self.pids = {}
 
-    def start(self, filename: str):
+    def start(self, filename: str, capture_filter: str = "",
+              interface: str = "", snaplen: int = 0, capture_id: int = 0):
         """
         Starts tcpdump
         :param url: filename for the pcap file
+        :param capture_filter: BPF filter expression restricting what is
+                               captured, or empty to capture everything
+        :param interface: interface to capture on, or empty for tcpdump's
+                          default
+        :param snaplen: bytes to capture from each packet, or 0 for
+                        tcpdump's default
+        :param capture_id: id used to stop this capture, unique among the
+                           running captures
         """
         # Create the filename
         # TODO: only pass filename and have tcpdump controller handle the path prefix
         filename = Path("/pcap_data") / filename
         # Get filename as bytes
         filename = str(filename).encode('utf-8')
+        capture_filter = capture_filter.encode('utf-8')
+        interface = interface.encode('utf-8')
 
         self.logger.info("Starting tcpdump")
 
-        # Send request over socket
-        msg = struct.pack("<BI", 0x00, len(filename)) + filename
+        # Send request over socket, flagging which options follow the filename
+        flags = 0
+        options = b''
+        if capture_id:
+            flags |= START_FLAG_ID
+            options += struct.pack("<I", capture_id)
+        if capture_filter:
+            flags |= START_FLAG_FILTER
+            options += struct.pack("<I", len(capture_filter)) + capture_filter
+        if interface:
+            flags |= START_FLAG_INTERFACE
+            options += struct.pack("<I", len(interface)) + interface
+        if snaplen:
+            flags |= START_FLAG_SNAPLEN
+            options += struct.pack("<I", snaplen)
+        if flags:
+            msg = struct.pack("<BI", 0x04, len(filename)) + filename
+            msg += struct.pack("<B", flags) + options
+        else:
+            # Without options, the plain start command works with older
+            # daemons too
+            msg = struct.pack("<BI", 0x00, len(filename)) + filename
         self.tcpdump.send(msg)
 
         # Handle response over socket
-        response = self.tcpdump.recv(1)[0]
+        response = self._recv_exact(1)[0]
         if response == 0x00:
-            self.logger.info("Successfully started tcpdump")
+            # Success is followed by tcpdump's PID
+            (pid,) = struct.unpack("<I", self._recv_exact(4))
+            self.pids[capture_id] = pid
+            self.logger.info("Successfully started tcpdump with PID %d", pid)
         elif response == 0x01:
-            raise TcpDumpError("Failed to start tcpdump")
+            # Failure is followed by a description of the error
+            (length,) = struct.unpack("<I", self._recv_exact(4))
+            message = self._recv_exact(length).decode('utf-8')
+            raise TcpDumpError("Failed to start tcpdump: {}".format(message))
         else:
             raise TcpDumpError("Invalid response from starting tcpdump")
 
-    def stop(self):
+    def _recv_exact(self, length: int) -> bytes:
+        """
+        Receives exactly the given number of bytes from the daemon
+        :param length: number of bytes to receive
+        """
+        data = b''
+        while len(data) < length:
+            chunk = self.tcpdump.recv(length - len(data))
+            if not chunk:
+                raise TcpDumpError("Connection to tcpdump controller closed")
+            data += chunk
+        return data
+
+    def stop(self, capture_id: int = 0):
         """
         Stops tcpdump
+        :param capture_id: id the capture was started with
         """
-        # Send request over socket
-        self.tcpdump.send(b'\x01')
+        # Send request over socket. The plain stop command stops capture 0
+        if capture_id:
+            self.tcpdump.send(struct.pack("<BI", 0x05, capture_id))
+        else:
+            self.tcpdump.send(b'\x01')
+        self.pids.pop(capture_id, None)
         # Handle response over socket
         response = self.tcpdump.recv(1)[0]
         if response == 0x00:
@@ -81,6 +145,38 @@ class TcpDump():
             raise TcpDumpError(
                 "Received invalid response code from tcpdump controller")
 
+    def status(self) -> typing.List[dict]:
+        """
+        Lists the captures the daemon is managing
+        :returns: a dict for each capture, with its id, PID, whether tcpdump is
+                  still running, and the pcap filename
+        """
+        # Send request over socket
+        self.tcpdump.send(b'\x03')
+        # Handle response over socket
+        response = self._recv_exact(1)[0]
+        if response != 0x00:
+            raise TcpDumpError(
+                "Received invalid response code from tcpdump controller")
+        (length,) = struct.unpack("<I", self._recv_exact(4))
+        payload = self._recv_exact(length)
+        (count,) = struct.unpack_from("<I", payload)
+        offset = 4
+        captures = []
+        for _ in range(count):
+            capture_id, pid, running, filename_length = struct.unpack_from(
+                "<IIBI", payload, offset)
+            offset += struct.calcsize("<IIBI")
+            filename = payload[offset:offset + filename_length]
+            offset += filename_length
+            captures.append({
+                "id": capture_id,
+                "pid": pid,
+                "running": bool(running),
+                "filename": filename.decode('utf-8'),
+            })
+        return captures
+
     def shutdown(self):
         """
         Shuts down the tcpdump controller
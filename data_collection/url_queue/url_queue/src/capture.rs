@@ -15,16 +15,19 @@
 // along with url_queue.  If not, see <http://www.gnu.org/licenses/>.
 
 use crate::config::Config;
-use crate::service::WorkQueueService;
+use crate::service::{FlushPolicy, ServiceOptions, WorkQueueService};
 use crate::shutdown;
 use crate::url::{UrlEntry, UrlsReader};
-use hex;
+use crate::work::WorkQueue;
+use log::info;
 use rand::{self, Rng};
 use serde_derive::{Deserialize, Serialize};
 use std::cmp::Ordering;
 use std::fmt;
-use std::io;
+use std::fs::File;
+use std::io::{self, BufReader};
 use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Copy, Clone, Ord, Debug, Eq, Hash, PartialEq, PartialOrd, Deserialize, Serialize)]
 pub enum CaptureWorkType {
@@ -47,69 +50,247 @@ impl fmt::Display for CaptureWorkType {
     }
 }
 
-#[derive(Clone, Debug, Eq, Ord, PartialEq, Deserialize, Serialize)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Deserialize, Serialize)]
 pub struct CaptureWork {
     pub index: u64,
     pub url: String,
     pub filename: PathBuf,
 }
 
-impl PartialOrd for CaptureWork {
+impl Ord for CaptureWork {
     /// Custom comparator used so that lower indexes appear as higher priority work
     ///
+    /// Work with the same index is ordered by its other fields, so the ordering agrees with
+    /// equality
     /// # Parameters
     /// * `other` - `CaptureWork` to compare against
+    fn cmp(&self, other: &CaptureWork) -> Ordering {
+        other
+            .index
+            .cmp(&self.index)
+            .then_with(|| self.url.cmp(&other.url))
+            .then_with(|| self.filename.cmp(&other.filename))
+    }
+}
+
+impl PartialOrd for CaptureWork {
     fn partial_cmp(&self, other: &CaptureWork) -> Option<Ordering> {
-        self.index
-            .partial_cmp(&other.index)
-            .map(|ord| ord.reverse())
+        Some(self.cmp(other))
     }
 }
 
-impl From<UrlEntry> for CaptureWork {
-    /// Constructor that creates a new piece of capture work
+impl CaptureWork {
+    /// Creates a new piece of capture work, saved to the given filename
     ///
-    /// filename is randomly generated
+    /// # Parameters
+    /// * `url_entry` - Url the worker should navigate to
+    /// * `filename` - Filename the worker should save the capture to
+    pub fn with_filename(url_entry: UrlEntry, filename: PathBuf) -> Self {
+        CaptureWork {
+            index: url_entry.index,
+            url: url_entry.url,
+            filename,
+        }
+    }
+
+    /// Creates a new piece of capture work, saved to a filename generated from the given RNG
     ///
     /// # Parameters
-    /// * `url` - Url the worker should navigate to
-    fn from(url_entry: UrlEntry) -> Self {
-        // Get access to the RNG
-        let mut rng = rand::thread_rng();
+    /// * `url_entry` - Url the worker should navigate to
+    /// * `rng` - RNG to generate the filename with
+    pub fn with_rng<R: Rng>(url_entry: UrlEntry, rng: &mut R) -> Self {
         // Generate 32 random bytes
         let random_bytes: [u8; 32] = rng.gen();
         // Hex-encode the bytes
         let filename = PathBuf::from(hex::encode(random_bytes)).with_extension("pcap");
         // Create a work item
-        CaptureWork {
-            index: url_entry.index,
-            url: url_entry.url,
-            filename,
-        }
+        CaptureWork::with_filename(url_entry, filename)
+    }
+}
+
+impl From<UrlEntry> for CaptureWork {
+    /// Constructor that creates a new piece of capture work
+    ///
+    /// filename is randomly generated
+    ///
+    /// # Parameters
+    /// * `url` - Url the worker should navigate to
+    fn from(url_entry: UrlEntry) -> Self {
+        CaptureWork::with_rng(url_entry, &mut rand::thread_rng())
     }
 }
 
-impl<'a> WorkQueueService<'a, CaptureWorkType, CaptureWork> {
+impl WorkQueueService<CaptureWorkType, CaptureWork> {
     /// Creates a new work queue service using options from the config
     ///
     /// # Parameters
     /// * `config` - config to load
     pub fn from_config(config: &Config) -> Result<(Self, shutdown::ServerShutdown), io::Error> {
+        let options = ServiceOptions {
+            max_report_size: config.max_report_size,
+            failures_path: config.failures_path.clone(),
+            max_retries: config.max_retries,
+            reregistration_policy: config.reregistration_policy,
+            lease_timeout: config.lease_timeout.map(Duration::from_secs),
+            snapshot_path: config.snapshot_path.clone(),
+            snapshot_interval: Duration::from_secs(config.snapshot_interval),
+            flush_policy: FlushPolicy {
+                every: config.report_flush_every.max(1),
+                interval: config.report_flush_interval.map(Duration::from_millis),
+            },
+            ..ServiceOptions::new(config.report_path.clone())
+        };
+        // Resume from the snapshot of a previous run if there is one
+        if let Some(ref snapshot_path) = config.snapshot_path {
+            if snapshot_path.is_file() {
+                info!("Loading remaining work from {:?}", snapshot_path);
+                let snapshot_file = BufReader::new(File::open(snapshot_path)?);
+                return WorkQueueService::with_work_queue(
+                    WorkQueue::load_from(snapshot_file)?,
+                    options,
+                );
+            }
+        }
+        // The CSV reader only supports single byte delimiters
+        if !config.urls_delimiter.is_ascii() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "urls_delimiter {:?} must be an ASCII character",
+                    config.urls_delimiter
+                ),
+            ));
+        }
         // Read URLs and generate work
         let work = UrlsReader::build()
             .with_limit_opt(config.num_urls)
-            .open(config.urls_path.clone())?
-            .flat_map(|url_entry| {
-                // Create work using both types
-                [CaptureWorkType::Normal, CaptureWorkType::Tor]
-                    .iter()
-                    .cloned()
-                    .map(move |work_type| {
-                        let url_entry = url_entry.clone();
-                        (work_type, CaptureWork::from(url_entry))
-                    })
-            });
+            .with_retries(
+                config.urls_open_retries,
+                Duration::from_millis(config.urls_open_backoff),
+            )
+            .with_headers(config.urls_has_headers)
+            .with_delimiter(config.urls_delimiter as u8)
+            .with_columns(config.urls_index_column, config.urls_url_column)
+            .with_renumbering(config.renumber_urls)
+            .open_many(
+                std::iter::once(&config.urls_path)
+                    .chain(&config.extra_urls_paths)
+                    .collect(),
+            )?;
+        let work = generate_work(work, config.work_types.clone());
         // Create the service
-        WorkQueueService::new(work, config.report_path.clone())
+        WorkQueueService::new(work, options)
+    }
+}
+
+/// Generates work of each of the given types for each URL
+///
+/// # Parameters
+/// * `url_entries` - URLs to generate work for
+/// * `work_types` - Types of work to generate for each URL
+fn generate_work<I>(
+    url_entries: I,
+    work_types: Vec<CaptureWorkType>,
+) -> impl Iterator<Item = (CaptureWorkType, CaptureWork)>
+where
+    I: IntoIterator<Item = UrlEntry>,
+{
+    url_entries.into_iter().flat_map(move |url_entry| {
+        work_types
+            .clone()
+            .into_iter()
+            .map(move |work_type| (work_type, CaptureWork::from(url_entry.clone())))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    /// Tests that work with lower indexes is ordered first, and that the ordering agrees with
+    /// equality
+    #[test]
+    fn test_work_order() {
+        let work = |index: u64, url: &str| CaptureWork {
+            index,
+            url: url.to_string(),
+            filename: PathBuf::from("example.pcap"),
+        };
+        assert!(work(1, "b.com") > work(2, "a.com"));
+        assert_ne!(work(1, "a.com").cmp(&work(1, "b.com")), Ordering::Equal);
+        assert_eq!(work(1, "a.com").cmp(&work(1, "a.com")), Ordering::Equal);
+    }
+
+    /// Tests that work is saved to the given filename, or one generated by the given RNG
+    #[test]
+    fn test_filename() {
+        let url_entry = UrlEntry {
+            index: 3,
+            url: "example.com".to_string(),
+        };
+        let work = CaptureWork::with_filename(url_entry.clone(), PathBuf::from("example.pcap"));
+        assert_eq!(work.index, 3);
+        assert_eq!(work.url, "example.com");
+        assert_eq!(work.filename, PathBuf::from("example.pcap"));
+        // The same seed generates the same filename
+        let work = CaptureWork::with_rng(url_entry.clone(), &mut StdRng::from_seed([7; 32]));
+        let other_work = CaptureWork::with_rng(url_entry, &mut StdRng::from_seed([7; 32]));
+        assert_eq!(work.filename, other_work.filename);
+        assert_eq!(work.filename.extension().unwrap(), "pcap");
+        assert_eq!(work.filename.file_stem().unwrap().len(), 64);
+    }
+
+    /// Tests that only the configured types of work are enqueued
+    #[test]
+    fn test_work_types() {
+        let config: Config = toml::from_str(
+            r#"
+            listen_addr = "127.0.0.1:3000"
+            urls_path = "top-1m.csv"
+            report_path = "report.json"
+            work_types = ["normal"]
+            "#,
+        )
+        .unwrap();
+        let url_entries = (0..3).map(|index| UrlEntry {
+            index,
+            url: format!("example{}.com", index),
+        });
+        let mut work_queue: WorkQueue<CaptureWorkType, CaptureWork> =
+            generate_work(url_entries, config.work_types).collect();
+        let tor_client = work_queue.add_client(vec![CaptureWorkType::Tor], None, None);
+        assert_eq!(work_queue.request_work(tor_client), None);
+        let normal_client = work_queue.add_client(vec![CaptureWorkType::Normal], None, None);
+        for index in 0..3 {
+            let (work_type, work) = work_queue.request_work(normal_client).unwrap();
+            assert_eq!((work_type, work.index), (CaptureWorkType::Normal, index));
+        }
+        assert_eq!(work_queue.request_work(normal_client), None);
+    }
+
+    /// Tests that work of both types is generated by default
+    #[test]
+    fn test_default_work_types() {
+        let config: Config = toml::from_str(
+            r#"
+            listen_addr = "127.0.0.1:3000"
+            urls_path = "top-1m.csv"
+            report_path = "report.json"
+            "#,
+        )
+        .unwrap();
+        let url_entry = UrlEntry {
+            index: 0,
+            url: "example.com".to_string(),
+        };
+        let work_types: Vec<CaptureWorkType> = generate_work(vec![url_entry], config.work_types)
+            .map(|(work_type, _)| work_type)
+            .collect();
+        assert_eq!(
+            work_types,
+            vec![CaptureWorkType::Normal, CaptureWorkType::Tor]
+        );
     }
 }
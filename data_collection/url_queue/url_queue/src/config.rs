@@ -14,21 +14,109 @@
 // You should have received a copy of the GNU General Public License
 // along with url_queue.  If not, see <http://www.gnu.org/licenses/>.
 
+// failure's derive implements Fail for ConfigLoadError inside a named const, which the lint
+// can't be allowed on from the enum itself
+#![allow(non_local_definitions)]
+
+use crate::capture::CaptureWorkType;
+use crate::work::ReregistrationPolicy;
 use failure::Fail;
 use serde_derive::{Deserialize, Serialize};
 use std::fs::File;
 use std::io::{self, BufReader, Read};
-use std::net::SocketAddr;
+use std::net::{SocketAddr, TcpListener};
 use std::path::{Path, PathBuf};
 
-use toml;
-
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub listen_addr: SocketAddr,
     pub urls_path: PathBuf,
+    /// More URLs files, read after `urls_path`
+    #[serde(default)]
+    pub extra_urls_paths: Vec<PathBuf>,
+    /// Whether to number the URLs from 1 across all of the URLs files, instead of using the
+    /// indices given in the files
+    #[serde(default)]
+    pub renumber_urls: bool,
     pub num_urls: Option<usize>,
+    /// Number of times to retry opening the URLs file after a transient error
+    #[serde(default)]
+    pub urls_open_retries: usize,
+    /// Time (in ms) to wait before first retrying to open the URLs file, doubled after each
+    /// retry
+    #[serde(default = "default_urls_open_backoff")]
+    pub urls_open_backoff: u64,
+    /// Whether the first row of the URLs file is a header
+    #[serde(default)]
+    pub urls_has_headers: bool,
+    /// Delimiter between the columns of the URLs file
+    #[serde(default = "default_urls_delimiter")]
+    pub urls_delimiter: char,
+    /// Column of the URLs file holding the index of each URL, starting at 0
+    #[serde(default)]
+    pub urls_index_column: usize,
+    /// Column of the URLs file holding each URL, starting at 0
+    #[serde(default = "default_urls_url_column")]
+    pub urls_url_column: usize,
     pub report_path: PathBuf,
+    /// Size (in bytes) the report file may reach before reports are written to the next
+    /// numbered file, such as report.1.json after report.json. Never moves on if not given
+    pub max_report_size: Option<u64>,
+    /// Types of work to generate for each URL
+    #[serde(default = "default_work_types")]
+    pub work_types: Vec<CaptureWorkType>,
+    /// Number of times failed work is retried before it is dropped. Retries forever if not given
+    pub max_retries: Option<usize>,
+    /// File to record work that was dropped after failing too many times
+    pub failures_path: Option<PathBuf>,
+    /// What to do when a worker registers again with the same worker ID
+    #[serde(default)]
+    pub reregistration_policy: ReregistrationPolicy,
+    /// Time (in seconds) a client has to report work before it is given to another client.
+    /// Work is never given out again if not given
+    pub lease_timeout: Option<u64>,
+    /// File to periodically save the remaining work to. If it exists on startup, work is loaded
+    /// from it instead of the URLs file
+    pub snapshot_path: Option<PathBuf>,
+    /// Minimum time (in seconds) between saves of the remaining work
+    #[serde(default = "default_snapshot_interval")]
+    pub snapshot_interval: u64,
+    /// Number of reports to write before flushing them to the report file
+    #[serde(default = "default_report_flush_every")]
+    pub report_flush_every: usize,
+    /// Time (in ms) after which written reports are flushed to the report file, even if fewer
+    /// than `report_flush_every` were written
+    pub report_flush_interval: Option<u64>,
+}
+
+/// Work of every type is generated by default
+fn default_work_types() -> Vec<CaptureWorkType> {
+    vec![CaptureWorkType::Normal, CaptureWorkType::Tor]
+}
+
+/// Default time (in ms) to wait before first retrying to open the URLs file
+fn default_urls_open_backoff() -> u64 {
+    100
+}
+
+/// URLs files are comma separated by default
+fn default_urls_delimiter() -> char {
+    ','
+}
+
+/// URLs files hold the index, then the URL, by default
+fn default_urls_url_column() -> usize {
+    1
+}
+
+/// Default minimum time (in seconds) between saves of the remaining work
+fn default_snapshot_interval() -> u64 {
+    60
+}
+
+/// Reports are flushed after every report by default, so none are lost in a crash
+fn default_report_flush_every() -> usize {
+    1
 }
 
 impl Config {
@@ -45,6 +133,52 @@ impl Config {
         // Parse the config
         Ok(toml::from_slice(&contents)?)
     }
+
+    /// Checks that the files and address in the config can be used, so mistakes are reported
+    /// before the server starts instead of deep inside it
+    ///
+    /// The URLs files aren't checked if they're read with retries, since they may not be
+    /// visible yet, or if work is resumed from a snapshot instead
+    pub fn validate(&self) -> Result<(), ConfigLoadError> {
+        // Check the URLs files exist
+        let resuming = self
+            .snapshot_path
+            .as_ref()
+            .is_some_and(|snapshot_path| snapshot_path.is_file());
+        if self.urls_open_retries == 0 && !resuming {
+            for urls_path in std::iter::once(&self.urls_path).chain(&self.extra_urls_paths) {
+                if !urls_path.is_file() {
+                    return Err(ConfigLoadError::UrlsFileMissing {
+                        path: urls_path.clone(),
+                    });
+                }
+            }
+        }
+        // Check the directories of the files written exist
+        let output_paths = std::iter::once(&self.report_path)
+            .chain(&self.failures_path)
+            .chain(&self.snapshot_path);
+        for output_path in output_paths {
+            // A bare filename is in the current directory
+            let output_dir = match output_path.parent() {
+                Some(output_dir) if output_dir != Path::new("") => output_dir,
+                _ => Path::new("."),
+            };
+            if !output_dir.is_dir() {
+                return Err(ConfigLoadError::OutputDirMissing {
+                    path: output_path.clone(),
+                });
+            }
+        }
+        // Check the address can be listened on
+        TcpListener::bind(self.listen_addr).map_err(|error| {
+            ConfigLoadError::ListenAddrUnavailable {
+                listen_addr: self.listen_addr,
+                error,
+            }
+        })?;
+        Ok(())
+    }
 }
 
 /// Custom error that handles all cases of config loading
@@ -54,6 +188,15 @@ pub enum ConfigLoadError {
     FileOpen { error: io::Error },
     #[fail(display = "error parsing toml: {}", error)]
     TomlParse { error: toml::de::Error },
+    #[fail(display = "URLs file {:?} does not exist", path)]
+    UrlsFileMissing { path: PathBuf },
+    #[fail(display = "directory to write {:?} to does not exist", path)]
+    OutputDirMissing { path: PathBuf },
+    #[fail(display = "cannot listen on {}: {}", listen_addr, error)]
+    ListenAddrUnavailable {
+        listen_addr: SocketAddr,
+        error: io::Error,
+    },
 }
 
 // TODO: make this implementation private
@@ -76,3 +219,55 @@ impl From<toml::de::Error> for ConfigLoadError {
         ConfigLoadError::TomlParse { error }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    /// Tests that each kind of unusable config is reported with its own error
+    #[test]
+    fn test_validate() {
+        let dir = env::temp_dir().join(format!("url_queue_test_{}_validate", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let urls_path = dir.join("urls.csv");
+        fs::write(&urls_path, "1,example.com\n").unwrap();
+        let config = |urls_path: &Path, report_path: &Path, listen_addr: SocketAddr| {
+            toml::from_str::<Config>(&format!(
+                "listen_addr = {:?}\nurls_path = {:?}\nreport_path = {:?}",
+                listen_addr.to_string(),
+                urls_path,
+                report_path
+            ))
+            .unwrap()
+        };
+        let free_addr = "127.0.0.1:0".parse().unwrap();
+        let report_path = dir.join("report.json");
+        config(&urls_path, &report_path, free_addr)
+            .validate()
+            .unwrap();
+        // A bare report filename is written to the current directory
+        config(&urls_path, Path::new("report.json"), free_addr)
+            .validate()
+            .unwrap();
+        let error = config(&dir.join("missing.csv"), &report_path, free_addr)
+            .validate()
+            .unwrap_err();
+        assert!(matches!(error, ConfigLoadError::UrlsFileMissing { .. }));
+        let error = config(&urls_path, &dir.join("missing/report.json"), free_addr)
+            .validate()
+            .unwrap_err();
+        assert!(matches!(error, ConfigLoadError::OutputDirMissing { .. }));
+        let listener = TcpListener::bind(free_addr).unwrap();
+        let error = config(&urls_path, &report_path, listener.local_addr().unwrap())
+            .validate()
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            ConfigLoadError::ListenAddrUnavailable { .. }
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -17,6 +17,7 @@
 
 pub mod capture;
 pub mod config;
+pub mod rotation;
 pub mod service;
 pub mod shutdown;
 pub mod url;
@@ -17,6 +17,7 @@
 
 mod capture;
 mod config;
+mod rotation;
 mod service;
 mod shutdown;
 mod url;
@@ -27,15 +28,39 @@ use crate::service::WorkQueueService;
 use crate::url::UrlsReader;
 use clap::{App, Arg};
 use hyper::header;
-use hyper::rt::Future;
-use hyper::service::service_fn;
+use hyper::server::conn::AddrIncoming;
+use hyper::server::Builder;
+use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Response, Server, StatusCode};
 use log::error;
 use std::io;
 use std::iter::FromIterator;
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-fn main() -> Result<(), io::Error> {
+/// Binds a server to the given address
+///
+/// Unlike `Server::bind`, this returns an error describing the address and the underlying cause
+/// instead of panicking
+fn bind(listen_addr: &SocketAddr) -> Result<Builder<AddrIncoming>, io::Error> {
+    Server::try_bind(listen_addr).map_err(|err| {
+        // Report the cause, since hyper's own message doesn't say why binding failed
+        let message = err.to_string();
+        match err.into_cause() {
+            Some(cause) => io::Error::new(
+                cause
+                    .downcast_ref::<io::Error>()
+                    .map_or(io::ErrorKind::Other, io::Error::kind),
+                format!("failed to bind {}: {}", listen_addr, cause),
+            ),
+            None => io::Error::other(format!("failed to bind {}: {}", listen_addr, message)),
+        }
+    })
+}
+
+#[tokio::main]
+async fn main() -> Result<(), io::Error> {
     // Initiate logger
     env_logger::init();
     // Parse command line arguments
@@ -55,15 +80,64 @@ fn main() -> Result<(), io::Error> {
     let config_file = matches.value_of("config_file").unwrap();
     // Load the config
     let config = config::Config::load(config_file).unwrap();
+    // Report mistakes in the config before starting
+    config
+        .validate()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
     // Create a server from generated work
-    let (service, shutdown_fut) = WorkQueueService::from_config(&config)?;
+    let (service, shutdown) = WorkQueueService::from_config(&config)?;
+    // Keep a handle to flush reports with
+    let flush_service = service.clone();
+    // Flush reports held back by the flush policy once its interval passes
+    if let Some(flush_interval) = config.report_flush_interval {
+        let flush_service = service.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_millis(flush_interval));
+            loop {
+                interval.tick().await;
+                if let Err(err) = flush_service.flush_if_due() {
+                    error!("Failed to flush reports: {}", err);
+                }
+            }
+        });
+    }
+    // Each connection gets a handle to the same service
+    let make_service = make_service_fn(move |_| {
+        let service = service.clone();
+        async move {
+            Ok::<_, io::Error>(service_fn(move |request| {
+                let service = service.clone();
+                async move { service.call(request).await }
+            }))
+        }
+    });
     // Create a server that listens on the given address
-    let server = Server::bind(&config.listen_addr)
-        .serve(service)
-        .with_graceful_shutdown(shutdown_fut)
-        .map_err(|err| error!("Error spawning service: {}", err));
+    let server = bind(&config.listen_addr)?
+        .serve(make_service)
+        .with_graceful_shutdown(async move { shutdown.wait().await });
     // Run the server
-    hyper::rt::run(server);
+    if let Err(err) = server.await {
+        error!("Error running service: {}", err);
+    }
+    // Flush any reports held back by the flush policy
+    flush_service.flush()?;
     // Return success from main
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Tests that binding to an address in use gives a descriptive error instead of panicking
+    #[test]
+    fn test_bind_in_use() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+        let err = bind(&listen_addr).err().unwrap();
+        let message = err.to_string();
+        assert!(message.starts_with(&format!("failed to bind {}: ", listen_addr)));
+        assert!(message.to_lowercase().contains("in use"));
+    }
+}
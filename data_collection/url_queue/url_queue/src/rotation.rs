@@ -0,0 +1,198 @@
+// Copyright 2018 Steven Sheffey
+// This file is part of url_queue.
+//
+// url_queue is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// url_queue is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with url_queue.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Appends lines to a file, moving on to the next numbered file once it reaches a maximum size
+///
+/// report.json is followed by report.1.json, report.2.json, and so on. Files are only changed
+/// between lines, so no line is split across files
+pub struct RotatingFile {
+    /// Path of the first file
+    path: PathBuf,
+    /// Size (in bytes) a file may reach before moving on to the next one. Never moves on if
+    /// not given
+    max_size: Option<u64>,
+    /// Number of the file being written to, where the first file is 0
+    index: usize,
+    /// Writes to the file being written to
+    writer: BufWriter<File>,
+    /// Size (in bytes) of the file being written to, including buffered writes
+    size: u64,
+    /// Whether the last byte written ended a line
+    at_line_start: bool,
+}
+
+impl RotatingFile {
+    /// Opens the last existing numbered file for appending, or creates the first file
+    ///
+    /// # Parameters
+    /// * `path` - Path of the first file
+    /// * `max_size` - Size (in bytes) a file may reach before moving on to the next one
+    pub fn open<P: AsRef<Path>>(path: P, max_size: Option<u64>) -> Result<Self, io::Error> {
+        let path = path.as_ref().to_path_buf();
+        // Resume appending to the last file written before a restart
+        let index = rotated_paths(&path).len().saturating_sub(1);
+        let (writer, size) = open_append(&rotated_path(&path, index))?;
+        Ok(RotatingFile {
+            path,
+            max_size,
+            index,
+            writer,
+            size,
+            at_line_start: true,
+        })
+    }
+
+    /// Moves on to the next numbered file if the current one has reached the maximum size
+    fn rotate_if_full(&mut self) -> Result<(), io::Error> {
+        let full = self.size > 0 && self.max_size.is_some_and(|max_size| self.size >= max_size);
+        if full {
+            self.writer.flush()?;
+            let (writer, size) = open_append(&rotated_path(&self.path, self.index + 1))?;
+            self.index += 1;
+            self.writer = writer;
+            self.size = size;
+        }
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        if self.at_line_start {
+            self.rotate_if_full()?;
+        }
+        let written = self.writer.write(buf)?;
+        self.size += written as u64;
+        if let Some(&last) = buf[..written].last() {
+            self.at_line_start = last == b'\n';
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.writer.flush()
+    }
+}
+
+/// Opens a file for appending, returning a buffered writer to it and its size
+///
+/// # Parameters
+/// * `path` - Path of the file
+fn open_append(path: &Path) -> Result<(BufWriter<File>, u64), io::Error> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok((BufWriter::new(file), size))
+}
+
+/// Gets the path of a numbered file, such as report.2.json for the file numbered 2 after
+/// report.json
+///
+/// # Parameters
+/// * `path` - Path of the first file
+/// * `index` - Number of the file, where the first file is 0
+pub fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    if index == 0 {
+        return path.to_path_buf();
+    }
+    let mut file_name = path.file_stem().unwrap_or_default().to_os_string();
+    file_name.push(format!(".{}", index));
+    if let Some(extension) = path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    path.with_file_name(file_name)
+}
+
+/// Gets the paths of the existing numbered files, in the order they were written
+///
+/// Numbering stops at the first missing file. Nothing is returned if the first file is missing
+///
+/// # Parameters
+/// * `path` - Path of the first file
+pub fn rotated_paths(path: &Path) -> Vec<PathBuf> {
+    (0..)
+        .map(|index| rotated_path(path, index))
+        .take_while(|path| path.is_file())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Tests that numbered files are named before the extension
+    #[test]
+    fn test_rotated_path() {
+        let path = Path::new("data/report.json");
+        assert_eq!(rotated_path(path, 0), PathBuf::from("data/report.json"));
+        assert_eq!(rotated_path(path, 2), PathBuf::from("data/report.2.json"));
+        assert_eq!(
+            rotated_path(Path::new("report"), 1),
+            PathBuf::from("report.1")
+        );
+    }
+
+    /// Tests that lines move on to the next file once one is full, and are never split
+    #[test]
+    fn test_rotation() {
+        let dir =
+            std::env::temp_dir().join(format!("url_queue_test_rotation_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("report.json");
+        {
+            let mut file = RotatingFile::open(&path, Some(10)).unwrap();
+            for line in &["first", "second line", "third"] {
+                writeln!(file, "{}", line).unwrap();
+            }
+            file.flush().unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "first\nsecond line\n");
+        assert_eq!(
+            fs::read_to_string(dir.join("report.1.json")).unwrap(),
+            "third\n"
+        );
+        // Reopening appends to the last file until it's full
+        {
+            let mut file = RotatingFile::open(&path, Some(10)).unwrap();
+            for line in &["fourth", "fifth"] {
+                writeln!(file, "{}", line).unwrap();
+            }
+            file.flush().unwrap();
+        }
+        assert_eq!(
+            rotated_paths(&path),
+            vec![
+                path.clone(),
+                dir.join("report.1.json"),
+                dir.join("report.2.json")
+            ]
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("report.1.json")).unwrap(),
+            "third\nfourth\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("report.2.json")).unwrap(),
+            "fifth\n"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -14,385 +14,920 @@
 // You should have received a copy of the GNU General Public License
 // along with url_queue.  If not, see <http://www.gnu.org/licenses/>.
 
+// failure's derive implements Fail for ReportError inside a named const, which the lint can't be
+// allowed on from the enum itself
+#![allow(non_local_definitions)]
+
 use crate::capture::{CaptureWork, CaptureWorkType};
+use crate::rotation::RotatingFile;
 use crate::shutdown;
 use crate::work::{
-    AddClientRequest, AddClientResponse, RemoveClientRequest, RemoveClientResponse, WorkQueue,
-    WorkReportRequest, WorkReportResponse, WorkRequest, WorkResponse,
+    AddClientRequest, AddClientResponse, HealthResponse, ParseErrorResponse, RemoveClientRequest,
+    RemoveClientResponse, ReregistrationPolicy, StatsResponse, WorkQueue, WorkReportRequest,
+    WorkReportResponse, WorkRequest, WorkResponse,
 };
-use futures::{future, Stream};
-use hyper::rt::Future;
-use hyper::service::{NewService, Service};
-use hyper::{Body, Method, Request, Response};
-use log::{error, info};
+use failure::Fail;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use log::{error, info, warn};
+use serde::de::DeserializeOwned;
 use serde_derive::{Deserialize, Serialize};
-use serde_json;
 use std::error;
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
+use std::hash::Hash;
 use std::io::{self, BufWriter, Write};
 use std::iter::FromIterator;
-use std::marker::PhantomData;
-use std::path::Path;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-pub struct WorkQueueService<'a, T, W> {
+/// Serves work to clients over HTTP
+///
+/// Clones share the same queue and sinks, so each connection can be given its own clone
+#[derive(Clone)]
+pub struct WorkQueueService<T, W> {
     /// Handles clients and work
     work_queue: Arc<Mutex<WorkQueue<T, W>>>,
-    /// Writes Reports to a file
-    report_sink: Arc<Mutex<BufWriter<File>>>,
-    /// Channel future used to shutdown the server        
-    shutdown: Arc<AtomicBool>,
-    /// TODO: figure out why this exists
-    _phantom: &'a PhantomData<()>,
+    /// Writes Reports to a file, moving on to the next numbered file when one is full
+    report_sink: Arc<Mutex<ReportSink<RotatingFile>>>,
+    /// Number of reports written to the report file
+    reports_written: Arc<AtomicUsize>,
+    /// Writes reports of work that was dropped after failing too many times to a file
+    failure_sink: Option<Arc<Mutex<ReportSink<BufWriter<File>>>>>,
+    /// Saves the work queue, so a restarted server can resume where it left off
+    snapshotter: Option<Arc<Snapshotter>>,
+    /// Used to shutdown the server
+    shutdown: shutdown::ServerShutdown,
 }
-/*impl<'a, T, W> NewService for WorkQueueService<'a, T, W>
-where
-    T: 'a + Eq + Clone + Hash + Send + DeserializeOwned + Serialize,
-    W: 'a + Clone + Ord + Send + Serialize,
-{*/
-impl<'a> NewService for WorkQueueService<'a, CaptureWorkType, CaptureWork> {
-    /// Type used to represent the request body
-    type ReqBody = Body;
-    /// Type used to represent the response body
-    type ResBody = Body;
-    /// Type of error to return when requests fail
-    type Error = io::Error;
-
-    /// Type of service to return
-    type Service = Self;
-    /// Type of error to return when service creation fails
-    type InitError = io::Error;
-
-    /// Type of Future to return
-    type Future = Box<dyn 'a + Future<Item = Self::Service, Error = Self::InitError> + Send>;
-
-    /// This creates a new service instance
-    fn new_service(&self) -> Self::Future {
-        Box::new(future::ok(Self {
-            work_queue: self.work_queue.clone(),
-            report_sink: self.report_sink.clone(),
-            shutdown: self.shutdown.clone(),
-            _phantom: &PhantomData,
-        }))
-    }
-}
-
-impl<'a> Service for WorkQueueService<'a, CaptureWorkType, CaptureWork> {
-    /// Type used to represent the request body
-    type ReqBody = Body;
-    /// Type used to represent the response body
-    type ResBody = Body;
-
-    /// Type of error to return when requests fail
-    type Error = io::Error;
-    /// Type of Future to return
-    type Future = Box<dyn 'a + Future<Item = Response<Body>, Error = Self::Error> + Send>;
 
+impl WorkQueueService<CaptureWorkType, CaptureWork> {
     /// This handles requests to the server
     ///
     /// # Parameters
     /// * `request` - an HTTP request to the server
-    fn call(&mut self, request: Request<Body>) -> Self::Future {
+    pub async fn call(&self, request: Request<Body>) -> Result<Response<Body>, io::Error> {
         // Log the request
         info!("{} {}", request.method(), request.uri().path());
         // Dispatch the request
-        Box::new(
-            match (request.method(), request.uri().path()) {
-                (&Method::POST, "/client/add") => self.client_add(request),
-                (&Method::POST, "/client/remove") => self.client_remove(request),
-                (&Method::POST, "/work/get") => self.work_get(request),
-                (&Method::POST, "/work/report") => self.work_report(request),
-                _ => Box::new(future::ok(Response::new(Body::from("404")))),
-            }
-            .map_err(|err| {
+        let response = match (request.method(), request.uri().path()) {
+            (&Method::POST, "/client/add") => self.client_add(request).await,
+            (&Method::POST, "/client/remove") => self.client_remove(request).await,
+            (&Method::POST, "/work/get") => self.work_get(request).await,
+            (&Method::POST, "/work/peek") => self.work_peek(request).await,
+            (&Method::POST, "/work/report") => self.work_report(request).await,
+            (&Method::POST, "/work/report/batch") => self.work_report_batch(request).await,
+            (&Method::GET, "/stats") => self.stats(),
+            (&Method::GET, "/health") => health_response(true),
+            (&Method::GET, "/ready") => health_response(!self.shutdown.is_triggered()),
+            // Distinguish known paths requested with the wrong method from unknown paths
+            (_, path) if PATHS.contains(&path) => status_response(StatusCode::METHOD_NOT_ALLOWED),
+            _ => status_response(StatusCode::NOT_FOUND),
+        };
+        match response {
+            // Bodies that couldn't be parsed are the client's fault
+            Err(ref err) if err.kind() == io::ErrorKind::InvalidData => parse_error_response(err),
+            Err(err) => {
                 error!("Request error: {}", err);
-                err
-            }),
-        )
+                Err(err)
+            }
+            response => response,
+        }
     }
 }
 
-/*impl<'a, T, W> FromIterator<(T, W)> for WorkQueueService<'a, T, W>
-where
-    T: Eq + Clone + Hash + Send + DeserializeOwned + Serialize,
-    W: Clone + Ord + Send + Serialize,
-{
-    /// Creates a work queue service from an iterator of work items
+impl WorkQueueService<CaptureWorkType, CaptureWork> {
+    /// Constructor
     ///
     /// # Parameters
-    /// * `iter` - Iterator of (work type, work item)
-    fn from_iter<I: IntoIterator<Item = (T, W)>>(iter: I) -> Self {
-    }
-}*/
-
-impl<'a> WorkQueueService<'a, CaptureWorkType, CaptureWork> {
-    /// Constructor
-    pub fn new<I, P>(
+    /// * `work_iter` - Work to serve
+    /// * `options` - Where reports are written and how work is handed out
+    pub fn new<I>(
         work_iter: I,
-        output_path: P,
+        options: ServiceOptions,
     ) -> Result<(Self, shutdown::ServerShutdown), io::Error>
     where
         I: IntoIterator<Item = (CaptureWorkType, CaptureWork)>,
-        P: AsRef<Path>,
     {
         // Import work into a queue
-        let work_queue = WorkQueue::from_iter(work_iter);
-        // Open the given path
-        let output_file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(output_path)?;
-        // Create a buffered writer on the file
-        let report_sink = BufWriter::new(output_file);
-        // Create shutdown future
-        let shutdown_fut = shutdown::ServerShutdown::new();
+        WorkQueueService::with_work_queue(WorkQueue::from_iter(work_iter), options)
+    }
+    /// Constructor that serves work from an existing queue, such as one loaded from a snapshot
+    ///
+    /// # Parameters
+    /// * `work_queue` - Queue of work to serve
+    /// * `options` - Where reports are written and how work is handed out
+    pub fn with_work_queue(
+        mut work_queue: WorkQueue<CaptureWorkType, CaptureWork>,
+        options: ServiceOptions,
+    ) -> Result<(Self, shutdown::ServerShutdown), io::Error> {
+        let ServiceOptions {
+            report_path,
+            max_report_size,
+            failures_path,
+            max_retries,
+            reregistration_policy,
+            lease_timeout,
+            snapshot_path,
+            snapshot_interval,
+            flush_policy,
+        } = options;
+        work_queue.set_max_retries(max_retries);
+        work_queue.set_reregistration_policy(reregistration_policy);
+        work_queue.set_lease_timeout(lease_timeout);
+        // Open the given path, or the last numbered file after it
+        let output_file = RotatingFile::open(report_path, max_report_size)?;
+        // Create a writer on the file, flushed out as the policy asks
+        let report_sink = ReportSink::new(output_file, flush_policy);
+        // Open the failures file if given
+        let failure_sink = match failures_path {
+            Some(failures_path) => {
+                let failures_file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(failures_path)?;
+                // Dropped work is rare, so it's always flushed immediately
+                Some(Arc::new(Mutex::new(ReportSink::new(
+                    BufWriter::new(failures_file),
+                    FlushPolicy::default(),
+                ))))
+            }
+            None => None,
+        };
+        // Create shutdown signal
+        let shutdown = shutdown::ServerShutdown::new();
         // Create the service
         Ok((
             WorkQueueService {
                 work_queue: Arc::new(Mutex::new(work_queue)),
                 report_sink: Arc::new(Mutex::new(report_sink)),
-                shutdown: shutdown_fut.flag.clone(),
-                _phantom: &PhantomData,
+                reports_written: Arc::new(AtomicUsize::new(0)),
+                failure_sink,
+                snapshotter: snapshot_path
+                    .map(|path| Arc::new(Snapshotter::new(path, snapshot_interval))),
+                shutdown: shutdown.clone(),
             },
-            shutdown_fut,
+            shutdown,
         ))
     }
+    /// Flushes every written report out to the report and failure files
+    ///
+    /// Called on graceful shutdown, so reports held back by the flush policy aren't lost
+    pub fn flush(&self) -> Result<(), io::Error> {
+        self.report_sink
+            .lock()
+            .map_err(|_| as_io_error("failed to acquire mutex"))?
+            .flush()?;
+        if let Some(ref failure_sink) = self.failure_sink {
+            failure_sink
+                .lock()
+                .map_err(|_| as_io_error("failed to acquire mutex"))?
+                .flush()?;
+        }
+        Ok(())
+    }
+    /// Flushes written reports out to the report file if the flush policy's interval has
+    /// passed, so reports aren't held back indefinitely when no more arrive
+    pub fn flush_if_due(&self) -> Result<(), io::Error> {
+        self.report_sink
+            .lock()
+            .map_err(|_| as_io_error("failed to acquire mutex"))?
+            .flush_if_due()
+    }
     /// Responds to a request to add a new client
     ///
-    /// Assumes the request is a GET request
+    /// Assumes the request is a POST request
     /// # Parameters
-    /// * `clients` - Client storage object used to handle client ids
     /// * `request` - HTTP request from the client
-    fn client_add(&mut self, request: Request<Body>) -> <Self as Service>::Future {
-        // Get a cloned reference to the work queue
-        let work_queue = self.work_queue.clone();
-        // Create a response
-        let response_future = request
-            // Extract body of the request
-            .into_body()
-            // Concatenate it all together
-            .concat2()
-            // Convert hyper errors to io::Error
-            .map_err(as_io_error)
-            // Parse the request body and request a client id
-            .and_then(move |post_body|
-                // Parse the request as JSON
-                serde_json::from_slice(&post_body)
-                    // Using the parsed request, register the client and obtain a client ID
-                    // Convert errors to io::Error
-                    .map_err(as_io_error))
-            .and_then(move |request: AddClientRequest<CaptureWorkType>| {
-                // Get a lock on the work queue
-                work_queue
-                    .lock()
-                    .map(|mut wq| wq.add_client(request.work_types))
-                    .map_err(|_| as_io_error("t"))
+    async fn client_add(&self, request: Request<Body>) -> Result<Response<Body>, io::Error> {
+        // Parse the request as JSON
+        let request: AddClientRequest<CaptureWorkType> = parse_body(request).await?;
+        // Using the parsed request, register the client and obtain a client ID, unless it asked
+        // for work types that are never served
+        let registration = self
+            .work_queue
+            .lock()
+            .map(|mut wq| {
+                let unknown_work_types: Vec<String> = wq
+                    .unknown_work_types(&request.work_types)
+                    .iter()
+                    .map(|work_type| work_type.to_string())
+                    .collect();
+                if !unknown_work_types.is_empty() {
+                    return Err(unknown_work_types);
+                }
+                let client_id =
+                    wq.add_client(request.work_types, request.worker_id, request.max_in_flight);
+                wq.set_work_selection(client_id, request.work_selection);
+                Ok(client_id)
             })
-            // Create a response body
-            .and_then(|client_id: u64| {
-                // Create the response object
-                let response = AddClientResponse {
+            .map_err(|_| as_io_error("failed to acquire mutex"))?;
+        // Create the response object
+        let (status, response) = match registration {
+            Ok(client_id) => (
+                StatusCode::OK,
+                AddClientResponse {
                     success: true,
                     client_id,
                     error: None,
-                };
-                // Serialize the response
-                serde_json::to_string(&response)
-                    // Convert serialization errors to io::Error
-                    .map_err(as_io_error)
-            })
-            // Create a response object
-            .and_then(|body: String| Ok(Response::new(Body::from(body))));
-        // Box the future
-        Box::new(response_future)
+                },
+            ),
+            Err(unknown_work_types) => {
+                warn!(
+                    "Rejecting client asking for work types that are never served: {}",
+                    unknown_work_types.join(", ")
+                );
+                (
+                    StatusCode::BAD_REQUEST,
+                    AddClientResponse {
+                        success: false,
+                        client_id: 0,
+                        error: Some(format!(
+                            "work types are never served: {}",
+                            unknown_work_types.join(", ")
+                        )),
+                    },
+                )
+            }
+        };
+        // Serialize the response
+        let body = serde_json::to_string(&response).map_err(as_io_error)?;
+        json_response(status, body)
     }
     /// Responds to a request to remove a client
     ///
-    /// Assumes the request is a GET request
+    /// Assumes the request is a POST request
     /// # Parameters
-    /// * `work_queue` - WorkQueue that handles clients
     /// * `request` - HTTP request from the client
-    fn client_remove(&mut self, request: Request<Body>) -> <Self as Service>::Future {
-        // Get a cloned reference to the work queue
-        let work_queue = self.work_queue.clone();
-        let shutdown = self.shutdown.clone();
-        // Create a response
-        let response_future = request
-            // Extract body of the request
-            .into_body()
-            // Concatenate it all together
-            .concat2()
-            // Convert hyper errors to io::Error
-            .map_err(as_io_error)
-            // Parse the request body
-            .and_then(|post_body| {
-                serde_json::from_slice(&post_body)
-                    // Convert errors to io::Error
-                    .map_err(as_io_error)
-            })
-            // Extract client ID and remove the client
-            .and_then(move |request: RemoveClientRequest| {
-                // Remove the client
-                work_queue
-                    // Get mutex lock on client
-                    .lock()
-                    // Remove client ID from client
-                    .map(|mut work_queue| {
-                        work_queue.remove_client(request.client_id);
-                        work_queue.num_clients()
-                    })
-                    // Convert error to io::Error
-                    .map_err(|_| as_io_error("failed to acquire mutex"))
-            })
-            // Serialize a response body
-            .and_then(move |num_clients: usize| {
-                // Send shutdown signal
-                if num_clients == 0 {
-                    shutdown.store(true, Ordering::SeqCst);
+    async fn client_remove(&self, request: Request<Body>) -> Result<Response<Body>, io::Error> {
+        // Parse the request body
+        let request: RemoveClientRequest = parse_body(request).await?;
+        // Remove the client
+        let num_clients = self
+            .work_queue
+            // Get mutex lock on client
+            .lock()
+            // Remove client ID from client
+            .map(|mut work_queue| {
+                work_queue.remove_client(request.client_id);
+                // Save the remaining work before shutting down
+                if work_queue.num_clients() == 0 {
+                    if let Some(ref snapshotter) = self.snapshotter {
+                        self.save_snapshot(snapshotter, &work_queue);
+                    }
                 }
-                Ok(())
+                work_queue.num_clients()
             })
-            .and_then(|()| {
-                // Create the response object
-                let response = RemoveClientResponse {
-                    success: true,
-                    error: None,
-                };
-                // Serialize the response
-                (serde_json::to_string(&response))
-                    // Convert serialization errors to io::Error
-                    .map_err(as_io_error)
-            })
-            // Create a response object
-            .and_then(|body: String| Ok(Response::new(Body::from(body))));
-        Box::new(response_future)
+            // Convert error to io::Error
+            .map_err(|_| as_io_error("failed to acquire mutex"))?;
+        // Send shutdown signal
+        if num_clients == 0 {
+            self.shutdown.trigger();
+        }
+        // Create the response object
+        let response = RemoveClientResponse {
+            success: true,
+            error: None,
+        };
+        // Serialize the response
+        let body = serde_json::to_string(&response).map_err(as_io_error)?;
+        json_response(StatusCode::OK, body)
     }
 
     /// Responds to a request for work
     ///
     /// # Parameters
-    /// * `work_queue` - Queue to request work from
     /// * `request` - request from the client
-    fn work_get(&mut self, request: Request<Body>) -> <Self as Service>::Future {
-        // Get a cloned reference to the work queue
-        let work_queue = self.work_queue.clone();
-        // Create a response
-        let response_future = request
-            // Extract body of the request
-            .into_body()
-            // Concatenate it all together
-            .concat2()
-            // Convert hyper errors to io::Error
-            .map_err(as_io_error)
-            // Parse the request body as JSON
-            .and_then(|post_body| serde_json::from_slice(&post_body).map_err(as_io_error))
-            // Get a lock on the work queue and request work
-            .and_then(move |request: WorkRequest| {
-                // Lock the work queue mutex
-                work_queue
-                    .lock()
-                    // Request work
-                    .map(|mut work_queue| {
-                        work_queue
-                            .request_work(request.client_id)
-                            .ok_or_else(|| as_io_error("Failed to request work"))
-                    })
-                    // Convert error to io::Error
-                    .map_err(|_| as_io_error("failed to acquire mutex"))
+    async fn work_get(&self, request: Request<Body>) -> Result<Response<Body>, io::Error> {
+        // Parse the request body as JSON
+        let request: WorkRequest = parse_body(request).await?;
+        // Request work. An empty queue isn't an error, so the client can tell it apart from the
+        // server failing
+        let response = self
+            .work_queue
+            .lock()
+            .map(|mut work_queue| {
+                let work = work_queue.request_work(request.client_id);
+                // Tell a client at its limit apart from one that has run out of work
+                if work.is_none() && work_queue.in_flight_limit_reached(request.client_id) {
+                    WorkResponse::limit_reached()
+                } else {
+                    WorkResponse::new(work)
+                }
             })
-            // Flatten the future
-            .flatten()
-            .and_then(|(work_type, work): (CaptureWorkType, CaptureWork)| {
+            // Convert error to io::Error
+            .map_err(|_| as_io_error("failed to acquire mutex"))?;
+        // Serialize the response
+        let body = serde_json::to_string(&response).map_err(as_io_error)?;
+        json_response(StatusCode::OK, body)
+    }
+    /// Responds to a request to see the work a client would be given next, without reserving it
+    ///
+    /// # Parameters
+    /// * `request` - request from the client
+    async fn work_peek(&self, request: Request<Body>) -> Result<Response<Body>, io::Error> {
+        // Parse the request body as JSON
+        let request: WorkRequest = parse_body(request).await?;
+        // Look at the work without taking it from the queue
+        let response = self
+            .work_queue
+            .lock()
+            .map(|mut work_queue| {
+                let work = work_queue.peek_work(request.client_id);
+                // A client at its limit wouldn't be given the work, so it isn't shown any
+                if work.is_none() && work_queue.in_flight_limit_reached(request.client_id) {
+                    WorkResponse::limit_reached()
+                } else {
+                    WorkResponse::peeked(work)
+                }
+            })
+            // Convert error to io::Error
+            .map_err(|_| as_io_error("failed to acquire mutex"))?;
+        // Serialize the response
+        let body = serde_json::to_string(&response).map_err(as_io_error)?;
+        json_response(StatusCode::OK, body)
+    }
+}
+impl WorkQueueService<CaptureWorkType, CaptureWork> {
+    /// Responds to a request for statistics about the queue
+    fn stats(&self) -> Result<Response<Body>, io::Error> {
+        let body = self
+            .work_queue
+            .lock()
+            .map_err(|_| as_io_error("failed to acquire mutex"))
+            .and_then(|work_queue| {
                 // Create the response object
-                let response = WorkResponse {
-                    success: true,
-                    work_type,
-                    work,
-                    error: None,
+                let response = StatsResponse {
+                    remaining_work: work_queue.remaining_work(),
+                    num_clients: work_queue.num_clients(),
+                    reports_written: self.reports_written.load(Ordering::SeqCst),
                 };
                 // Serialize the response
                 serde_json::to_string(&response)
                     // Convert serialization errors to io::Error
                     .map_err(as_io_error)
-            })
-            // Create a response object
-            .and_then(|body: String| Ok(Response::new(Body::from(body))));
-        // Return the response as a future
-        Box::new(response_future)
+            })?;
+        // Create a response object
+        json_response(StatusCode::OK, body)
     }
-}
-impl<'a> WorkQueueService<'a, CaptureWorkType, CaptureWork> {
     /// Handles a work report
     ///
     /// Assumes the request is a POST request
     /// # Parameters
-    /// * `report_sink` - Sink to use for writing reports
     /// * `request` - incoming request
-    fn work_report(&mut self, request: Request<Body>) -> <Self as Service>::Future {
-        // Get a cloned reference to the report sink
-        let report_sink = self.report_sink.clone();
-        // Get a cloned reference to the work queue
-        let work_queue = self.work_queue.clone();
-        // Create a response
-        let response_future = request
-            // Extract body of the request
-            .into_body()
-            // Concatenate it all together
-            .concat2()
-            // Convert hyper errors to io::Error
-            .map_err(as_io_error)
-            // Parse the request body as JSON
-            .and_then(|post_body| serde_json::from_slice(&post_body).map_err(as_io_error))
-            // Get a lock on the work queue and request work
-            .and_then(
-                move |request: WorkReportRequest<CaptureWorkType, CaptureWork>| {
-                    if request.success {
-                        report_sink
-                            // Get mutex lock on report sink
-                            .lock()
-                            // Report the given work report
-                            .map(|mut report_sink| {
-                                // Convert the report back into json
-                                let report = serde_json::to_string(&request)?;
-                                // Write the report to a file
-                                writeln!(*report_sink, "{}", report)?;
-                                // Flush to the file immediately
-                                report_sink.flush()?;
-                                // Return success if nothing failed
-                                Ok(())
-                            })
-                            // Convert error to io::Error
-                            .map_err(|_| as_io_error("failed to acquire mutex"))
-                    } else {
-                        work_queue
-                            .lock()
-                            .map(|mut work_queue| {
-                                work_queue.add_work(request.work_type, request.work);
-                                Ok(())
-                            })
-                            .map_err(|_| as_io_error("failed to acquire mutex"))
-                    }
+    async fn work_report(&self, request: Request<Body>) -> Result<Response<Body>, io::Error> {
+        // Parse the request body as JSON
+        let request: WorkReportRequest<CaptureWorkType, CaptureWork> = parse_body(request).await?;
+        // Handle it as a batch of one, which has a single result
+        let result = self.handle_reports(&[request]).remove(0);
+        // Create the response object
+        let (status, response) = match result {
+            Ok(()) => (
+                StatusCode::OK,
+                WorkReportResponse {
+                    success: true,
+                    error: None,
                 },
-            )
-            .flatten()
-            .and_then(|()| {
-                // Create the response object
-                let response = WorkReportResponse {
+            ),
+            Err(err) => {
+                error!("Failed to handle work report: {}", err);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    WorkReportResponse {
+                        success: false,
+                        error: Some(err.to_string()),
+                    },
+                )
+            }
+        };
+        // Serialize the response
+        let body = serde_json::to_string(&response).map_err(as_io_error)?;
+        json_response(status, body)
+    }
+    /// Records successful work and requeues failed work, then saves the remaining work if a
+    /// snapshot is due
+    ///
+    /// Returns the result of handling each report, in order
+    /// # Parameters
+    /// * `reports` - The reports to handle
+    fn handle_reports(
+        &self,
+        reports: &[WorkReportRequest<CaptureWorkType, CaptureWork>],
+    ) -> Vec<Result<(), ReportError>> {
+        let results = handle_reports(
+            &self.work_queue,
+            &self.report_sink,
+            self.failure_sink.as_deref(),
+            reports,
+        );
+        let num_written = reports
+            .iter()
+            .zip(&results)
+            .filter(|(report, result)| report.success && result.is_ok())
+            .count();
+        self.reports_written
+            .fetch_add(num_written, Ordering::SeqCst);
+        // Save the remaining work now and then, since reports change it
+        if let Some(ref snapshotter) = self.snapshotter {
+            if snapshotter.is_due() {
+                if let Ok(work_queue) = self.work_queue.lock() {
+                    self.save_snapshot(snapshotter, &work_queue);
+                }
+            }
+        }
+        results
+    }
+    /// Saves a snapshot of the remaining work, once the reports of finished work are flushed
+    ///
+    /// Finished work is left out of the snapshot, so its reports must reach their files first
+    /// or a crash would lose the work entirely. If they can't be flushed, no snapshot is saved
+    /// # Parameters
+    /// * `snapshotter` - Saves the snapshot
+    /// * `work_queue` - Queue to save
+    fn save_snapshot(
+        &self,
+        snapshotter: &Snapshotter,
+        work_queue: &WorkQueue<CaptureWorkType, CaptureWork>,
+    ) {
+        match self.flush() {
+            Ok(()) => snapshotter.save_logged(work_queue),
+            Err(err) => error!(
+                "Not saving work queue snapshot, failed to flush reports: {}",
+                err
+            ),
+        }
+    }
+    /// Handles a batch of work reports
+    ///
+    /// The queue and sinks are each locked and flushed once for the whole batch. Responds with
+    /// the result of each report, in order
+    ///
+    /// Assumes the request is a POST request
+    /// # Parameters
+    /// * `request` - incoming request
+    async fn work_report_batch(&self, request: Request<Body>) -> Result<Response<Body>, io::Error> {
+        // Parse the request body as a JSON array of reports
+        let reports: Vec<WorkReportRequest<CaptureWorkType, CaptureWork>> =
+            parse_body(request).await?;
+        let results = self.handle_reports(&reports);
+        // Create the response object
+        let response: Vec<WorkReportResponse> = results
+            .into_iter()
+            .map(|result| match result {
+                Ok(()) => WorkReportResponse {
                     success: true,
                     error: None,
-                };
-                // Serialize the response
-                serde_json::to_string(&response)
-                    // Convert serialization errors to io::Error
-                    .map_err(as_io_error)
+                },
+                Err(err) => {
+                    error!("Failed to handle work report: {}", err);
+                    WorkReportResponse {
+                        success: false,
+                        error: Some(err.to_string()),
+                    }
+                }
+            })
+            .collect();
+        // Serialize the response
+        let body = serde_json::to_string(&response).map_err(as_io_error)?;
+        json_response(StatusCode::OK, body)
+    }
+}
+/// Errors that can occur while handling a work report
+#[derive(Debug, Fail)]
+enum ReportError {
+    #[fail(display = "failed to serialize report: {}", error)]
+    Serialize { error: serde_json::Error },
+    #[fail(display = "failed to acquire mutex")]
+    Mutex,
+    #[fail(display = "failed to write report: {}", error)]
+    Write { error: io::Error },
+}
+
+/// Writes reports to a sink as lines of JSON, taking the lock and flushing at most once for all
+/// of them
+///
+/// Returns the result of writing each report. If the flush fails, every report that was
+/// written fails with it
+///
+/// # Parameters
+/// * `report_sink` - Sink to write the reports to
+/// * `reports` - Reports to write
+fn write_reports<R, S>(
+    report_sink: &Mutex<ReportSink<S>>,
+    reports: &[&R],
+) -> Vec<Result<(), ReportError>>
+where
+    R: serde::Serialize,
+    S: Write,
+{
+    // Convert the reports back into json
+    let reports = reports.iter().map(|report| {
+        serde_json::to_string(report).map_err(|error| ReportError::Serialize { error })
+    });
+    // Get mutex lock on report sink
+    let mut report_sink = match report_sink.lock() {
+        Ok(report_sink) => report_sink,
+        Err(_) => {
+            return reports
+                .map(|report| report.and(Err(ReportError::Mutex)))
+                .collect()
+        }
+    };
+    // Write the reports to a file
+    let mut results: Vec<Result<(), ReportError>> = reports
+        .map(|report| {
+            report.and_then(|report| {
+                report_sink
+                    .write_line(&report)
+                    .map_err(|error| ReportError::Write { error })
             })
-            // Create a response object
-            .and_then(|body: String| Ok(Response::new(Body::from(body))));
-        // Return the response as a future
-        Box::new(response_future)
+        })
+        .collect();
+    // Flush to the file once for the whole batch, if the policy asks for it
+    if let Err(error) = report_sink.flush_if_due() {
+        for result in results.iter_mut().filter(|result| result.is_ok()) {
+            *result = Err(ReportError::Write {
+                error: io::Error::new(error.kind(), error.to_string()),
+            });
+        }
     }
+    results
 }
+
+/// Handles a batch of reports, locking the work queue once and each sink once for the whole
+/// batch
+///
+/// The work of every report is finished. Successful reports are written to the report sink,
+/// while failed work is requeued, or recorded in the failure sink if it has failed too many
+/// times to be requeued. Returns the result of handling each report, in order
+///
+/// # Parameters
+/// * `work_queue` - Queue the work came from
+/// * `report_sink` - Sink to write successful reports to
+/// * `failure_sink` - Sink to use for writing reports of dropped work
+/// * `reports` - The reports to handle
+fn handle_reports<T, W, S, F>(
+    work_queue: &Mutex<WorkQueue<T, W>>,
+    report_sink: &Mutex<ReportSink<S>>,
+    failure_sink: Option<&Mutex<ReportSink<F>>>,
+    reports: &[WorkReportRequest<T, W>],
+) -> Vec<Result<(), ReportError>>
+where
+    T: Clone + Eq + Hash + serde::Serialize,
+    W: Clone + Eq + Hash + Ord + serde::Serialize,
+    S: Write,
+    F: Write,
+{
+    // Finish the work of every report, requeueing failed work, and find the work that failed
+    // too many times to be requeued
+    let dropped: Vec<bool> = match work_queue.lock() {
+        Ok(mut work_queue) => reports
+            .iter()
+            .map(|report| {
+                work_queue.finish_work(&report.work_type, &report.work);
+                !report.success
+                    && !work_queue
+                        .requeue_failed_work(report.work_type.clone(), report.work.clone())
+            })
+            .collect(),
+        Err(_) => return reports.iter().map(|_| Err(ReportError::Mutex)).collect(),
+    };
+    // Write the successful reports
+    let successful_reports: Vec<&WorkReportRequest<T, W>> =
+        reports.iter().filter(|report| report.success).collect();
+    let mut successful_results = write_reports(report_sink, &successful_reports).into_iter();
+    // Record the dropped work
+    let dropped_reports: Vec<&WorkReportRequest<T, W>> = reports
+        .iter()
+        .zip(&dropped)
+        .filter(|(_, &dropped)| dropped)
+        .map(|(report, _)| report)
+        .collect();
+    if !dropped_reports.is_empty() {
+        warn!(
+            "Dropping {} pieces of work that failed too many times",
+            dropped_reports.len()
+        );
+    }
+    let mut dropped_results = match failure_sink {
+        Some(failure_sink) => write_reports(failure_sink, &dropped_reports),
+        None => dropped_reports.iter().map(|_| Ok(())).collect(),
+    }
+    .into_iter();
+    // Match the results back up with the reports
+    reports
+        .iter()
+        .zip(dropped)
+        .map(|(report, dropped)| {
+            if report.success {
+                successful_results.next().unwrap_or(Ok(()))
+            } else if dropped {
+                dropped_results.next().unwrap_or(Ok(()))
+            } else {
+                Ok(())
+            }
+        })
+        .collect()
+}
+
+/// Options for a work queue service
+#[derive(Clone, Debug)]
+pub struct ServiceOptions {
+    /// File to write reports to
+    pub report_path: PathBuf,
+    /// Size (in bytes) a report file may reach before moving to the next
+    pub max_report_size: Option<u64>,
+    /// File to write reports of work dropped after too many failures to
+    pub failures_path: Option<PathBuf>,
+    /// Number of times failed work is retried before it is dropped
+    pub max_retries: Option<usize>,
+    /// What to do when a worker registers again
+    pub reregistration_policy: ReregistrationPolicy,
+    /// Time a client has to report work before it is given out again
+    pub lease_timeout: Option<Duration>,
+    /// File to periodically save the remaining work to
+    pub snapshot_path: Option<PathBuf>,
+    /// Minimum time between saves of the remaining work
+    pub snapshot_interval: Duration,
+    /// When to flush written reports out to the report file
+    pub flush_policy: FlushPolicy,
+}
+
+impl ServiceOptions {
+    /// Constructor with the defaults for every option but the report file
+    ///
+    /// # Parameters
+    /// * `report_path` - File to write reports to
+    pub fn new<P: Into<PathBuf>>(report_path: P) -> Self {
+        ServiceOptions {
+            report_path: report_path.into(),
+            max_report_size: None,
+            failures_path: None,
+            max_retries: None,
+            reregistration_policy: ReregistrationPolicy::default(),
+            lease_timeout: None,
+            snapshot_path: None,
+            snapshot_interval: Duration::from_secs(60),
+            flush_policy: FlushPolicy::default(),
+        }
+    }
+}
+
+/// When written reports are flushed out to their file
+///
+/// Reports are buffered until they're flushed, so any that haven't been are lost if the server
+/// crashes. Flushing after every report is the most durable, while flushing less often is
+/// faster when many reports arrive. Reports are always flushed on graceful shutdown
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FlushPolicy {
+    /// Number of reports to write before flushing them. 1 flushes after every report
+    pub every: usize,
+    /// Time after which written reports are flushed, even if fewer than `every` were written
+    pub interval: Option<Duration>,
+}
+
+impl Default for FlushPolicy {
+    /// Flushes after every report
+    fn default() -> Self {
+        FlushPolicy {
+            every: 1,
+            interval: None,
+        }
+    }
+}
+
+/// Writes reports as lines to a writer, flushing them out as a flush policy asks
+struct ReportSink<S> {
+    /// Writer the reports are written to
+    writer: S,
+    /// When written reports are flushed out
+    policy: FlushPolicy,
+    /// Number of reports written since the last flush
+    unflushed: usize,
+    /// Time of the last flush
+    last_flush: Instant,
+}
+
+impl<S: Write> ReportSink<S> {
+    /// Constructor
+    ///
+    /// # Parameters
+    /// * `writer` - Writer to write reports to
+    /// * `policy` - When written reports are flushed out
+    fn new(writer: S, policy: FlushPolicy) -> Self {
+        ReportSink {
+            writer,
+            policy,
+            unflushed: 0,
+            last_flush: Instant::now(),
+        }
+    }
+    /// Writes a report as a line, without flushing it
+    ///
+    /// # Parameters
+    /// * `report` - Serialized report to write
+    fn write_line(&mut self, report: &str) -> Result<(), io::Error> {
+        writeln!(self.writer, "{}", report)?;
+        self.unflushed += 1;
+        Ok(())
+    }
+    /// Flushes the written reports if enough were written, or enough time has passed, since
+    /// the last flush
+    fn flush_if_due(&mut self) -> Result<(), io::Error> {
+        let due = self.unflushed > 0
+            && (self.unflushed >= self.policy.every
+                || self
+                    .policy
+                    .interval
+                    .is_some_and(|interval| self.last_flush.elapsed() >= interval));
+        if due {
+            self.flush()?;
+        }
+        Ok(())
+    }
+    /// Flushes the written reports
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.writer.flush()?;
+        self.unflushed = 0;
+        self.last_flush = Instant::now();
+        Ok(())
+    }
+}
+
+/// Saves snapshots of a work queue to a file
+struct Snapshotter {
+    /// File to save snapshots to
+    path: PathBuf,
+    /// Minimum time between snapshots
+    interval: Duration,
+    /// Time the last snapshot was saved
+    last_save: Mutex<Option<Instant>>,
+}
+
+impl Snapshotter {
+    /// Constructor
+    ///
+    /// # Parameters
+    /// * `path` - File to save snapshots to
+    /// * `interval` - Minimum time between snapshots
+    fn new(path: PathBuf, interval: Duration) -> Self {
+        Snapshotter {
+            path,
+            interval,
+            last_save: Mutex::new(None),
+        }
+    }
+    /// Whether enough time has passed since the last snapshot to save another
+    fn is_due(&self) -> bool {
+        match self.last_save.lock() {
+            Ok(last_save) => match *last_save {
+                Some(last_save) => last_save.elapsed() >= self.interval,
+                None => true,
+            },
+            Err(_) => false,
+        }
+    }
+    /// Saves a snapshot, logging any failure instead of failing the request that caused it
+    ///
+    /// # Parameters
+    /// * `work_queue` - Queue to save
+    fn save_logged<T, W>(&self, work_queue: &WorkQueue<T, W>)
+    where
+        T: Clone + Eq + Hash + DeserializeOwned + serde::Serialize,
+        W: Clone + Hash + Ord + DeserializeOwned + serde::Serialize,
+    {
+        match self.save(work_queue) {
+            Ok(()) => {
+                if let Ok(mut last_save) = self.last_save.lock() {
+                    *last_save = Some(Instant::now());
+                }
+            }
+            Err(err) => error!("Failed to save work queue snapshot: {}", err),
+        }
+    }
+    /// Saves a snapshot, replacing the previous one atomically so a crash while saving doesn't
+    /// lose it
+    ///
+    /// # Parameters
+    /// * `work_queue` - Queue to save
+    fn save<T, W>(&self, work_queue: &WorkQueue<T, W>) -> Result<(), io::Error>
+    where
+        T: Clone + Eq + Hash + DeserializeOwned + serde::Serialize,
+        W: Clone + Hash + Ord + DeserializeOwned + serde::Serialize,
+    {
+        // Write to a temporary file next to the snapshot, then move it into place
+        let mut temp_file_name = self
+            .path
+            .file_name()
+            .map(|file_name| file_name.to_os_string())
+            .unwrap_or_default();
+        temp_file_name.push(".tmp");
+        let temp_path = self.path.with_file_name(temp_file_name);
+        {
+            let mut writer = BufWriter::new(File::create(&temp_path)?);
+            work_queue.save_to(&mut writer).map_err(as_io_error)?;
+            writer.flush()?;
+        }
+        fs::rename(&temp_path, &self.path)
+    }
+}
+
+/// Paths the service handles, with any method
+const PATHS: [&str; 9] = [
+    "/client/add",
+    "/client/remove",
+    "/work/get",
+    "/work/peek",
+    "/work/report",
+    "/work/report/batch",
+    "/stats",
+    "/health",
+    "/ready",
+];
+
+/// Reads the body of a request, and parses it as JSON
+///
+/// # Parameters
+/// * `request` - Request to read the body of
+async fn parse_body<R: DeserializeOwned>(request: Request<Body>) -> Result<R, io::Error> {
+    // Read the entire body
+    let body = hyper::body::to_bytes(request.into_body())
+        .await
+        // Convert hyper errors to io::Error
+        .map_err(as_io_error)?;
+    serde_json::from_slice(&body).map_err(as_parse_error)
+}
+
+/// Creates a response with a JSON body
+///
+/// # Parameters
+/// * `status` - Status of the response
+/// * `body` - Serialized JSON body
+fn json_response(status: StatusCode, body: String) -> Result<Response<Body>, io::Error> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .map_err(as_io_error)
+}
+
+/// Creates a response to a liveness or readiness probe
+///
+/// Probes never lock the work queue, so they're answered even while it's contended. The
+/// service is only created once its work has been loaded, so it's ready until it starts
+/// shutting down
+///
+/// # Parameters
+/// * `ok` - Whether the probe passed
+fn health_response(ok: bool) -> Result<Response<Body>, io::Error> {
+    let (status, response) = if ok {
+        (StatusCode::OK, HealthResponse { status: "ok" })
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            HealthResponse {
+                status: "unavailable",
+            },
+        )
+    };
+    let body = serde_json::to_string(&response).map_err(as_io_error)?;
+    json_response(status, body)
+}
+
+/// Creates a response whose body is just its status code
+///
+/// # Parameters
+/// * `status` - Status of the response
+fn status_response(status: StatusCode) -> Result<Response<Body>, io::Error> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(status.as_str().to_string()))
+        .map_err(as_io_error)
+}
+
+/// Creates a 400 response telling the client why its body couldn't be parsed
+///
+/// # Parameters
+/// * `error` - The parse error, which includes where in the body parsing failed
+fn parse_error_response(error: &io::Error) -> Result<Response<Body>, io::Error> {
+    warn!("Malformed request body: {}", error);
+    let response = ParseErrorResponse {
+        success: false,
+        error: error.to_string(),
+    };
+    let body = serde_json::to_string(&response).map_err(as_io_error)?;
+    json_response(StatusCode::BAD_REQUEST, body)
+}
+
+/// Converts a failure to parse a request body to `io::Error`, marked as invalid data so it's
+/// answered with a 400
+///
+/// # Parameters
+/// * `error` - The parse error
+fn as_parse_error(error: serde_json::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
 /// Function to convert errors and strings to `io::Error`
 ///
 /// # Parameters
@@ -401,5 +936,290 @@ fn as_io_error<E>(error: E) -> io::Error
 where
     E: Into<Box<dyn error::Error + Send + Sync>>,
 {
-    io::Error::new(io::ErrorKind::Other, error)
+    io::Error::other(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::ser::{self, Serializer};
+
+    /// Creates a sink writing reports to memory, flushing after every report
+    fn sink() -> Mutex<ReportSink<Vec<u8>>> {
+        Mutex::new(ReportSink::new(Vec::new(), FlushPolicy::default()))
+    }
+
+    /// A report that always fails to serialize
+    struct UnserializableReport;
+    impl serde::Serialize for UnserializableReport {
+        fn serialize<S: Serializer>(&self, _serializer: S) -> Result<S::Ok, S::Error> {
+            Err(ser::Error::custom("unserializable metadata"))
+        }
+    }
+
+    /// Tests that work is dropped and recorded once it fails too many times
+    #[test]
+    fn test_handle_failed_report() {
+        let mut work_queue: WorkQueue<u8, u64> = vec![(0, 7)].into_iter().collect();
+        work_queue.set_max_retries(Some(1));
+        let client_id = work_queue.add_client(vec![0], None, None);
+        let work_queue = Mutex::new(work_queue);
+        let failure_sink = sink();
+        let report = |work| WorkReportRequest {
+            success: false,
+            work_type: 0,
+            work,
+            type_index: 1,
+            start_time: 0,
+            finish_time: 0,
+        };
+        // The first failure is requeued
+        let (_, work) = work_queue.lock().unwrap().request_work(client_id).unwrap();
+        let results = handle_reports(&work_queue, &sink(), Some(&failure_sink), &[report(work)]);
+        assert!(results[0].is_ok());
+        assert!(failure_sink.lock().unwrap().writer.is_empty());
+        // The second failure is dropped and recorded
+        let (_, work) = work_queue.lock().unwrap().request_work(client_id).unwrap();
+        let results = handle_reports(&work_queue, &sink(), Some(&failure_sink), &[report(work)]);
+        assert!(results[0].is_ok());
+        assert_eq!(work_queue.lock().unwrap().request_work(client_id), None);
+        let failures = String::from_utf8(failure_sink.into_inner().unwrap().writer).unwrap();
+        assert!(failures.contains("\"work\":7"));
+    }
+
+    /// Tests that a batch of reports is handled as each report would be on its own
+    #[test]
+    fn test_handle_reports() {
+        let mut work_queue: WorkQueue<u8, u64> = vec![(0, 7), (0, 8), (0, 9)].into_iter().collect();
+        work_queue.set_max_retries(Some(0));
+        let client_id = work_queue.add_client(vec![0], None, None);
+        let work_queue = Mutex::new(work_queue);
+        let report_sink = sink();
+        let failure_sink = sink();
+        let report = |success, work| WorkReportRequest {
+            success,
+            work_type: 0,
+            work,
+            type_index: 1,
+            start_time: 0,
+            finish_time: 0,
+        };
+        let mut reports = Vec::new();
+        for &success in &[true, false, true] {
+            let (_, work) = work_queue.lock().unwrap().request_work(client_id).unwrap();
+            reports.push(report(success, work));
+        }
+        let results = handle_reports(&work_queue, &report_sink, Some(&failure_sink), &reports);
+        assert_eq!(results.len(), 3);
+        assert!(results.iter().all(Result::is_ok));
+        // Successful reports are written in order, and the failure is dropped and recorded
+        let written = String::from_utf8(report_sink.into_inner().unwrap().writer).unwrap();
+        let written: Vec<&str> = written.lines().collect();
+        assert_eq!(written.len(), 2);
+        let work = |index: usize| format!("\"work\":{}", reports[index].work);
+        assert!(written[0].contains(&work(0)));
+        assert!(written[1].contains(&work(2)));
+        let failures = String::from_utf8(failure_sink.into_inner().unwrap().writer).unwrap();
+        assert_eq!(failures.lines().count(), 1);
+        assert!(failures.contains(&work(1)));
+        assert_eq!(work_queue.lock().unwrap().request_work(client_id), None);
+    }
+
+    /// Tests that each report in a batch is written, or fails on its own
+    #[test]
+    fn test_write_reports() {
+        let report_sink = sink();
+        let results = write_reports(&report_sink, &[&vec![1], &vec![2, 3]]);
+        assert!(results.iter().all(Result::is_ok));
+        assert_eq!(report_sink.into_inner().unwrap().writer, b"[1]\n[2,3]\n");
+        let report_sink = sink();
+        let results = write_reports(&report_sink, &[&UnserializableReport]);
+        assert!(matches!(results[0], Err(ReportError::Serialize { .. })));
+        assert!(report_sink.into_inner().unwrap().writer.is_empty());
+    }
+
+    /// Tests that reports are flushed once enough are written, or enough time has passed
+    #[test]
+    fn test_flush_policy() {
+        let policy = FlushPolicy {
+            every: 3,
+            interval: None,
+        };
+        let mut report_sink = ReportSink::new(BufWriter::new(Vec::new()), policy);
+        for _ in 0..2 {
+            report_sink.write_line("{}").unwrap();
+            report_sink.flush_if_due().unwrap();
+        }
+        assert!(report_sink.writer.get_ref().is_empty());
+        report_sink.write_line("{}").unwrap();
+        report_sink.flush_if_due().unwrap();
+        assert_eq!(report_sink.writer.get_ref(), b"{}\n{}\n{}\n");
+        // Reports are flushed once the interval passes, however few were written
+        let policy = FlushPolicy {
+            every: 100,
+            interval: Some(Duration::from_millis(0)),
+        };
+        let mut report_sink = ReportSink::new(BufWriter::new(Vec::new()), policy);
+        report_sink.flush_if_due().unwrap();
+        report_sink.write_line("{}").unwrap();
+        report_sink.flush_if_due().unwrap();
+        assert_eq!(report_sink.writer.get_ref(), b"{}\n");
+    }
+
+    /// Tests that reports held back by the flush policy are flushed before a snapshot leaves
+    /// their work out
+    #[test]
+    fn test_snapshot_flushes_reports() {
+        let temp_path = |name: &str| {
+            std::env::temp_dir().join(format!(
+                "url_queue_test_snapshot_flushes_reports_{}_{}",
+                std::process::id(),
+                name
+            ))
+        };
+        let report_path = temp_path("reports.json");
+        let snapshot_path = temp_path("snapshot.json");
+        let work = CaptureWork {
+            index: 0,
+            url: "example.com".to_string(),
+            filename: PathBuf::from("example.pcap"),
+        };
+        let options = ServiceOptions {
+            snapshot_path: Some(snapshot_path.clone()),
+            snapshot_interval: Duration::from_secs(0),
+            flush_policy: FlushPolicy {
+                every: 100,
+                interval: None,
+            },
+            ..ServiceOptions::new(&report_path)
+        };
+        let (service, _) =
+            WorkQueueService::new(vec![(CaptureWorkType::Tor, work.clone())], options).unwrap();
+        let results = service.handle_reports(&[WorkReportRequest {
+            success: true,
+            work_type: CaptureWorkType::Tor,
+            work,
+            type_index: 1,
+            start_time: 0,
+            finish_time: 0,
+        }]);
+        assert!(results[0].is_ok());
+        assert!(snapshot_path.exists());
+        let reports = fs::read_to_string(&report_path).unwrap();
+        assert!(reports.contains("example.com"));
+        fs::remove_file(&report_path).unwrap();
+        fs::remove_file(&snapshot_path).unwrap();
+    }
+
+    /// Tests that each kind of request is answered with the matching status code
+    #[test]
+    fn test_status_codes() {
+        let report_path = std::env::temp_dir().join(format!(
+            "url_queue_test_status_codes_{}.json",
+            std::process::id()
+        ));
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let work = CaptureWork {
+            index: 0,
+            url: "example.com".to_string(),
+            filename: PathBuf::from("example.pcap"),
+        };
+        let (service, _) = WorkQueueService::new(
+            vec![(CaptureWorkType::Tor, work)],
+            ServiceOptions::new(&report_path),
+        )
+        .unwrap();
+        let status = |method: Method, path: &str, body: &'static str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(path)
+                .body(Body::from(body))
+                .unwrap();
+            runtime.block_on(service.call(request)).unwrap().status()
+        };
+        assert_eq!(
+            status(Method::POST, "/client/add", r#"{"work_types": ["tor"]}"#),
+            StatusCode::OK
+        );
+        // Clients asking for work types that are never served are rejected
+        assert_eq!(
+            status(
+                Method::POST,
+                "/client/add",
+                r#"{"work_types": ["tor", "normal"]}"#
+            ),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(status(Method::GET, "/stats", ""), StatusCode::OK);
+        assert_eq!(
+            status(Method::POST, "/work/report/batch", "[]"),
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(Method::POST, "/work/report/batch", "not json"),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(Method::POST, "/work/get", "not json"),
+            StatusCode::BAD_REQUEST
+        );
+        // Bodies that don't match the schema are explained to the client
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/client/add")
+            .body(Body::from(r#"{"work_types": "tor"}"#))
+            .unwrap();
+        let response = runtime.block_on(service.call(request)).unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = runtime
+            .block_on(hyper::body::to_bytes(response.into_body()))
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["success"], false);
+        let error = body["error"].as_str().unwrap();
+        assert!(error.contains("invalid type"), "{}", error);
+        assert!(error.contains("line 1 column"), "{}", error);
+        assert_eq!(
+            status(Method::GET, "/work/get", ""),
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+        assert_eq!(
+            status(Method::GET, "/work/peek", ""),
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+        assert_eq!(status(Method::GET, "/missing", ""), StatusCode::NOT_FOUND);
+        assert_eq!(status(Method::GET, "/health", ""), StatusCode::OK);
+        assert_eq!(status(Method::GET, "/ready", ""), StatusCode::OK);
+        // The last client leaving shuts the server down, so it's no longer ready
+        assert_eq!(
+            status(Method::POST, "/client/remove", r#"{"client_id": 1}"#),
+            StatusCode::OK
+        );
+        assert_eq!(status(Method::GET, "/health", ""), StatusCode::OK);
+        assert_eq!(
+            status(Method::GET, "/ready", ""),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        fs::remove_file(&report_path).unwrap();
+    }
+
+    /// Tests that reports are written as lines of JSON
+    #[test]
+    fn test_write_report() {
+        let report_sink = sink();
+        let results = write_reports(&report_sink, &[&vec![1, 2, 3]]);
+        assert!(results[0].is_ok());
+        assert_eq!(report_sink.into_inner().unwrap().writer, b"[1,2,3]\n");
+    }
+
+    /// Tests that a report that fails to serialize is reported as a serialization error
+    #[test]
+    fn test_write_report_serialize_error() {
+        let report_sink = sink();
+        let mut results = write_reports(&report_sink, &[&UnserializableReport]);
+        let error = results.pop().unwrap().unwrap_err();
+        assert!(matches!(error, ReportError::Serialize { .. }));
+        assert!(error.to_string().starts_with("failed to serialize report"));
+        assert!(report_sink.into_inner().unwrap().writer.is_empty());
+    }
 }
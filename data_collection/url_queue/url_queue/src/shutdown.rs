@@ -13,32 +13,78 @@
 //
 // You should have received a copy of the GNU General Public License
 // along with url_queue.  If not, see <http://www.gnu.org/licenses/>.
-use std::io;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
-use futures::{Async, Future, Poll};
+use tokio::sync::Notify;
 
+/// Signals the server to shut down
+///
+/// Clones share the same signal, so one can be given to the server while others trigger it
+#[derive(Clone, Default)]
 pub struct ServerShutdown {
-    pub flag: Arc<AtomicBool>,
+    /// Whether shutdown has been triggered
+    flag: Arc<AtomicBool>,
+    /// Wakes the tasks waiting for shutdown
+    notify: Arc<Notify>,
 }
 impl ServerShutdown {
     pub fn new() -> Self {
-        ServerShutdown {
-            flag: Arc::new(AtomicBool::new(false)),
+        ServerShutdown::default()
+    }
+    /// Triggers shutdown, waking every task waiting for it
+    pub fn trigger(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+    /// Whether shutdown has been triggered
+    pub fn is_triggered(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+    /// Waits until shutdown is triggered
+    pub async fn wait(&self) {
+        loop {
+            // Register for notifications before checking the flag, so a trigger in between
+            // isn't missed
+            let notified = self.notify.notified();
+            if self.is_triggered() {
+                return;
+            }
+            notified.await;
         }
     }
 }
 
-impl Future for ServerShutdown {
-    type Item = ();
-    type Error = io::Error;
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        // Check the flag
-        if self.flag.load(Ordering::SeqCst) {
-            Ok(Async::Ready(()))
-        } else {
-            Ok(Async::NotReady)
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use tokio::runtime::Runtime;
+    use tokio::time;
+
+    /// Tests that waiting tasks are woken as soon as shutdown is triggered, rather than
+    /// whenever they happen to be polled again
+    #[test]
+    fn test_wait_resolves_promptly() {
+        let runtime = Runtime::new().unwrap();
+        let shutdown = ServerShutdown::new();
+        let trigger = shutdown.clone();
+        let triggered_at = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            let triggered_at = Instant::now();
+            trigger.trigger();
+            triggered_at
+        });
+        runtime
+            .block_on(async { time::timeout(Duration::from_secs(5), shutdown.wait()).await })
+            .unwrap();
+        let resolved_at = Instant::now();
+        let triggered_at = triggered_at.join().unwrap();
+        assert!(resolved_at - triggered_at < Duration::from_millis(500));
+        // Waiting after shutdown was triggered resolves immediately
+        runtime
+            .block_on(async { time::timeout(Duration::from_secs(5), shutdown.wait()).await })
+            .unwrap();
     }
 }
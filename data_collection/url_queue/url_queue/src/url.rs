@@ -14,10 +14,19 @@
 // You should have received a copy of the GNU General Public License
 // along with url_queue.  If not, see <http://www.gnu.org/licenses/>.
 
-use csv;
+use flate2::read::GzDecoder;
+use log::warn;
 use serde_derive::{Deserialize, Serialize};
-use std::io::{self};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read};
 use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+/// Error code for a stale NFS file handle
+const ESTALE: i32 = 116;
+/// Bytes every gzip stream starts with
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
 
 /// Shorthand for an iterator that returns `UrlEntry`
 type UrlIterator = Box<dyn Iterator<Item = UrlEntry> + Send>;
@@ -50,12 +59,35 @@ impl UrlsReader {
 pub struct UrlsReaderBuilder {
     /// The maximum number of URLs to read
     limit: Option<usize>,
+    /// Number of times to retry opening the file after a transient error
+    retries: usize,
+    /// Time to wait before the first retry, doubled after each retry
+    backoff: Duration,
+    /// Whether the first row of the file is a header
+    has_headers: bool,
+    /// Delimiter between columns
+    delimiter: u8,
+    /// Column holding the index of each URL
+    index_column: usize,
+    /// Column holding each URL
+    url_column: usize,
+    /// Whether to number the URLs from 1 across all files, instead of using their indices
+    renumber: bool,
 }
 
 impl Default for UrlsReaderBuilder {
     /// Returns a UrlsReaderBuilder with no extra options
     fn default() -> Self {
-        UrlsReaderBuilder { limit: None }
+        UrlsReaderBuilder {
+            limit: None,
+            retries: 0,
+            backoff: Duration::from_millis(100),
+            has_headers: false,
+            delimiter: b',',
+            index_column: 0,
+            url_column: 1,
+            renumber: false,
+        }
     }
 }
 impl UrlsReaderBuilder {
@@ -78,33 +110,169 @@ impl UrlsReaderBuilder {
         self
     }
 
+    /// Retries opening the file if it fails with a transient error, such as a network mount
+    /// that is briefly unavailable
+    ///
+    /// # Parameters
+    /// * `retries` - the maximum number of times to retry
+    /// * `backoff` - time to wait before the first retry, which is doubled after each retry
+    #[inline]
+    pub fn with_retries(mut self, retries: usize, backoff: Duration) -> Self {
+        self.retries = retries;
+        self.backoff = backoff;
+        self
+    }
+
+    /// Sets whether the first row of the file is a header, which is skipped
+    ///
+    /// # Parameters
+    /// * `has_headers` - whether the file has a header row
+    #[inline]
+    pub fn with_headers(mut self, has_headers: bool) -> Self {
+        self.has_headers = has_headers;
+        self
+    }
+
+    /// Sets the delimiter between columns
+    ///
+    /// # Parameters
+    /// * `delimiter` - the delimiter, such as `b'\t'` for tab separated files
+    #[inline]
+    pub fn with_delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Sets which columns hold the index and URL of each entry. Other columns are ignored
+    ///
+    /// # Parameters
+    /// * `index_column` - position of the column holding the index, starting at 0
+    /// * `url_column` - position of the column holding the URL, starting at 0
+    #[inline]
+    pub fn with_columns(mut self, index_column: usize, url_column: usize) -> Self {
+        self.index_column = index_column;
+        self.url_column = url_column;
+        self
+    }
+
+    /// Sets whether to number the URLs from 1 across all of the files read, instead of using
+    /// the indices given in the files
+    ///
+    /// # Parameters
+    /// * `renumber` - whether to renumber the URLs
+    #[inline]
+    pub fn with_renumbering(mut self, renumber: bool) -> Self {
+        self.renumber = renumber;
+        self
+    }
+
+    /// Opens a file, retrying transient errors
+    ///
+    /// # Parameters
+    /// * `path` - path of the file to open
+    fn open_file(&self, path: &Path) -> Result<File, io::Error> {
+        let mut backoff = self.backoff;
+        let mut retries_left = self.retries;
+        loop {
+            match File::open(path) {
+                Ok(file) => return Ok(file),
+                Err(ref error) if retries_left > 0 && is_transient(error) => {
+                    warn!(
+                        "Failed to open {:?}: {}. Retrying in {:?}",
+                        path, error, backoff
+                    );
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                    retries_left -= 1;
+                }
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
     /// Reads from some path
     ///
+    /// Gzipped files, such as the .csv.gz lists that top 1M lists are published as, are
+    /// decompressed as they're read
+    ///
     /// # Parameters
     /// * `path` - path to read the urls from
-    /// * `limit` - the maximum number of URLs to read
+    #[allow(dead_code)]
     pub fn open<P>(self, path: P) -> Result<UrlsReader, io::Error>
     where
         P: AsRef<Path>,
     {
-        // Get an object that reads the CSV
-        let csv_reader = csv::ReaderBuilder::new()
-            // TODO: make this configurable
-            .has_headers(false)
-            // Fail here if we fail to open the file
-            .from_path(path)?
-            // Deserialize the records and take ownership
-            .into_deserialize()
-            // Flatten to remove errors
-            .flatten();
+        self.open_many(vec![path])
+    }
+
+    /// Reads from several paths, one after another
+    ///
+    /// The limit applies to the URLs of all of the files together
+    ///
+    /// # Parameters
+    /// * `paths` - paths to read the urls from, in order
+    pub fn open_many<P>(self, paths: Vec<P>) -> Result<UrlsReader, io::Error>
+    where
+        P: AsRef<Path>,
+    {
+        // Fail here if we fail to open any of the files
+        let csv_readers = paths
+            .iter()
+            .map(|path| self.open_entries(path.as_ref()))
+            .collect::<Result<Vec<UrlIterator>, io::Error>>()?;
+        let csv_reader = csv_readers.into_iter().flatten();
+        // Number the URLs across all of the files if asked to
+        let csv_reader: UrlIterator = if self.renumber {
+            Box::new(
+                (1..)
+                    .zip(csv_reader)
+                    .map(|(index, url_entry)| UrlEntry { index, ..url_entry }),
+            )
+        } else {
+            Box::new(csv_reader)
+        };
         // Add limit if given
         let csv_reader: UrlIterator = match self.limit {
             Some(limit) => Box::new(csv_reader.take(limit)),
-            None => Box::new(csv_reader),
+            None => csv_reader,
         };
         // Create our object
         Ok(UrlsReader { csv_reader })
     }
+
+    /// Opens a file, and reads the entries in it
+    ///
+    /// # Parameters
+    /// * `path` - path to read the urls from
+    fn open_entries(&self, path: &Path) -> Result<UrlIterator, io::Error> {
+        let mut file = BufReader::new(self.open_file(path)?);
+        // Detect gzipped files by their contents, so the extension doesn't matter
+        let file: Box<dyn Read + Send> = if file.fill_buf()?.starts_with(&GZIP_MAGIC) {
+            Box::new(GzDecoder::new(file))
+        } else {
+            Box::new(file)
+        };
+        // Get an object that reads the CSV
+        let (index_column, url_column) = (self.index_column, self.url_column);
+        let csv_reader = csv::ReaderBuilder::new()
+            .has_headers(self.has_headers)
+            .delimiter(self.delimiter)
+            // Rows may have any number of columns, as long as they have the ones we use
+            .flexible(true)
+            .from_reader(file)
+            // Read the records and take ownership
+            .into_records()
+            // Flatten to remove errors
+            .flatten()
+            // Pick the index and URL out of each record, skipping malformed ones
+            .flat_map(move |record| {
+                Some(UrlEntry {
+                    index: record.get(index_column)?.trim().parse().ok()?,
+                    url: record.get(url_column)?.trim().to_string(),
+                })
+            });
+        Ok(Box::new(csv_reader))
+    }
 }
 
 impl Iterator for UrlsReader {
@@ -117,3 +285,156 @@ impl Iterator for UrlsReader {
         self.csv_reader.next()
     }
 }
+
+/// Whether an error opening a file may go away if the open is retried
+///
+/// A missing file counts as transient, since a network mount may not be visible yet
+///
+/// # Parameters
+/// * `error` - the error from opening the file
+fn is_transient(error: &io::Error) -> bool {
+    match error.kind() {
+        ErrorKind::Interrupted | ErrorKind::NotFound | ErrorKind::TimedOut => true,
+        _ => error.raw_os_error() == Some(ESTALE),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    /// Tests that the reader succeeds once a missing file appears
+    #[test]
+    fn test_open_retry() {
+        let path = env::temp_dir().join(format!("url_queue_test_{}.csv", process::id()));
+        drop(fs::remove_file(&path));
+        // Without retries, the missing file is an error
+        assert!(UrlsReader::build().open(&path).is_err());
+        // The file appears after a delay, as a network mount might. It's written elsewhere and
+        // moved into place, so the reader never sees it partly written
+        let temp_path = path.with_extension("csv.tmp");
+        let writer_path = path.clone();
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            fs::write(&temp_path, "1,example.com\n2,example.org\n").unwrap();
+            fs::rename(&temp_path, &writer_path).unwrap();
+        });
+        let urls: Vec<String> = UrlsReader::build()
+            .with_retries(10, Duration::from_millis(10))
+            .open(&path)
+            .unwrap()
+            .map(|url_entry| url_entry.url)
+            .collect();
+        writer.join().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(urls, vec!["example.com", "example.org"]);
+    }
+
+    /// Tests that gzipped files are read the same as plain files
+    #[test]
+    fn test_open_gzip() {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+        use std::io::Write;
+        let contents = "1,example.com\n2,example.org\n";
+        let plain_path =
+            env::temp_dir().join(format!("url_queue_test_{}_plain.csv", process::id()));
+        let gzip_path =
+            env::temp_dir().join(format!("url_queue_test_{}_gzip.csv.gz", process::id()));
+        fs::write(&plain_path, contents).unwrap();
+        let mut encoder = GzEncoder::new(File::create(&gzip_path).unwrap(), Compression::default());
+        encoder.write_all(contents.as_bytes()).unwrap();
+        encoder.finish().unwrap();
+        for path in &[&plain_path, &gzip_path] {
+            let urls: Vec<String> = UrlsReader::build()
+                .open(path)
+                .unwrap()
+                .map(|url_entry| url_entry.url)
+                .collect();
+            assert_eq!(urls, vec!["example.com", "example.org"]);
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    /// Tests that files with headers, other delimiters, and other column orders can be read
+    #[test]
+    fn test_csv_options() {
+        let path = env::temp_dir().join(format!("url_queue_test_{}_options.tsv", process::id()));
+        fs::write(
+            &path,
+            "domain\trank\tcategory\nexample.com\t1\tnews\nexample.org\t2\tshopping\n",
+        )
+        .unwrap();
+        let url_entries: Vec<(u64, String)> = UrlsReader::build()
+            .with_headers(true)
+            .with_delimiter(b'\t')
+            .with_columns(1, 0)
+            .open(&path)
+            .unwrap()
+            .map(|url_entry| (url_entry.index, url_entry.url))
+            .collect();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            url_entries,
+            vec![
+                (1, "example.com".to_string()),
+                (2, "example.org".to_string())
+            ]
+        );
+    }
+
+    /// Tests that several files are read in order, with the limit applying to all of them
+    #[test]
+    fn test_open_many() {
+        let paths: Vec<PathBuf> = (0..2)
+            .map(|file_index| {
+                env::temp_dir().join(format!(
+                    "url_queue_test_{}_many_{}.csv",
+                    process::id(),
+                    file_index
+                ))
+            })
+            .collect();
+        fs::write(&paths[0], "1,a.com\n2,b.com\n").unwrap();
+        fs::write(&paths[1], "1,c.com\n2,d.com\n").unwrap();
+        let read = |renumber, limit| -> Vec<(u64, String)> {
+            UrlsReader::build()
+                .with_renumbering(renumber)
+                .with_limit(limit)
+                .open_many(paths.clone())
+                .unwrap()
+                .map(|url_entry| (url_entry.index, url_entry.url))
+                .collect()
+        };
+        let entry = |index, url: &str| (index, url.to_string());
+        assert_eq!(
+            read(false, 3),
+            vec![entry(1, "a.com"), entry(2, "b.com"), entry(1, "c.com")]
+        );
+        assert_eq!(
+            read(true, 4),
+            vec![
+                entry(1, "a.com"),
+                entry(2, "b.com"),
+                entry(3, "c.com"),
+                entry(4, "d.com")
+            ]
+        );
+        for path in &paths {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    /// Tests that only transient errors are retried
+    #[test]
+    fn test_is_transient() {
+        assert!(is_transient(&io::Error::from(ErrorKind::Interrupted)));
+        assert!(is_transient(&io::Error::from(ErrorKind::NotFound)));
+        assert!(is_transient(&io::Error::from_raw_os_error(ESTALE)));
+        assert!(!is_transient(&io::Error::from(ErrorKind::PermissionDenied)));
+    }
+}
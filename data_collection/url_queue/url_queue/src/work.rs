@@ -14,10 +14,38 @@
 // You should have received a copy of the GNU General Public License
 // along with url_queue.  If not, see <http://www.gnu.org/licenses/>.
 
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use serde_derive::{Deserialize, Serialize};
-use std::collections::{BinaryHeap, HashMap};
+use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::hash::Hash;
+use std::io::{Read, Write};
 use std::iter::FromIterator;
+use std::time::{Duration, Instant};
+
+/// What to do when a worker registers again under a worker ID that already has a client
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReregistrationPolicy {
+    /// Reuse the worker's existing client ID, requeuing the work it had in flight, since the
+    /// worker lost that work when it restarted
+    #[default]
+    Reuse,
+    /// Give the worker a new client ID, and move the work it had in flight to the new ID
+    Transfer,
+}
+
+/// How work types are chosen between when a client requests work
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum WorkSelection {
+    /// Give work of the first of the client's work types that has any left, so work of earlier
+    /// types is given out before work of later types
+    #[default]
+    Preference,
+    /// Take turns between the client's work types, so work of each type is interleaved
+    RoundRobin,
+}
 
 /// Handles work
 #[derive(Clone)]
@@ -29,6 +57,28 @@ pub struct WorkQueue<T, W> {
     clients: HashMap<u64, Vec<T>>,
     /// Monotonic counter for client IDs
     cur_client_id: u64,
+    /// Number of times each work item has failed
+    failures: HashMap<(T, W), usize>,
+    /// Number of times failed work is requeued before it is dropped
+    max_retries: Option<usize>,
+    /// Client ID of each worker that registered with a worker ID
+    workers: HashMap<String, u64>,
+    /// Work that has been given to each client, but not yet reported, along with when it was
+    /// given out
+    in_flight: HashMap<u64, Vec<(T, W, Instant)>>,
+    /// What to do when a worker registers again
+    reregistration_policy: ReregistrationPolicy,
+    /// Time a client has to report work before it is given to another client
+    lease_timeout: Option<Duration>,
+    /// Most work each client may hold without reporting it, for clients that have a limit
+    in_flight_limits: HashMap<u64, usize>,
+    /// Position in its work types to look for work from next, for clients that take turns
+    /// between their work types
+    round_robin_cursors: HashMap<u64, usize>,
+    /// Work taken back from a client and queued again, which the client may still report
+    requeued: HashMap<T, HashSet<W>>,
+    /// Queued work that was reported late, and is skipped instead of being given out again
+    reported: HashMap<T, HashSet<W>>,
 }
 
 impl<T, W> FromIterator<(T, W)> for WorkQueue<T, W>
@@ -49,7 +99,7 @@ where
             // Get access to the work queue for this work type
             work.entry(work_type)
                 // Create a new queue if required
-                .or_insert_with(BinaryHeap::new)
+                .or_default()
                 // Add work to the queue
                 .push(work_item);
         }
@@ -58,61 +108,362 @@ where
             work,
             clients: HashMap::new(),
             cur_client_id: 0,
+            failures: HashMap::new(),
+            max_retries: None,
+            workers: HashMap::new(),
+            in_flight: HashMap::new(),
+            reregistration_policy: ReregistrationPolicy::default(),
+            lease_timeout: None,
+            in_flight_limits: HashMap::new(),
+            round_robin_cursors: HashMap::new(),
+            requeued: HashMap::new(),
+            reported: HashMap::new(),
         }
     }
 }
 
 impl<T, W> WorkQueue<T, W> {
-    /// Work queue
-    /// Adds a client
-    pub fn add_client(&mut self, work_types: Vec<T>) -> u64 {
-        // Increment the current ID
-        self.cur_client_id += 1;
-        // Add a client using the current ID
-        self.clients.insert(self.cur_client_id, work_types);
-        // Return the new client's ID
-        self.cur_client_id
-    }
     /// Removes a client
     ///
     /// # Parameters
     /// * `client_id` - ID of the client to remove
     pub fn remove_client(&mut self, client_id: u64) {
         self.clients.remove(&client_id);
+        self.in_flight.remove(&client_id);
+        self.in_flight_limits.remove(&client_id);
+        self.round_robin_cursors.remove(&client_id);
+        self.workers
+            .retain(|_, worker_client_id| *worker_client_id != client_id);
+    }
+    /// Checks whether a client holds as much unreported work as it's allowed to
+    ///
+    /// # Parameters
+    /// * `client_id` - Client to check
+    pub fn in_flight_limit_reached(&self, client_id: u64) -> bool {
+        match self.in_flight_limits.get(&client_id) {
+            Some(&max_in_flight) => {
+                self.in_flight.get(&client_id).map_or(0, Vec::len) >= max_in_flight
+            }
+            None => false,
+        }
     }
     /// Returns the number of active clients
     pub fn num_clients(&self) -> usize {
         self.clients.len()
     }
+    /// Returns the given work types that the queue has never held work of, so a client asking
+    /// for only them would never be given any
+    ///
+    /// # Parameters
+    /// * `work_types` - Work types a client asked for
+    pub fn unknown_work_types<'a>(&self, work_types: &'a [T]) -> Vec<&'a T>
+    where
+        T: Eq + Hash,
+    {
+        work_types
+            .iter()
+            .filter(|work_type| !self.work.contains_key(work_type))
+            .collect()
+    }
+    /// Returns the number of work items waiting to be given out for each work type
+    pub fn remaining_work(&self) -> HashMap<&T, usize>
+    where
+        T: Eq + Hash,
+    {
+        self.work
+            .iter()
+            .map(|(work_type, work)| {
+                let num_reported = self.reported.get(work_type).map_or(0, HashSet::len);
+                (work_type, work.len().saturating_sub(num_reported))
+            })
+            .collect()
+    }
+    /// Sets the number of times failed work is requeued before it is dropped
+    ///
+    /// # Parameters
+    /// * `max_retries` - the maximum number of retries, or None to retry forever
+    pub fn set_max_retries(&mut self, max_retries: Option<usize>) {
+        self.max_retries = max_retries;
+    }
+    /// Sets what to do when a worker registers again under the same worker ID
+    ///
+    /// # Parameters
+    /// * `reregistration_policy` - the policy to use
+    pub fn set_reregistration_policy(&mut self, reregistration_policy: ReregistrationPolicy) {
+        self.reregistration_policy = reregistration_policy;
+    }
+    /// Sets how a client's work types are chosen between when it requests work
+    ///
+    /// # Parameters
+    /// * `client_id` - Client to set the selection for
+    /// * `work_selection` - How to choose between the client's work types
+    pub fn set_work_selection(&mut self, client_id: u64, work_selection: WorkSelection) {
+        match work_selection {
+            WorkSelection::Preference => {
+                self.round_robin_cursors.remove(&client_id);
+            }
+            WorkSelection::RoundRobin => {
+                self.round_robin_cursors.entry(client_id).or_insert(0);
+            }
+        }
+    }
+    /// Sets the time a client has to report work before it is given to another client
+    ///
+    /// # Parameters
+    /// * `lease_timeout` - the time to wait, or None to wait forever
+    pub fn set_lease_timeout(&mut self, lease_timeout: Option<Duration>) {
+        self.lease_timeout = lease_timeout;
+    }
 }
 
 impl<T, W> WorkQueue<T, W>
 where
     T: Clone + Eq + Hash,
-    W: Ord,
+    W: Clone + Hash + Ord,
 {
+    /// Adds a client
+    ///
+    /// If a worker ID is given and the worker already has a client, the reregistration policy
+    /// decides what happens to the existing client and its work
+    ///
+    /// # Parameters
+    /// * `work_types` - Work types the client supports, in order of preference
+    /// * `worker_id` - Identifies the worker across registrations
+    /// * `max_in_flight` - Most work the client may hold without reporting it, or None for no
+    ///   limit
+    pub fn add_client(
+        &mut self,
+        work_types: Vec<T>,
+        worker_id: Option<String>,
+        max_in_flight: Option<usize>,
+    ) -> u64 {
+        // Find the client the worker registered as previously
+        let previous_client_id = worker_id
+            .as_ref()
+            .and_then(|worker_id| self.workers.get(worker_id))
+            .cloned()
+            .filter(|client_id| self.clients.contains_key(client_id));
+        let mut transferred_work = Vec::new();
+        if let Some(previous_client_id) = previous_client_id {
+            let in_flight = self
+                .in_flight
+                .remove(&previous_client_id)
+                .unwrap_or_default();
+            match self.reregistration_policy {
+                ReregistrationPolicy::Reuse => {
+                    // Make the worker's lost work available again
+                    for (work_type, work_item, _) in in_flight {
+                        self.requeue_lost_work(work_type, work_item);
+                    }
+                    self.clients.insert(previous_client_id, work_types);
+                    self.set_in_flight_limit(previous_client_id, max_in_flight);
+                    return previous_client_id;
+                }
+                ReregistrationPolicy::Transfer => {
+                    self.clients.remove(&previous_client_id);
+                    self.in_flight_limits.remove(&previous_client_id);
+                    self.round_robin_cursors.remove(&previous_client_id);
+                    transferred_work = in_flight;
+                }
+            }
+        }
+        // Increment the current ID
+        self.cur_client_id += 1;
+        // Add a client using the current ID
+        self.clients.insert(self.cur_client_id, work_types);
+        self.set_in_flight_limit(self.cur_client_id, max_in_flight);
+        if let Some(worker_id) = worker_id {
+            self.workers.insert(worker_id, self.cur_client_id);
+        }
+        if !transferred_work.is_empty() {
+            self.in_flight.insert(self.cur_client_id, transferred_work);
+        }
+        // Return the new client's ID
+        self.cur_client_id
+    }
+    /// Sets or clears the most work a client may hold without reporting it
+    ///
+    /// # Parameters
+    /// * `client_id` - Client to limit
+    /// * `max_in_flight` - The limit, or None for no limit
+    fn set_in_flight_limit(&mut self, client_id: u64, max_in_flight: Option<usize>) {
+        match max_in_flight {
+            Some(max_in_flight) => {
+                self.in_flight_limits.insert(client_id, max_in_flight);
+            }
+            None => {
+                self.in_flight_limits.remove(&client_id);
+            }
+        }
+    }
     /// Retrieves work from the queue
     ///
+    /// Work whose lease has expired is requeued first, so it can be given out again. No work is
+    /// given to a client holding as much unreported work as it's allowed to, which
+    /// `in_flight_limit_reached` tells apart from the queue being empty.
+    ///
     /// # Parameters
     /// * `client_id` - Client to request work as
     pub fn request_work(&mut self, client_id: u64) -> Option<(T, W)> {
+        let now = Instant::now();
+        self.requeue_expired_leases(now);
+        self.discard_reported_work();
+        if self.in_flight_limit_reached(client_id) {
+            return None;
+        }
+        let start = self.selection_start(client_id);
         // Get mutable reference to work queues
         let work = &mut self.work;
         // Get the client's preferred work types
-        self.clients
-            .get(&client_id)?
-            // Convert from vec to iterator
-            .iter()
-            .cloned()
+        let work_types = self.clients.get(&client_id)?;
+        let (position, work_type, work_item) = selection_order(start, work_types.len())
             // Get the work queue for the given work type
-            .flat_map(|work_type: T| {
+            .flat_map(|position| {
+                let work_type = work_types[position].clone();
                 work.get_mut(&work_type)?
                     .pop()
-                    .map(|work_item| (work_type, work_item))
+                    .map(|work_item| (position, work_type, work_item))
             })
             // Grab the first work item
+            .next()?;
+        // Start from the next work type next time, if the client takes turns between them
+        if let Some(cursor) = self.round_robin_cursors.get_mut(&client_id) {
+            *cursor = position + 1;
+        }
+        // Requeued work given out again is in flight like any other work
+        if let Some(requeued) = self.requeued.get_mut(&work_type) {
+            requeued.remove(&work_item);
+        }
+        // Keep track of the work until it's reported
+        self.in_flight.entry(client_id).or_default().push((
+            work_type.clone(),
+            work_item.clone(),
+            now,
+        ));
+        Some((work_type, work_item))
+    }
+    /// Finds the work the client would be given next, without taking it from the queue
+    ///
+    /// Work whose lease has expired is requeued first, and a client holding as much unreported
+    /// work as it's allowed to is shown nothing, as when requesting work
+    ///
+    /// # Parameters
+    /// * `client_id` - Client to look for work as
+    pub fn peek_work(&mut self, client_id: u64) -> Option<(T, W)> {
+        self.requeue_expired_leases(Instant::now());
+        self.discard_reported_work();
+        if self.in_flight_limit_reached(client_id) {
+            return None;
+        }
+        let work = &self.work;
+        let work_types = self.clients.get(&client_id)?;
+        selection_order(self.selection_start(client_id), work_types.len())
+            // Look at the top of the work queue for each of the client's preferred work types
+            .flat_map(|position| {
+                let work_type = &work_types[position];
+                work.get(work_type)?
+                    .peek()
+                    .map(|work_item| (work_type.clone(), work_item.clone()))
+            })
             .next()
     }
+    /// Gives the position in a client's work types to start looking for work from
+    ///
+    /// # Parameters
+    /// * `client_id` - Client looking for work
+    fn selection_start(&self, client_id: u64) -> usize {
+        // Clients that don't take turns always start from their most preferred work type
+        self.round_robin_cursors
+            .get(&client_id)
+            .cloned()
+            .unwrap_or(0)
+    }
+    /// Requeues work that wasn't reported within the lease timeout
+    ///
+    /// Returns the number of work items requeued
+    ///
+    /// # Parameters
+    /// * `now` - Time to check the leases against
+    pub fn requeue_expired_leases(&mut self, now: Instant) -> usize {
+        let lease_timeout = match self.lease_timeout {
+            Some(lease_timeout) => lease_timeout,
+            None => return 0,
+        };
+        let mut expired = Vec::new();
+        for in_flight in self.in_flight.values_mut() {
+            let (kept, lost): (Vec<_>, Vec<_>) =
+                in_flight.drain(..).partition(|(_, _, leased_at)| {
+                    now.saturating_duration_since(*leased_at) < lease_timeout
+                });
+            *in_flight = kept;
+            expired.extend(lost);
+        }
+        let num_expired = expired.len();
+        for (work_type, work_item, _) in expired {
+            self.requeue_lost_work(work_type, work_item);
+        }
+        num_expired
+    }
+    /// Queues work taken back from a client again, remembering that the client may still
+    /// report it
+    ///
+    /// # Parameters
+    /// * `work_type` - Type of the work
+    /// * `work_item` - The work
+    fn requeue_lost_work(&mut self, work_type: T, work_item: W) {
+        self.requeued
+            .entry(work_type.clone())
+            .or_default()
+            .insert(work_item.clone());
+        self.add_work(work_type, work_item);
+    }
+    /// Takes work that was reported late off the top of each work queue, so the next work
+    /// given out or peeked at hasn't been done already
+    ///
+    /// Reported work further down a queue stays there until it reaches the top
+    fn discard_reported_work(&mut self) {
+        for (work_type, reported) in self.reported.iter_mut() {
+            if let Some(work) = self.work.get_mut(work_type) {
+                while work.peek().is_some_and(|top| reported.contains(top)) {
+                    if let Some(work_item) = work.pop() {
+                        reported.remove(&work_item);
+                    }
+                }
+            }
+        }
+        self.reported.retain(|_, reported| !reported.is_empty());
+    }
+    /// Stops tracking work that has been reported
+    ///
+    /// Work reported after it was taken back from the client and requeued is marked as
+    /// reported, and skipped once it reaches the top of its queue rather than being done again
+    ///
+    /// # Parameters
+    /// * `work_type` - Type of the reported work
+    /// * `work_item` - The reported work
+    pub fn finish_work(&mut self, work_type: &T, work_item: &W) {
+        for in_flight in self.in_flight.values_mut() {
+            if let Some(idx) = in_flight
+                .iter()
+                .position(|(in_flight_type, in_flight_item, _)| {
+                    in_flight_type == work_type && in_flight_item == work_item
+                })
+            {
+                in_flight.remove(idx);
+                return;
+            }
+        }
+        let was_requeued = self
+            .requeued
+            .get_mut(work_type)
+            .is_some_and(|requeued| requeued.remove(work_item));
+        if was_requeued {
+            self.reported
+                .entry(work_type.clone())
+                .or_default()
+                .insert(work_item.clone());
+        }
+    }
     /// Adds work to the queue
     ///
     /// # Parameters
@@ -123,12 +474,109 @@ where
         self.work
             .entry(work_type)
             // Create a new queue if required
-            .or_insert_with(BinaryHeap::new)
+            .or_default()
             // Add work to the queue
             .push(work_item);
     }
 }
 
+/// Gives the positions in a client's work types to look for work in, in order
+///
+/// # Parameters
+/// * `start` - Position to start from
+/// * `num_work_types` - Number of work types the client supports
+fn selection_order(start: usize, num_work_types: usize) -> impl Iterator<Item = usize> {
+    (0..num_work_types).map(move |offset| (start + offset) % num_work_types)
+}
+
+impl<T, W> WorkQueue<T, W>
+where
+    T: Clone + Eq + Hash + DeserializeOwned + Serialize,
+    W: Clone + Hash + Ord + DeserializeOwned + Serialize,
+{
+    /// Saves the work that hasn't been reported yet as JSON
+    ///
+    /// Work that is in flight is saved along with the queued work, since the clients it was
+    /// given to won't be known to a queue loaded from the snapshot
+    ///
+    /// # Parameters
+    /// * `writer` - Writer to save the snapshot to
+    pub fn save_to<Wr: Write>(&self, writer: Wr) -> Result<(), serde_json::Error> {
+        let mut work: HashMap<&T, Vec<&W>> = HashMap::new();
+        for (work_type, work_items) in &self.work {
+            let reported = self.reported.get(work_type);
+            work.entry(work_type).or_default().extend(
+                work_items.iter().filter(|work_item| {
+                    reported.is_none_or(|reported| !reported.contains(work_item))
+                }),
+            );
+        }
+        for (work_type, work_item, _) in self.in_flight.values().flatten() {
+            work.entry(work_type).or_default().push(work_item);
+        }
+        let snapshot = WorkSnapshot {
+            work: work.into_iter().collect(),
+        };
+        serde_json::to_writer(writer, &snapshot)
+    }
+    /// Loads a work queue from a snapshot saved by `save_to`
+    ///
+    /// The loaded queue has no clients, and its options are the defaults. Work types whose work
+    /// has all been reported are kept, so they stay known to the loaded queue
+    ///
+    /// # Parameters
+    /// * `reader` - Reader to load the snapshot from
+    pub fn load_from<R: Read>(reader: R) -> Result<Self, serde_json::Error> {
+        let snapshot: WorkSnapshot<T, W> = serde_json::from_reader(reader)?;
+        let mut work_queue = Self::from_iter(std::iter::empty());
+        for (work_type, work_items) in snapshot.work {
+            work_queue
+                .work
+                .entry(work_type)
+                .or_default()
+                .extend(work_items);
+        }
+        Ok(work_queue)
+    }
+}
+
+/// Work saved by `WorkQueue::save_to`, grouped by type
+#[derive(Deserialize, Serialize)]
+struct WorkSnapshot<T, W> {
+    work: Vec<(T, Vec<W>)>,
+}
+
+impl<T, W> WorkQueue<T, W>
+where
+    T: Clone + Eq + Hash,
+    W: Clone + Eq + Hash + Ord,
+{
+    /// Requeues work that a client failed to complete
+    ///
+    /// Work that has failed more than the maximum number of retries is dropped instead
+    /// Returns whether the work was requeued
+    ///
+    /// # Parameters
+    /// * `work_type` - Type of the failed work
+    /// * `work_item` - The failed work
+    pub fn requeue_failed_work(&mut self, work_type: T, work_item: W) -> bool {
+        // Count the failure
+        let failures = self
+            .failures
+            .entry((work_type.clone(), work_item.clone()))
+            .or_insert(0);
+        *failures += 1;
+        // Drop the work if it has failed too many times
+        match self.max_retries {
+            Some(max_retries) if *failures > max_retries => false,
+            _ => {
+                self.add_work(work_type, work_item);
+                true
+            }
+        }
+    }
+}
+
 /// Represents the HTTP request for
 /// POST /work/get
 #[derive(Deserialize)]
@@ -137,13 +585,61 @@ pub struct WorkRequest {
 }
 /// Represents the HTTP response for
 /// POST /work/get
+/// POST /work/peek
+///
+/// When there's no work left for the client, success is false and no work is given
 #[derive(Serialize)]
 pub struct WorkResponse<T, W> {
     pub success: bool,
-    pub work_type: T,
-    pub work: W,
+    pub work_type: Option<T>,
+    pub work: Option<W>,
+    /// Whether the work was reserved for the client, rather than only shown to it
+    pub reserved: bool,
     pub error: Option<String>,
 }
+impl<T, W> WorkResponse<T, W> {
+    /// Creates a response giving the client work, or telling it there's none left
+    ///
+    /// # Parameters
+    /// * `work` - Work given to the client, if there was any
+    pub fn new(work: Option<(T, W)>) -> Self {
+        let reserved = work.is_some();
+        WorkResponse {
+            reserved,
+            ..Self::peeked(work)
+        }
+    }
+    /// Creates a response telling the client it must report work before it's given more
+    pub fn limit_reached() -> Self {
+        WorkResponse {
+            error: Some("in-flight limit reached".to_string()),
+            ..Self::peeked(None)
+        }
+    }
+    /// Creates a response showing the client the work it would be given next, without
+    /// reserving it
+    ///
+    /// # Parameters
+    /// * `work` - Work the client would be given, if there is any
+    pub fn peeked(work: Option<(T, W)>) -> Self {
+        match work {
+            Some((work_type, work)) => WorkResponse {
+                success: true,
+                work_type: Some(work_type),
+                work: Some(work),
+                reserved: false,
+                error: None,
+            },
+            None => WorkResponse {
+                success: false,
+                work_type: None,
+                work: None,
+                reserved: false,
+                error: Some("no work".to_string()),
+            },
+        }
+    }
+}
 /// Represents the HTTP request for
 /// POST /work/report
 #[derive(Debug, Deserialize, Serialize)]
@@ -163,18 +659,44 @@ pub struct WorkReportRequest<T, W> {
 }
 
 /// Represents the HTTP response for
-/// POST /work/report
+/// POST /work/report, and each item of the response for POST /work/report/batch
 #[derive(Serialize)]
 pub struct WorkReportResponse {
     pub success: bool,
     pub error: Option<String>,
 }
 
+/// Represents the HTTP response for
+/// GET /stats
+#[derive(Serialize)]
+pub struct StatsResponse<'a, T: Eq + Hash> {
+    /// Number of work items waiting to be given out for each work type
+    pub remaining_work: HashMap<&'a T, usize>,
+    /// Number of active clients
+    pub num_clients: usize,
+    /// Number of reports written to the report file
+    pub reports_written: usize,
+}
+
+/// Represents the HTTP response for
+/// GET /health and GET /ready
+#[derive(Serialize)]
+pub struct HealthResponse {
+    pub status: &'static str,
+}
+
 /// Represents the HTTP request for
 /// POST /client/add
 #[derive(Deserialize)]
 pub struct AddClientRequest<T> {
     pub work_types: Vec<T>,
+    /// Identifies the worker across registrations, so a restarted worker can be recognized
+    pub worker_id: Option<String>,
+    /// Most work the client may hold without reporting it
+    pub max_in_flight: Option<usize>,
+    /// How to choose between the client's work types
+    #[serde(default)]
+    pub work_selection: WorkSelection,
 }
 /// Represents the HTTP response for
 /// POST /client/add
@@ -197,3 +719,260 @@ pub struct RemoveClientResponse {
     pub success: bool,
     pub error: Option<String>,
 }
+/// Represents the HTTP response for
+/// any POST whose body couldn't be parsed
+#[derive(Serialize)]
+pub struct ParseErrorResponse {
+    pub success: bool,
+    /// What was wrong with the body, and where
+    pub error: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that work types the queue never held work of are found
+    #[test]
+    fn test_unknown_work_types() {
+        let mut work_queue: WorkQueue<u8, u64> = vec![(0, 7)].into_iter().collect();
+        assert!(work_queue.unknown_work_types(&[0]).is_empty());
+        assert_eq!(work_queue.unknown_work_types(&[1, 0, 2]), vec![&1, &2]);
+        // Work types stay known once their work runs out
+        let client_id = work_queue.add_client(vec![0], None, None);
+        let (work_type, work) = work_queue.request_work(client_id).unwrap();
+        assert!(work_queue.unknown_work_types(&[0]).is_empty());
+        // Or once it's all reported and the queue is loaded from a snapshot
+        work_queue.finish_work(&work_type, &work);
+        let mut snapshot = Vec::new();
+        work_queue.save_to(&mut snapshot).unwrap();
+        let work_queue: WorkQueue<u8, u64> = WorkQueue::load_from(&snapshot[..]).unwrap();
+        assert!(work_queue.unknown_work_types(&[0]).is_empty());
+    }
+
+    /// Tests that work failing more than the maximum number of retries is dropped
+    #[test]
+    fn test_max_retries() {
+        let mut work_queue: WorkQueue<u8, u64> = vec![(0, 1)].into_iter().collect();
+        work_queue.set_max_retries(Some(2));
+        let client_id = work_queue.add_client(vec![0], None, None);
+        // The first two failures are retried
+        for _ in 0..2 {
+            let (work_type, work) = work_queue.request_work(client_id).unwrap();
+            assert!(work_queue.requeue_failed_work(work_type, work));
+        }
+        // The third failure is dropped
+        let (work_type, work) = work_queue.request_work(client_id).unwrap();
+        assert!(!work_queue.requeue_failed_work(work_type, work));
+        assert_eq!(work_queue.request_work(client_id), None);
+    }
+
+    /// Tests that a worker registering again reclaims the work it never reported
+    #[test]
+    fn test_reregister_reuse() {
+        let mut work_queue: WorkQueue<u8, u64> = vec![(0, 1), (0, 2)].into_iter().collect();
+        let client_id = work_queue.add_client(vec![0], Some("worker".to_string()), None);
+        let (_, work) = work_queue.request_work(client_id).unwrap();
+        // The worker restarts before reporting
+        let new_client_id = work_queue.add_client(vec![0], Some("worker".to_string()), None);
+        assert_eq!(new_client_id, client_id);
+        assert_eq!(work_queue.num_clients(), 1);
+        assert_eq!(work_queue.request_work(client_id), Some((0, work)));
+        // Reported work is not reclaimed
+        work_queue.finish_work(&0, &work);
+        work_queue.add_client(vec![0], Some("worker".to_string()), None);
+        assert_eq!(work_queue.request_work(client_id), Some((0, 1)));
+        assert_eq!(work_queue.request_work(client_id), None);
+    }
+
+    /// Tests that queued and in flight work survive a snapshot, while reported work doesn't
+    #[test]
+    fn test_snapshot() {
+        let mut work_queue: WorkQueue<u8, u64> =
+            vec![(0, 1), (0, 2), (0, 3), (1, 4)].into_iter().collect();
+        let client_id = work_queue.add_client(vec![0], None, None);
+        // Work 3 is reported, and work 2 is handed out but never reported
+        let (work_type, work) = work_queue.request_work(client_id).unwrap();
+        work_queue.finish_work(&work_type, &work);
+        work_queue.request_work(client_id).unwrap();
+        let mut snapshot = Vec::new();
+        work_queue.save_to(&mut snapshot).unwrap();
+        let mut work_queue: WorkQueue<u8, u64> = WorkQueue::load_from(&snapshot[..]).unwrap();
+        assert_eq!(work_queue.num_clients(), 0);
+        let client_id = work_queue.add_client(vec![0, 1], None, None);
+        let mut work = Vec::new();
+        while let Some(work_item) = work_queue.request_work(client_id) {
+            work.push(work_item);
+        }
+        assert_eq!(work, vec![(0, 2), (0, 1), (1, 4)]);
+    }
+
+    /// Tests that the remaining work is counted for each work type
+    #[test]
+    fn test_remaining_work() {
+        let mut work_queue: WorkQueue<u8, u64> = vec![(0, 1), (0, 2), (1, 3)].into_iter().collect();
+        let client_id = work_queue.add_client(vec![0], None, None);
+        work_queue.request_work(client_id).unwrap();
+        let remaining_work = work_queue.remaining_work();
+        assert_eq!((remaining_work[&0], remaining_work[&1]), (1, 1));
+    }
+
+    /// Tests that an empty queue is reported as a response without work rather than an error
+    #[test]
+    fn test_work_response() {
+        let mut work_queue: WorkQueue<u8, u64> = vec![(0, 1)].into_iter().collect();
+        let client_id = work_queue.add_client(vec![0], None, None);
+        let response = WorkResponse::new(work_queue.request_work(client_id));
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            r#"{"success":true,"work_type":0,"work":1,"reserved":true,"error":null}"#
+        );
+        let response = WorkResponse::new(work_queue.request_work(client_id));
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            r#"{"success":false,"work_type":null,"work":null,"reserved":false,"error":"no work"}"#
+        );
+    }
+
+    /// Tests that a client at its in-flight limit gets no more work until it reports some, while
+    /// other clients are unaffected
+    #[test]
+    fn test_max_in_flight() {
+        let mut work_queue: WorkQueue<u8, u64> = (1..=5).map(|work| (0, work)).collect();
+        let limited = work_queue.add_client(vec![0], None, Some(2));
+        let unlimited = work_queue.add_client(vec![0], None, None);
+        let (_, first) = work_queue.request_work(limited).unwrap();
+        assert!(!work_queue.in_flight_limit_reached(limited));
+        work_queue.request_work(limited).unwrap();
+        assert!(work_queue.in_flight_limit_reached(limited));
+        assert_eq!(work_queue.request_work(limited), None);
+        // Peeking doesn't show work the client wouldn't be given
+        assert_eq!(work_queue.peek_work(limited), None);
+        // The other client isn't held back, and its work doesn't count against the limit
+        work_queue.request_work(unlimited).unwrap();
+        work_queue.request_work(unlimited).unwrap();
+        assert!(!work_queue.in_flight_limit_reached(unlimited));
+        // Reporting work makes room for more
+        work_queue.finish_work(&0, &first);
+        assert!(!work_queue.in_flight_limit_reached(limited));
+        assert!(work_queue.request_work(limited).is_some());
+        assert_eq!(work_queue.request_work(limited), None);
+        let response = WorkResponse::<u8, u64>::limit_reached();
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            r#"{"success":false,"work_type":null,"work":null,"reserved":false,"error":"in-flight limit reached"}"#
+        );
+    }
+
+    /// Tests that a client taking turns between work types gets interleaved work, while the
+    /// default drains its preferred work type first
+    #[test]
+    fn test_round_robin() {
+        let work: Vec<(u8, u64)> = vec![(0, 1), (0, 2), (0, 3), (1, 4), (1, 5)];
+        let mut work_queue: WorkQueue<u8, u64> = work.iter().cloned().collect();
+        let client_id = work_queue.add_client(vec![0, 1], None, None);
+        let types: Vec<u8> = (0..5)
+            .map(|_| work_queue.request_work(client_id).unwrap().0)
+            .collect();
+        assert_eq!(types, vec![0, 0, 0, 1, 1]);
+        let mut work_queue: WorkQueue<u8, u64> = work.into_iter().collect();
+        let client_id = work_queue.add_client(vec![0, 1], None, None);
+        work_queue.set_work_selection(client_id, WorkSelection::RoundRobin);
+        assert_eq!(work_queue.peek_work(client_id), Some((0, 3)));
+        let types: Vec<u8> = (0..5)
+            .map(|_| work_queue.request_work(client_id).unwrap().0)
+            .collect();
+        // Once a work type runs out, the rest of the work is of the other types
+        assert_eq!(types, vec![0, 1, 0, 1, 0]);
+        assert_eq!(work_queue.request_work(client_id), None);
+    }
+
+    /// Tests that peeking shows the work the client would be given next, without taking it
+    #[test]
+    fn test_peek_work() {
+        let mut work_queue: WorkQueue<u8, u64> = vec![(0, 3), (0, 1), (1, 2)].into_iter().collect();
+        let client_id = work_queue.add_client(vec![1, 0], None, None);
+        assert_eq!(work_queue.peek_work(client_id), Some((1, 2)));
+        assert_eq!(work_queue.peek_work(client_id), Some((1, 2)));
+        assert_eq!(work_queue.request_work(client_id), Some((1, 2)));
+        assert_eq!(work_queue.peek_work(client_id), Some((0, 3)));
+        assert_eq!(work_queue.request_work(client_id), Some((0, 3)));
+        let response = WorkResponse::peeked(work_queue.peek_work(client_id));
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            r#"{"success":true,"work_type":0,"work":1,"reserved":false,"error":null}"#
+        );
+        assert_eq!(work_queue.peek_work(client_id + 1), None);
+    }
+
+    /// Tests that a worker registering again can move its work to a new client ID
+    #[test]
+    fn test_reregister_transfer() {
+        let mut work_queue: WorkQueue<u8, u64> = vec![(0, 1), (0, 2)].into_iter().collect();
+        work_queue.set_reregistration_policy(ReregistrationPolicy::Transfer);
+        let client_id = work_queue.add_client(vec![0], Some("worker".to_string()), None);
+        let (_, work) = work_queue.request_work(client_id).unwrap();
+        let new_client_id = work_queue.add_client(vec![0], Some("worker".to_string()), None);
+        assert_ne!(new_client_id, client_id);
+        assert_eq!(work_queue.num_clients(), 1);
+        let in_flight: Vec<(u8, u64)> = work_queue.in_flight[&new_client_id]
+            .iter()
+            .map(|&(work_type, work_item, _)| (work_type, work_item))
+            .collect();
+        assert_eq!(in_flight, vec![(0, work)]);
+        // The work is still in flight, so it isn't handed out again
+        assert_eq!(work_queue.request_work(new_client_id), Some((0, 1)));
+        assert_eq!(work_queue.request_work(new_client_id), None);
+    }
+
+    /// Tests that work that is never reported is given out again once its lease expires
+    #[test]
+    fn test_lease_timeout() {
+        let mut work_queue: WorkQueue<u8, u64> = vec![(0, 1), (0, 2)].into_iter().collect();
+        work_queue.set_lease_timeout(Some(Duration::from_secs(60)));
+        let client_id = work_queue.add_client(vec![0], None, None);
+        let (_, lost_work) = work_queue.request_work(client_id).unwrap();
+        let (_, reported_work) = work_queue.request_work(client_id).unwrap();
+        work_queue.finish_work(&0, &reported_work);
+        // Leases that haven't expired are kept
+        assert_eq!(work_queue.requeue_expired_leases(Instant::now()), 0);
+        assert_eq!(work_queue.request_work(client_id), None);
+        // Only the unreported work reappears after the timeout
+        let later = Instant::now() + Duration::from_secs(61);
+        assert_eq!(work_queue.requeue_expired_leases(later), 1);
+        assert_eq!(work_queue.request_work(client_id), Some((0, lost_work)));
+        assert_eq!(work_queue.request_work(client_id), None);
+    }
+
+    /// Tests that work reported after its lease expired isn't given out again
+    #[test]
+    fn test_late_report() {
+        let mut work_queue: WorkQueue<u8, u64> = vec![(0, 1), (0, 2)].into_iter().collect();
+        work_queue.set_lease_timeout(Some(Duration::from_secs(60)));
+        let client_id = work_queue.add_client(vec![0], None, None);
+        let (_, late_work) = work_queue.request_work(client_id).unwrap();
+        let later = Instant::now() + Duration::from_secs(61);
+        assert_eq!(work_queue.requeue_expired_leases(later), 1);
+        // The slow client reports the work after it was requeued
+        work_queue.finish_work(&0, &late_work);
+        assert_eq!(work_queue.remaining_work()[&0], 1);
+        let mut snapshot = Vec::new();
+        work_queue.save_to(&mut snapshot).unwrap();
+        let loaded: WorkQueue<u8, u64> = WorkQueue::load_from(&snapshot[..]).unwrap();
+        assert_eq!(loaded.remaining_work()[&0], 1);
+        let (_, other_work) = work_queue.request_work(client_id).unwrap();
+        assert_ne!(other_work, late_work);
+        assert_eq!(work_queue.request_work(client_id), None);
+        // Late work below the top of the queue is skipped once it reaches the top
+        let mut work_queue: WorkQueue<u8, u64> = vec![(0, 1), (0, 2)].into_iter().collect();
+        work_queue.set_lease_timeout(Some(Duration::from_secs(60)));
+        let client_id = work_queue.add_client(vec![0], None, None);
+        assert_eq!(work_queue.request_work(client_id), Some((0, 2)));
+        assert_eq!(work_queue.requeue_expired_leases(later), 1);
+        work_queue.add_work(0, 3);
+        work_queue.finish_work(&0, &2);
+        assert_eq!(work_queue.request_work(client_id), Some((0, 3)));
+        assert_eq!(work_queue.request_work(client_id), Some((0, 1)));
+        assert_eq!(work_queue.request_work(client_id), None);
+    }
+}
@@ -1,12 +1,18 @@
 use clap::{crate_authors, crate_version, App, Arg};
-use std::convert::TryInto;
+use log::warn;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::OsStr;
 use std::fs::OpenOptions;
-use std::io::{BufWriter, Write};
-use std::path::PathBuf;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
 use url_queue::capture::{CaptureWork, CaptureWorkType};
 use url_queue::work::WorkReportRequest;
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+fn main() -> Result<(), Box<dyn Error>> {
+    // Start the logger
+    env_logger::init();
     // Parse command line arguments
     let matches = App::new("LBNL packet dataset report generator")
         .version(crate_version!())
@@ -40,8 +46,6 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     if !dataset_path.is_dir() {
         panic!("Dataset path is not directory");
     }
-    // Get directory iterator
-    let dir_iter = dataset_path.read_dir()?;
     // Generate report file
     let report_path = dataset_path.join("report.json");
     let report_file = OpenOptions::new()
@@ -51,33 +55,238 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .open(report_path)?;
     // Wrap report file  in a buffered writer
     let mut report_writer = BufWriter::new(report_file);
-    // Iterate over files in directory
-    for (idx, dir_entry) in dir_iter.enumerate() {
-        // Unwrap the directory entry
-        let dir_entry = dir_entry?;
-        // Check the filename
-        let file_path = dir_entry.path();
-        let extension = file_path.extension().unwrap();
-        if extension == "anon" || (extension == "anon-scanners" && include_scanners) {
-            let idx: u64 = idx.try_into().unwrap();
-            // Construct a report
+    write_report(&dataset_path, include_scanners, &mut report_writer)?;
+    report_writer.flush()?;
+
+    Ok(())
+}
+
+/// Finds every file under a directory, descending into subdirectories
+///
+/// Symbolic links to directories aren't followed, so links can't cause a loop
+///
+/// # Parameters
+/// * `root` - Directory to search
+fn find_files(root: &Path) -> Result<Vec<PathBuf>, io::Error> {
+    let mut files = Vec::new();
+    // Directories left to search
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for dir_entry in dir.read_dir()? {
+            let dir_entry = dir_entry?;
+            if dir_entry.file_type()?.is_dir() {
+                dirs.push(dir_entry.path());
+            } else {
+                files.push(dir_entry.path());
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Writes a report of each capture in the dataset, as a line of JSON
+///
+/// Captures are reported and numbered in order of their paths, so the report is the same each
+/// time it's generated. Captures are found in subdirectories of the dataset as well.
+/// Directories and files whose extension isn't that of a capture are skipped, as are files
+/// without an extension, with a warning
+///
+/// # Parameters
+/// * `dataset_path` - Path to the directory containing the LBNL packet dataset
+/// * `include_scanners` - Whether to include data from scanners
+/// * `report_writer` - Writer to write the report to
+fn write_report<W: Write>(
+    dataset_path: &Path,
+    include_scanners: bool,
+    report_writer: &mut W,
+) -> Result<(), Box<dyn Error>> {
+    // Find every capture in the dataset
+    let mut capture_paths: Vec<PathBuf> = find_files(dataset_path)?
+        .into_iter()
+        .filter(|file_path| {
+            // Skip directories, such as the links to them the search doesn't descend into
+            if file_path.is_dir() {
+                return false;
+            }
+            // Check the filename
+            match file_path.extension().and_then(OsStr::to_str) {
+                Some(extension) => {
+                    extension == "anon" || (extension == "anon-scanners" && include_scanners)
+                }
+                None => {
+                    warn!("Skipping {:?}, which has no extension", file_path);
+                    false
+                }
+            }
+        })
+        .collect();
+    // Sort the captures before numbering them, so the report doesn't depend on the order the
+    // filesystem lists them in
+    capture_paths.sort();
+    // Count each capture within its class, starting from 1
+    let mut type_indices: HashMap<CaptureWorkType, u64> = HashMap::new();
+    let captures: Vec<(CaptureWorkType, u64, PathBuf)> = capture_paths
+        .into_iter()
+        .map(|capture_path| {
+            let work_type = CaptureWorkType::Normal;
+            let type_index = type_indices.entry(work_type).or_insert(0);
+            *type_index += 1;
+            (work_type, *type_index, capture_path)
+        })
+        .collect();
+    // Construct and serialize the reports in parallel
+    let reports = captures
+        .into_par_iter()
+        .enumerate()
+        .map(|(idx, (work_type, type_index, capture_path))| {
             let work = WorkReportRequest {
                 success: true,
-                work_type: CaptureWorkType::Normal,
+                work_type,
                 work: CaptureWork {
-                    index: idx,
+                    index: idx as u64,
                     url: "unknown".to_string(),
-                    filename: file_path,
+                    filename: capture_path,
                 },
-                type_index: idx,
+                type_index,
                 start_time: 0,
                 finish_time: 0,
             };
-            // Output the json
-            serde_json::to_writer(&mut report_writer, &work)?;
-            report_writer.write(b"\n")?;
-        }
+            serde_json::to_vec(&work)
+        })
+        .collect::<Result<Vec<Vec<u8>>, _>>()?;
+    // Output the json in sorted order
+    for report in reports {
+        report_writer.write_all(&report)?;
+        report_writer.write_all(b"\n")?;
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempdir::TempDir;
+
+    /// Parses each line of a written report
+    fn read_report(report: &[u8]) -> Vec<WorkReportRequest<CaptureWorkType, CaptureWork>> {
+        serde_json::Deserializer::from_slice(report)
+            .into_iter()
+            .map(|report| report.unwrap())
+            .collect()
+    }
+
+    /// Tests that files without an extension are skipped, and captures are counted per class
+    #[test]
+    fn test_write_report() {
+        let dataset_dir = TempDir::new("data_generator").unwrap();
+        for filename in &["a.anon", "README", "b.anon", "c.anon-scanners", "d.anon"] {
+            fs::write(dataset_dir.path().join(filename), b"").unwrap();
+        }
+        let mut report = Vec::new();
+        write_report(dataset_dir.path(), false, &mut report).unwrap();
+        let reports = read_report(&report);
+        assert_eq!(reports.len(), 3);
+        // Each capture is counted once within its class, in the order they were reported
+        let mut type_indices: Vec<u64> = reports.iter().map(|report| report.type_index).collect();
+        type_indices.sort();
+        assert_eq!(type_indices, vec![1, 2, 3]);
+        // Indices are unique
+        let mut indices: Vec<u64> = reports.iter().map(|report| report.work.index).collect();
+        indices.sort();
+        indices.dedup();
+        assert_eq!(indices.len(), 3);
+        assert!(reports
+            .iter()
+            .all(|report| report.work.filename.extension().unwrap() == "anon"));
+        // Scanners are included if asked for
+        let mut report = Vec::new();
+        write_report(dataset_dir.path(), true, &mut report).unwrap();
+        assert_eq!(report.iter().filter(|&&byte| byte == b'\n').count(), 4);
+    }
+
+    /// Tests that captures are numbered in order of their paths
+    #[test]
+    fn test_write_report_sorted() {
+        let dataset_dir = TempDir::new("data_generator").unwrap();
+        fs::create_dir(dataset_dir.path().join("b")).unwrap();
+        for filename in &["c.anon", "b/z.anon", "a.anon", "b/a.anon"] {
+            fs::write(dataset_dir.path().join(filename), b"").unwrap();
+        }
+        let mut report = Vec::new();
+        write_report(dataset_dir.path(), false, &mut report).unwrap();
+        let reports = read_report(&report);
+        let reports: Vec<(u64, u64, PathBuf)> = reports
+            .into_iter()
+            .map(|report| (report.work.index, report.type_index, report.work.filename))
+            .collect();
+        assert_eq!(
+            reports,
+            vec![
+                (0, 1, dataset_dir.path().join("a.anon")),
+                (1, 2, dataset_dir.path().join("b/a.anon")),
+                (2, 3, dataset_dir.path().join("b/z.anon")),
+                (3, 4, dataset_dir.path().join("c.anon")),
+            ]
+        );
+        // Generating the report again gives the same report
+        let mut second_report = Vec::new();
+        write_report(dataset_dir.path(), false, &mut second_report).unwrap();
+        assert_eq!(report, second_report);
+    }
+
+    /// Tests that directories and files that aren't captures are skipped instead of aborting the
+    /// report
+    #[test]
+    fn test_write_report_mixed() {
+        let dataset_dir = TempDir::new("data_generator").unwrap();
+        for filename in &["a.anon", "README", ".hidden", "notes.txt", "b.anon.txt"] {
+            fs::write(dataset_dir.path().join(filename), b"").unwrap();
+        }
+        // Directories named like captures aren't captures
+        fs::create_dir(dataset_dir.path().join("empty.anon")).unwrap();
+        std::os::unix::fs::symlink(
+            dataset_dir.path().join("empty.anon"),
+            dataset_dir.path().join("link.anon"),
+        )
+        .unwrap();
+        let mut report = Vec::new();
+        write_report(dataset_dir.path(), true, &mut report).unwrap();
+        let reports = read_report(&report);
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].work.filename, dataset_dir.path().join("a.anon"));
+    }
+
+    /// Tests that captures in nested subdirectories are found
+    #[test]
+    fn test_write_report_nested() {
+        let dataset_dir = TempDir::new("data_generator").unwrap();
+        let day_dir = dataset_dir.path().join("2005-01-07");
+        fs::create_dir_all(day_dir.join("hour")).unwrap();
+        fs::write(dataset_dir.path().join("a.anon"), b"").unwrap();
+        fs::write(day_dir.join("b.anon"), b"").unwrap();
+        fs::write(day_dir.join("c.anon-scanners"), b"").unwrap();
+        fs::write(day_dir.join("hour").join("d.anon"), b"").unwrap();
+        let mut report = Vec::new();
+        write_report(dataset_dir.path(), false, &mut report).unwrap();
+        let reports = read_report(&report);
+        let mut filenames: Vec<PathBuf> = reports
+            .into_iter()
+            .map(|report| report.work.filename)
+            .collect();
+        filenames.sort();
+        assert_eq!(
+            filenames,
+            vec![
+                day_dir.join("b.anon"),
+                day_dir.join("hour").join("d.anon"),
+                dataset_dir.path().join("a.anon"),
+            ]
+        );
+        // The scanner flag applies in subdirectories too
+        let mut report = Vec::new();
+        write_report(dataset_dir.path(), true, &mut report).unwrap();
+        assert_eq!(report.iter().filter(|&&byte| byte == b'\n').count(), 4);
+    }
+}
@@ -16,7 +16,7 @@
 use failure;
 use pnet_packet::ip::IpNextHeaderProtocols::{Icmp, Tcp, Udp};
 use serde::{Deserialize, Deserializer};
-use serde_derive::Deserialize;
+use serde_derive::{Deserialize, Serialize};
 use serde_json;
 
 use std::convert::From;
@@ -25,8 +25,8 @@ use std::io::{BufRead, BufReader};
 use std::net::IpAddr;
 use std::path::Path;
 
-/// Connection state for a flow
-#[derive(Debug, Deserialize)]
+/// Connection state for a flow, as zeek writes it in the connection log
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
 pub enum ConnState {
     /// Connection attempt seen, no reply.
     S0,
@@ -65,10 +65,16 @@ pub enum ConnState {
     /// No SYN seen, just midstream traffic (a “partial connection”
     /// that was not later closed).
     OTH,
-    /// Unknown. used as a default
+    /// Unknown. used as a default, and for states this version doesn't know about
+    #[serde(other)]
     UNK,
 }
 
+impl ConnState {
+    /// Number of connection states
+    pub const COUNT: usize = 14;
+}
+
 impl Default for ConnState {
     /// Returns a default state
     fn default() -> Self {
@@ -77,7 +83,7 @@ impl Default for ConnState {
 }
 
 /// History entry for connection state
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum HistoryEntry {
     /// s 	a SYN w/o the ACK bit set
     Syn,
@@ -104,6 +110,10 @@ pub enum HistoryEntry {
     // Used so we have something to use for invalid input
     Unknown,
 }
+impl HistoryEntry {
+    /// Number of history entries
+    pub const COUNT: usize = 12;
+}
 impl From<char> for HistoryEntry {
     /// Parses a char into a HistoryEntry
     fn from(c: char) -> Self {
@@ -124,8 +134,17 @@ impl From<char> for HistoryEntry {
     }
 }
 
+/// Endpoint of a connection
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Endpoint {
+    /// The endpoint that started the connection
+    Originator,
+    /// The endpoint that responded to the connection
+    Responder,
+}
+
 /// Represents a transport protocol, as supported by Bro
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
 pub enum TransportProtocol {
     /// An unknown transport-layer protocol.
     #[serde(rename = "unknown_transport")]
@@ -151,6 +170,16 @@ impl TransportProtocol {
             TransportProtocol::Icmp => Icmp.0,
         }
     }
+
+    /// Returns the transport protocol with an IP nextProtocol code
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            code if code == Tcp.0 => TransportProtocol::Tcp,
+            code if code == Udp.0 => TransportProtocol::Udp,
+            code if code == Icmp.0 => TransportProtocol::Icmp,
+            _ => TransportProtocol::Unknown,
+        }
+    }
 }
 
 /// Used to deserialize a floating-point timestamp (in seconds) as an integer timestamp (in
@@ -198,6 +227,52 @@ pub struct Connection {
     pub resp_ip_bytes: Option<i64>,
 }
 impl Connection {
+    /// Parses the history of the connection, along with which endpoint each entry came from
+    ///
+    /// Zeek writes entries from the originator in upper case, and entries from the responder
+    /// in lower case. Entries without a case, such as the direction being flipped, are
+    /// attributed to the responder
+    pub fn parsed_history(&self) -> Vec<(Endpoint, HistoryEntry)> {
+        self.history
+            .chars()
+            .map(|entry| {
+                let endpoint = if entry.is_ascii_uppercase() {
+                    Endpoint::Originator
+                } else {
+                    Endpoint::Responder
+                };
+                (endpoint, HistoryEntry::from(entry.to_ascii_lowercase()))
+            })
+            .collect()
+    }
+
+    /// Whether an endpoint's history contains an entry
+    ///
+    /// # Parameters
+    /// * `endpoint` - Endpoint the entry came from
+    /// * `entry` - Entry to look for
+    pub fn has_history_entry(&self, endpoint: Endpoint, entry: HistoryEntry) -> bool {
+        self.parsed_history().contains(&(endpoint, entry))
+    }
+
+    /// Whether the originator sent a SYN and the responder answered with a SYN+ACK
+    pub fn has_handshake(&self) -> bool {
+        self.has_history_entry(Endpoint::Originator, HistoryEntry::Syn)
+            && self.has_history_entry(Endpoint::Responder, HistoryEntry::Handshake)
+    }
+
+    /// Whether both endpoints sent packets with a payload
+    pub fn has_data_both_ways(&self) -> bool {
+        self.has_history_entry(Endpoint::Originator, HistoryEntry::Data)
+            && self.has_history_entry(Endpoint::Responder, HistoryEntry::Data)
+    }
+
+    /// Whether either endpoint sent a packet with the RST bit set
+    pub fn has_reset(&self) -> bool {
+        self.has_history_entry(Endpoint::Originator, HistoryEntry::Rst)
+            || self.has_history_entry(Endpoint::Responder, HistoryEntry::Rst)
+    }
+
     pub fn load_connections(
         path: &Path,
     ) -> Result<impl Iterator<Item = Connection>, failure::Error> {
@@ -212,3 +287,169 @@ impl Connection {
         Ok(connections)
     }
 }
+
+/// A TLS session zeek found, as written in the ssl log
+#[derive(Debug, Deserialize)]
+pub struct SslRecord {
+    #[serde(rename = "ts")]
+    #[serde(deserialize_with = "parse_bro_timestamp")]
+    pub timestamp: u64,
+    /// Identifier of the connection the session was carried in
+    pub uid: String,
+    /// Negotiated protocol version, such as TLSv12
+    pub version: Option<String>,
+    /// Negotiated cipher suite
+    pub cipher: Option<String>,
+    /// Server name the client asked for with SNI
+    pub server_name: Option<String>,
+    /// Whether the session was resumed
+    pub resumed: Option<bool>,
+    /// Whether the handshake finished
+    pub established: Option<bool>,
+}
+impl SslRecord {
+    pub fn load_ssl(path: &Path) -> Result<impl Iterator<Item = SslRecord>, failure::Error> {
+        // Open the ssl log
+        let ssl_log_file: File = File::open(path)?;
+        let ssl_log_reader = BufReader::new(ssl_log_file);
+        // Parse each line
+        let ssl_records = ssl_log_reader.lines().flatten().flat_map(|line| {
+            // Parse the line as json
+            serde_json::from_str(&line)
+        });
+        Ok(ssl_records)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Creates a TCP connection from port `orig_port` on 10.0.0.1 to port 443 on 10.0.0.2,
+    /// starting at the given time (in s) and lasting 1s
+    pub(crate) fn connection_at(uid: &str, orig_port: u16, start: f64) -> Connection {
+        serde_json::from_str(&format!(
+            r#"{{"ts": {}, "uid": "{}", "id.orig_h": "10.0.0.1", "id.orig_p": {},
+            "id.resp_h": "10.0.0.2", "id.resp_p": 443, "proto": "tcp", "duration": 1.0}}"#,
+            start, uid, orig_port
+        ))
+        .unwrap()
+    }
+
+    /// Creates a connection with the given history
+    fn connection(history: &str) -> Connection {
+        Connection {
+            history: history.to_string(),
+            ..connection_at("C1", 50000, 1.0)
+        }
+    }
+
+    /// Tests that a connection log line written by zeek is parsed, including its state
+    #[test]
+    fn test_parse_connection() {
+        let line = r#"{"ts":1541096432.437408,"uid":"CHhAvVGS1DHFjwGM9","id.orig_h":"10.0.0.1","id.orig_p":50312,"id.resp_h":"93.184.216.34","id.resp_p":443,"proto":"tcp","service":"ssl","duration":0.512345,"orig_bytes":517,"resp_bytes":4432,"conn_state":"SF","local_orig":false,"local_resp":false,"missed_bytes":0,"history":"ShADadFf","orig_pkts":12,"orig_ip_bytes":1149,"resp_pkts":10,"resp_ip_bytes":4960,"tunnel_parents":[]}"#;
+        let connection: Connection = serde_json::from_str(line).unwrap();
+        assert_eq!(connection.uid, "CHhAvVGS1DHFjwGM9");
+        assert_eq!(connection.timestamp, 1_541_096_432_437_408_000);
+        assert_eq!(connection.duration, 512_345_000);
+        assert_eq!(connection.conn_state, Some(ConnState::SF));
+        assert_eq!(connection.service.as_deref(), Some("ssl"));
+        assert_eq!(
+            (connection.orig_pkts, connection.resp_pkts),
+            (Some(12), Some(10))
+        );
+        // Every state zeek writes is parsed
+        let states = [
+            ("S0", ConnState::S0),
+            ("S1", ConnState::S1),
+            ("SF", ConnState::SF),
+            ("REJ", ConnState::REJ),
+            ("S2", ConnState::S2),
+            ("S3", ConnState::S3),
+            ("RSTO", ConnState::RSTO),
+            ("RSTR", ConnState::RSTR),
+            ("RSTOS0", ConnState::RSTOS0),
+            ("RSTRH", ConnState::RSTRH),
+            ("SH", ConnState::SH),
+            ("SHR", ConnState::SHR),
+            ("OTH", ConnState::OTH),
+        ];
+        for &(name, state) in &states {
+            let conn_state: ConnState = serde_json::from_str(&format!("\"{}\"", name)).unwrap();
+            assert_eq!(conn_state, state);
+        }
+        // States this version doesn't know about don't cause the connection to be dropped
+        let line = line.replace(r#""conn_state":"SF""#, r#""conn_state":"XYZ""#);
+        let connection: Connection = serde_json::from_str(&line).unwrap();
+        assert_eq!(connection.conn_state, Some(ConnState::UNK));
+    }
+
+    /// Tests that an ssl log line written by zeek is parsed
+    #[test]
+    fn test_parse_ssl_record() {
+        let line = r#"{"ts":1541096432.512961,"uid":"CHhAvVGS1DHFjwGM9","id.orig_h":"10.0.0.1","id.orig_p":50312,"id.resp_h":"93.184.216.34","id.resp_p":443,"version":"TLSv12","cipher":"TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256","curve":"x25519","server_name":"example.com","resumed":false,"established":true}"#;
+        let ssl_record: SslRecord = serde_json::from_str(line).unwrap();
+        assert_eq!(ssl_record.uid, "CHhAvVGS1DHFjwGM9");
+        assert_eq!(ssl_record.timestamp, 1_541_096_432_512_961_000);
+        assert_eq!(ssl_record.version.as_deref(), Some("TLSv12"));
+        assert_eq!(
+            ssl_record.cipher.as_deref(),
+            Some("TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256")
+        );
+        assert_eq!(ssl_record.server_name.as_deref(), Some("example.com"));
+        assert_eq!(
+            (ssl_record.resumed, ssl_record.established),
+            (Some(false), Some(true))
+        );
+        // Sessions that never finished the handshake are missing most fields
+        let line = r#"{"ts":1541096432.5,"uid":"C2","id.orig_h":"10.0.0.1","id.orig_p":50313,"id.resp_h":"93.184.216.34","id.resp_p":443,"established":false}"#;
+        let ssl_record: SslRecord = serde_json::from_str(line).unwrap();
+        assert_eq!(
+            (
+                ssl_record.version,
+                ssl_record.cipher,
+                ssl_record.server_name
+            ),
+            (None, None, None)
+        );
+    }
+
+    /// Tests that history entries are parsed along with the endpoint they came from
+    #[test]
+    fn test_parsed_history() {
+        use Endpoint::*;
+        assert_eq!(
+            connection("ShADad^x").parsed_history(),
+            vec![
+                (Originator, HistoryEntry::Syn),
+                (Responder, HistoryEntry::Handshake),
+                (Originator, HistoryEntry::Ack),
+                (Originator, HistoryEntry::Data),
+                (Responder, HistoryEntry::Ack),
+                (Responder, HistoryEntry::Data),
+                (Responder, HistoryEntry::DirectionFlipped),
+                (Responder, HistoryEntry::Unknown),
+            ]
+        );
+        assert!(connection("").parsed_history().is_empty());
+    }
+
+    /// Tests the predicates over a connection's history
+    #[test]
+    fn test_history_predicates() {
+        let complete = connection("ShADadFf");
+        assert!(complete.has_handshake());
+        assert!(complete.has_data_both_ways());
+        assert!(!complete.has_reset());
+        // The responder reset the connection without answering the SYN
+        let refused = connection("Sr");
+        assert!(!refused.has_handshake());
+        assert!(!refused.has_data_both_ways());
+        assert!(refused.has_reset());
+        // Only the originator sent data
+        let one_way = connection("ShADaR");
+        assert!(one_way.has_handshake());
+        assert!(!one_way.has_data_both_ways());
+        assert!(one_way.has_reset());
+    }
+}
@@ -0,0 +1,284 @@
+// Copyright 2018 Steven Sheffey
+// This file is part of packet_captor_sakura.
+//
+// packet_captor_sakura is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// packet_captor_sakura is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with packet_captor_sakura.  If not, see <https:// www.gnu.org/licenses/>.
+
+use crate::bro_types::TransportProtocol;
+use crate::dataset::{CompressionFormat, OutputFormat};
+use crate::packet::NoNextHeaderHandling;
+use failure::Error;
+use serde_derive::Deserialize;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+/// Options controlling how features are generated. Any option missing from the config file
+/// takes its default value
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct GeneratorConfig {
+    /// Ports used by the servers of interest. Only flows to or from these ports are used, and
+    /// they are used to infer the direction of packets
+    pub server_ports: Vec<u16>,
+    /// Transport protocols used by the servers of interest. Only flows using one of these are
+    /// used, so HTTP/3 (QUIC, over UDP) can be kept apart from HTTPS over TCP
+    pub server_protocols: Vec<TransportProtocol>,
+    /// Directory the pcap filenames in the report are relative to. Absolute filenames are used
+    /// as they are. The directory containing the report is used if not given
+    pub pcap_root: Option<PathBuf>,
+    /// Name or path of the zeek executable
+    pub zeek_path: PathBuf,
+    /// Scripts zeek runs on each pcap. These must produce a connection log, and TLS metadata
+    /// is attached to flows if they also produce an ssl log
+    pub zeek_scripts: Vec<String>,
+    /// Time (in ns) to wait for the remaining fragments of a fragmented IPv4 datagram.
+    /// Fragments are not reassembled if not given
+    pub fragment_timeout: Option<u64>,
+    /// Maximum total size (in bytes) of pcaps to read into memory ahead of processing them.
+    /// Pcaps are not read ahead if not given
+    pub prefetch_bytes: Option<usize>,
+    /// Number of pcap records to read at a time and parse across threads. Since pcaps are
+    /// already processed in parallel, this is only worth it when there are fewer pcaps than
+    /// cores. Records are parsed one at a time if not given
+    pub parse_batch_size: Option<usize>,
+    /// Minimum mean payload entropy (in bits per byte) of a flow. Flows below this are likely
+    /// plaintext, and are dropped. Flows are not filtered by entropy if not given
+    pub min_flow_entropy: Option<f64>,
+    /// Whether to only keep flows whose connection zeek saw established and terminated
+    /// normally (with state SF), dropping truncated or failed connections
+    pub require_clean_completion: bool,
+    /// Whether to fall back to guessing that the end of a flow using a well known port is the
+    /// server, when neither the server ports nor the handshake tell the direction of a packet
+    pub infer_direction_from_well_known_ports: bool,
+    /// Number of bytes at the start of each direction's payload to output for each flow.
+    /// Payloads are not output if this is 0
+    pub payload_prefix_length: usize,
+    /// Time (in ns) before a connection starts during which packets may still be associated
+    /// with it
+    pub grace_period_before: u64,
+    /// Time (in ns) after a connection ends during which packets may still be associated with
+    /// it
+    pub grace_period_after: u64,
+    /// Fraction of a pcap's packets that may fall within the grace periods of more than one
+    /// connection before a warning is logged, suggesting the grace periods be reduced
+    pub max_ambiguous_fraction: Option<f64>,
+    /// How to handle IPv6 packets with no next header, which carry no transport layer
+    pub no_next_header: NoNextHeaderHandling,
+    /// Whether to generate features from the connection log alone, without reading any
+    /// packets from the pcaps
+    pub conn_only: bool,
+    /// Compression used for the output class files
+    pub compression: CompressionFormat,
+    /// Format of the output class files
+    pub output_format: OutputFormat,
+    /// Whether to sort the flows of each class by URL and index before saving them, so
+    /// repeated runs write identical output
+    pub sort_flows: bool,
+    /// Bins used to generate flow features
+    pub bins: BinConfig,
+}
+
+impl Default for GeneratorConfig {
+    fn default() -> Self {
+        GeneratorConfig {
+            server_ports: vec![443],
+            server_protocols: vec![TransportProtocol::Tcp, TransportProtocol::Udp],
+            pcap_root: None,
+            zeek_path: PathBuf::from("zeek"),
+            zeek_scripts: vec![
+                "base/protocols/conn".to_string(),
+                "base/protocols/ssl".to_string(),
+            ],
+            fragment_timeout: None,
+            prefetch_bytes: None,
+            parse_batch_size: None,
+            min_flow_entropy: None,
+            require_clean_completion: false,
+            infer_direction_from_well_known_ports: false,
+            payload_prefix_length: 0,
+            grace_period_before: 1_000_000_000,
+            grace_period_after: 5_000_000_000,
+            max_ambiguous_fraction: None,
+            no_next_header: NoNextHeaderHandling::default(),
+            conn_only: false,
+            compression: CompressionFormat::default(),
+            output_format: OutputFormat::default(),
+            sort_flows: false,
+            bins: BinConfig::default(),
+        }
+    }
+}
+
+/// Bins used to generate flow features
+///
+/// Each bin is given by its maximum value, so a value falls into the first bin whose maximum
+/// it is less than. Values that are not less than the last bin's maximum are ignored
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct BinConfig {
+    /// Payload length bins (in bytes)
+    pub payload_length: Vec<usize>,
+    /// Interarrival time bins (in `ia_unit`) for packets from the client
+    pub ia_from_client: Vec<u64>,
+    /// Interarrival time bins (in `ia_unit`) for packets to the client
+    pub ia_to_client: Vec<u64>,
+    /// Unit the interarrival time bins are given in
+    pub ia_unit: TimeUnit,
+    /// Time (in ns) between two packets after which they are considered part of different
+    /// bursts
+    pub burst_idle_threshold: u64,
+    /// Burst size bins (in packets)
+    pub burst_size: Vec<usize>,
+    /// Length (in ns) of the sliding window peak throughput is measured over
+    pub throughput_window: u64,
+}
+
+impl Default for BinConfig {
+    fn default() -> Self {
+        let payload_length: Vec<usize> = (10..=100)
+            .step_by(10)
+            .chain((200..=1000).step_by(100))
+            .chain((2000..=10000).step_by(1000))
+            .chain(Some(65536))
+            .collect();
+        // Create variable so it's easier to keep track of time periods
+        // Our timestamps are in nanoseconds. Convert here to ms
+        let ms: u64 = 1_000_000;
+        let ia_from_client: Vec<u64> = (ms..=10 * ms)
+            .step_by(ms as usize)
+            .chain((20 * ms..=100 * ms).step_by(10 * ms as usize))
+            .chain((200 * ms..=1000 * ms).step_by(100 * ms as usize))
+            .chain(Some(10_000 * ms))
+            .collect();
+        // Use the same periods for to_client
+        let ia_to_client = ia_from_client.clone();
+        // Burst sizes are binned by powers of two
+        let burst_size: Vec<usize> = (1..=10)
+            .map(|exponent| 1 << exponent)
+            .chain(Some(usize::MAX))
+            .collect();
+        BinConfig {
+            payload_length,
+            ia_from_client,
+            ia_to_client,
+            ia_unit: TimeUnit::default(),
+            burst_idle_threshold: 100 * ms,
+            burst_size,
+            throughput_window: 100 * ms,
+        }
+    }
+}
+
+impl BinConfig {
+    /// Interarrival time bins (in ns) for packets from the client
+    pub fn ia_from_client_nanos(&self) -> Vec<u64> {
+        self.ia_unit.to_nanos(&self.ia_from_client)
+    }
+
+    /// Interarrival time bins (in ns) for packets to the client
+    pub fn ia_to_client_nanos(&self) -> Vec<u64> {
+        self.ia_unit.to_nanos(&self.ia_to_client)
+    }
+}
+
+/// Unit of a time given in the config
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeUnit {
+    /// Nanoseconds, the unit of packet timestamps
+    #[default]
+    Ns,
+    /// Microseconds
+    Us,
+    /// Milliseconds
+    Ms,
+    /// Seconds
+    S,
+}
+
+impl TimeUnit {
+    /// Number of nanoseconds in one of this unit
+    pub fn nanos(self) -> u64 {
+        match self {
+            TimeUnit::Ns => 1,
+            TimeUnit::Us => 1_000,
+            TimeUnit::Ms => 1_000_000,
+            TimeUnit::S => 1_000_000_000,
+        }
+    }
+
+    /// Converts times in this unit to nanoseconds, saturating times too long to represent
+    /// # Parameters
+    /// * `times` - Times in this unit
+    pub fn to_nanos(self, times: &[u64]) -> Vec<u64> {
+        times
+            .iter()
+            .map(|time| time.saturating_mul(self.nanos()))
+            .collect()
+    }
+}
+
+impl GeneratorConfig {
+    /// Loads a config from a TOML file
+    pub fn load<P>(path: P) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        // Open the file
+        let config_file = File::open(path)?;
+        let mut reader = BufReader::new(config_file);
+        // Read in the entire file
+        let mut contents: Vec<u8> = Vec::with_capacity(200);
+        reader.read_to_end(&mut contents)?;
+        // Parse the config
+        Ok(toml::from_slice(&contents)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that options missing from a config take their default values
+    #[test]
+    fn test_defaults() {
+        let config: GeneratorConfig = toml::from_str("").unwrap();
+        assert_eq!(config.server_ports, vec![443]);
+        let config: GeneratorConfig = toml::from_str("server_ports = [80, 853]").unwrap();
+        assert_eq!(config.server_ports, vec![80, 853]);
+        assert_eq!(config.fragment_timeout, None);
+        // Bins can be partially overridden
+        let config: GeneratorConfig =
+            toml::from_str("[bins]\npayload_length = [100, 1500, 65536]").unwrap();
+        assert_eq!(config.bins.payload_length, vec![100, 1500, 65536]);
+        assert_eq!(
+            config.bins.ia_from_client,
+            BinConfig::default().ia_from_client
+        );
+    }
+
+    /// Tests that interarrival time bins are converted from their unit to nanoseconds
+    #[test]
+    fn test_ia_unit() {
+        // Bins are in nanoseconds by default
+        let bins = BinConfig::default();
+        assert_eq!(bins.ia_from_client_nanos(), bins.ia_from_client);
+        let config: GeneratorConfig = toml::from_str(
+            "[bins]\nia_unit = \"us\"\nia_from_client = [10, 100]\nia_to_client = [1, 9223372036854775807]",
+        )
+        .unwrap();
+        assert_eq!(config.bins.ia_from_client_nanos(), vec![10_000, 100_000]);
+        assert_eq!(config.bins.ia_to_client_nanos(), vec![1_000, u64::MAX]);
+    }
+}
@@ -13,35 +13,168 @@
 //
 // You should have received a copy of the GNU General Public License
 // along with packet_captor_sakura.  If not, see <https:// www.gnu.org/licenses/>.
-use crate::bro_types::Connection;
+use crate::bro_types::{ConnState, Connection, HistoryEntry, SslRecord, TransportProtocol};
+use crate::config::{BinConfig, GeneratorConfig};
 use crate::features::{
-    DirectionInferenceMethod, FlowFeatures, NormalizedFlowFeatures, PacketFeatures,
+    mean_payload_entropy, ConnectionFeatures, DirectionInferenceMethod, FlowFeatures,
+    NormalizedFlowFeatures, PacketDirection, PacketFeatures, TlsMetadata, NUM_ENTROPY_BINS,
 };
-use crate::flow_aggregator::FlowAggregator;
-use crate::packet::Packet;
+use crate::flow_aggregator::{Flow, FlowAggregator};
+use crate::packet::{LoadOptions, Packet};
+use crate::prefetch::{par_map_in_order, PcapPrefetcher};
+use crate::progress::Progress;
 use failure::{ensure, format_err, Error};
+use flate2::read::GzDecoder;
 use flate2::write::GzEncoder;
 use flate2::Compression;
-use itertools::Itertools;
-use log::info;
+use itertools::{Either, Itertools};
+use log::{info, warn};
 use rayon::prelude::*;
-use serde_derive::Serialize;
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, Write};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::str::FromStr;
 use tempdir::TempDir;
 use url_queue::capture::{CaptureWork, CaptureWorkType};
+use url_queue::rotation::rotated_paths;
 use url_queue::work::WorkReportRequest;
 
+/// Names of the subdirectories the train, validation, and test sets are saved to
+const SPLIT_NAMES: [&str; 3] = ["train", "val", "test"];
+/// Offset basis of the 64 bit FNV-1a hash
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+/// Prime of the 64 bit FNV-1a hash
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Compression used for the class files written by `Dataset::save`
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CompressionFormat {
+    /// Gzip, written to .json.gz
+    #[default]
+    Gzip,
+    /// Zstandard, written to .json.zst
+    Zstd,
+    /// No compression, written to .json
+    None,
+}
+
+impl CompressionFormat {
+    /// Extension of class files written with this compression
+    pub fn extension(self) -> &'static str {
+        match self {
+            CompressionFormat::Gzip => "json.gz",
+            CompressionFormat::Zstd => "json.zst",
+            CompressionFormat::None => "json",
+        }
+    }
+}
+
+impl FromStr for CompressionFormat {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "gzip" => Ok(CompressionFormat::Gzip),
+            "zstd" => Ok(CompressionFormat::Zstd),
+            "none" => Ok(CompressionFormat::None),
+            _ => Err(format_err!("Unknown compression format {:?}", format)),
+        }
+    }
+}
+
+/// Format of the class files written by `Dataset::save`
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    /// A line of JSON per flow
+    #[default]
+    Json,
+    /// A row of comma separated values per flow, after a header row naming the columns. Only
+    /// the class, URL, whether the flow was the first of its class, and the binned features
+    /// are written, as they are for `Bincode`
+    Csv,
+    /// A `BincodeHeader`, followed by a fixed size record per flow holding whether it was the
+    /// first of its class, then its binned features as little endian f64s, in the order the
+    /// header lists them. For packet features these are the payload length (`pl`),
+    /// interarrival from and to client (`iaf`, `iat`), burst size (`bs`), and payload entropy
+    /// (`en`) bins. For connection features they are the connection state (`cs`) and history
+    /// (`ho`, `hr`) frequencies. The URL, scalar features, payload prefixes, and TLS metadata
+    /// are only written as `Json`
+    Bincode,
+}
+
+impl OutputFormat {
+    /// Extension of class files written in this format with the given compression
+    pub fn extension(self, compression: CompressionFormat) -> &'static str {
+        match (self, compression) {
+            (OutputFormat::Json, compression) => compression.extension(),
+            (OutputFormat::Csv, CompressionFormat::Gzip) => "csv.gz",
+            (OutputFormat::Csv, CompressionFormat::Zstd) => "csv.zst",
+            (OutputFormat::Csv, CompressionFormat::None) => "csv",
+            (OutputFormat::Bincode, CompressionFormat::Gzip) => "bin.gz",
+            (OutputFormat::Bincode, CompressionFormat::Zstd) => "bin.zst",
+            (OutputFormat::Bincode, CompressionFormat::None) => "bin",
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            "bincode" => Ok(OutputFormat::Bincode),
+            _ => Err(format_err!("Unknown output format {:?}", format)),
+        }
+    }
+}
+
+/// Header of a class file written as bincode, describing the records after it
+#[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct BincodeHeader {
+    /// Number of records after the header
+    pub num_records: u64,
+    /// Short key and length of each binned feature, in the order they're written
+    pub columns: Vec<(String, u64)>,
+}
+
+/// Options controlling how a dataset is saved
+#[derive(Clone, Debug, Default)]
+pub struct SaveOptions {
+    /// Compression used for the class files
+    pub compression: CompressionFormat,
+    /// Format the class files are written in
+    pub format: OutputFormat,
+    /// Whether to sort the flows of each class by URL and index before writing them, so the
+    /// output doesn't depend on the order they were loaded in
+    pub sort_flows: bool,
+    /// Short key and length of each binned feature, as `Features::columns` lays them out for
+    /// the config. CSV and bincode headers are built from these, and every flow must match them
+    pub columns: Vec<(&'static str, usize)>,
+}
+
 pub struct Dataset {
     classes: HashMap<CaptureWorkType, Vec<FlowData>>,
 }
 
 impl Dataset {
     /// Loads a dataset from a directory
-    pub fn load<P>(data_dir: P) -> Result<Self, Error>
+    /// # Parameters
+    /// * `data_dir` - Directory containing report.json, and the pcaps unless the config gives
+    ///   another root for them
+    /// * `config` - Options for feature generation
+    /// * `status_file` - File to periodically write progress to, as JSON
+    pub fn load<P>(
+        data_dir: P,
+        config: &GeneratorConfig,
+        status_file: Option<&Path>,
+    ) -> Result<Self, Error>
     where
         P: AsRef<Path>,
     {
@@ -49,100 +182,567 @@ impl Dataset {
         let data_dir = data_dir.as_ref();
         // Ensure the data directory is a directory
         ensure!(data_dir.is_dir(), "Path to dataset must be a directory");
-        // Open the report
+        // Flows are only kept if they use one of the server ports
+        ensure!(
+            !config.server_ports.is_empty(),
+            "At least one server port is required"
+        );
+        // Open the report, followed by the numbered files it was rotated into
         let mut report_path = PathBuf::from(data_dir);
         report_path.push("report.json");
         ensure!(report_path.is_file(), "Data path must contain report.json");
-        let report_file = File::open(report_path)?;
-        let report_file = BufReader::new(report_file);
-        // Read and parse report file
-        let mut work: Vec<WorkReportRequest<CaptureWorkType, CaptureWork>> = report_file
-            .lines()
-            .flatten()
-            .flat_map(|line| serde_json::from_str(&line))
-            .collect();
+        let mut work: Vec<WorkReportRequest<CaptureWorkType, CaptureWork>> = Vec::new();
+        for report_path in rotated_paths(&report_path) {
+            let report_file = BufReader::new(File::open(report_path)?);
+            // Read and parse report file
+            work.extend(
+                report_file
+                    .lines()
+                    .flatten()
+                    .flat_map(|line| serde_json::from_str(&line)),
+            );
+        }
+        // Filter out failed work
+        work.retain(|report| report.success);
         // Sort reports by type and name
         work.par_sort_unstable_by_key(|report| (report.work_type, report.work.index));
-        // Extract data from each work item
-        let classes = work
-            .into_par_iter()
-            // Filter out failed work
-            .filter(|report| report.success)
+        // Pcaps are found next to the report unless they're stored elsewhere
+        let pcap_root = config.pcap_root.as_deref().unwrap_or(data_dir);
+        // Read pcaps ahead in the order they're processed, unless their packets aren't needed
+        let prefetch_bytes = config.prefetch_bytes.filter(|_| !config.conn_only);
+        let prefetcher = prefetch_bytes.map(|max_bytes| {
+            let pcap_paths = work
+                .iter()
+                .map(|report| pcap_root.join(&report.work.filename))
+                .collect();
+            PcapPrefetcher::new(pcap_paths, max_bytes)
+        });
+        let prefetcher = prefetcher.as_ref();
+        // Track how much of the work has been processed
+        let progress = Progress::new(work.len(), status_file.map(Path::to_path_buf));
+        // Extract data from each work item, in the order the pcaps are read ahead
+        let flow_data = par_map_in_order(work, |report| {
             // Load flow data from the PCAP for this work
-            .flat_map(|report| FlowData::load(report, data_dir))
+            let flow_data = FlowData::load(report, pcap_root, config, prefetcher);
+            // Count the work as done
+            progress.item_done(flow_data.is_ok());
+            flow_data
+        });
+        if let Some(prefetcher) = prefetcher {
+            let (hits, misses) = prefetcher.hits_and_misses();
+            info!("{} of {} pcaps were read ahead", hits, hits + misses);
+        }
+        let classes = flow_data
+            .into_iter()
+            // Skip work that failed to load
+            .flatten()
             // Separate out group type so we can aggregate
             .map(|flow_data| (flow_data.class, flow_data))
-            // Collect into one big vector
-            .collect::<Vec<_>>()
-            // Convert to iterator for itertools
-            .into_iter()
             // Group by type
             .into_group_map();
+        progress.finish()?;
+        Ok(Dataset { classes })
+    }
+
+    /// Creates a dataset from flows already grouped by class
+    /// # Parameters
+    /// * `classes` - Flows of each class
+    pub fn from_classes(classes: HashMap<CaptureWorkType, Vec<FlowData>>) -> Self {
+        Dataset { classes }
+    }
+
+    /// Adds the flows of another dataset to this one, such as one generated on another machine
+    ///
+    /// Classes only in the other dataset are added as they are
+    /// # Parameters
+    /// * `other` - Dataset to take the flows of
+    pub fn merge(&mut self, other: Dataset) {
+        for (class, flows) in other.classes {
+            self.classes.entry(class).or_default().extend(flows);
+        }
+    }
+
+    /// Assigns an integer label to each class
+    ///
+    /// Labels are assigned in order of the class names, so they are stable across runs
+    pub fn labels(&self) -> BTreeMap<String, usize> {
+        self.classes
+            .keys()
+            .map(|class| class.to_string())
+            .sorted()
+            .enumerate()
+            .map(|(label, class)| (class, label))
+            .collect()
+    }
+
+    /// Loads a dataset previously written by `save` as JSON, without needing zeek or the pcaps
+    ///
+    /// The classes are found from labels.json, and each class file may use any compression
+    /// # Parameters
+    /// * `output_path` - Path the class datasets were written to
+    pub fn load_tensors<P>(output_path: P) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let output_path = output_path.as_ref();
+        let labels_file = File::open(output_path.join("labels.json"))?;
+        let labels: BTreeMap<String, usize> = serde_json::from_reader(BufReader::new(labels_file))?;
+        let mut classes = HashMap::new();
+        for class_name in labels.keys() {
+            let class: CaptureWorkType =
+                serde_json::from_value(serde_json::Value::String(class_name.clone()))?;
+            // Find the class file, whichever compression it was written with
+            let (class_filename, compression) = [
+                CompressionFormat::Gzip,
+                CompressionFormat::Zstd,
+                CompressionFormat::None,
+            ]
+            .iter()
+            .map(|&compression| {
+                let class_filename = output_path
+                    .join(class_name)
+                    .with_extension(compression.extension());
+                (class_filename, compression)
+            })
+            .find(|(class_filename, _)| class_filename.is_file())
+            .ok_or_else(|| format_err!("No class file found for {}", class_name))?;
+            let class_file = BufReader::new(File::open(class_filename)?);
+            let reader: Box<dyn BufRead> = match compression {
+                CompressionFormat::Gzip => Box::new(BufReader::new(GzDecoder::new(class_file))),
+                CompressionFormat::Zstd => {
+                    Box::new(BufReader::new(zstd::Decoder::with_buffer(class_file)?))
+                }
+                CompressionFormat::None => Box::new(class_file),
+            };
+            // Read each flow from its line of JSON
+            let mut flows = Vec::new();
+            for line in reader.lines() {
+                let tensor: DataTensor = serde_json::from_str(&line?)?;
+                flows.push(FlowData::from(tensor));
+            }
+            classes.insert(class, flows);
+        }
         Ok(Dataset { classes })
     }
 
     // Saves a dataset to a json file
     /// # Parameters
     /// * `output_path` - Path to write the class datasets to
-    pub fn save<P>(self, output_path: P) -> Result<(), Error>
+    /// * `options` - Options controlling how the class datasets are written
+    pub fn save<P>(self, output_path: P, options: SaveOptions) -> Result<(), Error>
     where
         P: AsRef<Path>,
     {
-        // This type is used to represent flows as tensors instead of raw features
-        #[derive(Serialize)]
-        struct FlowDataTensor {
-            #[serde(rename = "c")]
-            class: CaptureWorkType,
-            #[serde(rename = "u")]
-            url: String,
-            #[serde(rename = "f")]
-            is_first_of_class: bool,
-            #[serde(rename = "pl")]
-            payload_length_freq_bins: Vec<f64>,
-            #[serde(rename = "iaf")]
-            interarrival_freq_from_client_bins: Vec<f64>,
-            #[serde(rename = "iat")]
-            interarrival_freq_to_client_bins: Vec<f64>,
-        };
-        impl FlowDataTensor {
-            fn from_flow_data(flow: FlowData) -> Self {
-                FlowDataTensor {
-                    class: flow.class,
-                    url: flow.url,
-                    is_first_of_class: flow.is_first_of_class,
-                    payload_length_freq_bins: flow.features.payload_length_freq_bins,
-                    interarrival_freq_from_client_bins: flow
-                        .features
-                        .interarrival_freq_from_client_bins,
-                    interarrival_freq_to_client_bins: flow
-                        .features
-                        .interarrival_freq_to_client_bins,
+        /// Writes each flow as a line of JSON
+        fn write_flows<W: Write>(writer: &mut W, flows: Vec<FlowData>) -> Result<(), Error> {
+            for flow in flows {
+                let FlowData {
+                    class,
+                    url,
+                    index,
+                    is_first_of_class,
+                    num_flows,
+                    trans_protocols,
+                    features,
+                    tls,
+                } = flow;
+                match features {
+                    Features::Packets(features) => serde_json::to_writer(
+                        &mut *writer,
+                        &FlowDataTensor {
+                            class,
+                            url,
+                            index,
+                            is_first_of_class,
+                            num_flows,
+                            trans_protocols,
+                            features: *features,
+                            tls,
+                        },
+                    )?,
+                    Features::Connections(features) => serde_json::to_writer(
+                        &mut *writer,
+                        &ConnectionDataTensor {
+                            class,
+                            url,
+                            index,
+                            is_first_of_class,
+                            trans_protocols,
+                            features,
+                            tls,
+                        },
+                    )?,
+                }
+                writer.write_all(b"\n")?;
+            }
+            Ok(())
+        }
+        /// Ensures a flow's binned features are laid out as the columns say
+        fn check_columns(flow: &FlowData, columns: &[(&str, usize)]) -> Result<(), Error> {
+            let binned = flow.features.binned();
+            ensure!(
+                binned.len() == columns.len()
+                    && binned
+                        .iter()
+                        .zip(columns)
+                        .all(|((name, bins), (column, len))| name == column && bins.len() == *len),
+                "Flow for {} has bins {:?}, but the configured bins are {:?}",
+                flow.url,
+                binned
+                    .iter()
+                    .map(|(name, bins)| (*name, bins.len()))
+                    .collect::<Vec<_>>(),
+                columns
+            );
+            Ok(())
+        }
+        /// Writes each flow as a row of CSV, naming the columns after the configured bins
+        fn write_csv_flows<W: Write>(
+            writer: &mut W,
+            flows: Vec<FlowData>,
+            columns: &[(&str, usize)],
+        ) -> Result<(), Error> {
+            let mut csv_writer = csv::Writer::from_writer(writer);
+            let mut header = vec![
+                "class".to_string(),
+                "url".to_string(),
+                "is_first_of_class".to_string(),
+            ];
+            for (name, len) in columns {
+                header.extend((0..*len).map(|idx| format!("{}_{}", name, idx)));
+            }
+            csv_writer.write_record(&header)?;
+            for flow in flows {
+                // Rows must line up with the header
+                check_columns(&flow, columns)?;
+                let mut record = vec![
+                    flow.class.to_string(),
+                    flow.url,
+                    flow.is_first_of_class.to_string(),
+                ];
+                for (_, bins) in flow.features.binned() {
+                    record.extend(bins.iter().map(f64::to_string));
                 }
+                csv_writer.write_record(&record)?;
             }
+            csv_writer.flush()?;
+            Ok(())
         }
+        /// Writes a header describing the layout of the records, then each flow as a record,
+        /// taking the layout from the configured bins
+        fn write_bincode_flows<W: Write>(
+            writer: &mut W,
+            flows: Vec<FlowData>,
+            columns: &[(&str, usize)],
+        ) -> Result<(), Error> {
+            let header = BincodeHeader {
+                num_records: flows.len() as u64,
+                columns: columns
+                    .iter()
+                    .map(|(name, len)| (name.to_string(), *len as u64))
+                    .collect(),
+            };
+            bincode::serialize_into(&mut *writer, &header)?;
+            for flow in flows {
+                // Records must all be the same size for consumers to index them
+                check_columns(&flow, columns)?;
+                bincode::serialize_into(&mut *writer, &flow.is_first_of_class)?;
+                for (_, bins) in flow.features.binned().iter() {
+                    for value in bins.iter() {
+                        bincode::serialize_into(&mut *writer, value)?;
+                    }
+                }
+            }
+            Ok(())
+        }
+        /// Writes each flow in the chosen format
+        fn write_class<W: Write>(
+            writer: &mut W,
+            flows: Vec<FlowData>,
+            format: OutputFormat,
+            columns: &[(&str, usize)],
+        ) -> Result<(), Error> {
+            match format {
+                OutputFormat::Json => write_flows(writer, flows),
+                OutputFormat::Csv => write_csv_flows(writer, flows, columns),
+                OutputFormat::Bincode => write_bincode_flows(writer, flows, columns),
+            }
+        }
+        let SaveOptions {
+            compression,
+            format,
+            sort_flows,
+            columns,
+        } = options;
+        // Save the mapping of class names to labels
+        let labels_file = File::create(output_path.as_ref().join("labels.json"))?;
+        serde_json::to_writer(BufWriter::new(labels_file), &self.labels())?;
+        // Describe each class, so consumers can preallocate without reading the class files
+        let mut metadata: BTreeMap<String, ClassMetadata> = BTreeMap::new();
         // Save each class
-        for (class, flows) in self.classes {
+        for (class, mut flows) in self.classes {
+            if sort_flows {
+                flows.sort_by(|lhs, rhs| (&lhs.url, lhs.index).cmp(&(&rhs.url, rhs.index)));
+            }
+            metadata.insert(class.to_string(), ClassMetadata::from_flows(&flows));
             let class_filename = output_path
                 .as_ref()
                 .join(class.to_string())
-                .with_extension("json.gz");
+                .with_extension(format.extension(compression));
             // Open a write handle to the file
             let output_file = File::create(class_filename)?;
-            let output_file_writer = BufWriter::new(output_file);
-            // Write to the file using gzip
-            let mut gz_writer = GzEncoder::new(output_file_writer, Compression::fast());
-            // Write bytes from each data point to the file
+            let mut output_file_writer = BufWriter::new(output_file);
+            // Write bytes from each data point to the file, finishing the compressed stream so
+            // it isn't truncated
+            match compression {
+                CompressionFormat::Gzip => {
+                    let mut gz_writer = GzEncoder::new(output_file_writer, Compression::fast());
+                    write_class(&mut gz_writer, flows, format, &columns)?;
+                    gz_writer.finish()?.flush()?;
+                }
+                CompressionFormat::Zstd => {
+                    let mut zstd_writer = zstd::Encoder::new(output_file_writer, 0)?;
+                    write_class(&mut zstd_writer, flows, format, &columns)?;
+                    zstd_writer.finish()?.flush()?;
+                }
+                CompressionFormat::None => {
+                    write_class(&mut output_file_writer, flows, format, &columns)?;
+                    output_file_writer.flush()?;
+                }
+            }
+        }
+        // Save the metadata
+        let metadata_file = File::create(output_path.as_ref().join("metadata.json"))?;
+        serde_json::to_writer(BufWriter::new(metadata_file), &metadata)?;
+        Ok(())
+    }
+
+    /// Saves the dataset split into train, validation, and test sets, in the train/, val/, and
+    /// test/ subdirectories
+    ///
+    /// Each URL is assigned to a set by hashing it with the seed, so all of a URL's flows land
+    /// in the same set, and the same seed always gives the same sets. Every set holds every
+    /// class, so their labels match
+    /// # Parameters
+    /// * `output_path` - Path to create the set directories in
+    /// * `ratios` - Fractions of the URLs to put in the train, validation, and test sets
+    /// * `seed` - Seed hashed along with each URL
+    /// * `options` - Options controlling how the class datasets are written
+    pub fn save_split<P>(
+        self,
+        output_path: P,
+        ratios: [f64; 3],
+        seed: u64,
+        options: SaveOptions,
+    ) -> Result<(), Error>
+    where
+        P: AsRef<Path>,
+    {
+        ensure!(
+            ratios.iter().all(|&ratio| ratio >= 0.0),
+            "Split ratios must not be negative"
+        );
+        ensure!(
+            (ratios.iter().sum::<f64>() - 1.0).abs() < 1e-6,
+            "Split ratios must sum to 1, not {:?}",
+            ratios
+        );
+        // Assign each flow to a set by its URL
+        let mut splits: Vec<HashMap<CaptureWorkType, Vec<FlowData>>> =
+            SPLIT_NAMES.iter().map(|_| HashMap::new()).collect();
+        for (class, flows) in self.classes {
+            for split in &mut splits {
+                split.insert(class, Vec::new());
+            }
             for flow in flows {
-                serde_json::to_writer(&mut gz_writer, &FlowDataTensor::from_flow_data(flow))?;
-                gz_writer.write(b"\n")?;
+                let split = split_index(&flow.url, seed, &ratios);
+                splits[split].entry(class).or_default().push(flow);
             }
-            // Flush the writer
-            gz_writer.flush()?;
+        }
+        // Save each set as its own dataset
+        for (split_name, classes) in SPLIT_NAMES.iter().zip(splits) {
+            let split_path = output_path.as_ref().join(split_name);
+            fs::create_dir_all(&split_path)?;
+            Dataset { classes }.save(split_path, options.clone())?;
         }
         Ok(())
     }
 }
 
+/// Chooses the set a URL belongs to, by where the hash of the URL and seed falls among the
+/// cumulative ratios of the sets
+/// # Parameters
+/// * `url` - URL to assign
+/// * `seed` - Seed hashed along with the URL
+/// * `ratios` - Fractions of the URLs to put in each set
+fn split_index(url: &str, seed: u64, ratios: &[f64]) -> usize {
+    // FNV-1a, unlike the standard library's hasher, is the same across releases
+    let hash = seed
+        .to_le_bytes()
+        .iter()
+        .chain(url.as_bytes())
+        .fold(FNV_OFFSET_BASIS, |hash, &byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME)
+        });
+    let position = hash as f64 / u64::MAX as f64;
+    let mut cumulative_ratio = 0.0;
+    for (idx, ratio) in ratios.iter().enumerate() {
+        cumulative_ratio += ratio;
+        if position < cumulative_ratio {
+            return idx;
+        }
+    }
+    // Rounding may leave the cumulative ratio just short of 1
+    ratios.iter().rposition(|&ratio| ratio > 0.0).unwrap_or(0)
+}
+
+/// Represents flows as tensors instead of raw features
+#[derive(Deserialize, Serialize)]
+struct FlowDataTensor {
+    #[serde(rename = "c")]
+    class: CaptureWorkType,
+    #[serde(rename = "u")]
+    url: String,
+    #[serde(rename = "i", default)]
+    index: u64,
+    #[serde(rename = "f")]
+    is_first_of_class: bool,
+    #[serde(rename = "nf", default)]
+    num_flows: usize,
+    #[serde(rename = "tp", default)]
+    trans_protocols: BTreeMap<TransportProtocol, usize>,
+    #[serde(flatten)]
+    features: NormalizedFlowFeatures,
+    #[serde(rename = "tls", default, skip_serializing_if = "Vec::is_empty")]
+    tls: Vec<TlsMetadata>,
+}
+impl From<FlowDataTensor> for FlowData {
+    fn from(tensor: FlowDataTensor) -> Self {
+        FlowData {
+            class: tensor.class,
+            url: tensor.url,
+            index: tensor.index,
+            is_first_of_class: tensor.is_first_of_class,
+            num_flows: tensor.num_flows,
+            trans_protocols: tensor.trans_protocols,
+            features: Features::Packets(Box::new(tensor.features)),
+            tls: tensor.tls,
+        }
+    }
+}
+/// Flows generated from the connection log alone only have connection features
+#[derive(Deserialize, Serialize)]
+struct ConnectionDataTensor {
+    #[serde(rename = "c")]
+    class: CaptureWorkType,
+    #[serde(rename = "u")]
+    url: String,
+    #[serde(rename = "i", default)]
+    index: u64,
+    #[serde(rename = "f")]
+    is_first_of_class: bool,
+    #[serde(rename = "tp", default)]
+    trans_protocols: BTreeMap<TransportProtocol, usize>,
+    #[serde(flatten)]
+    features: ConnectionFeatures,
+    #[serde(rename = "tls", default, skip_serializing_if = "Vec::is_empty")]
+    tls: Vec<TlsMetadata>,
+}
+impl From<ConnectionDataTensor> for FlowData {
+    fn from(tensor: ConnectionDataTensor) -> Self {
+        FlowData {
+            class: tensor.class,
+            url: tensor.url,
+            index: tensor.index,
+            is_first_of_class: tensor.is_first_of_class,
+            num_flows: tensor.features.num_connections,
+            trans_protocols: tensor.trans_protocols,
+            features: Features::Connections(tensor.features),
+            tls: tensor.tls,
+        }
+    }
+}
+
+/// A line of a saved class file, holding either kind of flow
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DataTensor {
+    Packets(Box<FlowDataTensor>),
+    Connections(Box<ConnectionDataTensor>),
+}
+impl From<DataTensor> for FlowData {
+    fn from(tensor: DataTensor) -> Self {
+        match tensor {
+            DataTensor::Packets(tensor) => FlowData::from(*tensor),
+            DataTensor::Connections(tensor) => FlowData::from(*tensor),
+        }
+    }
+}
+
+/// Features extracted for a flow
+#[derive(Debug)]
+pub enum Features {
+    /// Features of the packets of the flow
+    Packets(Box<NormalizedFlowFeatures>),
+    /// Features of the connections of the flow, from the connection log alone
+    Connections(ConnectionFeatures),
+}
+
+impl Features {
+    /// Short key and length of each binned feature generated with the config, in the order
+    /// `binned` gives them
+    /// # Parameters
+    /// * `config` - Options for feature generation
+    pub fn columns(config: &GeneratorConfig) -> Vec<(&'static str, usize)> {
+        if config.conn_only {
+            vec![
+                ("cs", ConnState::COUNT),
+                ("ho", HistoryEntry::COUNT),
+                ("hr", HistoryEntry::COUNT),
+            ]
+        } else {
+            vec![
+                ("pl", config.bins.payload_length.len()),
+                ("iaf", config.bins.ia_from_client.len()),
+                ("iat", config.bins.ia_to_client.len()),
+                ("bs", config.bins.burst_size.len()),
+                ("en", NUM_ENTROPY_BINS),
+            ]
+        }
+    }
+
+    /// Binned features, named by their short key, in the order they're written as CSV
+    fn binned(&self) -> Vec<(&'static str, &[f64])> {
+        match *self {
+            Features::Packets(ref features) => vec![
+                ("pl", &features.payload_length_freq_bins),
+                ("iaf", &features.interarrival_freq_from_client_bins),
+                ("iat", &features.interarrival_freq_to_client_bins),
+                ("bs", &features.burst_size_freq_bins),
+                ("en", &features.entropy_freq_bins),
+            ],
+            Features::Connections(ref features) => vec![
+                ("cs", &features.conn_state_freq),
+                ("ho", &features.history_freq_from_orig),
+                ("hr", &features.history_freq_from_resp),
+            ],
+        }
+    }
+}
+
+/// Describes the capture a pcap file was recorded for
+#[derive(Clone, Debug)]
+pub struct CaptureInfo {
+    /// Class of data gathered in the pcap
+    pub class: CaptureWorkType,
+    /// The URL that was requested
+    pub url: String,
+    /// Index of the URL in the URLs file
+    pub index: u64,
+    /// Whether the pcap was the first of its class to be run on the worker
+    pub is_first_of_class: bool,
+}
+
 /// Represents data from a single flow. Many of these can exist per pcap file
 #[derive(Debug)]
 pub struct FlowData {
@@ -150,18 +750,28 @@ pub struct FlowData {
     class: CaptureWorkType,
     /// The URL that was requested that this flow was performed as part of
     url: String,
+    /// Index of the URL in the URLs file
+    index: u64,
     /// Whether this pcap was the first of its class to be run on the worker
     /// This matters for meek (first time initialization)
     pub is_first_of_class: bool,
-    /// Features of the packets of this flow
-    features: NormalizedFlowFeatures,
+    /// Number of flows (or connections, for connection features) combined into this one. The
+    /// features of a flow without any are all zeroes
+    num_flows: usize,
+    /// Number of the flows combined into this one using each transport protocol
+    trans_protocols: BTreeMap<TransportProtocol, usize>,
+    /// Features of this flow
+    features: Features,
+    /// TLS metadata of the sessions zeek found in the flow's connections
+    tls: Vec<TlsMetadata>,
 }
 impl FlowData {
     /// Loads a class dataset from a directory
-    #[allow(unused)]
     pub fn load<P>(
         report: WorkReportRequest<CaptureWorkType, CaptureWork>,
         data_path: P,
+        config: &GeneratorConfig,
+        prefetcher: Option<&PcapPrefetcher>,
     ) -> Result<Self, Error>
     where
         P: AsRef<Path>,
@@ -174,130 +784,180 @@ impl FlowData {
             ..
         } = report;
         // Split work
-        let CaptureWork { url, filename, .. } = work;
+        let CaptureWork {
+            index,
+            url,
+            filename,
+        } = work;
         // Copy the paths
         let data_path = data_path.as_ref();
-        // Create a scratch dir
-        // TODO: change name here when we change the crate name
-        let scratch_dir = TempDir::new("data_generator")?;
-        // Get path to scratch dir
-        let scratch_path = scratch_dir.path();
         // Ensure the data directory is a directory
         ensure!(data_path.is_dir(), "Class directory must be a directory");
         // Iterate over the PCAP files in the class directory
-        // Get path to the pcap file using the data directory and filename
+        // Get path to the pcap file using the data directory and filename. Absolute filenames
+        // replace the data directory
         let pcap_path = data_path.join(filename);
+        // Take the pcap's contents if they were read ahead. This is done up front so the
+        // prefetcher doesn't keep holding them if loading fails
+        let pcap_data = prefetcher.and_then(|prefetcher| prefetcher.take(&pcap_path));
         // Ensure pcap_file is a file
         ensure!(
             pcap_path.is_file(),
             "Items in a class directory must be files"
         );
-        // Ensure the scratch directory is a directory
-        ensure!(
-            scratch_path.is_dir(),
-            "Scratch directory must be a directory"
-        );
-        // Run BRO on the pcap file
-        info!("Running bro on {:?}", pcap_path);
-        let bro_return = Command::new("zeek")
-            .current_dir(scratch_path)
-            .arg("-b")
-            .arg("-e")
-            .arg("redef LogAscii::use_json=T")
-            .arg("-C")
-            .arg("-r")
-            .arg(
-                pcap_path
-                    .to_str()
-                    .ok_or_else(|| format_err!("Path string could not be parsed"))?,
-            )
-            .arg("base/protocols/conn")
-            .status()?;
-        info!("Finished running bro on {:?}", pcap_path);
-        // Check error code
-        ensure!(bro_return.success(), "Bro exited with failure code");
-        info!("Loading connection log for {:?}", pcap_path);
-        // Load the connection log
-        let conn_log_path = scratch_path.join("conn.log");
-        let connections = Connection::load_connections(&conn_log_path)?
-            .filter(|connection| connection.orig_port == 443 || connection.resp_port == 443);
-        // Delete the bro folder
-        info!("Cleaning up bro scratch dir");
-        scratch_dir.close()?;
+        let capture = CaptureInfo {
+            class,
+            url,
+            index,
+            is_first_of_class: type_index == 1,
+        };
+        // Find the connections and TLS sessions in the pcap
+        let (connections, ssl_records) = run_zeek(&pcap_path, config)?;
+        // Skip the packets entirely when only connection features are wanted
+        if config.conn_only {
+            return Ok(FlowData::from_connections(
+                capture,
+                connections,
+                ssl_records,
+                config,
+            ));
+        }
         // Read in packets from the pcap
-        info!("Loading packets from {:?}", pcap_path);
-        let packets = Packet::load_from_pcap(&pcap_path)?
-            .filter(|packet| packet.src_port == 443 || packet.dst_port == 443)
-            .collect();
-        // Aggregate the connection log and pcap
-        // Initialize a flow aggregator
-        info!("Performing packet aggregation");
-        let mut flow_aggregator = FlowAggregator::new(connections, 1_000_000_000, 5_000_000_000);
-        // Load the packets into the aggregator
-        flow_aggregator.load_packets(packets);
-        // Create a set of directional inference methods
-        // TODO: take this as config
-        let dir_inference_methods = vec![DirectionInferenceMethod::ServerPort(443)];
-        // Create a set of feature generation bins
-        // TODO: take this as config
-        let payload_size_bins: Vec<usize> = (10..=100)
-            .step_by(10)
-            .chain((200..=1000).step_by(100))
-            .chain((2000..=10000).step_by(1000))
-            .chain(Some(65536))
-            .collect();
-        // Create variable so it's easier to keep track of time periods
-        // Our timestamps are in nanoseconds. Convert here to ms
-        let ms: u64 = 1_000_000;
-        let interarrival_from_client_bins: Vec<u64> = (1 * ms..=10 * ms)
-            .step_by(1 * ms as usize)
-            .chain((20 * ms..=100 * ms).step_by(10 * ms as usize))
-            .chain((200 * ms..=1000 * ms).step_by(100 * ms as usize))
-            .chain(Some(10_000 * ms))
-            .collect();
-        // Use the same periods for to_client
-        let interarrival_to_client_bins = interarrival_from_client_bins.clone();
-        // Extract the aggregated flows from the aggregator
-        let (num_flows, features) = flow_aggregator
-            .into_aggregated_flows()
+        let packets = load_packets(&pcap_path, pcap_data, config)?;
+        Ok(FlowData::from_packets_and_connections(
+            capture,
+            packets,
+            connections,
+            ssl_records,
+            config,
+        ))
+    }
+
+    /// Generates flow data from packets and the connections they belong to, without touching
+    /// the filesystem
+    /// # Parameters
+    /// * `capture` - Capture the pcap was recorded for
+    /// * `packets` - Packets loaded from the pcap
+    /// * `connections` - Connections zeek found in the pcap
+    /// * `ssl_records` - TLS sessions zeek found in the pcap
+    /// * `config` - Options for feature generation
+    pub fn from_packets_and_connections(
+        capture: CaptureInfo,
+        packets: impl IntoIterator<Item = Packet>,
+        connections: Vec<Connection>,
+        ssl_records: Vec<SslRecord>,
+        config: &GeneratorConfig,
+    ) -> Self {
+        let CaptureInfo {
+            class,
+            url,
+            index,
+            is_first_of_class,
+        } = capture;
+        let flows = aggregate_flows(&url, packets, connections, config);
+        // Attach the TLS metadata of the flows that were kept
+        let tls = join_ssl_records(ssl_records, |uid| flows.contains_key(uid));
+        // Count the flows using each transport protocol
+        let mut trans_protocols = BTreeMap::new();
+        for flow in flows.values() {
+            let trans_protocol = TransportProtocol::from_code(flow.key.trans_protocol());
+            *trans_protocols.entry(trans_protocol).or_insert(0) += 1;
+        }
+        let bins = &config.bins;
+        // Fold the flows in the order of their uids, so the payload prefixes they're combined
+        // into are the same each run
+        let flows = flows
             .into_iter()
-            // Convert each flow's packets into features
-            .map(move |(_, packets)| {
-                PacketFeatures::from_stripped_packets(packets, &dir_inference_methods)
-            })
-            // Encapsulate the flow
-            .map(|features| {
-                FlowFeatures::generate(
-                    &features,
-                    &payload_size_bins,
-                    &interarrival_from_client_bins,
-                    &interarrival_to_client_bins,
-                )
-            })
+            .sorted_by(|(lhs, _), (rhs, _)| lhs.cmp(rhs))
+            .map(|(_, flow)| flow);
+        let (num_flows, features) = generate_flow_features(flows, config)
             // Aggregate the many flows associated with a request into a single flow
             .fold(
                 (
                     0,
                     FlowFeatures::empty(
-                        payload_size_bins.len(),
-                        interarrival_from_client_bins.len(),
-                        interarrival_to_client_bins.len(),
+                        bins.payload_length.len(),
+                        bins.ia_from_client.len(),
+                        bins.ia_to_client.len(),
+                        bins.burst_size.len(),
+                        config.payload_prefix_length,
                     ),
                 ),
-                |(count, flow_acc), flow| (0, flow_acc + flow),
+                |(count, flow_acc), flow| (count + 1, flow_acc + flow),
             );
-        Ok(FlowData {
+        FlowData {
             class,
-            url: url.clone(),
-            is_first_of_class: type_index == 1,
-            features: features.normalize(),
-        })
+            url,
+            index,
+            is_first_of_class,
+            num_flows,
+            trans_protocols,
+            features: Features::Packets(Box::new(features.normalize())),
+            tls,
+        }
+    }
+
+    /// Generates flow data from the connections zeek found, without looking at any packets
+    /// # Parameters
+    /// * `capture` - Capture the pcap was recorded for
+    /// * `connections` - Connections zeek found in the pcap
+    /// * `ssl_records` - TLS sessions zeek found in the pcap
+    /// * `config` - Options for feature generation
+    pub fn from_connections(
+        capture: CaptureInfo,
+        connections: Vec<Connection>,
+        ssl_records: Vec<SslRecord>,
+        config: &GeneratorConfig,
+    ) -> Self {
+        let CaptureInfo {
+            class,
+            url,
+            index,
+            is_first_of_class,
+        } = capture;
+        // Only keep connections using the server ports and protocols, that completed cleanly if
+        // asked for
+        let connections: Vec<Connection> = connections
+            .into_iter()
+            .filter(|connection| {
+                (config.server_ports.contains(&connection.orig_port)
+                    || config.server_ports.contains(&connection.resp_port))
+                    && config.server_protocols.contains(&connection.trans_protocol)
+            })
+            .filter(|connection| {
+                !config.require_clean_completion || connection.conn_state == Some(ConnState::SF)
+            })
+            .collect();
+        let tls = join_ssl_records(ssl_records, |uid| {
+            connections.iter().any(|connection| connection.uid == uid)
+        });
+        // Count the connections using each transport protocol
+        let mut trans_protocols = BTreeMap::new();
+        for connection in &connections {
+            *trans_protocols
+                .entry(connection.trans_protocol)
+                .or_insert(0) += 1;
+        }
+        FlowData {
+            class,
+            url,
+            index,
+            is_first_of_class,
+            num_flows: connections.len(),
+            trans_protocols,
+            features: Features::Connections(ConnectionFeatures::from_connections(&connections)),
+            tls,
+        }
     }
 }
 
-#[derive(Serialize)]
+/// Describes the flows saved for a class
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
 struct ClassMetadata {
+    /// Number of flows saved
     num_samples: usize,
+    /// Lengths of the payload length, interarrival from client, and interarrival to client bins,
+    /// or of the connection state and history frequencies for connection features
     sample_size: Vec<usize>,
 }
 impl ClassMetadata {
@@ -307,4 +967,1466 @@ impl ClassMetadata {
             sample_size,
         }
     }
+
+    /// Describes the flows of a class
+    ///
+    /// Every flow is generated with the same bins, so the bin lengths are taken from the first
+    /// flow. A class without flows has no bin lengths
+    /// # Parameters
+    /// * `flows` - Flows of the class
+    fn from_flows(flows: &[FlowData]) -> Self {
+        let sample_size = flows
+            .first()
+            .map(|flow| match flow.features {
+                Features::Packets(ref features) => vec![
+                    features.payload_length_freq_bins.len(),
+                    features.interarrival_freq_from_client_bins.len(),
+                    features.interarrival_freq_to_client_bins.len(),
+                ],
+                Features::Connections(ref features) => vec![
+                    features.conn_state_freq.len(),
+                    features.history_freq_from_orig.len(),
+                ],
+            })
+            .unwrap_or_default();
+        ClassMetadata::new(flows.len(), sample_size)
+    }
+}
+
+/// Generates the features of each flow in a pcap, without aggregating them into a sample
+///
+/// Zeek is run on the pcap to find its connections, and flows are only kept if they use one
+/// of the server ports. Flows are ordered by the uid zeek gave their connection
+/// # Parameters
+/// * `pcap_path` - Path to the pcap file
+/// * `server_ports` - Ports the servers of interest listen on
+/// * `bin_config` - Bins used to generate the features
+pub fn features_from_pcap<P>(
+    pcap_path: P,
+    server_ports: &[u16],
+    bin_config: &BinConfig,
+) -> Result<Vec<NormalizedFlowFeatures>, Error>
+where
+    P: AsRef<Path>,
+{
+    let config = GeneratorConfig {
+        server_ports: server_ports.to_vec(),
+        bins: bin_config.clone(),
+        ..GeneratorConfig::default()
+    };
+    flow_features_from_pcap(pcap_path.as_ref(), &config)
+}
+
+/// Generates the features of each flow in a pcap, ordered by their connection's uid
+/// # Parameters
+/// * `pcap_path` - Path to the pcap file
+/// * `config` - Options for feature generation
+fn flow_features_from_pcap(
+    pcap_path: &Path,
+    config: &GeneratorConfig,
+) -> Result<Vec<NormalizedFlowFeatures>, Error> {
+    ensure!(
+        !config.server_ports.is_empty(),
+        "At least one server port is required"
+    );
+    ensure!(pcap_path.is_file(), "Path to pcap must be a file");
+    let (connections, _) = run_zeek(pcap_path, config)?;
+    let packets = load_packets(pcap_path, None, config)?;
+    let flows = aggregate_flows(&pcap_path.to_string_lossy(), packets, connections, config);
+    let flows = flows
+        .into_iter()
+        .sorted_by(|(lhs, _), (rhs, _)| lhs.cmp(rhs))
+        .map(|(_, flow)| flow);
+    Ok(generate_flow_features(flows, config)
+        .map(FlowFeatures::normalize)
+        .collect())
+}
+
+/// Runs zeek on a pcap in a scratch directory, returning the connections and TLS sessions it
+/// found
+/// # Parameters
+/// * `pcap_path` - Path to the pcap file
+/// * `config` - Options for feature generation, giving the zeek executable and scripts
+fn run_zeek(
+    pcap_path: &Path,
+    config: &GeneratorConfig,
+) -> Result<(Vec<Connection>, Vec<SslRecord>), Error> {
+    // Create a scratch dir
+    // TODO: change name here when we change the crate name
+    let scratch_dir = TempDir::new("data_generator")?;
+    // Get path to scratch dir
+    let scratch_path = scratch_dir.path();
+    // Ensure the scratch directory is a directory
+    ensure!(
+        scratch_path.is_dir(),
+        "Scratch directory must be a directory"
+    );
+    // Run BRO on the pcap file
+    info!("Running bro on {:?}", pcap_path);
+    let bro_return = Command::new(&config.zeek_path)
+        .current_dir(scratch_path)
+        .arg("-b")
+        .arg("-e")
+        .arg("redef LogAscii::use_json=T")
+        .arg("-C")
+        .arg("-r")
+        .arg(
+            pcap_path
+                .to_str()
+                .ok_or_else(|| format_err!("Path string could not be parsed"))?,
+        )
+        .args(&config.zeek_scripts)
+        .status()
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => format_err!(
+                "Zeek executable {:?} was not found. Set zeek_path in the config to its location",
+                config.zeek_path
+            ),
+            _ => format_err!(
+                "Failed to run zeek executable {:?}: {}",
+                config.zeek_path,
+                err
+            ),
+        })?;
+    info!("Finished running bro on {:?}", pcap_path);
+    // Check error code
+    ensure!(bro_return.success(), "Bro exited with failure code");
+    info!("Loading connection log for {:?}", pcap_path);
+    // Load the connection log
+    let conn_log_path = scratch_path.join("conn.log");
+    let connections: Vec<Connection> = Connection::load_connections(&conn_log_path)?.collect();
+    // Load the ssl log, which zeek only writes if it ran the ssl script and saw TLS
+    let ssl_log_path = scratch_path.join("ssl.log");
+    let ssl_records: Vec<SslRecord> = if ssl_log_path.is_file() {
+        SslRecord::load_ssl(&ssl_log_path)?.collect()
+    } else {
+        Vec::new()
+    };
+    // Delete the bro folder
+    info!("Cleaning up bro scratch dir");
+    scratch_dir.close()?;
+    Ok((connections, ssl_records))
+}
+
+/// Loads the packets of a pcap, from its contents if they were read ahead
+///
+/// Packets are parsed as they're iterated over, so they don't all need to be held at once
+/// # Parameters
+/// * `pcap_path` - Path to the pcap file
+/// * `pcap_data` - Contents of the pcap file, if they were read ahead
+/// * `config` - Options for feature generation
+fn load_packets(
+    pcap_path: &Path,
+    pcap_data: Option<Vec<u8>>,
+    config: &GeneratorConfig,
+) -> Result<impl Iterator<Item = Packet>, Error> {
+    // Read in packets from the pcap
+    info!("Loading packets from {:?}", pcap_path);
+    let load_options = LoadOptions {
+        fragment_timeout: config.fragment_timeout,
+        payload_prefix_length: config.payload_prefix_length,
+        no_next_header: config.no_next_header,
+        parse_batch_size: config.parse_batch_size,
+    };
+    Ok(match pcap_data {
+        Some(pcap_data) => Either::Left(Packet::load_from_pcap_data(pcap_data, load_options)?),
+        None => Either::Right(Packet::load_from_pcap(pcap_path, load_options)?),
+    })
+}
+
+/// Aggregates packets into the flows of the connections they belong to, keeping only the
+/// flows the config asks for
+/// # Parameters
+/// * `name` - Name of the pcap, such as its URL, used in log messages
+/// * `packets` - Packets loaded from the pcap, which are filtered as they're loaded
+/// * `connections` - Connections zeek found in the pcap
+/// * `config` - Options for feature generation
+fn aggregate_flows(
+    name: &str,
+    packets: impl IntoIterator<Item = Packet>,
+    connections: Vec<Connection>,
+    config: &GeneratorConfig,
+) -> HashMap<String, Flow> {
+    // Only keep connections and packets using the server ports and protocols
+    let connections = connections.into_iter().filter(|connection| {
+        (config.server_ports.contains(&connection.orig_port)
+            || config.server_ports.contains(&connection.resp_port))
+            && config.server_protocols.contains(&connection.trans_protocol)
+    });
+    let packets = packets.into_iter().filter(|packet| {
+        (config.server_ports.contains(&packet.src_port)
+            || config.server_ports.contains(&packet.dst_port))
+            && config
+                .server_protocols
+                .contains(&TransportProtocol::from_code(packet.trans_protocol))
+    });
+    // Aggregate the connection log and pcap
+    // Initialize a flow aggregator
+    info!("Performing packet aggregation");
+    let mut flow_aggregator = FlowAggregator::new(
+        connections,
+        config.grace_period_before,
+        config.grace_period_after,
+    )
+    .with_payload_prefix_length(config.payload_prefix_length);
+    // Load the packets into the aggregator
+    flow_aggregator.load_packets(packets);
+    // Packets that could belong to several connections may be assigned to the wrong one
+    let num_packets = flow_aggregator.num_packets();
+    let num_ambiguous_packets = flow_aggregator.num_ambiguous_packets();
+    info!(
+        "{} of {} packets for {} matched more than one connection",
+        num_ambiguous_packets, num_packets, name
+    );
+    if let Some(max_ambiguous_fraction) = config.max_ambiguous_fraction {
+        if num_packets > 0
+            && num_ambiguous_packets as f64 / num_packets as f64 > max_ambiguous_fraction
+        {
+            warn!(
+                "{} of {} packets for {} matched more than one connection. Consider reducing \
+                 grace_period_before and grace_period_after",
+                num_ambiguous_packets, num_packets, name
+            );
+        }
+    }
+    // Connections without packets suggest the pcap and connection log don't correspond
+    let unmatched_connections = flow_aggregator.unmatched_connections();
+    if !unmatched_connections.is_empty() {
+        warn!(
+            "{} connections for {} matched no packets: {:?}",
+            unmatched_connections.len(),
+            name,
+            unmatched_connections
+        );
+    }
+    // Extract the aggregated flows from the aggregator
+    let mut flows = flow_aggregator.into_aggregated_flows();
+    // Drop flows that didn't complete cleanly
+    if config.require_clean_completion {
+        let num_flows = flows.len();
+        let num_dropped = retain_clean_completion(&mut flows);
+        info!(
+            "Dropped {} of {} flows for {} that didn't complete cleanly",
+            num_dropped, num_flows, name
+        );
+    }
+    // Drop flows that are likely plaintext
+    if let Some(min_flow_entropy) = config.min_flow_entropy {
+        let num_flows = flows.len();
+        let num_dropped = retain_min_entropy(&mut flows, min_flow_entropy);
+        info!(
+            "Dropped {} of {} flows for {} below the minimum entropy",
+            num_dropped, num_flows, name
+        );
+    }
+    flows
+}
+
+/// Gives the methods used to infer the direction of packets, in the order they're tried
+///
+/// The configured server ports are preferred, and well known ports are only used if asked for
+/// # Parameters
+/// * `config` - Options for feature generation
+fn direction_inference_methods(config: &GeneratorConfig) -> Vec<DirectionInferenceMethod> {
+    let server_port_method = match config.server_ports[..] {
+        [server_port] => DirectionInferenceMethod::ServerPort(server_port),
+        _ => DirectionInferenceMethod::ServerPorts(config.server_ports.iter().cloned().collect()),
+    };
+    let mut methods = vec![server_port_method, DirectionInferenceMethod::Handshake];
+    if config.infer_direction_from_well_known_ports {
+        methods.push(DirectionInferenceMethod::WellKnown);
+    }
+    methods.push(DirectionInferenceMethod::Ephemeral);
+    methods
+}
+
+/// Generates the features of each flow, inferring the direction of its packets
+/// # Parameters
+/// * `flows` - Flows to generate features for
+/// * `config` - Options for feature generation
+fn generate_flow_features<'a, I>(
+    flows: I,
+    config: &'a GeneratorConfig,
+) -> impl Iterator<Item = FlowFeatures> + 'a
+where
+    I: IntoIterator<Item = Flow>,
+    I::IntoIter: 'a,
+{
+    let dir_inference_methods = direction_inference_methods(config);
+    flows.into_iter().map(move |flow| {
+        // Convert each flow's packets into features
+        let packet_features =
+            PacketFeatures::from_stripped_packets(&flow.key, &flow.packets, &dir_inference_methods);
+        // Encapsulate the flow
+        let features =
+            FlowFeatures::generate(&packet_features, &config.bins, config.payload_prefix_length);
+        // The flow's prefixes are kept by endpoint, so find which one is the client
+        let a_is_client =
+            flow.packets
+                .iter()
+                .zip(&packet_features)
+                .find_map(|(packet, features)| match features.direction {
+                    PacketDirection::FromClient => Some(packet.sent_by_a),
+                    PacketDirection::ToClient => Some(!packet.sent_by_a),
+                    PacketDirection::Unknown => None,
+                });
+        match a_is_client {
+            Some(true) => {
+                features.with_payload_prefixes(&flow.payload_prefix_a, &flow.payload_prefix_b)
+            }
+            Some(false) => {
+                features.with_payload_prefixes(&flow.payload_prefix_b, &flow.payload_prefix_a)
+            }
+            None => features,
+        }
+    })
+}
+
+/// Joins TLS sessions to the flows they were carried in by their connection's uid
+///
+/// Sessions are ordered by uid, so the output doesn't depend on the order zeek logged them in
+/// # Parameters
+/// * `ssl_records` - TLS sessions zeek found in the pcap
+/// * `is_kept` - Whether the flow with a uid was kept
+fn join_ssl_records<F>(ssl_records: Vec<SslRecord>, is_kept: F) -> Vec<TlsMetadata>
+where
+    F: Fn(&str) -> bool,
+{
+    ssl_records
+        .into_iter()
+        .filter(|ssl_record| is_kept(&ssl_record.uid))
+        .sorted_by(|a, b| a.uid.cmp(&b.uid))
+        .map(TlsMetadata::from)
+        .collect()
+}
+
+/// Drops flows whose connection wasn't established and terminated normally
+///
+/// Returns the number of flows dropped
+/// # Parameters
+/// * `flows` - Packets of each flow
+fn retain_clean_completion(flows: &mut HashMap<String, Flow>) -> usize {
+    let num_flows = flows.len();
+    flows.retain(|_, flow| flow.conn_state == Some(ConnState::SF));
+    num_flows - flows.len()
+}
+
+/// Drops flows whose mean payload entropy is below a minimum
+///
+/// Returns the number of flows dropped
+/// # Parameters
+/// * `flows` - Packets of each flow
+/// * `min_entropy` - Minimum mean payload entropy (in bits per byte) of a flow
+fn retain_min_entropy(flows: &mut HashMap<String, Flow>, min_entropy: f64) -> usize {
+    let num_flows = flows.len();
+    flows.retain(|_, flow| mean_payload_entropy(&flow.packets) >= min_entropy);
+    num_flows - flows.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bro_types::tests::connection_at;
+    use crate::bro_types::ConnState;
+    use crate::flow_aggregator::PacketKey;
+    use crate::packet::tests::{ethernet, ipv4, packet_at, reply, stripped_packet_at, tcp};
+    use crate::packet::StrippedPacket;
+    use crate::pcap::tests::build_pcap;
+    use crate::progress::Status;
+    use std::collections::HashSet;
+    use std::fs;
+    use std::io::Read;
+    use std::os::unix::fs::PermissionsExt;
+    use tempdir::TempDir;
+
+    /// Creates a flow of a URL with all zero packet features, with a single bin for each binned
+    /// feature
+    fn flow_data(class: CaptureWorkType, url: &str) -> FlowData {
+        FlowData {
+            class,
+            url: url.to_string(),
+            index: 0,
+            is_first_of_class: false,
+            num_flows: 0,
+            trans_protocols: BTreeMap::new(),
+            features: Features::Packets(Box::new(FlowFeatures::empty(1, 1, 1, 1, 0).normalize())),
+            tls: vec![],
+        }
+    }
+
+    /// Creates the capture of https://example.com for a class
+    fn capture(class: CaptureWorkType, is_first_of_class: bool) -> CaptureInfo {
+        CaptureInfo {
+            class,
+            url: "https://example.com".to_string(),
+            index: 0,
+            is_first_of_class,
+        }
+    }
+
+    /// Tests that labels do not depend on the order classes were loaded in
+    #[test]
+    fn test_labels() {
+        let mut classes = HashMap::new();
+        classes.insert(CaptureWorkType::Tor, vec![]);
+        classes.insert(CaptureWorkType::Normal, vec![]);
+        let dataset = Dataset { classes };
+        let labels = dataset.labels();
+        assert_eq!(labels["normal"], 0);
+        assert_eq!(labels["tor"], 1);
+        // The saved labels match
+        let output_dir = TempDir::new("data_generator").unwrap();
+        dataset
+            .save(output_dir.path(), SaveOptions::default())
+            .unwrap();
+        let labels_file = File::open(output_dir.path().join("labels.json")).unwrap();
+        let saved_labels: BTreeMap<String, usize> = serde_json::from_reader(labels_file).unwrap();
+        assert_eq!(saved_labels, labels);
+    }
+
+    /// Tests that the saved metadata describes each class
+    #[test]
+    fn test_metadata() {
+        let flow = || FlowData {
+            features: Features::Packets(Box::new(FlowFeatures::empty(5, 4, 3, 2, 0).normalize())),
+            ..flow_data(CaptureWorkType::Tor, "https://example.com")
+        };
+        let mut classes = HashMap::new();
+        classes.insert(CaptureWorkType::Tor, vec![flow(), flow()]);
+        classes.insert(CaptureWorkType::Normal, vec![]);
+        let output_dir = TempDir::new("data_generator").unwrap();
+        Dataset { classes }
+            .save(output_dir.path(), SaveOptions::default())
+            .unwrap();
+        let metadata_file = File::open(output_dir.path().join("metadata.json")).unwrap();
+        let metadata: BTreeMap<String, ClassMetadata> =
+            serde_json::from_reader(metadata_file).unwrap();
+        assert_eq!(metadata["tor"], ClassMetadata::new(2, vec![5, 4, 3]));
+        assert_eq!(metadata["normal"], ClassMetadata::new(0, vec![]));
+    }
+
+    /// Tests that each compression format writes one line of JSON per flow
+    #[test]
+    fn test_compression() {
+        let flow = || FlowData {
+            features: Features::Packets(Box::new(FlowFeatures::empty(5, 4, 3, 2, 0).normalize())),
+            ..flow_data(CaptureWorkType::Tor, "https://example.com")
+        };
+        for &compression in &[
+            CompressionFormat::Gzip,
+            CompressionFormat::Zstd,
+            CompressionFormat::None,
+        ] {
+            let mut classes = HashMap::new();
+            classes.insert(CaptureWorkType::Tor, vec![flow(), flow()]);
+            let output_dir = TempDir::new("data_generator").unwrap();
+            Dataset { classes }
+                .save(
+                    output_dir.path(),
+                    SaveOptions {
+                        compression,
+                        ..SaveOptions::default()
+                    },
+                )
+                .unwrap();
+            let class_file = File::open(
+                output_dir
+                    .path()
+                    .join("tor")
+                    .with_extension(compression.extension()),
+            )
+            .unwrap();
+            let contents = match compression {
+                CompressionFormat::Gzip => {
+                    let mut contents = Vec::new();
+                    flate2::read::GzDecoder::new(class_file)
+                        .read_to_end(&mut contents)
+                        .unwrap();
+                    contents
+                }
+                CompressionFormat::Zstd => zstd::decode_all(class_file).unwrap(),
+                CompressionFormat::None => {
+                    let mut contents = Vec::new();
+                    let mut class_file = class_file;
+                    class_file.read_to_end(&mut contents).unwrap();
+                    contents
+                }
+            };
+            let lines: Vec<serde_json::Value> = contents
+                .lines()
+                .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+                .collect();
+            assert_eq!(lines.len(), 2, "{:?}", compression);
+            assert_eq!(lines[0]["u"], "https://example.com");
+            assert_eq!(lines[0]["nf"], 0);
+        }
+    }
+
+    /// Tests that CSV output has a header naming each binned feature, and a row per flow
+    #[test]
+    fn test_csv() {
+        let flow = |url| FlowData {
+            features: Features::Packets(Box::new(FlowFeatures::empty(2, 1, 1, 2, 0).normalize())),
+            ..flow_data(CaptureWorkType::Tor, url)
+        };
+        let mut classes = HashMap::new();
+        classes.insert(
+            CaptureWorkType::Tor,
+            vec![flow("https://example.com"), flow("https://example.org")],
+        );
+        classes.insert(CaptureWorkType::Normal, vec![]);
+        let output_dir = TempDir::new("data_generator").unwrap();
+        Dataset { classes }
+            .save(
+                output_dir.path(),
+                SaveOptions {
+                    compression: CompressionFormat::None,
+                    format: OutputFormat::Csv,
+                    columns: vec![("pl", 2), ("iaf", 1), ("iat", 1), ("bs", 2), ("en", 8)],
+                    ..SaveOptions::default()
+                },
+            )
+            .unwrap();
+        let mut reader = csv::Reader::from_path(output_dir.path().join("tor.csv")).unwrap();
+        let mut header = vec!["class", "url", "is_first_of_class", "pl_0", "pl_1", "iaf_0"];
+        header.extend(&["iat_0", "bs_0", "bs_1"]);
+        let entropy_columns: Vec<String> = (0..8).map(|idx| format!("en_{}", idx)).collect();
+        header.extend(entropy_columns.iter().map(String::as_str));
+        assert_eq!(reader.headers().unwrap(), header);
+        let rows: Vec<csv::StringRecord> = reader.records().map(Result::unwrap).collect();
+        assert_eq!(rows.len(), 2);
+        let mut row = vec!["tor", "https://example.org", "false"];
+        row.extend(std::iter::repeat_n("0", 14));
+        assert_eq!(rows[1], row);
+        // Classes without flows still name every configured bin
+        let contents = fs::read_to_string(output_dir.path().join("normal.csv")).unwrap();
+        assert_eq!(contents, format!("{}\n", header.join(",")));
+        // Flows whose bins don't match the configured bins are rejected, even the first
+        let mut classes = HashMap::new();
+        classes.insert(CaptureWorkType::Tor, vec![flow("https://example.com")]);
+        let err = Dataset { classes }
+            .save(
+                output_dir.path(),
+                SaveOptions {
+                    compression: CompressionFormat::None,
+                    format: OutputFormat::Csv,
+                    columns: vec![("pl", 3), ("iaf", 1), ("iat", 1), ("bs", 2), ("en", 8)],
+                    ..SaveOptions::default()
+                },
+            )
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Flow for https://example.com has bins"));
+    }
+
+    /// Tests that the columns given for a config match the bins of the flows generated with it
+    #[test]
+    fn test_columns() {
+        let config = GeneratorConfig::default();
+        let flow_data = FlowData::from_packets_and_connections(
+            capture(CaptureWorkType::Tor, true),
+            vec![],
+            vec![],
+            vec![],
+            &config,
+        );
+        let binned = flow_data.features.binned();
+        let layout: Vec<(&str, usize)> = binned
+            .iter()
+            .map(|(name, bins)| (*name, bins.len()))
+            .collect();
+        assert_eq!(layout, Features::columns(&config));
+        let config = GeneratorConfig {
+            conn_only: true,
+            ..config
+        };
+        let flow_data = FlowData::from_connections(
+            capture(CaptureWorkType::Tor, true),
+            vec![],
+            vec![],
+            &config,
+        );
+        let binned = flow_data.features.binned();
+        let layout: Vec<(&str, usize)> = binned
+            .iter()
+            .map(|(name, bins)| (*name, bins.len()))
+            .collect();
+        assert_eq!(layout, Features::columns(&config));
+    }
+
+    /// Tests that bincode output has a header describing fixed size records
+    #[test]
+    fn test_bincode() {
+        let flow = |is_first_of_class, payload_length| {
+            let mut features = FlowFeatures::empty(2, 1, 1, 2, 0).normalize();
+            features.payload_length_freq_bins = payload_length;
+            FlowData {
+                is_first_of_class,
+                features: Features::Packets(Box::new(features)),
+                ..flow_data(CaptureWorkType::Tor, "https://example.com")
+            }
+        };
+        let mut classes = HashMap::new();
+        classes.insert(
+            CaptureWorkType::Tor,
+            vec![flow(true, vec![0.25, 0.75]), flow(false, vec![1.0, 0.0])],
+        );
+        classes.insert(CaptureWorkType::Normal, vec![]);
+        let save_options = SaveOptions {
+            compression: CompressionFormat::None,
+            format: OutputFormat::Bincode,
+            columns: vec![("pl", 2), ("iaf", 1), ("iat", 1), ("bs", 2), ("en", 8)],
+            ..SaveOptions::default()
+        };
+        let output_dir = TempDir::new("data_generator").unwrap();
+        Dataset { classes }
+            .save(output_dir.path(), save_options.clone())
+            .unwrap();
+        let contents = fs::read(output_dir.path().join("tor.bin")).unwrap();
+        let mut reader = &contents[..];
+        let header: BincodeHeader = bincode::deserialize_from(&mut reader).unwrap();
+        assert_eq!(
+            header,
+            BincodeHeader {
+                num_records: 2,
+                columns: vec![
+                    ("pl".to_string(), 2),
+                    ("iaf".to_string(), 1),
+                    ("iat".to_string(), 1),
+                    ("bs".to_string(), 2),
+                    ("en".to_string(), 8)
+                ],
+            }
+        );
+        // Each record is a byte followed by 14 f64s
+        let record_size = 1 + 14 * 8;
+        assert_eq!(reader.len(), 2 * record_size);
+        assert_eq!(reader[0], 1);
+        assert_eq!(&reader[1..9], &0.25f64.to_le_bytes());
+        assert_eq!(&reader[9..17], &0.75f64.to_le_bytes());
+        assert_eq!(reader[record_size], 0);
+        assert_eq!(
+            &reader[record_size + 1..record_size + 9],
+            &1.0f64.to_le_bytes()
+        );
+        // Classes without flows only have the header
+        let contents = fs::read(output_dir.path().join("normal.bin")).unwrap();
+        let header: BincodeHeader = bincode::deserialize(&contents).unwrap();
+        assert_eq!(header.num_records, 0);
+        // Records that aren't the same size are rejected
+        let mut classes = HashMap::new();
+        classes.insert(
+            CaptureWorkType::Tor,
+            vec![flow(true, vec![0.5, 0.5]), flow(false, vec![1.0])],
+        );
+        assert!(Dataset { classes }
+            .save(output_dir.path(), save_options)
+            .is_err());
+    }
+
+    /// Tests that sorted flows are written in the same order however they were loaded
+    #[test]
+    fn test_sort_flows() {
+        let flow = |url, index| FlowData {
+            index,
+            ..flow_data(CaptureWorkType::Tor, url)
+        };
+        let save = |flows: Vec<FlowData>, sort_flows| {
+            let mut classes = HashMap::new();
+            classes.insert(CaptureWorkType::Tor, flows);
+            let output_dir = TempDir::new("data_generator").unwrap();
+            Dataset { classes }
+                .save(
+                    output_dir.path(),
+                    SaveOptions {
+                        compression: CompressionFormat::None,
+                        sort_flows,
+                        ..SaveOptions::default()
+                    },
+                )
+                .unwrap();
+            let contents = fs::read_to_string(output_dir.path().join("tor.json")).unwrap();
+            contents
+                .lines()
+                .map(|line| {
+                    let flow: serde_json::Value = serde_json::from_str(line).unwrap();
+                    (
+                        flow["u"].as_str().unwrap().to_string(),
+                        flow["i"].as_u64().unwrap(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let expected = vec![
+            ("https://a.com".to_string(), 2),
+            ("https://b.com".to_string(), 0),
+            ("https://b.com".to_string(), 1),
+        ];
+        let unsorted = || {
+            vec![
+                flow("https://b.com", 1),
+                flow("https://a.com", 2),
+                flow("https://b.com", 0),
+            ]
+        };
+        assert_eq!(save(unsorted(), true), expected);
+        assert_eq!(save(unsorted().into_iter().rev().collect(), true), expected);
+        // Without sorting, flows are written in the order they were loaded
+        assert_ne!(save(unsorted(), false), expected);
+    }
+
+    /// Tests that each URL's flows are saved to a single set, chosen the same way every time
+    #[test]
+    fn test_save_split() {
+        let flow = |class, index| FlowData {
+            index,
+            ..flow_data(class, &format!("https://example{}.com", index % 500))
+        };
+        let dataset = || {
+            let mut classes = HashMap::new();
+            for &class in &[CaptureWorkType::Normal, CaptureWorkType::Tor] {
+                classes.insert(class, (0..1000).map(|index| flow(class, index)).collect());
+            }
+            Dataset { classes }
+        };
+        let options = SaveOptions {
+            compression: CompressionFormat::None,
+            ..SaveOptions::default()
+        };
+        let output_dir = TempDir::new("data_generator").unwrap();
+        dataset()
+            .save_split(output_dir.path(), [0.8, 0.1, 0.1], 7, options.clone())
+            .unwrap();
+        let mut split_urls: Vec<HashSet<String>> = Vec::new();
+        for split_name in &SPLIT_NAMES {
+            let split = Dataset::load_tensors(output_dir.path().join(split_name)).unwrap();
+            // Every set has every class, so the labels match
+            assert_eq!(split.labels().len(), 2);
+            let urls: HashSet<String> = split.classes[&CaptureWorkType::Tor]
+                .iter()
+                .map(|flow| flow.url.clone())
+                .collect();
+            split_urls.push(urls);
+        }
+        // No URL is in more than one set, and the sets are roughly the requested sizes
+        assert_eq!(split_urls.iter().map(HashSet::len).sum::<usize>(), 500);
+        assert!(split_urls[0].is_disjoint(&split_urls[1]));
+        assert!(split_urls[0].is_disjoint(&split_urls[2]));
+        assert!(split_urls[1].is_disjoint(&split_urls[2]));
+        assert!((350..450).contains(&split_urls[0].len()));
+        assert!((25..75).contains(&split_urls[1].len()));
+        // The same seed gives the same sets
+        let url = "https://example0.com";
+        let split = split_index(url, 7, &[0.8, 0.1, 0.1]);
+        assert!(split_urls[split].contains(url));
+        // Ratios must be a split of the whole
+        let error_dir = TempDir::new("data_generator").unwrap();
+        assert!(dataset()
+            .save_split(error_dir.path(), [0.8, 0.1, 0.2], 7, options.clone())
+            .is_err());
+        assert!(dataset()
+            .save_split(error_dir.path(), [1.2, -0.1, -0.1], 7, options)
+            .is_err());
+    }
+
+    /// Tests that merging datasets combines the flows of each class
+    #[test]
+    fn test_merge() {
+        let mut classes = HashMap::new();
+        classes.insert(
+            CaptureWorkType::Normal,
+            vec![flow_data(CaptureWorkType::Normal, "https://a.com")],
+        );
+        let mut dataset = Dataset::from_classes(classes);
+        let mut classes = HashMap::new();
+        classes.insert(
+            CaptureWorkType::Normal,
+            vec![flow_data(CaptureWorkType::Normal, "https://b.com")],
+        );
+        classes.insert(
+            CaptureWorkType::Tor,
+            vec![flow_data(CaptureWorkType::Tor, "https://c.com")],
+        );
+        dataset.merge(Dataset::from_classes(classes));
+        let urls = |class| {
+            dataset.classes[&class]
+                .iter()
+                .map(|flow| flow.url.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            urls(CaptureWorkType::Normal),
+            vec!["https://a.com", "https://b.com"]
+        );
+        assert_eq!(urls(CaptureWorkType::Tor), vec!["https://c.com"]);
+        assert_eq!(dataset.labels().len(), 2);
+    }
+
+    /// Tests that a saved dataset is loaded back as it was, whatever its compression
+    #[test]
+    fn test_load_tensors() {
+        let packets_flow = || FlowData {
+            is_first_of_class: true,
+            features: Features::Packets(Box::new(FlowFeatures::empty(5, 4, 3, 2, 4).normalize())),
+            tls: vec![TlsMetadata {
+                version: Some("TLSv13".to_string()),
+                cipher: None,
+                server_name: Some("example.com".to_string()),
+            }],
+            ..flow_data(CaptureWorkType::Tor, "https://example.com")
+        };
+        let connections_flow = || FlowData {
+            features: Features::Connections(ConnectionFeatures::from_connections(&[])),
+            ..flow_data(CaptureWorkType::Normal, "https://example.org")
+        };
+        let read_class_files = |dir: &Path| {
+            ["normal.json", "tor.json"]
+                .iter()
+                .map(|filename| fs::read_to_string(dir.join(filename)).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let mut classes = HashMap::new();
+        classes.insert(CaptureWorkType::Tor, vec![packets_flow(), packets_flow()]);
+        classes.insert(CaptureWorkType::Normal, vec![connections_flow()]);
+        let expected_dir = TempDir::new("data_generator").unwrap();
+        Dataset { classes }
+            .save(
+                expected_dir.path(),
+                SaveOptions {
+                    compression: CompressionFormat::None,
+                    ..SaveOptions::default()
+                },
+            )
+            .unwrap();
+        for &compression in &[
+            CompressionFormat::Gzip,
+            CompressionFormat::Zstd,
+            CompressionFormat::None,
+        ] {
+            let saved_dir = TempDir::new("data_generator").unwrap();
+            let mut classes = HashMap::new();
+            classes.insert(CaptureWorkType::Tor, vec![packets_flow(), packets_flow()]);
+            classes.insert(CaptureWorkType::Normal, vec![connections_flow()]);
+            Dataset { classes }
+                .save(
+                    saved_dir.path(),
+                    SaveOptions {
+                        compression,
+                        ..SaveOptions::default()
+                    },
+                )
+                .unwrap();
+            let dataset = Dataset::load_tensors(saved_dir.path()).unwrap();
+            assert_eq!(dataset.classes[&CaptureWorkType::Tor].len(), 2);
+            assert_eq!(dataset.classes[&CaptureWorkType::Normal].len(), 1);
+            // Saving the loaded dataset writes the same flows
+            let resaved_dir = TempDir::new("data_generator").unwrap();
+            dataset
+                .save(
+                    resaved_dir.path(),
+                    SaveOptions {
+                        compression: CompressionFormat::None,
+                        ..SaveOptions::default()
+                    },
+                )
+                .unwrap();
+            assert_eq!(
+                read_class_files(resaved_dir.path()),
+                read_class_files(expected_dir.path()),
+                "{:?}",
+                compression
+            );
+        }
+        // Classes without a class file can't be loaded
+        let missing_dir = TempDir::new("data_generator").unwrap();
+        fs::write(missing_dir.path().join("labels.json"), r#"{"tor": 0}"#).unwrap();
+        assert!(Dataset::load_tensors(missing_dir.path()).is_err());
+    }
+
+    /// Tests that the status file reflects completion after loading, counting failed work
+    #[test]
+    fn test_status_file() {
+        let data_dir = TempDir::new("data_generator").unwrap();
+        let report = |index| WorkReportRequest {
+            success: true,
+            work_type: CaptureWorkType::Normal,
+            work: CaptureWork {
+                index,
+                url: "https://example.com".to_string(),
+                filename: PathBuf::from(format!("{}.pcap", index)),
+            },
+            type_index: index,
+            start_time: 0,
+            finish_time: 0,
+        };
+        let mut report_file = File::create(data_dir.path().join("report.json")).unwrap();
+        for index in 0..2 {
+            serde_json::to_writer(&mut report_file, &report(index)).unwrap();
+            writeln!(report_file).unwrap();
+            File::create(data_dir.path().join(format!("{}.pcap", index))).unwrap();
+        }
+        // Zeek can't be run, so every work item fails
+        let config = GeneratorConfig {
+            zeek_path: data_dir.path().join("zeek"),
+            ..GeneratorConfig::default()
+        };
+        let status_path = data_dir.path().join("status.json");
+        let dataset = Dataset::load(data_dir.path(), &config, Some(&status_path)).unwrap();
+        assert!(dataset.classes.is_empty());
+        let status: Status = serde_json::from_reader(File::open(&status_path).unwrap()).unwrap();
+        assert_eq!(
+            (status.total, status.done, status.errors, status.finished),
+            (2, 2, 2, true)
+        );
+    }
+
+    /// Writes a report of successful normal work, one item for each pcap
+    fn write_report(data_dir: &Path, filenames: &[PathBuf]) {
+        let mut report_file = File::create(data_dir.join("report.json")).unwrap();
+        for (index, filename) in filenames.iter().enumerate() {
+            let report = WorkReportRequest {
+                success: true,
+                work_type: CaptureWorkType::Normal,
+                work: CaptureWork {
+                    index: index as u64,
+                    url: "https://example.com".to_string(),
+                    filename: filename.clone(),
+                },
+                type_index: index as u64 + 1,
+                start_time: 0,
+                finish_time: 0,
+            };
+            serde_json::to_writer(&mut report_file, &report).unwrap();
+            writeln!(report_file).unwrap();
+        }
+    }
+
+    /// Writes a script standing in for zeek, which writes a connection log with a connection
+    /// from each of the given client ports to its working directory
+    fn fake_zeek_with_connections(dir: &Path, orig_ports: &[u16]) -> PathBuf {
+        let zeek_path = dir.join("zeek");
+        let mut script = "#!/bin/sh\n".to_string();
+        for (idx, orig_port) in orig_ports.iter().enumerate() {
+            script.push_str(&format!(
+                concat!(
+                    "echo '{{\"ts\": 1.0, \"uid\": \"C{}\", \"id.orig_h\": \"10.0.0.1\", ",
+                    "\"id.orig_p\": {}, \"id.resp_h\": \"10.0.0.2\", \"id.resp_p\": 443, ",
+                    "\"proto\": \"tcp\", \"duration\": 2.0, \"orig_bytes\": 100, ",
+                    "\"resp_bytes\": 1000, \"conn_state\": \"SF\", \"history\": \"ShADadFf\"}}' ",
+                    ">> conn.log\n",
+                ),
+                idx + 1,
+                orig_port
+            ));
+        }
+        fs::write(&zeek_path, script).unwrap();
+        fs::set_permissions(&zeek_path, fs::Permissions::from_mode(0o755)).unwrap();
+        zeek_path
+    }
+
+    /// Tests that every connection of a URL's pcap is counted in its sample
+    #[test]
+    fn test_num_flows() {
+        let data_dir = TempDir::new("data_generator").unwrap();
+        write_report(data_dir.path(), &[PathBuf::from("0.pcap")]);
+        let frame = |src_port, payload: &[u8]| {
+            ethernet(0x0800, &ipv4(6, &tcp(src_port, 443, 0x18, payload)))
+        };
+        let pcap = build_pcap(&[
+            (1_100_000, frame(50000, &[1; 100])),
+            (1_200_000, frame(50001, &[2; 200])),
+            (1_300_000, frame(50000, &[3; 300])),
+            (1_400_000, frame(50002, &[4; 400])),
+        ]);
+        fs::write(data_dir.path().join("0.pcap"), pcap).unwrap();
+        let config = GeneratorConfig {
+            zeek_path: fake_zeek_with_connections(data_dir.path(), &[50000, 50001, 50002]),
+            ..GeneratorConfig::default()
+        };
+        let dataset = Dataset::load(data_dir.path(), &config, None).unwrap();
+        let flows = &dataset.classes[&CaptureWorkType::Normal];
+        assert_eq!(flows.len(), 1);
+        assert_eq!(flows[0].num_flows, 3);
+        match flows[0].features {
+            Features::Packets(ref features) => {
+                assert_eq!(features.total_packets_from_client, 4);
+            }
+            Features::Connections(_) => panic!("Expected packet features"),
+        }
+    }
+
+    /// Tests that features are generated for each flow of a pcap, in order of their uid
+    #[test]
+    fn test_flow_features_from_pcap() {
+        let data_dir = TempDir::new("data_generator").unwrap();
+        let frame = |src_port, payload: &[u8]| {
+            ethernet(0x0800, &ipv4(6, &tcp(src_port, 443, 0x18, payload)))
+        };
+        let pcap = build_pcap(&[
+            (1_100_000, frame(50000, &[1; 100])),
+            (1_200_000, frame(50001, &[2; 200])),
+            (1_300_000, frame(50000, &[3; 300])),
+        ]);
+        let pcap_path = data_dir.path().join("0.pcap");
+        fs::write(&pcap_path, pcap).unwrap();
+        let config = GeneratorConfig {
+            zeek_path: fake_zeek_with_connections(data_dir.path(), &[50000, 50001]),
+            ..GeneratorConfig::default()
+        };
+        let features = flow_features_from_pcap(&pcap_path, &config).unwrap();
+        let total_packets: Vec<usize> = features
+            .iter()
+            .map(|features| features.total_packets_from_client)
+            .collect();
+        assert_eq!(total_packets, vec![2, 1]);
+        // Flows only count if they use a server port
+        let config = GeneratorConfig {
+            server_ports: vec![80],
+            ..config
+        };
+        let features = flow_features_from_pcap(&pcap_path, &config).unwrap();
+        assert!(features.is_empty());
+    }
+
+    /// Tests that pcaps are found under a separate root, or at absolute paths
+    #[test]
+    fn test_pcap_root() {
+        let data_dir = TempDir::new("data_generator").unwrap();
+        let pcap_root = TempDir::new("data_generator").unwrap();
+        let other_dir = TempDir::new("data_generator").unwrap();
+        fs::create_dir(pcap_root.path().join("shard0")).unwrap();
+        fs::write(pcap_root.path().join("shard0/0.pcap"), b"").unwrap();
+        let absolute_path = other_dir.path().join("1.pcap");
+        fs::write(&absolute_path, b"").unwrap();
+        write_report(
+            data_dir.path(),
+            &[PathBuf::from("shard0/0.pcap"), absolute_path],
+        );
+        let config = GeneratorConfig {
+            zeek_path: fake_zeek_with_connections(data_dir.path(), &[50000]),
+            conn_only: true,
+            ..GeneratorConfig::default()
+        };
+        // Without the root, only the absolute path is found
+        let dataset = Dataset::load(data_dir.path(), &config, None).unwrap();
+        assert_eq!(dataset.classes[&CaptureWorkType::Normal].len(), 1);
+        // With the root, both are found
+        let config = GeneratorConfig {
+            pcap_root: Some(pcap_root.path().to_path_buf()),
+            ..config
+        };
+        let dataset = Dataset::load(data_dir.path(), &config, None).unwrap();
+        assert_eq!(dataset.classes[&CaptureWorkType::Normal].len(), 2);
+    }
+
+    /// Tests that reports rotated into numbered files are read along with report.json
+    #[test]
+    fn test_rotated_reports() {
+        let data_dir = TempDir::new("data_generator").unwrap();
+        let filenames: Vec<PathBuf> = (0..3)
+            .map(|index| PathBuf::from(format!("{}.pcap", index)))
+            .collect();
+        for filename in &filenames {
+            fs::write(data_dir.path().join(filename), b"").unwrap();
+        }
+        write_report(data_dir.path(), &filenames);
+        // Move the last report into the next numbered file
+        let report_path = data_dir.path().join("report.json");
+        let report = fs::read_to_string(&report_path).unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+        fs::write(&report_path, format!("{}\n{}\n", lines[0], lines[1])).unwrap();
+        fs::write(
+            data_dir.path().join("report.1.json"),
+            format!("{}\n", lines[2]),
+        )
+        .unwrap();
+        let config = GeneratorConfig {
+            zeek_path: fake_zeek_with_connections(data_dir.path(), &[50000]),
+            conn_only: true,
+            ..GeneratorConfig::default()
+        };
+        let dataset = Dataset::load(data_dir.path(), &config, None).unwrap();
+        assert_eq!(dataset.classes[&CaptureWorkType::Normal].len(), 3);
+    }
+
+    /// Tests that conn-only mode generates connection features without parsing the pcap
+    #[test]
+    fn test_conn_only() {
+        let data_dir = TempDir::new("data_generator").unwrap();
+        write_report(data_dir.path(), &[PathBuf::from("0.pcap")]);
+        // The pcap isn't valid, so loading would fail if its packets were parsed
+        fs::write(data_dir.path().join("0.pcap"), b"not a pcap").unwrap();
+        let config = GeneratorConfig {
+            zeek_path: fake_zeek_with_connections(data_dir.path(), &[50000]),
+            conn_only: true,
+            ..GeneratorConfig::default()
+        };
+        let dataset = Dataset::load(data_dir.path(), &config, None).unwrap();
+        let flows = &dataset.classes[&CaptureWorkType::Normal];
+        assert_eq!(flows.len(), 1);
+        match flows[0].features {
+            Features::Connections(ref features) => {
+                assert_eq!(features.num_connections, 1);
+                assert_eq!((features.orig_bytes, features.resp_bytes), (100, 1000));
+                assert_eq!(features.duration, 2_000_000_000);
+                assert_eq!(features.conn_state_freq.len(), ConnState::COUNT);
+                assert_eq!(features.history_freq_from_orig.len(), HistoryEntry::COUNT);
+                assert_eq!(features.history_freq_from_resp.len(), HistoryEntry::COUNT);
+            }
+            Features::Packets(_) => panic!("Expected connection features"),
+        }
+        // The saved flow holds the connection features
+        let output_dir = TempDir::new("data_generator").unwrap();
+        dataset
+            .save(
+                output_dir.path(),
+                SaveOptions {
+                    compression: CompressionFormat::None,
+                    ..SaveOptions::default()
+                },
+            )
+            .unwrap();
+        let line = fs::read_to_string(output_dir.path().join("normal.json")).unwrap();
+        let flow: serde_json::Value = serde_json::from_str(line.trim()).unwrap();
+        assert_eq!(flow["nc"], 1);
+        assert_eq!(flow["cs"].as_array().unwrap().len(), ConnState::COUNT);
+        assert!(flow.get("pl").is_none());
+    }
+
+    /// Tests the full feature computation on packets and connections built in memory
+    #[test]
+    fn test_from_packets_and_connections() {
+        let ms: u64 = 1_000_000;
+        let packet = |timestamp, from_client, tcp_flags, payload_length| {
+            let packet = Packet {
+                tcp_flags,
+                payload_length,
+                entropy: 7.9,
+                ..packet_at(50000, 1_000 * ms + timestamp * ms)
+            };
+            if from_client {
+                packet
+            } else {
+                reply(packet)
+            }
+        };
+        let packets = || {
+            vec![
+                packet(0, true, 0x02, 0),
+                packet(1, false, 0x12, 0),
+                packet(2, true, 0x10, 0),
+                packet(3, true, 0x18, 100),
+                packet(10, false, 0x18, 1000),
+                packet(11, false, 0x18, 1000),
+                packet(12, true, 0x10, 0),
+            ]
+        };
+        let connection = |uid, resp_port| Connection {
+            resp_port,
+            ..connection_at(uid, 50000, 1.0)
+        };
+        let ssl_record = |uid: &str, server_name: &str| -> SslRecord {
+            serde_json::from_str(&format!(
+                r#"{{"ts": 1.0, "uid": "{}", "version": "TLSv13",
+                "cipher": "TLS_AES_128_GCM_SHA256", "server_name": "{}"}}"#,
+                uid, server_name
+            ))
+            .unwrap()
+        };
+        let config = GeneratorConfig::default();
+        let flow_data = FlowData::from_packets_and_connections(
+            capture(CaptureWorkType::Tor, true),
+            packets(),
+            vec![connection("C1", 443), connection("C2", 80)],
+            vec![
+                ssl_record("C2", "other.com"),
+                ssl_record("C1", "example.com"),
+            ],
+            &config,
+        );
+        assert_eq!(flow_data.class, CaptureWorkType::Tor);
+        assert!(flow_data.is_first_of_class);
+        assert_eq!(flow_data.num_flows, 1);
+        // Only the session of the kept flow is attached
+        assert_eq!(
+            flow_data.tls,
+            vec![TlsMetadata {
+                version: Some("TLSv13".to_string()),
+                cipher: Some("TLS_AES_128_GCM_SHA256".to_string()),
+                server_name: Some("example.com".to_string()),
+            }]
+        );
+        let features = match flow_data.features {
+            Features::Packets(features) => *features,
+            Features::Connections(_) => panic!("Expected packet features"),
+        };
+        assert_eq!(features.num_bursts, 1);
+        assert_eq!(features.payload_length_freq_bins[0], 4.0 / 7.0);
+        assert!((features.payload_length_freq_bins.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+        assert_eq!(features.data_ack_ratio_from_client, 1.0 / 3.0);
+        assert_eq!(features.data_ack_ratio_to_client, 2.0);
+        assert!((features.payload_cv_from_client - 3f64.sqrt()).abs() < 1e-9);
+        assert!((features.payload_cv_to_client - 0.5f64.sqrt()).abs() < 1e-9);
+        // Packets without a matching connection don't form a flow
+        let flow_data = FlowData::from_packets_and_connections(
+            capture(CaptureWorkType::Tor, false),
+            packets(),
+            vec![connection("C2", 80)],
+            vec![],
+            &config,
+        );
+        // The all zero features are marked as coming from no flows
+        assert_eq!(flow_data.num_flows, 0);
+        match flow_data.features {
+            Features::Packets(features) => {
+                assert_eq!(features.num_bursts, 0);
+                assert_eq!(features.payload_length_freq_bins[0], 0.0);
+            }
+            Features::Connections(_) => panic!("Expected packet features"),
+        }
+    }
+
+    /// Tests that payload prefixes are told apart by whether the client or server sent them,
+    /// whichever end of the flow's key they are
+    #[test]
+    fn test_payload_prefixes() {
+        let packet = |timestamp, from_client, payload_prefix: &[u8]| {
+            let packet = Packet {
+                tcp_flags: 0x18,
+                payload_prefix: payload_prefix.to_vec(),
+                payload_length: payload_prefix.len(),
+                ..packet_at(50000, timestamp)
+            };
+            if from_client {
+                packet
+            } else {
+                reply(packet)
+            }
+        };
+        let config = GeneratorConfig {
+            payload_prefix_length: 2,
+            ..GeneratorConfig::default()
+        };
+        let flow_data = FlowData::from_packets_and_connections(
+            capture(CaptureWorkType::Normal, true),
+            vec![
+                packet(1_100_000_000, true, &[0x16, 0x03, 0x01]),
+                packet(1_200_000_000, false, &[0x17, 0x03, 0x03]),
+            ],
+            vec![connection_at("C1", 50000, 1.0)],
+            vec![],
+            &config,
+        );
+        match flow_data.features {
+            Features::Packets(features) => {
+                assert_eq!(
+                    features.payload_prefix_from_client,
+                    Some("1603".to_string())
+                );
+                assert_eq!(features.payload_prefix_to_client, Some("1703".to_string()));
+            }
+            Features::Connections(_) => panic!("Expected packet features"),
+        }
+    }
+
+    /// Tests that the payload prefixes of several flows are combined in the order of their uids
+    #[test]
+    fn test_payload_prefixes_order() {
+        let packet = |src_port, timestamp, payload_prefix: &[u8]| Packet {
+            tcp_flags: 0x18,
+            payload_prefix: payload_prefix.to_vec(),
+            payload_length: payload_prefix.len(),
+            ..packet_at(src_port, timestamp)
+        };
+        let config = GeneratorConfig {
+            payload_prefix_length: 6,
+            ..GeneratorConfig::default()
+        };
+        // The flows are kept in a hash map, so repeat to give its order a chance to differ
+        for _ in 0..10 {
+            let flow_data = FlowData::from_packets_and_connections(
+                capture(CaptureWorkType::Normal, true),
+                vec![
+                    packet(50002, 1_100_000_000, &[0x03, 0x03]),
+                    packet(50000, 1_200_000_000, &[0x01, 0x01]),
+                    packet(50001, 1_300_000_000, &[0x02, 0x02]),
+                ],
+                vec![
+                    connection_at("C3", 50002, 1.0),
+                    connection_at("C1", 50000, 1.0),
+                    connection_at("C2", 50001, 1.0),
+                ],
+                vec![],
+                &config,
+            );
+            match flow_data.features {
+                Features::Packets(features) => assert_eq!(
+                    features.payload_prefix_from_client,
+                    Some("010102020303".to_string())
+                ),
+                Features::Connections(_) => panic!("Expected packet features"),
+            }
+        }
+    }
+
+    /// Tests that well known ports are only used to infer directions when asked for
+    #[test]
+    fn test_direction_inference_methods() {
+        let config = GeneratorConfig::default();
+        let methods = direction_inference_methods(&config);
+        assert!(methods[0] == DirectionInferenceMethod::ServerPort(443));
+        assert!(!methods.contains(&DirectionInferenceMethod::WellKnown));
+        let config = GeneratorConfig {
+            infer_direction_from_well_known_ports: true,
+            ..config
+        };
+        let methods = direction_inference_methods(&config);
+        // Well known ports are tried before falling back to ephemeral ports
+        assert!(
+            methods[2..]
+                == [
+                    DirectionInferenceMethod::WellKnown,
+                    DirectionInferenceMethod::Ephemeral
+                ]
+        );
+    }
+
+    /// Tests that flows that didn't complete cleanly are dropped when asked for
+    #[test]
+    fn test_require_clean_completion() {
+        let packet = |src_port| Packet {
+            tcp_flags: 0x18,
+            payload_length: 100,
+            entropy: 7.9,
+            ..packet_at(src_port, 1_050_000_000)
+        };
+        let connection = |uid, orig_port, conn_state| Connection {
+            conn_state: Some(conn_state),
+            ..connection_at(uid, orig_port, 1.0)
+        };
+        let flow_data = |require_clean_completion| {
+            let config = GeneratorConfig {
+                require_clean_completion,
+                ..GeneratorConfig::default()
+            };
+            let flow_data = FlowData::from_packets_and_connections(
+                capture(CaptureWorkType::Normal, true),
+                vec![packet(50000), packet(50001), packet(50001)],
+                vec![
+                    connection("C1", 50000, ConnState::SF),
+                    connection("C2", 50001, ConnState::RSTO),
+                ],
+                vec![],
+                &config,
+            );
+            match flow_data.features {
+                Features::Packets(features) => features.total_packets_from_client,
+                Features::Connections(_) => panic!("Expected packet features"),
+            }
+        };
+        assert_eq!(flow_data(false), 3);
+        // Only the SF flow survives
+        assert_eq!(flow_data(true), 1);
+    }
+
+    /// Tests that flows are only kept if they use a server protocol, and are counted by protocol
+    #[test]
+    fn test_server_protocols() {
+        let packet = |trans_protocol, src_port| Packet {
+            trans_protocol,
+            tcp_flags: 0,
+            payload_length: 100,
+            entropy: 7.9,
+            ..packet_at(src_port, 1_050_000_000)
+        };
+        let connection = |uid, trans_protocol, orig_port| Connection {
+            trans_protocol,
+            ..connection_at(uid, orig_port, 1.0)
+        };
+        let flow_data = |server_protocols| {
+            let config = GeneratorConfig {
+                server_protocols,
+                ..GeneratorConfig::default()
+            };
+            FlowData::from_packets_and_connections(
+                capture(CaptureWorkType::Normal, true),
+                vec![packet(6, 50000), packet(17, 50001), packet(17, 50001)],
+                vec![
+                    connection("C1", TransportProtocol::Tcp, 50000),
+                    connection("C2", TransportProtocol::Udp, 50001),
+                ],
+                vec![],
+                &config,
+            )
+        };
+        use TransportProtocol::*;
+        let both = flow_data(vec![Tcp, Udp]);
+        assert_eq!(both.num_flows, 2);
+        assert_eq!(
+            both.trans_protocols,
+            vec![(Tcp, 1), (Udp, 1)].into_iter().collect()
+        );
+        let quic = flow_data(vec![Udp]);
+        assert_eq!(quic.num_flows, 1);
+        assert_eq!(quic.trans_protocols, vec![(Udp, 1)].into_iter().collect());
+        match quic.features {
+            Features::Packets(features) => assert_eq!(features.total_packets_from_client, 2),
+            Features::Connections(_) => panic!("Expected packet features"),
+        }
+        // The counts are saved with the flow
+        let mut classes = HashMap::new();
+        classes.insert(CaptureWorkType::Normal, vec![both]);
+        let output_dir = TempDir::new("data_generator").unwrap();
+        Dataset { classes }
+            .save(
+                output_dir.path(),
+                SaveOptions {
+                    compression: CompressionFormat::None,
+                    ..SaveOptions::default()
+                },
+            )
+            .unwrap();
+        let line = fs::read_to_string(output_dir.path().join("normal.json")).unwrap();
+        let flow: serde_json::Value = serde_json::from_str(line.trim()).unwrap();
+        assert_eq!(flow["tp"], serde_json::json!({"tcp": 1, "udp": 1}));
+    }
+
+    /// Tests that low entropy flows are dropped, while high entropy flows are kept
+    #[test]
+    fn test_retain_min_entropy() {
+        let packet = |payload_length, entropy| StrippedPacket {
+            payload_length,
+            entropy,
+            ..stripped_packet_at(false, 0)
+        };
+        let flow = |packets| Flow {
+            key: PacketKey::new(
+                "10.0.0.1".parse().unwrap(),
+                "10.0.0.2".parse().unwrap(),
+                6,
+                50000,
+                443,
+            ),
+            conn_state: None,
+            packets,
+            payload_prefix_a: Vec::new(),
+            payload_prefix_b: Vec::new(),
+        };
+        let mut flows = HashMap::new();
+        // Plaintext-like flow
+        flows.insert(
+            "plaintext".to_string(),
+            flow(vec![packet(100, 4.5), packet(200, 4.9)]),
+        );
+        // Encrypted-like flow, where an empty ACK doesn't lower the mean
+        flows.insert(
+            "encrypted".to_string(),
+            flow(vec![packet(100, 7.5), packet(0, 0.0), packet(200, 7.9)]),
+        );
+        // Flow without any payload
+        flows.insert("empty".to_string(), flow(vec![packet(0, 0.0)]));
+        assert_eq!(retain_min_entropy(&mut flows, 7.0), 2);
+        assert_eq!(flows.keys().collect::<Vec<_>>(), vec!["encrypted"]);
+    }
 }
@@ -18,14 +18,13 @@ pub trait ShannonEntropy {
 }
 impl ShannonEntropy for [u8] {
     fn shannon_entropy(&self) -> f64 {
-        // Initialize a dataset of byte frequencies
-        let mut frequencies: [usize; 256] = [0; 256];
-        // Get byte frequencies
-        for byte in self {
-            frequencies[*byte as usize] += 1;
-        }
+        shannon_entropy_streaming(self.iter().cloned())
+    }
+}
+impl ShannonEntropy for ByteHistogram {
+    fn shannon_entropy(&self) -> f64 {
         // Iterate over frequencies
-        frequencies
+        self.frequencies
             .iter()
             .map(|frequency| {
                 // Handle 0 values
@@ -33,7 +32,7 @@ impl ShannonEntropy for [u8] {
                     0.0
                 } else {
                     // Normalize the frequency
-                    let frequency: f64 = (*frequency as f64) / (self.len() as f64);
+                    let frequency: f64 = (*frequency as f64) / (self.num_bytes as f64);
                     // Individual entropy value
                     frequency * frequency.log2()
                 }
@@ -43,6 +42,78 @@ impl ShannonEntropy for [u8] {
     }
 }
 
+pub trait ChiSquare {
+    fn chi_square(&self) -> f64;
+}
+impl ChiSquare for [u8] {
+    fn chi_square(&self) -> f64 {
+        ByteHistogram::new(self.iter().cloned()).chi_square()
+    }
+}
+impl ChiSquare for ByteHistogram {
+    /// Calculates the chi-square statistic of the byte distribution against a uniform
+    /// distribution. Encrypted data scores low, while compressed or plain data scores higher
+    fn chi_square(&self) -> f64 {
+        // Handle empty data, which has no expected frequency
+        if self.num_bytes == 0 {
+            return 0.0;
+        }
+        let expected = self.num_bytes as f64 / self.frequencies.len() as f64;
+        self.frequencies
+            .iter()
+            .map(|frequency| {
+                let difference = *frequency as f64 - expected;
+                difference * difference / expected
+            })
+            .sum()
+    }
+}
+
+/// Number of occurrences of each byte value in a sequence of bytes, which both its entropy and
+/// chi-square statistic are calculated from
+pub struct ByteHistogram {
+    /// Number of occurrences of each byte value
+    frequencies: [usize; 256],
+    /// Total number of bytes
+    num_bytes: usize,
+}
+
+impl ByteHistogram {
+    /// Counts the occurrences of each byte value, and the total number of bytes
+    ///
+    /// # Parameters
+    /// * `bytes` - Bytes to count
+    pub fn new<I>(bytes: I) -> Self
+    where
+        I: IntoIterator<Item = u8>,
+    {
+        // Initialize a dataset of byte frequencies
+        let mut frequencies: [usize; 256] = [0; 256];
+        // Get byte frequencies, counting the bytes as they're seen
+        let mut num_bytes: usize = 0;
+        for byte in bytes {
+            frequencies[byte as usize] += 1;
+            num_bytes += 1;
+        }
+        ByteHistogram {
+            frequencies,
+            num_bytes,
+        }
+    }
+}
+
+/// Calculates the shannon entropy (in bits per byte) of a sequence of bytes in a single pass,
+/// without holding the whole sequence in memory
+///
+/// # Parameters
+/// * `bytes` - Bytes to calculate the entropy of
+pub fn shannon_entropy_streaming<I>(bytes: I) -> f64
+where
+    I: IntoIterator<Item = u8>,
+{
+    ByteHistogram::new(bytes).shannon_entropy()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -58,7 +129,7 @@ mod tests {
         // Many single values
         for exponent in 1..10 {
             // Build a slice
-            let data: Vec<u8> = iter::repeat(1).take(2_usize.pow(exponent)).collect();
+            let data: Vec<u8> = iter::repeat_n(1, 2_usize.pow(exponent)).collect();
             // Evaluate entropy
             assert_eq!(data.shannon_entropy(), 0.0);
         }
@@ -71,4 +142,52 @@ mod tests {
         }
         // TODO: more distribution tests
     }
+
+    /// Tests that entropy can be calculated from an iterator without collecting it
+    #[test]
+    fn test_shannon_entropy_streaming() {
+        assert_eq!(shannon_entropy_streaming(iter::empty()), 0.0);
+        // Uniform distribution, split across several chunks
+        for exponent in 1..8 {
+            let chunks: Vec<Vec<u8>> = (0..2_u8.pow(exponent))
+                .collect::<Vec<u8>>()
+                .chunks(3)
+                .map(|chunk| chunk.to_vec())
+                .collect();
+            let bytes = chunks.iter().flat_map(|chunk| chunk.iter().cloned());
+            assert_eq!(shannon_entropy_streaming(bytes), exponent as f64);
+        }
+        // The slice implementation gives the same result
+        let data: Vec<u8> = b"an uneven distribution of bytes".to_vec();
+        assert_eq!(
+            shannon_entropy_streaming(data.iter().cloned()),
+            data.shannon_entropy()
+        );
+    }
+
+    /// Tests the chi-square statistic against a uniform distribution
+    #[test]
+    fn test_chi_square() {
+        assert_eq!([].chi_square(), 0.0);
+        // Every byte value once is exactly uniform
+        let uniform: Vec<u8> = (0..=255).collect();
+        assert_eq!(uniform.chi_square(), 0.0);
+        // Repeating it stays uniform
+        let repeated: Vec<u8> = uniform.iter().cycle().take(256 * 4).cloned().collect();
+        assert_eq!(repeated.chi_square(), 0.0);
+        // Identical bytes are as far from uniform as possible: (n - n/256)^2 / (n/256) for the
+        // repeated value, plus n/256 for each of the other 255 values
+        let identical: Vec<u8> = vec![0x41; 1024];
+        assert_eq!(identical.chi_square(), 1024.0 * 255.0);
+        assert!(identical.chi_square() > repeated.chi_square());
+    }
+
+    /// Tests that a single histogram gives the same scores as counting the bytes for each
+    #[test]
+    fn test_byte_histogram() {
+        let data: Vec<u8> = b"an uneven distribution of bytes".to_vec();
+        let histogram = ByteHistogram::new(data.iter().cloned());
+        assert_eq!(histogram.shannon_entropy(), data.shannon_entropy());
+        assert_eq!(histogram.chi_square(), data.chi_square());
+    }
 }
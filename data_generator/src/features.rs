@@ -13,20 +13,41 @@
 //
 // You should have received a copy of the GNU General Public License
 // along with packet_captor_sakura.  If not, see <https:// www.gnu.org/licenses/>.
+use crate::bro_types::{ConnState, Connection, Endpoint, HistoryEntry, SslRecord};
+use crate::config::BinConfig;
+use crate::flow_aggregator::PacketKey;
 use crate::packet::*;
-use serde_derive::Serialize;
+use serde_derive::{Deserialize, Serialize};
 use std::collections::HashSet;
 use std::ops;
 
+/// SYN flag of a TCP header
+const TCP_FLAG_SYN: u8 = 0x02;
+/// ACK flag of a TCP header
+const TCP_FLAG_ACK: u8 = 0x10;
+/// Transport protocol number of TCP
+const TRANS_PROTOCOL_TCP: u8 = 6;
+/// Number of payload entropy bins, each 1 bit per byte wide, covering entropies from 0 to 8
+pub const NUM_ENTROPY_BINS: usize = 8;
+
 /// Per-packet features
 #[derive(Clone, Debug)]
 pub struct PacketFeatures {
+    /// Transport protocol
+    pub trans_protocol: u8,
     /// Length of the application-layer payload
     pub payload_length: usize,
-    /// Time since last packet of this direction
-    interarrival_time: u64,
+    /// Time (in ns) since last packet of this direction
+    pub interarrival_time: u64,
     /// Direction
     pub direction: PacketDirection,
+    /// Timestamp for the packet's occurrence
+    pub timestamp: u64,
+    /// Shannon entropy (in bits per byte) of the application-layer payload
+    pub entropy: f64,
+    /// Chi-square statistic of the application-layer payload's bytes against a uniform
+    /// distribution
+    pub chi_square: f64,
 }
 
 /// Per-packet features
@@ -53,6 +74,10 @@ pub enum DirectionInferenceMethod {
     Ephemeral,
     ServerPort(u16),
     ServerPorts(HashSet<u16>),
+    /// The client is the endpoint that sent the first SYN of the flow's TCP handshake
+    Handshake,
+    /// The server is the endpoint using a well-known port, below 1024
+    WellKnown,
 }
 impl DirectionInferenceMethod {
     /// Minimum ephemeral port according to IANA standards
@@ -63,34 +88,35 @@ impl DirectionInferenceMethod {
     pub const MIN_LINUX_EPH_PORT: u16 = 32768;
     /// Maximum ephemeral port used frequently by the Linux kernel
     pub const MAX_LINUX_EPH_PORT: u16 = 61000;
+    /// Minimum port above the well-known ports assigned by IANA
+    pub const MIN_REGISTERED_PORT: u16 = 1024;
 
     /// Infers the direction of a packet using many methods
-    pub fn infer_multiple(src_port: u16, dst_port: u16, methods: &[Self]) -> PacketDirection {
-        use DirectionInferenceMethod::*;
-        let last_ephemeral: Option<Option<PacketDirection>> = None;
+    ///
+    /// Methods are tried in order, falling back to the next method until one succeeds
+    /// # Parameters
+    /// * `src_port` - Source port of the packet
+    /// * `dst_port` - Destination port of the packet
+    /// * `client_port` - Port of the client, if known from the flow's TCP handshake
+    /// * `methods` - Methods to try
+    pub fn infer_multiple(
+        src_port: u16,
+        dst_port: u16,
+        client_port: Option<u16>,
+        methods: &[Self],
+    ) -> PacketDirection {
         methods
             .iter()
-            .scan(last_ephemeral, |last_ephemeral, method| {
-                // If method is ephemeral
-                if *method == Ephemeral {
-                    // If ephemeral check has been run
-                    if let Some(last_ephemeral) = last_ephemeral {
-                        // Return cached value
-                        last_ephemeral.clone()
-                    } else {
-                        let dir = method.infer(src_port, dst_port);
-                        *last_ephemeral = Some(dir.clone());
-                        dir
-                    }
-                } else {
-                    method.infer(src_port, dst_port)
-                }
-            })
-            .next()
-            .unwrap_or_else(|| PacketDirection::Unknown)
+            .find_map(|method| method.infer(src_port, dst_port, client_port))
+            .unwrap_or(PacketDirection::Unknown)
     }
     /// Infers the direction of a packet using our chosen method
-    pub fn infer(&self, src_port: u16, dst_port: u16) -> Option<PacketDirection> {
+    pub fn infer(
+        &self,
+        src_port: u16,
+        dst_port: u16,
+        client_port: Option<u16>,
+    ) -> Option<PacketDirection> {
         use DirectionInferenceMethod::*;
         match *self {
             Ephemeral => Self::infer_ephemeral(src_port, dst_port),
@@ -100,6 +126,28 @@ impl DirectionInferenceMethod {
             ServerPorts(ref server_ports) => {
                 Self::infer_from_server_ports(src_port, dst_port, &server_ports)
             }
+            Handshake => Self::infer_from_handshake(src_port, dst_port, client_port?),
+            WellKnown => Self::infer_well_known(src_port, dst_port),
+        }
+    }
+
+    /// Infers packet direction when the client's port is known from the TCP handshake
+    /// If this inference fails, return None
+    fn infer_from_handshake(
+        src_port: u16,
+        dst_port: u16,
+        client_port: u16,
+    ) -> Option<PacketDirection> {
+        use PacketDirection::*;
+        // Endpoints can't be told apart if they use the same port
+        if src_port == dst_port {
+            None
+        } else if src_port == client_port {
+            Some(FromClient)
+        } else if dst_port == client_port {
+            Some(ToClient)
+        } else {
+            None
         }
     }
 
@@ -137,6 +185,19 @@ impl DirectionInferenceMethod {
         }
     }
 
+    /// Infers packet direction when exactly one of the ports is well-known
+    /// If this inference fails, return None
+    fn infer_well_known(src_port: u16, dst_port: u16) -> Option<PacketDirection> {
+        use PacketDirection::*;
+        let src_well_known = src_port < Self::MIN_REGISTERED_PORT;
+        let dst_well_known = dst_port < Self::MIN_REGISTERED_PORT;
+        match (src_well_known, dst_well_known) {
+            (false, true) => Some(FromClient),
+            (true, false) => Some(ToClient),
+            _ => None,
+        }
+    }
+
     /// Infers packet direction based on whether a port seems to be ephemeral
     /// On linux systems, ephemeral ports are usually >= 32768
     /// If this inference fails, return None
@@ -163,38 +224,57 @@ impl DirectionInferenceMethod {
 
 impl PacketFeatures {
     /// Creates a set of packet features from packets
+    /// # Parameters
+    /// * `key` - Key of the flow the packets belong to
+    /// * `packets` - Packets of the flow
+    /// * `dir_inference_methods` - Methods used to infer the direction of each packet
     pub fn from_stripped_packets(
-        packets: Vec<StrippedPacket>,
+        key: &PacketKey,
+        packets: &[StrippedPacket],
         dir_inference_methods: &[DirectionInferenceMethod],
     ) -> Vec<Self> {
         // Keep track of the timestamp of the last packet in each direction
         struct LastTimestamps {
             from_client: Option<u64>,
             to_client: Option<u64>,
+            /// Port of the client, once the flow's TCP handshake has been seen
+            client_port: Option<u16>,
         }
         // Iterate over the packets
         packets
-            .into_iter()
+            .iter()
             .scan(
                 LastTimestamps {
                     from_client: None,
                     to_client: None,
+                    client_port: None,
                 },
                 |lts, packet| {
+                    // Recover the packet's ports from the flow's key
+                    let (src_port, dst_port) = key.ports(packet.sent_by_a);
+                    // The first SYN is sent by the client, and the first SYN-ACK to it
+                    if lts.client_port.is_none() && packet.tcp_flags & TCP_FLAG_SYN != 0 {
+                        lts.client_port = if packet.tcp_flags & TCP_FLAG_ACK == 0 {
+                            Some(src_port)
+                        } else {
+                            Some(dst_port)
+                        };
+                    }
                     // Determine the packet's direction
                     let direction = DirectionInferenceMethod::infer_multiple(
-                        packet.src_port,
-                        packet.dst_port,
+                        src_port,
+                        dst_port,
+                        lts.client_port,
                         dir_inference_methods,
                     );
                     // Get interarrival time
                     use PacketDirection::*;
                     let interarrival_time = match direction {
                         FromClient => {
-                            // Calculate interarrival time
+                            // Calculate interarrival time, clamping out of order packets to 0
                             let iat = lts
                                 .from_client
-                                .map(|lfc| packet.timestamp - lfc)
+                                .map(|lfc| packet.timestamp.saturating_sub(lfc))
                                 .unwrap_or_else(|| 0);
                             // Set the new last_from_client time
                             lts.from_client = Some(packet.timestamp);
@@ -202,10 +282,10 @@ impl PacketFeatures {
                             iat
                         }
                         ToClient => {
-                            // Calculate interarrival time
+                            // Calculate interarrival time, clamping out of order packets to 0
                             let iat = lts
                                 .to_client
-                                .map(|lfc| packet.timestamp - lfc)
+                                .map(|lfc| packet.timestamp.saturating_sub(lfc))
                                 .unwrap_or_else(|| 0);
                             // Set the new last_to_client time
                             lts.to_client = Some(packet.timestamp);
@@ -216,9 +296,13 @@ impl PacketFeatures {
                     };
                     // Return the feature set
                     Some(PacketFeatures {
+                        trans_protocol: key.trans_protocol(),
                         payload_length: packet.payload_length,
                         interarrival_time,
                         direction,
+                        timestamp: packet.timestamp,
+                        entropy: packet.entropy,
+                        chi_square: packet.chi_square,
                     })
                 },
             )
@@ -226,6 +310,19 @@ impl PacketFeatures {
     }
 }
 
+impl PacketFeatures {
+    /// Whether this is a TCP packet without a payload, such as a pure acknowledgment
+    fn is_pure_ack(&self) -> bool {
+        self.trans_protocol == TRANS_PROTOCOL_TCP && self.payload_length == 0
+    }
+
+    /// Time (in ms) since last packet of this direction, the unit interarrival bins are
+    /// chosen in
+    pub fn interarrival_time_ms(&self) -> f64 {
+        self.interarrival_time as f64 / 1_000_000.0
+    }
+}
+
 impl Into<[f64; 3]> for PacketFeatures {
     /// Converts packet features into a tensor
     fn into(self) -> [f64; 3] {
@@ -237,6 +334,142 @@ impl Into<[f64; 3]> for PacketFeatures {
     }
 }
 
+/// Calculates the mean entropy of the payloads in a flow, ignoring packets without a payload
+///
+/// Flows without any payload have a mean entropy of 0
+pub fn mean_payload_entropy(packets: &[StrippedPacket]) -> f64 {
+    let (entropy_sum, num_payloads) = packets
+        .iter()
+        .filter(|packet| packet.payload_length > 0)
+        .fold((0.0, 0), |(entropy_sum, num_payloads), packet| {
+            (entropy_sum + packet.entropy, num_payloads + 1)
+        });
+    if num_payloads == 0 {
+        0.0
+    } else {
+        entropy_sum / f64::from(num_payloads)
+    }
+}
+
+/// Finds the entropy bin a payload's entropy falls into. Entropies of exactly 8 bits per byte
+/// fall into the last bin
+fn entropy_bin(entropy: f64) -> usize {
+    (entropy.max(0.0) as usize).min(NUM_ENTROPY_BINS - 1)
+}
+
+/// Appends bytes to a payload prefix, without letting it grow past the maximum length
+pub(crate) fn extend_prefix(prefix: &mut Vec<u8>, bytes: &[u8], max_length: usize) {
+    let num_bytes = max_length.saturating_sub(prefix.len()).min(bytes.len());
+    prefix.extend_from_slice(&bytes[..num_bytes]);
+}
+
+/// Running sums and extremes of a set of values, from which their moments are calculated
+///
+/// Sums are kept rather than the statistics themselves so that flows can be merged exactly
+#[derive(Clone, Copy, Debug, Default)]
+struct Moments {
+    /// Number of values
+    count: usize,
+    /// Sum of the values
+    sum: u128,
+    /// Sum of the squared values
+    sum_of_squares: u128,
+    /// Smallest value
+    min: Option<u64>,
+    /// Largest value
+    max: Option<u64>,
+}
+
+impl Moments {
+    /// Adds a value to the sums
+    fn push(&mut self, value: u64) {
+        self.count += 1;
+        self.sum += u128::from(value);
+        self.sum_of_squares += u128::from(value) * u128::from(value);
+        self.min = Some(self.min.map_or(value, |min| min.min(value)));
+        self.max = Some(self.max.map_or(value, |max| max.max(value)));
+    }
+
+    /// Calculates the mean of the values, or 0 if there are none
+    fn mean(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.sum as f64 / self.count as f64
+    }
+
+    /// Calculates the population variance of the values, or 0 if there are none
+    fn variance(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        let mean = self.mean();
+        // Rounding can make the variance of near-identical values slightly negative
+        (self.sum_of_squares as f64 / self.count as f64 - mean * mean).max(0.0)
+    }
+
+    /// Calculates the coefficient of variation (standard deviation over mean) of the values
+    ///
+    /// This is 0 if there are no values, or their mean is 0
+    fn coefficient_of_variation(&self) -> f64 {
+        if self.sum == 0 {
+            return 0.0;
+        }
+        self.variance().sqrt() / self.mean()
+    }
+
+    /// Summarizes the values
+    fn summarize(&self) -> SummaryStatistics {
+        SummaryStatistics {
+            mean: self.mean(),
+            variance: self.variance(),
+            min: self.min.unwrap_or(0),
+            max: self.max.unwrap_or(0),
+            count: self.count,
+        }
+    }
+}
+
+impl ops::Add for Moments {
+    type Output = Self;
+    /// Combines the sums of two sets of values
+    fn add(self, rhs: Self) -> Self::Output {
+        // Take the extreme of whichever sets have values
+        let combine =
+            |lhs: Option<u64>, rhs: Option<u64>, pick: fn(u64, u64) -> u64| match (lhs, rhs) {
+                (Some(lhs), Some(rhs)) => Some(pick(lhs, rhs)),
+                (lhs, rhs) => lhs.or(rhs),
+            };
+        Moments {
+            count: self.count + rhs.count,
+            sum: self.sum + rhs.sum,
+            sum_of_squares: self.sum_of_squares + rhs.sum_of_squares,
+            min: combine(self.min, rhs.min, u64::min),
+            max: combine(self.max, rhs.max, u64::max),
+        }
+    }
+}
+
+/// Summary statistics of a set of values
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SummaryStatistics {
+    /// Mean of the values
+    #[serde(rename = "m")]
+    pub mean: f64,
+    /// Population variance of the values
+    #[serde(rename = "v")]
+    pub variance: f64,
+    /// Smallest value, or 0 if there are none
+    #[serde(rename = "n")]
+    pub min: u64,
+    /// Largest value, or 0 if there are none
+    #[serde(rename = "x")]
+    pub max: u64,
+    /// Number of values
+    #[serde(rename = "c")]
+    pub count: usize,
+}
+
 /// Overall flow features, extracted from packet-level features
 #[derive(Debug)]
 pub struct FlowFeatures {
@@ -248,6 +481,61 @@ pub struct FlowFeatures {
     /// Frequency of interarrival times (to client) for this flow,
     /// separated into bins
     interarrival_freq_to_client_bins: Vec<usize>,
+    /// Number of bursts of packets, separated by idle periods
+    num_bursts: usize,
+    /// Number of idle periods between bursts
+    num_inter_burst_gaps: usize,
+    /// Sum of the idle periods between bursts (in ns)
+    inter_burst_gap_sum: u64,
+    /// Longest idle period between bursts (in ns)
+    max_inter_burst_gap: u64,
+    /// Frequency of burst sizes (in packets) for this flow, separated into bins
+    burst_size_freq_bins: Vec<usize>,
+    /// Frequency of payload entropies for this flow, separated into bins. Packets without a
+    /// payload are ignored
+    entropy_freq_bins: Vec<usize>,
+    /// Sum of the chi-square statistics of the payloads, ignoring packets without a payload
+    payload_chi_square_sum: f64,
+    /// Most payload bytes sent within any window of the throughput window's length
+    peak_window_bytes: u64,
+    /// Moments of payload lengths from the client
+    payload_length_moments_from_client: Moments,
+    /// Moments of payload lengths to the client
+    payload_length_moments_to_client: Moments,
+    /// Moments of interarrival times (in ns) from the client, leaving out the first packet from
+    /// the client, which has nothing to arrive after
+    interarrival_moments_from_client: Moments,
+    /// Moments of interarrival times (in ns) to the client, leaving out the first packet to
+    /// the client, which has nothing to arrive after
+    interarrival_moments_to_client: Moments,
+    /// Number of packets from the client carrying a payload
+    data_packets_from_client: usize,
+    /// Number of packets to the client carrying a payload
+    data_packets_to_client: usize,
+    /// Number of TCP packets from the client without a payload
+    pure_acks_from_client: usize,
+    /// Number of TCP packets to the client without a payload
+    pure_acks_to_client: usize,
+    /// Number of packets from the client
+    total_packets_from_client: usize,
+    /// Number of packets to the client
+    total_packets_to_client: usize,
+    /// Number of packets whose direction couldn't be inferred
+    unknown_direction_packets: usize,
+    /// Number of payload bytes from the client
+    total_bytes_from_client: u64,
+    /// Number of payload bytes to the client
+    total_bytes_to_client: u64,
+    /// Timestamp (in ns) of the first packet, if there are any packets
+    first_timestamp: Option<u64>,
+    /// Timestamp (in ns) of the last packet, if there are any packets
+    last_timestamp: Option<u64>,
+    /// Maximum number of bytes of payload kept in each direction, or 0 if none are kept
+    payload_prefix_length: usize,
+    /// First bytes of the payloads from the client, concatenated
+    payload_prefix_from_client: Vec<u8>,
+    /// First bytes of the payloads to the client, concatenated
+    payload_prefix_to_client: Vec<u8>,
 }
 
 impl FlowFeatures {
@@ -258,30 +546,84 @@ impl FlowFeatures {
     ///
     /// # Parameters:
     /// * `packet_features` - Set of packet features to create flow features from
-    /// * `payload_length_bin_sizes` - Set of maximum sizes for each payload length bin
-    /// * `interarrival_from_client_bin_sizes` - Set of maximum sizes for each interarrival time bin
-    ///                                          (from client)
-    /// * `interarrival_to_client_bin_sizes` - Set of maximum sizes for each interarrival time bin
-    ///                                        (to client)
+    /// * `bins` - Maximum sizes of each bin, along with the burst idle threshold and the
+    ///   throughput window
+    /// * `payload_prefix_length` - Maximum number of bytes of payload kept in each direction
     pub fn generate(
         packet_features: &[PacketFeatures],
-        payload_length_bin_sizes: &[usize],
-        interarrival_from_client_bin_sizes: &[u64],
-        interarrival_to_client_bin_sizes: &[u64],
+        bins: &BinConfig,
+        payload_prefix_length: usize,
     ) -> Self {
+        // Get the bins, with interarrival times in the packets' nanoseconds
+        let payload_length_bin_sizes = &bins.payload_length;
+        let interarrival_from_client_bin_sizes = bins.ia_from_client_nanos();
+        let interarrival_to_client_bin_sizes = bins.ia_to_client_nanos();
+        let burst_idle_threshold = bins.burst_idle_threshold;
+        let burst_size_bin_sizes = &bins.burst_size;
+        let throughput_window = bins.throughput_window;
         // Initialize the bins
         let mut payload_length_freq_bins = vec![0; payload_length_bin_sizes.len()];
         let mut interarrival_freq_from_client_bins =
             vec![0; interarrival_from_client_bin_sizes.len()];
         let mut interarrival_freq_to_client_bins = vec![0; interarrival_to_client_bin_sizes.len()];
+        let mut entropy_freq_bins = vec![0; NUM_ENTROPY_BINS];
+        let mut payload_chi_square_sum = 0.0;
+        let mut payload_length_moments_from_client = Moments::default();
+        let mut payload_length_moments_to_client = Moments::default();
+        let mut interarrival_moments_from_client = Moments::default();
+        let mut interarrival_moments_to_client = Moments::default();
+        let mut data_packets_from_client = 0;
+        let mut data_packets_to_client = 0;
+        let mut pure_acks_from_client = 0;
+        let mut pure_acks_to_client = 0;
+        let mut total_packets_from_client = 0;
+        let mut total_packets_to_client = 0;
+        let mut unknown_direction_packets = 0;
+        let mut total_bytes_from_client = 0;
+        let mut total_bytes_to_client = 0;
         // Generate the frequencies
         for packet in packet_features {
+            match packet.direction {
+                PacketDirection::FromClient => {
+                    total_packets_from_client += 1;
+                    total_bytes_from_client += packet.payload_length as u64;
+                    payload_length_moments_from_client.push(packet.payload_length as u64);
+                    // The first packet's interarrival time of 0 is only a placeholder
+                    if total_packets_from_client > 1 {
+                        interarrival_moments_from_client.push(packet.interarrival_time);
+                    }
+                    if packet.payload_length > 0 {
+                        data_packets_from_client += 1;
+                    } else if packet.is_pure_ack() {
+                        pure_acks_from_client += 1;
+                    }
+                }
+                PacketDirection::ToClient => {
+                    total_packets_to_client += 1;
+                    total_bytes_to_client += packet.payload_length as u64;
+                    payload_length_moments_to_client.push(packet.payload_length as u64);
+                    if total_packets_to_client > 1 {
+                        interarrival_moments_to_client.push(packet.interarrival_time);
+                    }
+                    if packet.payload_length > 0 {
+                        data_packets_to_client += 1;
+                    } else if packet.is_pure_ack() {
+                        pure_acks_to_client += 1;
+                    }
+                }
+                PacketDirection::Unknown => unknown_direction_packets += 1,
+            }
             for (idx, bin_max) in payload_length_bin_sizes.iter().enumerate() {
                 if packet.payload_length < *bin_max {
                     payload_length_freq_bins[idx] += 1;
                     break;
                 }
             }
+            // Empty payloads have no entropy to speak of
+            if packet.payload_length > 0 {
+                entropy_freq_bins[entropy_bin(packet.entropy)] += 1;
+                payload_chi_square_sum += packet.chi_square;
+            }
             for (idx, bin_max) in interarrival_from_client_bin_sizes.iter().enumerate() {
                 if packet.direction == PacketDirection::FromClient
                     && packet.interarrival_time < *bin_max
@@ -299,24 +641,154 @@ impl FlowFeatures {
                 }
             }
         }
+        // Split the flow into bursts separated by idle periods
+        let mut burst_size_freq_bins = vec![0; burst_size_bin_sizes.len()];
+        let mut num_bursts = 0;
+        let mut num_inter_burst_gaps = 0;
+        let mut inter_burst_gap_sum = 0;
+        let mut max_inter_burst_gap = 0;
+        // Records a burst's size once it has ended
+        let mut end_burst = |burst_size: usize| {
+            num_bursts += 1;
+            for (idx, bin_max) in burst_size_bin_sizes.iter().enumerate() {
+                if burst_size < *bin_max {
+                    burst_size_freq_bins[idx] += 1;
+                    break;
+                }
+            }
+        };
+        let mut last_timestamp: Option<u64> = None;
+        let mut burst_size = 0;
+        for packet in packet_features {
+            if let Some(last_timestamp) = last_timestamp {
+                let gap = packet.timestamp.saturating_sub(last_timestamp);
+                // A long enough gap ends the current burst
+                if gap > burst_idle_threshold {
+                    end_burst(burst_size);
+                    burst_size = 0;
+                    num_inter_burst_gaps += 1;
+                    inter_burst_gap_sum += gap;
+                    max_inter_burst_gap = max_inter_burst_gap.max(gap);
+                }
+            }
+            burst_size += 1;
+            last_timestamp = Some(packet.timestamp);
+        }
+        // End the last burst
+        if burst_size > 0 {
+            end_burst(burst_size);
+        }
+        // Slide a window over the packets, finding the most bytes sent within it
+        let mut peak_window_bytes = 0;
+        let mut window_bytes = 0;
+        let mut window_start = 0;
+        for (end, packet) in packet_features.iter().enumerate() {
+            window_bytes += packet.payload_length as u64;
+            // Drop packets too long before this one to share its window
+            while window_start <= end
+                && packet
+                    .timestamp
+                    .saturating_sub(packet_features[window_start].timestamp)
+                    >= throughput_window
+            {
+                window_bytes -= packet_features[window_start].payload_length as u64;
+                window_start += 1;
+            }
+            peak_window_bytes = peak_window_bytes.max(window_bytes);
+        }
+        // Find the span of the flow
+        let first_timestamp = packet_features.iter().map(|packet| packet.timestamp).min();
+        let last_timestamp = packet_features.iter().map(|packet| packet.timestamp).max();
         // Return the flow features
         FlowFeatures {
             payload_length_freq_bins,
             interarrival_freq_from_client_bins,
             interarrival_freq_to_client_bins,
+            num_bursts,
+            num_inter_burst_gaps,
+            inter_burst_gap_sum,
+            max_inter_burst_gap,
+            burst_size_freq_bins,
+            entropy_freq_bins,
+            payload_chi_square_sum,
+            peak_window_bytes,
+            payload_length_moments_from_client,
+            payload_length_moments_to_client,
+            interarrival_moments_from_client,
+            interarrival_moments_to_client,
+            data_packets_from_client,
+            data_packets_to_client,
+            pure_acks_from_client,
+            pure_acks_to_client,
+            total_packets_from_client,
+            total_packets_to_client,
+            unknown_direction_packets,
+            total_bytes_from_client,
+            total_bytes_to_client,
+            first_timestamp,
+            last_timestamp,
+            payload_prefix_length,
+            payload_prefix_from_client: Vec::new(),
+            payload_prefix_to_client: Vec::new(),
         }
     }
 
+    /// Sets the first bytes of payload sent in each direction, which are kept by the flow
+    /// rather than its packets. Prefixes are cut to the maximum length
+    pub fn with_payload_prefixes(mut self, from_client: &[u8], to_client: &[u8]) -> Self {
+        self.payload_prefix_from_client.clear();
+        extend_prefix(
+            &mut self.payload_prefix_from_client,
+            from_client,
+            self.payload_prefix_length,
+        );
+        self.payload_prefix_to_client.clear();
+        extend_prefix(
+            &mut self.payload_prefix_to_client,
+            to_client,
+            self.payload_prefix_length,
+        );
+        self
+    }
+
     /// Generates an empty set of flow features with all zeroes
     pub fn empty(
         num_payload_length_bins: usize,
         num_ia_from_client_bins: usize,
         num_ia_to_client_bins: usize,
+        num_burst_size_bins: usize,
+        payload_prefix_length: usize,
     ) -> Self {
         FlowFeatures {
             payload_length_freq_bins: vec![0; num_payload_length_bins],
             interarrival_freq_from_client_bins: vec![0; num_ia_from_client_bins],
             interarrival_freq_to_client_bins: vec![0; num_ia_to_client_bins],
+            num_bursts: 0,
+            num_inter_burst_gaps: 0,
+            inter_burst_gap_sum: 0,
+            max_inter_burst_gap: 0,
+            burst_size_freq_bins: vec![0; num_burst_size_bins],
+            entropy_freq_bins: vec![0; NUM_ENTROPY_BINS],
+            payload_chi_square_sum: 0.0,
+            peak_window_bytes: 0,
+            payload_length_moments_from_client: Moments::default(),
+            payload_length_moments_to_client: Moments::default(),
+            interarrival_moments_from_client: Moments::default(),
+            interarrival_moments_to_client: Moments::default(),
+            data_packets_from_client: 0,
+            data_packets_to_client: 0,
+            pure_acks_from_client: 0,
+            pure_acks_to_client: 0,
+            total_packets_from_client: 0,
+            total_packets_to_client: 0,
+            unknown_direction_packets: 0,
+            total_bytes_from_client: 0,
+            total_bytes_to_client: 0,
+            first_timestamp: None,
+            last_timestamp: None,
+            payload_prefix_length,
+            payload_prefix_from_client: Vec::new(),
+            payload_prefix_to_client: Vec::new(),
         }
     }
 
@@ -339,12 +811,65 @@ impl ops::Add for FlowFeatures {
         for (idx, freq) in rhs.interarrival_freq_to_client_bins.iter().enumerate() {
             self.interarrival_freq_to_client_bins[idx] += freq;
         }
+        // Add burst counts
+        self.num_bursts += rhs.num_bursts;
+        self.num_inter_burst_gaps += rhs.num_inter_burst_gaps;
+        self.inter_burst_gap_sum += rhs.inter_burst_gap_sum;
+        self.max_inter_burst_gap = self.max_inter_burst_gap.max(rhs.max_inter_burst_gap);
+        for (idx, freq) in rhs.burst_size_freq_bins.iter().enumerate() {
+            self.burst_size_freq_bins[idx] += freq;
+        }
+        // Add payload entropy counts
+        for (idx, freq) in rhs.entropy_freq_bins.iter().enumerate() {
+            self.entropy_freq_bins[idx] += freq;
+        }
+        // Flows of a request overlap, but the windows are only measured within each flow
+        self.payload_chi_square_sum += rhs.payload_chi_square_sum;
+        self.peak_window_bytes = self.peak_window_bytes.max(rhs.peak_window_bytes);
+        // Combine payload length and interarrival time moments
+        self.payload_length_moments_from_client =
+            self.payload_length_moments_from_client + rhs.payload_length_moments_from_client;
+        self.payload_length_moments_to_client =
+            self.payload_length_moments_to_client + rhs.payload_length_moments_to_client;
+        self.interarrival_moments_from_client =
+            self.interarrival_moments_from_client + rhs.interarrival_moments_from_client;
+        self.interarrival_moments_to_client =
+            self.interarrival_moments_to_client + rhs.interarrival_moments_to_client;
+        // Add data and pure ACK counts
+        self.data_packets_from_client += rhs.data_packets_from_client;
+        self.data_packets_to_client += rhs.data_packets_to_client;
+        self.pure_acks_from_client += rhs.pure_acks_from_client;
+        self.pure_acks_to_client += rhs.pure_acks_to_client;
+        // Add packet and byte totals
+        self.total_packets_from_client += rhs.total_packets_from_client;
+        self.total_packets_to_client += rhs.total_packets_to_client;
+        self.unknown_direction_packets += rhs.unknown_direction_packets;
+        self.total_bytes_from_client += rhs.total_bytes_from_client;
+        self.total_bytes_to_client += rhs.total_bytes_to_client;
+        // Flows of a request overlap, so the combined duration is the span from the earliest
+        // packet to the latest rather than the sum of the durations
+        self.first_timestamp = match (self.first_timestamp, rhs.first_timestamp) {
+            (Some(lhs), Some(rhs)) => Some(lhs.min(rhs)),
+            (lhs, rhs) => lhs.or(rhs),
+        };
+        self.last_timestamp = self.last_timestamp.max(rhs.last_timestamp);
+        // Append payload prefixes, up to the maximum length
+        extend_prefix(
+            &mut self.payload_prefix_from_client,
+            &rhs.payload_prefix_from_client,
+            self.payload_prefix_length,
+        );
+        extend_prefix(
+            &mut self.payload_prefix_to_client,
+            &rhs.payload_prefix_to_client,
+            self.payload_prefix_length,
+        );
         self
     }
 }
 
 /// Flow features after normalizing each feature
-#[derive(Debug, Serialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct NormalizedFlowFeatures {
     /// Frequency of packet sizes for this flow, separated into bins
     #[serde(rename = "pl")]
@@ -357,6 +882,76 @@ pub struct NormalizedFlowFeatures {
     /// separated into bins
     #[serde(rename = "iat")]
     pub interarrival_freq_to_client_bins: Vec<f64>,
+    /// Number of bursts of packets in this flow
+    #[serde(rename = "nb")]
+    pub num_bursts: usize,
+    /// Mean idle period between bursts (in ns)
+    #[serde(rename = "ibm")]
+    pub mean_inter_burst_gap: f64,
+    /// Longest idle period between bursts (in ns)
+    #[serde(rename = "ibx")]
+    pub max_inter_burst_gap: u64,
+    /// Frequency of burst sizes for this flow, separated into bins
+    #[serde(rename = "bs")]
+    pub burst_size_freq_bins: Vec<f64>,
+    /// Frequency of payload entropies for this flow, separated into bins
+    #[serde(rename = "en")]
+    pub entropy_freq_bins: Vec<f64>,
+    /// Mean chi-square statistic of the payloads against a uniform distribution, ignoring
+    /// packets without a payload
+    #[serde(rename = "csm")]
+    pub mean_payload_chi_square: f64,
+    /// Most payload bytes sent within any window of the throughput window's length
+    #[serde(rename = "pwb")]
+    pub peak_window_bytes: u64,
+    /// Coefficient of variation of payload lengths from the client
+    #[serde(rename = "cvf")]
+    pub payload_cv_from_client: f64,
+    /// Coefficient of variation of payload lengths to the client
+    #[serde(rename = "cvt")]
+    pub payload_cv_to_client: f64,
+    /// Summary statistics of payload lengths from the client
+    #[serde(rename = "plfs")]
+    pub payload_length_stats_from_client: SummaryStatistics,
+    /// Summary statistics of payload lengths to the client
+    #[serde(rename = "plts")]
+    pub payload_length_stats_to_client: SummaryStatistics,
+    /// Summary statistics of interarrival times (in ns) from the client
+    #[serde(rename = "iafs")]
+    pub interarrival_stats_from_client: SummaryStatistics,
+    /// Summary statistics of interarrival times (in ns) to the client
+    #[serde(rename = "iats")]
+    pub interarrival_stats_to_client: SummaryStatistics,
+    /// Number of packets carrying a payload per pure ACK, from the client
+    #[serde(rename = "daf")]
+    pub data_ack_ratio_from_client: f64,
+    /// Number of packets carrying a payload per pure ACK, to the client
+    #[serde(rename = "dat")]
+    pub data_ack_ratio_to_client: f64,
+    /// Number of packets from the client, left unnormalized
+    #[serde(rename = "tpf")]
+    pub total_packets_from_client: usize,
+    /// Number of packets to the client, left unnormalized
+    #[serde(rename = "tpt")]
+    pub total_packets_to_client: usize,
+    /// Number of packets whose direction couldn't be inferred, left unnormalized
+    #[serde(rename = "tpu")]
+    pub unknown_direction_packets: usize,
+    /// Number of payload bytes from the client, left unnormalized
+    #[serde(rename = "tbf")]
+    pub total_bytes_from_client: u64,
+    /// Number of payload bytes to the client, left unnormalized
+    #[serde(rename = "tbt")]
+    pub total_bytes_to_client: u64,
+    /// Time (in ns) from the first packet to the last
+    #[serde(rename = "du")]
+    pub duration: u64,
+    /// Hex encoded start of the payloads from the client, if payloads were kept
+    #[serde(rename = "ppf", skip_serializing_if = "Option::is_none")]
+    pub payload_prefix_from_client: Option<String>,
+    /// Hex encoded start of the payloads to the client, if payloads were kept
+    #[serde(rename = "ppt", skip_serializing_if = "Option::is_none")]
+    pub payload_prefix_to_client: Option<String>,
 }
 
 impl From<FlowFeatures> for NormalizedFlowFeatures {
@@ -371,11 +966,15 @@ impl From<FlowFeatures> for NormalizedFlowFeatures {
             .interarrival_freq_to_client_bins
             .iter()
             .sum::<usize>();
+        let bs_sum = flow_features.burst_size_freq_bins.iter().sum::<usize>();
+        let en_sum = flow_features.entropy_freq_bins.iter().sum::<usize>();
         // Allow handling normalization when all values are zero
         let zero_handler = |d| if d == 0 { 1.0 } else { d as f64 };
         let pl_sum = zero_handler(pl_sum);
         let iaf_sum = zero_handler(iaf_sum);
         let iat_sum = zero_handler(iat_sum);
+        let bs_sum = zero_handler(bs_sum);
+        let en_sum = zero_handler(en_sum);
         // Normalize against sum for each
         let payload_length_freq_bins = flow_features
             .payload_length_freq_bins
@@ -393,11 +992,657 @@ impl From<FlowFeatures> for NormalizedFlowFeatures {
             .into_iter()
             .map(|c| c as f64 / iat_sum)
             .collect();
+        let burst_size_freq_bins = flow_features
+            .burst_size_freq_bins
+            .into_iter()
+            .map(|c| c as f64 / bs_sum)
+            .collect();
+        let entropy_freq_bins = flow_features
+            .entropy_freq_bins
+            .into_iter()
+            .map(|c| c as f64 / en_sum)
+            .collect();
+        // Encode the payload prefixes, if they were kept
+        let (payload_prefix_from_client, payload_prefix_to_client) =
+            if flow_features.payload_prefix_length > 0 {
+                (
+                    Some(hex::encode(&flow_features.payload_prefix_from_client)),
+                    Some(hex::encode(&flow_features.payload_prefix_to_client)),
+                )
+            } else {
+                (None, None)
+            };
+        // Compare data packets to pure ACKs in each direction
+        let data_ack_ratio_from_client = flow_features.data_packets_from_client as f64
+            / zero_handler(flow_features.pure_acks_from_client);
+        let data_ack_ratio_to_client = flow_features.data_packets_to_client as f64
+            / zero_handler(flow_features.pure_acks_to_client);
+        // Measure the span of the flow
+        let duration = match (flow_features.first_timestamp, flow_features.last_timestamp) {
+            (Some(first_timestamp), Some(last_timestamp)) => last_timestamp - first_timestamp,
+            _ => 0,
+        };
+        // Average the gaps between bursts
+        let mean_inter_burst_gap = flow_features.inter_burst_gap_sum as f64
+            / zero_handler(flow_features.num_inter_burst_gaps);
         // Return the normalized flow features
         NormalizedFlowFeatures {
             payload_length_freq_bins,
             interarrival_freq_from_client_bins,
             interarrival_freq_to_client_bins,
+            num_bursts: flow_features.num_bursts,
+            mean_inter_burst_gap,
+            max_inter_burst_gap: flow_features.max_inter_burst_gap,
+            burst_size_freq_bins,
+            entropy_freq_bins,
+            mean_payload_chi_square: flow_features.payload_chi_square_sum / en_sum,
+            peak_window_bytes: flow_features.peak_window_bytes,
+            payload_cv_from_client: flow_features
+                .payload_length_moments_from_client
+                .coefficient_of_variation(),
+            payload_cv_to_client: flow_features
+                .payload_length_moments_to_client
+                .coefficient_of_variation(),
+            payload_length_stats_from_client: flow_features
+                .payload_length_moments_from_client
+                .summarize(),
+            payload_length_stats_to_client: flow_features
+                .payload_length_moments_to_client
+                .summarize(),
+            interarrival_stats_from_client: flow_features
+                .interarrival_moments_from_client
+                .summarize(),
+            interarrival_stats_to_client: flow_features.interarrival_moments_to_client.summarize(),
+            data_ack_ratio_from_client,
+            data_ack_ratio_to_client,
+            total_packets_from_client: flow_features.total_packets_from_client,
+            total_packets_to_client: flow_features.total_packets_to_client,
+            unknown_direction_packets: flow_features.unknown_direction_packets,
+            total_bytes_from_client: flow_features.total_bytes_from_client,
+            total_bytes_to_client: flow_features.total_bytes_to_client,
+            duration,
+            payload_prefix_from_client,
+            payload_prefix_to_client,
+        }
+    }
+}
+
+/// Features of the connections of a flow, taken from the connection log alone
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct ConnectionFeatures {
+    /// Number of connections
+    #[serde(rename = "nc")]
+    pub num_connections: usize,
+    /// Payload bytes sent by the originators
+    #[serde(rename = "ob")]
+    pub orig_bytes: u64,
+    /// Payload bytes sent by the responders
+    #[serde(rename = "rb")]
+    pub resp_bytes: u64,
+    /// Packets sent by the originators
+    #[serde(rename = "op")]
+    pub orig_packets: u64,
+    /// Packets sent by the responders
+    #[serde(rename = "rp")]
+    pub resp_packets: u64,
+    /// Duration (in ns) of the longest connection
+    #[serde(rename = "d")]
+    pub duration: u64,
+    /// Number of connections where the originator's SYN was answered with a SYN+ACK
+    #[serde(rename = "nh")]
+    pub num_handshakes: usize,
+    /// Number of connections where both endpoints sent data
+    #[serde(rename = "nd")]
+    pub num_data_both_ways: usize,
+    /// Number of connections reset by either endpoint
+    #[serde(rename = "nr")]
+    pub num_resets: usize,
+    /// Frequency of each connection state, in the order `ConnState` declares them
+    #[serde(rename = "cs")]
+    pub conn_state_freq: Vec<f64>,
+    /// Frequency of each history entry from the originators, in the order `HistoryEntry`
+    /// declares them
+    #[serde(rename = "ho")]
+    pub history_freq_from_orig: Vec<f64>,
+    /// Frequency of each history entry from the responders, in the order `HistoryEntry`
+    /// declares them
+    #[serde(rename = "hr")]
+    pub history_freq_from_resp: Vec<f64>,
+}
+
+impl ConnectionFeatures {
+    /// Summarizes a set of connections
+    /// # Parameters
+    /// * `connections` - Connections of the flow
+    pub fn from_connections(connections: &[Connection]) -> Self {
+        // Counts that zeek couldn't determine are logged as missing or negative
+        let count = |value: Option<i64>| value.unwrap_or(0).max(0) as u64;
+        let mut conn_state_counts = vec![0; ConnState::COUNT];
+        let mut history_counts_from_orig = vec![0; HistoryEntry::COUNT];
+        let mut history_counts_from_resp = vec![0; HistoryEntry::COUNT];
+        for connection in connections {
+            conn_state_counts[connection.conn_state.unwrap_or_default() as usize] += 1;
+            for (endpoint, entry) in connection.parsed_history() {
+                let history_counts = match endpoint {
+                    Endpoint::Originator => &mut history_counts_from_orig,
+                    Endpoint::Responder => &mut history_counts_from_resp,
+                };
+                history_counts[entry as usize] += 1;
+            }
         }
+        let num_matching = |predicate: fn(&Connection) -> bool| {
+            connections
+                .iter()
+                .filter(|connection| predicate(connection))
+                .count()
+        };
+        // Normalize the counts against their sums
+        let normalize = |counts: Vec<usize>| {
+            let sum = counts.iter().sum::<usize>().max(1) as f64;
+            counts.into_iter().map(|c| c as f64 / sum).collect()
+        };
+        ConnectionFeatures {
+            num_connections: connections.len(),
+            orig_bytes: connections.iter().map(|c| count(c.orig_bytes)).sum(),
+            resp_bytes: connections.iter().map(|c| count(c.resp_bytes)).sum(),
+            orig_packets: connections.iter().map(|c| count(c.orig_pkts)).sum(),
+            resp_packets: connections.iter().map(|c| count(c.resp_pkts)).sum(),
+            duration: connections.iter().map(|c| c.duration).max().unwrap_or(0),
+            num_handshakes: num_matching(Connection::has_handshake),
+            num_data_both_ways: num_matching(Connection::has_data_both_ways),
+            num_resets: num_matching(Connection::has_reset),
+            conn_state_freq: normalize(conn_state_counts),
+            history_freq_from_orig: normalize(history_counts_from_orig),
+            history_freq_from_resp: normalize(history_counts_from_resp),
+        }
+    }
+}
+
+/// TLS metadata of a session carried in one of a flow's connections. These are categorical
+/// features, and are left as zeek wrote them
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct TlsMetadata {
+    /// Negotiated protocol version
+    #[serde(rename = "v")]
+    pub version: Option<String>,
+    /// Negotiated cipher suite
+    #[serde(rename = "ci")]
+    pub cipher: Option<String>,
+    /// Server name the client asked for
+    #[serde(rename = "sni")]
+    pub server_name: Option<String>,
+}
+
+impl From<SslRecord> for TlsMetadata {
+    fn from(ssl_record: SslRecord) -> Self {
+        TlsMetadata {
+            version: ssl_record.version,
+            cipher: ssl_record.cipher,
+            server_name: ssl_record.server_name,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bro_types::tests::connection_at;
+    use crate::config::TimeUnit;
+    use crate::packet::tests::stripped_packet_at;
+    use PacketDirection::*;
+    /// Creates the key of a TCP flow between 10.0.0.1 and 10.0.0.2, where endpoint a is
+    /// 10.0.0.1 using the lower port
+    fn test_key(port_a: u16, port_b: u16) -> PacketKey {
+        PacketKey::new(
+            "10.0.0.1".parse().unwrap(),
+            "10.0.0.2".parse().unwrap(),
+            TRANS_PROTOCOL_TCP,
+            port_a,
+            port_b,
+        )
+    }
+    /// Creates features for a TCP packet sent in the given direction at the given time
+    fn packet_features(
+        direction: PacketDirection,
+        payload_length: usize,
+        timestamp: u64,
+    ) -> PacketFeatures {
+        PacketFeatures {
+            trans_protocol: TRANS_PROTOCOL_TCP,
+            payload_length,
+            interarrival_time: 0,
+            direction,
+            timestamp,
+            entropy: 0.0,
+            chi_square: 0.0,
+        }
+    }
+    /// Creates bins with a single bin for each binned feature, without bursts or throughput
+    /// windows
+    fn single_bins() -> BinConfig {
+        BinConfig {
+            payload_length: vec![65536],
+            ia_from_client: vec![u64::MAX],
+            ia_to_client: vec![u64::MAX],
+            ia_unit: TimeUnit::Ns,
+            burst_idle_threshold: 0,
+            burst_size: vec![2],
+            throughput_window: 0,
+        }
+    }
+    /// Generates the features of a flow with a single bin for each binned feature, without
+    /// bursts, throughput windows, or payload prefixes
+    fn generate(packets: &[PacketFeatures]) -> FlowFeatures {
+        FlowFeatures::generate(packets, &single_bins(), 0)
+    }
+    /// Tests that bursts separated by an idle period are detected
+    #[test]
+    fn test_inter_burst_gaps() {
+        let ms: u64 = 1_000_000;
+        let packets: Vec<PacketFeatures> = [0, 1, 2, 1000, 1001]
+            .iter()
+            .map(|t| packet_features(FromClient, 100, t * ms))
+            .collect();
+        let bins = BinConfig {
+            burst_idle_threshold: 100 * ms,
+            burst_size: vec![3, 10],
+            ..single_bins()
+        };
+        let features = FlowFeatures::generate(&packets, &bins, 0);
+        assert_eq!(features.num_bursts, 2);
+        assert_eq!(features.num_inter_burst_gaps, 1);
+        assert_eq!(features.max_inter_burst_gap, 998 * ms);
+        assert_eq!(features.burst_size_freq_bins, vec![1, 1]);
+        let features = features.normalize();
+        assert_eq!(features.num_bursts, 2);
+        assert_eq!(features.mean_inter_burst_gap, (998 * ms) as f64);
+        assert_eq!(features.burst_size_freq_bins, vec![0.5, 0.5]);
+    }
+
+    /// Tests that the handshake determines direction, and is used as a fallback
+    #[test]
+    fn test_handshake_direction() {
+        let key = test_key(1234, 5678);
+        let packet = |src_port, _dst_port, tcp_flags| StrippedPacket {
+            tcp_flags,
+            ..stripped_packet_at(src_port == 1234, 0)
+        };
+        let packets = vec![
+            packet(1234, 5678, TCP_FLAG_SYN),
+            packet(5678, 1234, TCP_FLAG_SYN | TCP_FLAG_ACK),
+            packet(1234, 5678, TCP_FLAG_ACK),
+            packet(5678, 1234, TCP_FLAG_ACK),
+        ];
+        let methods = vec![
+            DirectionInferenceMethod::ServerPort(443),
+            DirectionInferenceMethod::Handshake,
+            DirectionInferenceMethod::Ephemeral,
+        ];
+        let directions: Vec<PacketDirection> =
+            PacketFeatures::from_stripped_packets(&key, &packets, &methods)
+                .into_iter()
+                .map(|features| features.direction)
+                .collect();
+        assert_eq!(directions, vec![FromClient, ToClient, FromClient, ToClient]);
+        // Without the client's SYN, the SYN-ACK identifies the client
+        let packets = vec![
+            packet(5678, 1234, TCP_FLAG_SYN | TCP_FLAG_ACK),
+            packet(1234, 5678, TCP_FLAG_ACK),
+        ];
+        let directions: Vec<PacketDirection> =
+            PacketFeatures::from_stripped_packets(&key, &packets, &methods)
+                .into_iter()
+                .map(|features| features.direction)
+                .collect();
+        assert_eq!(directions, vec![ToClient, FromClient]);
+        // Without a handshake, nothing can be inferred from these ports
+        let packets = vec![packet(1234, 5678, TCP_FLAG_ACK)];
+        let features = PacketFeatures::from_stripped_packets(&key, &packets, &methods);
+        assert_eq!(features[0].direction, Unknown);
+    }
+
+    /// Tests that the endpoint using a well-known port is the server, unless both or neither do
+    #[test]
+    fn test_well_known_direction() {
+        let infer = |src_port, dst_port| {
+            DirectionInferenceMethod::WellKnown.infer(src_port, dst_port, None)
+        };
+        assert_eq!(infer(1024, 443), Some(FromClient));
+        assert_eq!(infer(443, 50000), Some(ToClient));
+        assert_eq!(infer(53, 443), None);
+        assert_eq!(infer(1024, 8080), None);
+        // Configured server ports are preferred, falling back to well-known ports
+        let methods = vec![
+            DirectionInferenceMethod::ServerPort(8443),
+            DirectionInferenceMethod::WellKnown,
+        ];
+        let infer_multiple = |src_port, dst_port| {
+            DirectionInferenceMethod::infer_multiple(src_port, dst_port, None, &methods)
+        };
+        assert_eq!(infer_multiple(80, 8443), FromClient);
+        assert_eq!(infer_multiple(80, 2000), ToClient);
+        assert_eq!(infer_multiple(2000, 3000), Unknown);
+    }
+
+    /// Tests that out of order and equal timestamps don't underflow the interarrival time
+    #[test]
+    fn test_interarrival_out_of_order() {
+        let key = test_key(443, 50000);
+        let packet = |timestamp, src_port, _dst_port| StrippedPacket {
+            payload_length: 100,
+            ..stripped_packet_at(src_port == 443, timestamp)
+        };
+        let packets = vec![
+            packet(1000, 50000, 443),
+            packet(1000, 443, 50000),
+            packet(900, 50000, 443),
+            packet(900, 443, 50000),
+            packet(900, 443, 50000),
+            packet(1500, 50000, 443),
+        ];
+        let methods = vec![DirectionInferenceMethod::ServerPort(443)];
+        let interarrival_times: Vec<u64> =
+            PacketFeatures::from_stripped_packets(&key, &packets, &methods)
+                .into_iter()
+                .map(|features| features.interarrival_time)
+                .collect();
+        assert_eq!(interarrival_times, vec![0, 0, 0, 0, 0, 600]);
+    }
+
+    /// Tests that interarrival times are converted from ns to ms
+    #[test]
+    fn test_interarrival_time_ms() {
+        let features = PacketFeatures {
+            interarrival_time: 2_500_000,
+            ..packet_features(FromClient, 0, 0)
+        };
+        assert_eq!(features.interarrival_time_ms(), 2.5);
+    }
+
+    /// Tests that uniformly sized flows have a lower coefficient of variation than variable ones
+    #[test]
+    fn test_payload_cv() {
+        let packet = |payload_length| packet_features(FromClient, payload_length, 0);
+        let uniform = generate(&[packet(500), packet(500), packet(500)]);
+        let variable = generate(&[packet(1), packet(1000), packet(20)]);
+        let uniform_cv = uniform.normalize().payload_cv_from_client;
+        let variable_cv = variable.normalize().payload_cv_from_client;
+        assert!(uniform_cv.abs() < 1e-9);
+        assert!(variable_cv > 1.0);
+        // Merging flows gives the same result as one flow with all of the packets
+        let merged = generate(&[packet(1), packet(1000)]) + generate(&[packet(20)]);
+        assert!((merged.normalize().payload_cv_from_client - variable_cv).abs() < 1e-9);
+        // Flows without payloads don't divide by zero
+        let empty = generate(&[packet(0), packet(0)]).normalize();
+        assert_eq!(empty.payload_cv_from_client, 0.0);
+        assert_eq!(empty.payload_cv_to_client, 0.0);
+    }
+
+    /// Tests that payload entropies are binned, ignoring packets without a payload
+    #[test]
+    fn test_entropy_bins() {
+        let packet = |payload_length, entropy| PacketFeatures {
+            entropy,
+            ..packet_features(FromClient, payload_length, 0)
+        };
+        let features = generate(&[packet(100, 0.5), packet(100, 7.5), packet(0, 0.0)])
+            + generate(&[packet(100, 8.0), packet(100, 7.9)]);
+        assert_eq!(features.entropy_freq_bins, vec![1, 0, 0, 0, 0, 0, 0, 3]);
+        let normalized = features.normalize();
+        assert_eq!(normalized.entropy_freq_bins[0], 0.25);
+        assert_eq!(normalized.entropy_freq_bins[7], 0.75);
+        // Flows without payloads don't divide by zero
+        let empty = generate(&[packet(0, 0.0)]).normalize();
+        assert_eq!(empty.entropy_freq_bins, vec![0.0; NUM_ENTROPY_BINS]);
+    }
+
+    /// Tests that the mean chi-square statistic covers the payloads of every merged flow
+    #[test]
+    fn test_mean_payload_chi_square() {
+        let packet = |payload_length, chi_square| PacketFeatures {
+            chi_square,
+            ..packet_features(FromClient, payload_length, 0)
+        };
+        let features = generate(&[packet(100, 300.0), packet(0, 0.0)])
+            + generate(&[packet(100, 100.0), packet(100, 200.0)]);
+        assert_eq!(features.normalize().mean_payload_chi_square, 200.0);
+        // Flows without payloads don't divide by zero
+        let empty = generate(&[packet(0, 0.0)]).normalize();
+        assert_eq!(empty.mean_payload_chi_square, 0.0);
+    }
+
+    /// Tests that payload prefixes are capped at the maximum length, including when merged
+    #[test]
+    fn test_payload_prefix() {
+        let generate_with_prefixes = |from_client: &[u8], to_client: &[u8]| {
+            FlowFeatures::generate(&[], &single_bins(), 4)
+                .with_payload_prefixes(from_client, to_client)
+        };
+        let features = generate_with_prefixes(&[0x16, 0x03, 0x01], &[0x16, 0x03, 0x03, 0xdd, 0xee]);
+        assert_eq!(features.payload_prefix_from_client, vec![0x16, 0x03, 0x01]);
+        assert_eq!(
+            features.payload_prefix_to_client,
+            vec![0x16, 0x03, 0x03, 0xdd]
+        );
+        // Merged flows are also capped
+        let features = (features + generate_with_prefixes(&[0xaa, 0xbb], &[0xff])).normalize();
+        assert_eq!(
+            features.payload_prefix_from_client,
+            Some("160301aa".to_string())
+        );
+        assert_eq!(
+            features.payload_prefix_to_client,
+            Some("160303dd".to_string())
+        );
+        // Prefixes are left out entirely unless asked for
+        let features = generate(&[]).with_payload_prefixes(&[0x16], &[0x16]);
+        assert_eq!(features.normalize().payload_prefix_from_client, None);
+    }
+
+    /// Tests that data packets and pure ACKs are counted per direction
+    #[test]
+    fn test_data_ack_ratio() {
+        let packet = |trans_protocol, direction, payload_length| PacketFeatures {
+            trans_protocol,
+            ..packet_features(direction, payload_length, 0)
+        };
+        let packets = vec![
+            packet(TRANS_PROTOCOL_TCP, FromClient, 100),
+            packet(TRANS_PROTOCOL_TCP, ToClient, 1400),
+            packet(TRANS_PROTOCOL_TCP, ToClient, 1400),
+            packet(TRANS_PROTOCOL_TCP, ToClient, 1400),
+            packet(TRANS_PROTOCOL_TCP, FromClient, 0),
+            packet(TRANS_PROTOCOL_TCP, FromClient, 0),
+            packet(TRANS_PROTOCOL_TCP, FromClient, 0),
+            packet(TRANS_PROTOCOL_TCP, FromClient, 0),
+            packet(TRANS_PROTOCOL_TCP, ToClient, 0),
+            // Empty UDP datagrams aren't acknowledgments
+            packet(17, ToClient, 0),
+        ];
+        let features = generate(&packets);
+        assert_eq!(features.data_packets_from_client, 1);
+        assert_eq!(features.pure_acks_from_client, 4);
+        assert_eq!(features.data_packets_to_client, 3);
+        assert_eq!(features.pure_acks_to_client, 1);
+        let features = features.normalize();
+        assert_eq!(features.data_ack_ratio_from_client, 0.25);
+        assert_eq!(features.data_ack_ratio_to_client, 3.0);
+    }
+
+    /// Tests that packet and byte totals are counted per direction, summed across flows, and
+    /// left unnormalized
+    #[test]
+    fn test_totals() {
+        let packet = |direction, payload_length| packet_features(direction, payload_length, 0);
+        let first = generate(&[
+            packet(FromClient, 100),
+            packet(ToClient, 1400),
+            packet(ToClient, 0),
+            // Packets without a direction are only counted as unknown
+            packet(Unknown, 500),
+        ]);
+        assert_eq!(first.total_packets_from_client, 1);
+        assert_eq!(first.total_packets_to_client, 2);
+        assert_eq!(first.total_bytes_from_client, 100);
+        assert_eq!(first.total_bytes_to_client, 1400);
+        assert_eq!(first.unknown_direction_packets, 1);
+        let second = generate(&[packet(FromClient, 200), packet(FromClient, 0)]);
+        let features = (first + second).normalize();
+        assert_eq!(features.total_packets_from_client, 3);
+        assert_eq!(features.total_packets_to_client, 2);
+        assert_eq!(features.unknown_direction_packets, 1);
+        assert_eq!(features.total_bytes_from_client, 300);
+        assert_eq!(features.total_bytes_to_client, 1400);
+    }
+
+    /// Tests that peak throughput is measured over the densest window of packets
+    #[test]
+    fn test_peak_window_bytes() {
+        let ms: u64 = 1_000_000;
+        let packet =
+            |timestamp, payload_length| packet_features(ToClient, payload_length, timestamp);
+        // A steady trickle, with one dense burst of 4 large packets within 10ms
+        let mut packets: Vec<PacketFeatures> = (0..10).map(|t| packet(t * 100 * ms, 100)).collect();
+        packets.extend((0..4).map(|t| packet(1050 * ms + t * 3 * ms, 1400)));
+        packets.extend((11..20).map(|t| packet(t * 100 * ms, 100)));
+        let generate = |throughput_window| {
+            let bins = BinConfig {
+                throughput_window,
+                ..single_bins()
+            };
+            FlowFeatures::generate(&packets, &bins, 0)
+        };
+        assert_eq!(generate(10 * ms).peak_window_bytes, 4 * 1400);
+        // A wider window also takes in the next packet of the trickle
+        assert_eq!(generate(101 * ms).peak_window_bytes, 4 * 1400 + 100);
+        // Nothing fits in an empty window
+        assert_eq!(generate(0).peak_window_bytes, 0);
+        // Combined flows keep the highest peak
+        let features = generate(10 * ms) + FlowFeatures::empty(1, 1, 1, 1, 0);
+        assert_eq!(features.normalize().peak_window_bytes, 4 * 1400);
+    }
+
+    /// Tests that duration spans the packets of a flow, and of the flows combined
+    #[test]
+    fn test_duration() {
+        let packet = |timestamp| packet_features(FromClient, 100, timestamp);
+        assert_eq!(generate(&[]).normalize().duration, 0);
+        assert_eq!(generate(&[packet(1000)]).normalize().duration, 0);
+        assert_eq!(
+            generate(&[packet(1000), packet(4000)]).normalize().duration,
+            3000
+        );
+        let first = generate(&[packet(1000), packet(1500), packet(4000)]);
+        let second = generate(&[packet(2000), packet(6000)]);
+        // Overlapping flows span from the earliest packet to the latest
+        let empty = FlowFeatures::empty(1, 1, 1, 1, 0);
+        assert_eq!((empty + first + second).normalize().duration, 5000);
+    }
+
+    /// Tests that summary statistics are calculated per direction, and merge exactly
+    #[test]
+    fn test_summary_statistics() {
+        let packet = |direction, payload_length, interarrival_time| PacketFeatures {
+            interarrival_time,
+            ..packet_features(direction, payload_length, 0)
+        };
+        let first = generate(&[
+            packet(FromClient, 100, 0),
+            packet(ToClient, 1000, 0),
+            packet(FromClient, 300, 20_000_000_000),
+        ]);
+        let second = generate(&[
+            packet(FromClient, 200, 10_000_000_000),
+            packet(Unknown, 5, 0),
+        ]);
+        let features = (first + second).normalize();
+        let stats = &features.payload_length_stats_from_client;
+        assert_eq!(stats.mean, 200.0);
+        assert!((stats.variance - 20000.0 / 3.0).abs() < 1e-9);
+        assert_eq!((stats.min, stats.max, stats.count), (100, 300, 3));
+        assert_eq!(
+            features.payload_length_stats_to_client,
+            SummaryStatistics {
+                mean: 1000.0,
+                variance: 0.0,
+                min: 1000,
+                max: 1000,
+                count: 1,
+            }
+        );
+        // The first packet in each direction of each flow has no interarrival time
+        let stats = &features.interarrival_stats_from_client;
+        assert_eq!(
+            (stats.mean, stats.min, stats.count),
+            (20_000_000_000.0, 20_000_000_000, 1)
+        );
+        assert_eq!(features.interarrival_stats_to_client.count, 0);
+        // Large interarrival times don't overflow when squared
+        let features = generate(&[
+            packet(FromClient, 0, 0),
+            packet(FromClient, 0, 10_000_000_000),
+            packet(FromClient, 0, 30_000_000_000),
+        ])
+        .normalize();
+        let stats = features.interarrival_stats_from_client;
+        assert_eq!(stats.mean, 20_000_000_000.0);
+        assert!((stats.variance / 1e20 - 1.0).abs() < 1e-9);
+        assert_eq!((stats.min, stats.max), (10_000_000_000, 30_000_000_000));
+        // Directions without packets are all zeroes
+        assert_eq!(
+            features.interarrival_stats_to_client,
+            SummaryStatistics {
+                mean: 0.0,
+                variance: 0.0,
+                min: 0,
+                max: 0,
+                count: 0,
+            }
+        );
+    }
+
+    /// Tests that connection features sum volumes and count states and history entries
+    #[test]
+    fn test_connection_features() {
+        let connection = |conn_state, history: &str, duration| Connection {
+            duration,
+            orig_bytes: Some(100),
+            resp_bytes: Some(1000),
+            orig_pkts: Some(5),
+            resp_pkts: Some(4),
+            conn_state: Some(conn_state),
+            history: history.to_string(),
+            ..connection_at("C1", 50000, 1.0)
+        };
+        let features = ConnectionFeatures::from_connections(&[
+            connection(ConnState::SF, "ShADadFf", 1_500_000_000),
+            connection(ConnState::S0, "S", 500_000_000),
+        ]);
+        assert_eq!(features.num_connections, 2);
+        assert_eq!((features.orig_bytes, features.resp_bytes), (200, 2000));
+        assert_eq!((features.orig_packets, features.resp_packets), (10, 8));
+        assert_eq!(features.duration, 1_500_000_000);
+        // Only the first connection completed its handshake and sent data both ways
+        assert_eq!(features.num_handshakes, 1);
+        assert_eq!(features.num_data_both_ways, 1);
+        assert_eq!(features.num_resets, 0);
+        assert_eq!(features.conn_state_freq.len(), ConnState::COUNT);
+        assert_eq!(features.conn_state_freq[ConnState::S0 as usize], 0.5);
+        assert_eq!(features.conn_state_freq[ConnState::SF as usize], 0.5);
+        assert_eq!(features.history_freq_from_orig.len(), HistoryEntry::COUNT);
+        // The originators sent S, A, D, F, and S
+        assert_eq!(
+            features.history_freq_from_orig[HistoryEntry::Syn as usize],
+            0.4
+        );
+        // The responder sent h, a, d, and f
+        assert_eq!(
+            features.history_freq_from_resp[HistoryEntry::Handshake as usize],
+            0.25
+        );
+        assert_eq!(
+            features.history_freq_from_resp[HistoryEntry::Syn as usize],
+            0.0
+        );
     }
 }
@@ -14,25 +14,47 @@
 // You should have received a copy of the GNU General Public License
 // along with packet_captor_sakura.  If not, see <https:// www.gnu.org/licenses/>.
 
-use crate::bro_types::Connection;
+use crate::bro_types::{ConnState, Connection};
+use crate::features::extend_prefix;
 use crate::packet::{Packet, StrippedPacket};
 use itertools::Itertools;
 use log::warn;
-use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::net::IpAddr;
 
+/// Packets associated with a single flow
+pub struct Flow {
+    /// Identifies the flow's endpoints and transport protocol
+    pub key: PacketKey,
+    /// State zeek logged for the flow's connection, if it logged one
+    pub conn_state: Option<ConnState>,
+    /// Packets of the flow, stripped of the fields in the key
+    pub packets: Vec<StrippedPacket>,
+    /// First bytes of the payloads sent by endpoint a of the key, concatenated in the order
+    /// they were loaded
+    pub payload_prefix_a: Vec<u8>,
+    /// First bytes of the payloads sent by endpoint b of the key, concatenated in the order
+    /// they were loaded
+    pub payload_prefix_b: Vec<u8>,
+}
+
 /// Associates packets with flows
 pub struct FlowAggregator {
-    /// The main data structure is a mapping of ID to a set of packets
-    // TODO: Use something that strips the packet of fields contained in the packetkey
-    data: HashMap<String, Vec<StrippedPacket>>,
+    /// The main data structure is a mapping of ID to a flow
+    data: HashMap<String, Flow>,
     /// This is used to efficiently associate packets with flows
-    connection_map: HashMap<PacketKey, Vec<FlowPeriod>>,
+    connection_map: HashMap<PacketKey, FlowPeriods>,
     /// Time (in ns) to allow a packet with a pre-flow timestamp tp be associated with a flow
     grace_period_before: u64,
     /// Time (in ns) to allow a packet with a post-flow timestamp tp be associated with a flow
     grace_period_after: u64,
+    /// Number of packets loaded
+    num_packets: usize,
+    /// Number of packets that fell within the time period (including grace periods) of more
+    /// than one flow
+    num_ambiguous_packets: usize,
+    /// Maximum number of bytes of payload kept from each endpoint of a flow
+    payload_prefix_length: usize,
 }
 
 impl FlowAggregator {
@@ -41,9 +63,9 @@ impl FlowAggregator {
     /// # Parameters
     /// * `connections` - Set of connections to associate future packets with
     /// * `grace_period_before` - Time (in ns) to allow a packet with a pre-flow timestamp to be
-    ///                           associated with a flow
+    ///   associated with a flow
     /// * `grace_period_after` - Time (in ns) to allow a packet with a post-flow timestamp to be
-    ///                          associated with a flow
+    ///   associated with a flow
     pub fn new(
         connections: impl Iterator<Item = Connection>,
         grace_period_before: u64,
@@ -59,125 +81,73 @@ impl FlowAggregator {
                 // Return the key and time period
                 (key, period)
             })
-            .into_group_map();
-        // TODO: determine if we care about connections that don't map to any packets
-
+            .into_group_map()
+            .into_iter()
+            .map(|(key, periods)| (key, FlowPeriods::new(periods)))
+            .collect();
         FlowAggregator {
             data: HashMap::new(),
             connection_map,
             grace_period_before,
             grace_period_after,
+            num_packets: 0,
+            num_ambiguous_packets: 0,
+            payload_prefix_length: 0,
         }
     }
 
+    /// Keeps the first bytes of payload sent by each endpoint of each flow, as many as are
+    /// carried by the loaded packets up to the given length
+    pub fn with_payload_prefix_length(mut self, payload_prefix_length: usize) -> Self {
+        self.payload_prefix_length = payload_prefix_length;
+        self
+    }
+
     /// Loads packets into the aggregator
     ///
     /// # Parameters
     /// * `packets` - the packets to aggregate
-    pub fn load_packets(&mut self, packets: Vec<Packet>) {
-        // Represents the time relationship between the packet and the flow, if the packet did not
-        // happen during the flow
-        // The contained value is the absolute value of the time difference between the packet and
-        // the flow
-        #[derive(Copy, Clone, Eq, PartialEq, PartialOrd)]
-        enum TimeDifference {
-            After(u64),
-            Before(u64),
-        }
-        impl Ord for TimeDifference {
-            /// Compares two timedifferences
-            fn cmp(&self, other: &TimeDifference) -> Ordering {
-                use TimeDifference::*;
-                match (self, other) {
-                    // If the packet is after both flows, prefer the one it is closer to
-                    (After(ref my_delta), After(ref their_delta)) => my_delta.cmp(&their_delta),
-                    // If the packet is before both flows, prefer the one it is closer to
-                    (Before(ref my_delta), Before(ref their_delta)) => my_delta.cmp(&their_delta),
-                    // If the packet is after this flow and before the other, prefer this flow
-                    (After(_), Before(_)) => Ordering::Less,
-                    // If the packet is before this flow and after the other, prefer the other flow
-                    (Before(_), After(_)) => Ordering::Greater,
-                }
-            }
-        }
-        // Used to keep track of potential flow matches
-        struct FlowPossibility {
-            // ID of the potential flow
-            id: String,
-            // Relationship between the packet's time and the flow period
-            time_difference: Option<TimeDifference>,
-        }
+    pub fn load_packets(&mut self, packets: impl IntoIterator<Item = Packet>) {
         // For each packet
         // TODO: parallelize this with par_iter
         // TODO: connection_map must be readable by many threads
         // TODO: mutex lock data
         for packet in packets {
+            self.num_packets += 1;
             // Get identifiable information from the packet
             let key = PacketKey::from(&packet);
             // Search the connection list for connections with a matching identifier
             if let Some(periods) = self.connection_map.get(&key) {
-                let flow_id = periods
-                    .iter()
-                    // Iterate over the possible periods. The result will either be a single
-                    // possibility (Err is used for this, but it is not an error) or a set of
-                    // possibilities. The single possibility implies that a perfect match was found,
-                    // while a set of possibilities will be returned if a perfect match is not found
-                    .try_fold(Vec::new(), |mut possibilities, period| {
-                        // If the packet's time period matches perfectly
-                        if packet.timestamp >= period.start && packet.timestamp <= period.end {
-                            // Exit the iteration early with an "Error"
-                            // TODO: create a type with a more accurate name
-                            Err(FlowPossibility {
-                                id: period.id.clone(),
-                                time_difference: None,
-                            })
-                        }
-                        // If the packet occured after the time period (within the grace period)
-                        else if packet.timestamp < period.end + self.grace_period_after {
-                            possibilities.push(FlowPossibility {
-                                id: period.id.clone(),
-                                time_difference: Some(TimeDifference::After(
-                                    (period.end + self.grace_period_after) - packet.timestamp,
-                                )),
-                            });
-                            Ok(possibilities)
-                        }
-                        // If the packet occured before the period (within the grace period)
-                        else if packet.timestamp + self.grace_period_before > period.start {
-                            possibilities.push(FlowPossibility {
-                                id: period.id.clone(),
-                                time_difference: Some(TimeDifference::Before(
-                                    (packet.timestamp + self.grace_period_before) - period.start,
-                                )),
-                            });
-                            Ok(possibilities)
-                        } else {
-                            Ok(possibilities)
-                        }
-                    })
-                    // This function is only run in the case that a set of possibilities occurs
-                    .map(|mut possibilities: Vec<FlowPossibility>| {
-                        // Sort the possibilities
-                        // This sort should put more suitable possibilities first in the array
-                        possibilities
-                            .sort_unstable_by_key(|possibility| possibility.time_difference);
-                        // Get the first result
-                        // If the set is empty, this will be None
-                        possibilities
-                            .first()
-                            .map(|possibility| possibility.id.clone())
-                    })
-                    // This function is run if the iteration short-circuited and we got a perfect
-                    // match. All we do here is extract the ID and map it to Option<String> to
-                    // have the same type as we get when we have to check possibilities
-                    .unwrap_or_else(|possibility: FlowPossibility| Some(possibility.id));
-
-                if let Some(flow_id) = flow_id {
+                let (period, num_candidates) = periods.find(
+                    packet.timestamp,
+                    self.grace_period_before,
+                    self.grace_period_after,
+                );
+                // Count packets that could belong to more than one flow
+                if num_candidates > 1 {
+                    self.num_ambiguous_packets += 1;
+                }
+                if let Some(period) = period {
                     // Insert it
-                    self.data
-                        .entry(flow_id)
-                        .or_insert_with(|| vec![])
-                        .push(packet.strip());
+                    let flow = self.data.entry(period.id.clone()).or_insert_with(|| Flow {
+                        key,
+                        conn_state: period.conn_state,
+                        packets: vec![],
+                        payload_prefix_a: Vec::new(),
+                        payload_prefix_b: Vec::new(),
+                    });
+                    // Keep the start of the payload until the flow's prefix is full
+                    let payload_prefix = if key.is_sent_by_a(packet.src_ip, packet.src_port) {
+                        &mut flow.payload_prefix_a
+                    } else {
+                        &mut flow.payload_prefix_b
+                    };
+                    extend_prefix(
+                        payload_prefix,
+                        &packet.payload_prefix,
+                        self.payload_prefix_length,
+                    );
+                    flow.packets.push(packet.strip(&key));
                 } else {
                     warn!(
                         "Failed to find a connection that matches the timestamp of: {:?}",
@@ -189,12 +159,50 @@ impl FlowAggregator {
             }
         }
         // Sort packets
-        for (_, packets) in &mut self.data {
-            packets.sort_unstable_by_key(|packet| packet.timestamp)
+        for (_, flow) in &mut self.data {
+            flow.packets.sort_unstable_by_key(|packet| packet.timestamp)
         }
     }
+    /// Finds the ID of the flow a packet belongs to, without loading it
+    ///
+    /// Packets are matched to flows with the same endpoints and transport protocol, choosing
+    /// between them by time as `load_packets` does. Returns `None` if no flow matches
+    ///
+    /// # Parameters
+    /// * `packet` - the packet to classify
+    pub fn classify(&self, packet: &Packet) -> Option<String> {
+        let periods = self.connection_map.get(&PacketKey::from(packet))?;
+        let (period, _) = periods.find(
+            packet.timestamp,
+            self.grace_period_before,
+            self.grace_period_after,
+        );
+        period.map(|period| period.id.clone())
+    }
+    /// Gets the number of packets loaded
+    pub fn num_packets(&self) -> usize {
+        self.num_packets
+    }
+    /// Gets the number of packets that fell within the time period (including grace periods) of
+    /// more than one flow. These are assigned to the closest flow, but may belong to another
+    pub fn num_ambiguous_packets(&self) -> usize {
+        self.num_ambiguous_packets
+    }
+    /// Gets the IDs of connections that no packets were associated with
+    ///
+    /// These usually mean the pcap and connection log don't fully correspond, such as when
+    /// packets were cut short by the snaplen or missed by the capture
+    pub fn unmatched_connections(&self) -> Vec<String> {
+        self.connection_map
+            .values()
+            .flat_map(|periods| periods.periods.iter().map(|(_, period)| period))
+            .filter(|period| !self.data.contains_key(&period.id))
+            .map(|period| period.id.clone())
+            .sorted()
+            .collect()
+    }
     /// Consumes the aggregator and returns aggregated flows
-    pub fn into_aggregated_flows(self) -> HashMap<String, Vec<StrippedPacket>> {
+    pub fn into_aggregated_flows(self) -> HashMap<String, Flow> {
         self.data
     }
 }
@@ -202,7 +210,7 @@ impl FlowAggregator {
 /// Identifies a packet. This serves as a primary key capable of associating a packet with a flow
 ///
 /// Timestamp is not included, and is determined later
-#[derive(Debug, Eq, Hash, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct PacketKey {
     ip_a: IpAddr,
     ip_b: IpAddr,
@@ -233,6 +241,32 @@ impl PacketKey {
             port_b,
         }
     }
+
+    /// Whether a packet with the given source was sent by endpoint a
+    ///
+    /// # Parameters
+    /// * `src_ip` - Source IP address of the packet
+    /// * `src_port` - Source port of the packet
+    pub fn is_sent_by_a(&self, src_ip: IpAddr, src_port: u16) -> bool {
+        src_ip == self.ip_a && src_port == self.port_a
+    }
+
+    /// Gets the source and destination ports of a packet
+    ///
+    /// # Parameters
+    /// * `sent_by_a` - Whether the packet was sent by endpoint a
+    pub fn ports(&self, sent_by_a: bool) -> (u16, u16) {
+        if sent_by_a {
+            (self.port_a, self.port_b)
+        } else {
+            (self.port_b, self.port_a)
+        }
+    }
+
+    /// Gets the transport protocol
+    pub fn trans_protocol(&self) -> u8 {
+        self.trans_protocol
+    }
 }
 
 impl<'a> From<&'a Packet> for PacketKey {
@@ -268,6 +302,7 @@ struct FlowPeriod {
     start: u64,
     end: u64,
     pub id: String,
+    conn_state: Option<ConnState>,
 }
 
 impl<'a> From<&'a Connection> for FlowPeriod {
@@ -277,6 +312,351 @@ impl<'a> From<&'a Connection> for FlowPeriod {
             start: connection.timestamp,
             end: connection.timestamp + connection.duration,
             id: connection.uid.clone(),
+            conn_state: connection.conn_state,
+        }
+    }
+}
+
+/// Time periods of the flows sharing a `PacketKey`, indexed by start time so the flows a packet
+/// could belong to are found without scanning every period
+struct FlowPeriods {
+    /// Periods in order of start time, each with the position of its connection in the
+    /// connection log. Periods starting at the same time stay in the order they were logged
+    periods: Vec<(usize, FlowPeriod)>,
+    /// Longest duration (in ns) of any period. A period can't contain a time unless it starts
+    /// at most this long before it
+    max_duration: u64,
+    /// For each position in `periods`, the position of the period ending first from there on
+    earliest_end_from: Vec<usize>,
+}
+
+impl FlowPeriods {
+    /// Indexes the periods of flows sharing a `PacketKey`
+    ///
+    /// # Parameters
+    /// * `periods` - Periods in the order their connections were logged
+    fn new(periods: Vec<FlowPeriod>) -> Self {
+        let mut periods: Vec<(usize, FlowPeriod)> = periods.into_iter().enumerate().collect();
+        periods.sort_by_key(|(_, period)| period.start);
+        let max_duration = periods
+            .iter()
+            .map(|(_, period)| period.end - period.start)
+            .max()
+            .unwrap_or(0);
+        // Ties in end time go to the first logged period
+        let end_key = |position: usize| (periods[position].1.end, periods[position].0);
+        let mut earliest_end_from = vec![0; periods.len()];
+        for position in (0..periods.len()).rev() {
+            earliest_end_from[position] = match earliest_end_from.get(position + 1) {
+                Some(&later) if end_key(later) < end_key(position) => later,
+                _ => position,
+            };
+        }
+        FlowPeriods {
+            periods,
+            max_duration,
+            earliest_end_from,
+        }
+    }
+
+    /// Finds the flow a packet belongs to, and the number of flows whose time periods
+    /// (including grace periods) the packet falls within
+    ///
+    /// A packet belongs to the first logged flow whose period contains it. Otherwise, it
+    /// belongs to the flow ending first among those that haven't ended a grace period before
+    /// the packet. If every flow ended earlier than that, it belongs to the flow starting last.
+    /// Remaining ties go to the first logged flow
+    ///
+    /// # Parameters
+    /// * `timestamp` - Time (in ns) of the packet
+    /// * `grace_period_before` - Time (in ns) before a flow during which the packet may be
+    ///   associated with it
+    /// * `grace_period_after` - Time (in ns) after a flow during which the packet may be
+    ///   associated with it
+    fn find(
+        &self,
+        timestamp: u64,
+        grace_period_before: u64,
+        grace_period_after: u64,
+    ) -> (Option<&FlowPeriod>, usize) {
+        // Only periods starting within this window can contain the packet, or have it within
+        // their grace periods
+        let window_start = timestamp.saturating_sub(grace_period_after + self.max_duration);
+        let window_end = timestamp + grace_period_before;
+        let from = self
+            .periods
+            .partition_point(|(_, period)| period.start < window_start);
+        let to = self
+            .periods
+            .partition_point(|(_, period)| period.start <= window_end);
+        let window = &self.periods[from..to];
+        let num_candidates = window
+            .iter()
+            .filter(|(_, period)| timestamp <= period.end + grace_period_after)
+            .count();
+        // Prefer the first logged period the packet happened during
+        let containing = window
+            .iter()
+            .filter(|(_, period)| timestamp >= period.start && timestamp <= period.end)
+            .min_by_key(|(order, _)| *order);
+        if let Some((_, period)) = containing {
+            return (Some(period), num_candidates);
+        }
+        // Otherwise prefer the period ending first, among those the packet isn't after. These
+        // start within the window, or after it
+        let unfinished = window
+            .iter()
+            .filter(|(_, period)| timestamp < period.end + grace_period_after)
+            .chain(
+                self.earliest_end_from
+                    .get(to)
+                    .map(|&later| &self.periods[later]),
+            )
+            .min_by_key(|(order, period)| (period.end, *order));
+        if let Some((_, period)) = unfinished {
+            return (Some(period), num_candidates);
+        }
+        // Otherwise every period has ended, so prefer the one starting last
+        let latest_start = self.periods.last().map(|(_, period)| period.start);
+        let latest = latest_start.map(|latest_start| {
+            let position = self
+                .periods
+                .partition_point(|(_, period)| period.start < latest_start);
+            &self.periods[position].1
+        });
+        (latest, num_candidates)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bro_types::tests::connection_at;
+    use crate::packet::tests::{packet_at, reply};
+
+    /// Creates a TCP connection from 10.0.0.1 to port 443 on 10.0.0.2, lasting from 1s to 2s
+    fn connection(uid: &str, orig_port: u16) -> Connection {
+        connection_at(uid, orig_port, 1.0)
+    }
+
+    /// Creates a packet from 10.0.0.1 to port 443 on 10.0.0.2 at 1.5s
+    fn packet(src_port: u16) -> Packet {
+        packet_at(src_port, 1_500_000_000)
+    }
+
+    /// Tests that connections without any packets are reported
+    #[test]
+    fn test_unmatched_connections() {
+        let connections = vec![
+            connection("C1", 50000),
+            connection("C2", 50001),
+            connection("C3", 50002),
+        ];
+        let mut flow_aggregator = FlowAggregator::new(connections.into_iter(), 0, 0);
+        assert_eq!(
+            flow_aggregator.unmatched_connections(),
+            vec!["C1", "C2", "C3"]
+        );
+        flow_aggregator.load_packets(vec![packet(50001), packet(50001)]);
+        assert_eq!(flow_aggregator.unmatched_connections(), vec!["C1", "C3"]);
+    }
+
+    /// Tests that the start of each endpoint's payloads is kept, up to the prefix length
+    #[test]
+    fn test_payload_prefix() {
+        let packet = |from_client, timestamp, payload_prefix: &[u8]| {
+            let packet = Packet {
+                payload_prefix: payload_prefix.to_vec(),
+                payload_length: payload_prefix.len(),
+                ..packet_at(50000, timestamp)
+            };
+            if from_client {
+                packet
+            } else {
+                reply(packet)
+            }
+        };
+        let connections = vec![connection("C1", 50000)];
+        let mut flow_aggregator =
+            FlowAggregator::new(connections.into_iter(), 0, 0).with_payload_prefix_length(4);
+        flow_aggregator.load_packets(vec![
+            packet(true, 1_100_000_000, &[0x16, 0x03, 0x01]),
+            packet(false, 1_200_000_000, &[0x16, 0x03, 0x03]),
+            packet(true, 1_300_000_000, &[0xaa, 0xbb, 0xcc]),
+            packet(false, 1_400_000_000, &[0xdd]),
+            packet(false, 1_500_000_000, &[0xee]),
+        ]);
+        let flows = flow_aggregator.into_aggregated_flows();
+        // The server's port is lower, so it's endpoint a
+        assert_eq!(flows["C1"].payload_prefix_a, vec![0x16, 0x03, 0x03, 0xdd]);
+        assert_eq!(flows["C1"].payload_prefix_b, vec![0x16, 0x03, 0x01, 0xaa]);
+        // Nothing is kept unless asked for
+        let connections = vec![connection("C1", 50000)];
+        let mut flow_aggregator = FlowAggregator::new(connections.into_iter(), 0, 0);
+        flow_aggregator.load_packets(vec![packet(true, 1_100_000_000, &[0x16, 0x03, 0x01])]);
+        assert!(flow_aggregator.into_aggregated_flows()["C1"]
+            .payload_prefix_b
+            .is_empty());
+    }
+
+    /// Tests that packets within the grace periods of two flows are counted as ambiguous
+    #[test]
+    fn test_ambiguous_packets() {
+        let s: u64 = 1_000_000_000;
+        // The flows run from 1s to 2s and 3s to 4s, with a gap between them
+        let connections = || {
+            vec![
+                connection_at("C1", 50000, 1.0),
+                connection_at("C2", 50000, 3.0),
+            ]
+        };
+        let packets = || {
+            vec![
+                packet_at(50000, 1_500_000_000),
+                packet_at(50000, 2_500_000_000),
+                packet_at(50000, 3_500_000_000),
+            ]
+        };
+        // Without grace periods, the packet in the gap belongs to neither flow
+        let mut flow_aggregator = FlowAggregator::new(connections().into_iter(), 0, 0);
+        flow_aggregator.load_packets(packets());
+        assert_eq!(flow_aggregator.num_packets(), 3);
+        assert_eq!(flow_aggregator.num_ambiguous_packets(), 0);
+        // With overlapping grace periods, it could belong to either
+        let mut flow_aggregator = FlowAggregator::new(connections().into_iter(), s, s);
+        flow_aggregator.load_packets(packets());
+        assert_eq!(flow_aggregator.num_packets(), 3);
+        assert_eq!(flow_aggregator.num_ambiguous_packets(), 1);
+        // It's still assigned to exactly one of them
+        let flows = flow_aggregator.into_aggregated_flows();
+        assert_eq!(flows["C1"].packets.len() + flows["C2"].packets.len(), 3);
+    }
+
+    /// Tests that packets are matched to the flow whose period, including grace periods, they
+    /// fall in, or else the closer flow
+    #[test]
+    fn test_classify() {
+        let ms: u64 = 1_000_000;
+        // The flows run from 1s to 2s and 5s to 6s
+        let connections = vec![
+            connection_at("C1", 50000, 1.0),
+            connection_at("C2", 50000, 5.0),
+        ];
+        let flow_aggregator = FlowAggregator::new(connections.into_iter(), 500 * ms, 500 * ms);
+        let classify =
+            |src_port, timestamp| flow_aggregator.classify(&packet_at(src_port, timestamp * ms));
+        // Inside a period
+        assert_eq!(classify(50000, 1500), Some("C1".to_string()));
+        assert_eq!(classify(50000, 6000), Some("C2".to_string()));
+        // Within the grace period after a flow
+        assert_eq!(classify(50000, 2300), Some("C1".to_string()));
+        // Within the grace period before a flow
+        assert_eq!(classify(50000, 4700), Some("C2".to_string()));
+        // Outside both, the closer flow wins
+        assert_eq!(classify(50000, 3800), Some("C2".to_string()));
+        assert_eq!(classify(50000, 7000), Some("C2".to_string()));
+        // Packets from the server match the same flow
+        let response = reply(packet_at(50000, 1500 * ms));
+        assert_eq!(flow_aggregator.classify(&response), Some("C1".to_string()));
+        // Packets between other endpoints don't match any flow
+        assert_eq!(classify(50001, 1500), None);
+        // Classifying doesn't load the packet
+        assert_eq!(flow_aggregator.num_packets(), 0);
+    }
+
+    /// Tests that packets tied between flows go to the first logged one, whatever their uids
+    #[test]
+    fn test_tie_break() {
+        let ms: u64 = 1_000_000;
+        for &uids in &[["C1", "C2"], ["C2", "C1"]] {
+            // Both flows run from 1s to 2s
+            let connections = uids.iter().map(|uid| connection_at(uid, 50000, 1.0));
+            let flow_aggregator = FlowAggregator::new(connections, 500 * ms, 500 * ms);
+            for &timestamp in &[700, 1500, 2300] {
+                assert_eq!(
+                    flow_aggregator.classify(&packet_at(50000, timestamp * ms)),
+                    Some(uids[0].to_string())
+                );
+            }
+        }
+    }
+
+    /// Finds the flow a packet belongs to by scanning every period, in the order they were
+    /// logged
+    fn find_linear(
+        periods: &[FlowPeriod],
+        timestamp: u64,
+        grace_period_before: u64,
+        grace_period_after: u64,
+    ) -> (Option<String>, usize) {
+        let num_candidates = periods
+            .iter()
+            .filter(|period| {
+                timestamp + grace_period_before >= period.start
+                    && timestamp <= period.end + grace_period_after
+            })
+            .count();
+        if let Some(period) = periods
+            .iter()
+            .find(|period| timestamp >= period.start && timestamp <= period.end)
+        {
+            return (Some(period.id.clone()), num_candidates);
+        }
+        // Packets after a period's grace period sort after every other packet
+        let mut possibilities: Vec<(bool, u64, &FlowPeriod)> = periods
+            .iter()
+            .map(|period| {
+                if timestamp < period.end + grace_period_after {
+                    (false, period.end + grace_period_after - timestamp, period)
+                } else {
+                    (true, timestamp + grace_period_before - period.start, period)
+                }
+            })
+            .collect();
+        possibilities.sort_by_key(|(is_before, delta, _)| (*is_before, *delta));
+        let flow_id = possibilities
+            .first()
+            .map(|(_, _, period)| period.id.clone());
+        (flow_id, num_candidates)
+    }
+
+    /// Tests that the indexed lookup assigns packets exactly as scanning every period does
+    #[test]
+    fn test_find_matches_linear_scan() {
+        // Generate overlapping periods of varying lengths, logged out of order
+        let mut state: u64 = 12345;
+        let mut next = move |max: u64| {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            (state >> 33) % max
+        };
+        let periods: Vec<FlowPeriod> = (0..2000)
+            .map(|index| {
+                let start = next(1_000_000);
+                FlowPeriod {
+                    start,
+                    end: start + next(2000),
+                    id: format!("C{}", index),
+                    conn_state: None,
+                }
+            })
+            .collect();
+        let indexed = FlowPeriods::new(periods.clone());
+        for &(before, after) in &[(0, 0), (100, 500), (5000, 5000)] {
+            for _ in 0..2000 {
+                let timestamp = next(1_010_000);
+                let (period, num_candidates) = indexed.find(timestamp, before, after);
+                let period = period.map(|period| period.id.clone());
+                assert_eq!(
+                    (period, num_candidates),
+                    find_linear(&periods, timestamp, before, after),
+                    "timestamp {} with grace periods {} and {}",
+                    timestamp,
+                    before,
+                    after
+                );
+            }
         }
     }
 }
@@ -14,19 +14,12 @@
 // You should have received a copy of the GNU General Public License
 // along with packet_captor_sakura.  If not, see <https:// www.gnu.org/licenses/>.
 
-mod bro_types;
-mod dataset;
-mod entropy;
-mod features;
-mod flow_aggregator;
-mod packet;
-mod pcap;
-
-use crate::dataset::*;
 use clap::{App, Arg};
+use data_generator::config::GeneratorConfig;
+use data_generator::dataset::*;
 use failure::{format_err, Error};
 use log::{error, info};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 fn run() -> Result<(), Error> {
     // Start the logger
@@ -50,6 +43,68 @@ fn run() -> Result<(), Error> {
                 .required(true)
                 .index(2),
         )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .value_name("CONFIG_FILE")
+                .help("Path to a TOML file with options for feature generation")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("compression")
+                .long("compression")
+                .value_name("FORMAT")
+                .help("Compression used for the output files, overriding the config")
+                .possible_values(&["gzip", "zstd", "none"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .value_name("FORMAT")
+                .help("Format of the output files, overriding the config")
+                .possible_values(&["json", "csv", "bincode"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sort_flows")
+                .long("sort-flows")
+                .help("Sort the flows of each class by URL, so reruns write identical output"),
+        )
+        .arg(
+            Arg::with_name("split")
+                .long("split")
+                .value_name("TRAIN,VAL,TEST")
+                .help("Fractions of the URLs to save to train/, val/, and test/ subdirectories")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("split_seed")
+                .long("split-seed")
+                .value_name("SEED")
+                .help("Seed used to assign URLs to the train, validation, and test sets")
+                .default_value("0")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("pcap_root")
+                .long("pcap-root")
+                .value_name("PCAP_ROOT")
+                .help("Directory the pcaps in the report are relative to, overriding the config")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("conn_only")
+                .long("conn-only")
+                .help("Generate features from the connection log alone, skipping the packets"),
+        )
+        .arg(
+            Arg::with_name("status_file")
+                .long("status-file")
+                .value_name("STATUS_FILE")
+                .help("Path to periodically write progress to, as JSON")
+                .takes_value(true),
+        )
         .get_matches();
     // Get the data directory path
     let data_dir: &Path = Path::new(
@@ -63,13 +118,56 @@ fn run() -> Result<(), Error> {
             .value_of("output_dir")
             .ok_or_else(|| format_err!("output directory is required"))?,
     );
+    // Load the config, falling back to defaults if none was given
+    let mut config = match matches.value_of("config") {
+        Some(config_path) => GeneratorConfig::load(config_path)?,
+        None => GeneratorConfig::default(),
+    };
+    if let Some(pcap_root) = matches.value_of("pcap_root") {
+        config.pcap_root = Some(PathBuf::from(pcap_root));
+    }
+    if matches.is_present("conn_only") {
+        config.conn_only = true;
+    }
     // Loading the dataset is bound to the lifetime of the scratch directory, since sometimes we
     // create a temp dir
     info!("Loading the dataset");
-    let dataset = Dataset::load(data_dir)?;
+    let status_file = matches.value_of("status_file").map(Path::new);
+    let dataset = Dataset::load(data_dir, &config, status_file)?;
     info!("Finished loading the dataset");
     info!("Saving the dataset");
-    dataset.save(output_dir)?;
+    let compression = match matches.value_of("compression") {
+        Some(compression) => compression.parse()?,
+        None => config.compression,
+    };
+    let format = match matches.value_of("format") {
+        Some(format) => format.parse()?,
+        None => config.output_format,
+    };
+    let options = SaveOptions {
+        compression,
+        format,
+        sort_flows: config.sort_flows || matches.is_present("sort_flows"),
+        columns: Features::columns(&config),
+    };
+    match matches.value_of("split") {
+        Some(split) => {
+            let ratios = split
+                .split(',')
+                .map(str::parse)
+                .collect::<Result<Vec<f64>, _>>()?;
+            let ratios = match ratios[..] {
+                [train, val, test] => [train, val, test],
+                _ => return Err(format_err!("--split takes 3 ratios, not {:?}", split)),
+            };
+            let seed = matches
+                .value_of("split_seed")
+                .ok_or_else(|| format_err!("split seed is required"))?
+                .parse()?;
+            dataset.save_split(output_dir, ratios, seed, options)?;
+        }
+        None => dataset.save(output_dir, options)?,
+    }
     info!("Finished saving the dataset");
     Ok(())
 }
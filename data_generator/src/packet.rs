@@ -15,15 +15,24 @@
 // along with packet_captor_sakura.  If not, see <https:// www.gnu.org/licenses/>.
 
 use crate::entropy::*;
+use crate::flow_aggregator::PacketKey;
 use crate::pcap::*;
+use crate::reassembly::Ipv4Reassembler;
+use byteorder::{BigEndian, ByteOrder};
 use failure::{Error, Fail};
-use pnet_packet::ethernet::{EtherTypes, EthernetPacket};
-use pnet_packet::ip::IpNextHeaderProtocols;
-use pnet_packet::ipv4::Ipv4Packet;
+use itertools::Either;
+use pnet_packet::ethernet::{EtherType, EtherTypes, EthernetPacket};
+use pnet_packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet_packet::ipv4::{Ipv4Flags, Ipv4Packet};
 use pnet_packet::ipv6::Ipv6Packet;
 use pnet_packet::tcp::TcpPacket;
 use pnet_packet::udp::UdpPacket;
+use pnet_packet::vlan::VlanPacket;
 use pnet_packet::FromPacket;
+use rayon::prelude::*;
+use serde_derive::Deserialize;
+use std::io::{Cursor, Read};
+use std::iter;
 use std::net::IpAddr;
 use std::path::Path;
 
@@ -40,10 +49,18 @@ pub struct Packet {
     pub src_port: u16,
     /// Destination port
     pub dst_port: u16,
+    /// TCP flags, or 0 if this is not a TCP packet
+    pub tcp_flags: u8,
+    /// Start of the application layer payload, if it was asked for when loading. This is only
+    /// kept until the packet is added to its flow
+    pub payload_prefix: Vec<u8>,
     /// Length of the application layer payload
     pub payload_length: usize,
     /// Entropy of the application layer payload
     pub entropy: f64,
+    /// Chi-square statistic of the application layer payload's bytes against a uniform
+    /// distribution
+    pub chi_square: f64,
     /// Timestamp for the packet's occurrence
     pub timestamp: u64,
 }
@@ -52,83 +69,290 @@ pub struct Packet {
 pub enum ParsePacketError {
     #[fail(display = "Failed to parse ethernet header")]
     InvalidEthernetHeader,
+    #[fail(display = "Failed to parse VLAN header")]
+    InvalidVlanHeader,
     #[fail(display = "Failed to parse IPV4 header")]
     InvalidIpv4Header,
     #[fail(display = "Failed to parse IPV6 header")]
     InvalidIpv6Header,
+    #[fail(display = "IPV6 fragments are not reassembled")]
+    UnsupportedIpv6Fragment,
     #[fail(display = "Unsupported ethertype")]
     InvalidInternetLayer,
     #[fail(display = "Failed to parse TCP header")]
     InvalidTcpHeader,
     #[fail(display = "Failed to parse UDP header")]
     InvalidUdpHeader,
+    #[fail(display = "Failed to parse SCTP header")]
+    InvalidSctpHeader,
     #[fail(display = "Unsupported transport protocol")]
     InvalidTransportProtocol,
 }
 
+/// Fields extracted from the internet layer of a frame
+#[derive(Debug)]
+pub struct InternetLayer {
+    /// Source IP address
+    pub src_ip: IpAddr,
+    /// Destination IP address
+    pub dst_ip: IpAddr,
+    /// Payload carried by the internet layer
+    pub payload: Vec<u8>,
+    /// Transport protocol
+    pub trans_protocol: IpNextHeaderProtocol,
+    /// Present if this is a fragment of an IPv4 datagram
+    pub fragment: Option<Ipv4Fragment>,
+}
+
+/// Fields describing where a fragment belongs in an IPv4 datagram
+#[derive(Clone, Copy, Debug)]
+pub struct Ipv4Fragment {
+    /// Identifies the datagram the fragment belongs to
+    pub identification: u16,
+    /// Offset (in bytes) of the fragment's payload into the datagram's payload
+    pub offset: usize,
+    /// Whether more fragments follow this one
+    pub more_fragments: bool,
+}
+
+/// How to handle IPv6 packets with no next header (protocol 59), which carry no transport
+/// layer
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum NoNextHeaderHandling {
+    /// Load the packet with an empty payload and zero ports
+    #[default]
+    Record,
+    /// Leave the packet out, without treating it as an error
+    Skip,
+}
+
+/// Options controlling how packets are loaded from a pcap
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LoadOptions {
+    /// Time (in ns) to wait for the fragments of an IPv4 datagram. If not given, fragments are
+    /// not reassembled
+    pub fragment_timeout: Option<u64>,
+    /// Number of bytes at the start of each packet's payload to keep
+    pub payload_prefix_length: usize,
+    /// How to handle IPv6 packets with no next header
+    pub no_next_header: NoNextHeaderHandling,
+    /// Number of records to read at a time and parse across threads. If not given, records are
+    /// parsed one at a time on the calling thread
+    pub parse_batch_size: Option<usize>,
+}
+
 impl Packet {
-    pub fn load_from_pcap(pcap_path: &Path) -> Result<impl Iterator<Item = Self>, Error> {
+    /// Loads packets from a pcap, skipping any records that fail to parse
+    /// # Parameters
+    /// * `pcap_path` - Path to the pcap
+    /// * `options` - Options controlling how packets are loaded
+    pub fn load_from_pcap(
+        pcap_path: &Path,
+        options: LoadOptions,
+    ) -> Result<impl Iterator<Item = Self>, Error> {
+        Ok(Self::load_from_pcap_with_errors(pcap_path, options)?.flatten())
+    }
+
+    /// Loads packets from a pcap, yielding the parse error for any record that fails to parse
+    /// # Parameters
+    /// * `pcap_path` - Path to the pcap
+    /// * `options` - Options controlling how packets are loaded
+    pub fn load_from_pcap_with_errors(
+        pcap_path: &Path,
+        options: LoadOptions,
+    ) -> Result<impl Iterator<Item = Result<Self, ParsePacketError>>, Error> {
         // Open the pcap file
-        let pcap_reader = PcapReader::open(pcap_path).expect("Failed to initialize Pcap reader");
+        let pcap_reader = PcapReader::open(pcap_path)?;
+        Ok(Self::load_from_reader(pcap_reader, options))
+    }
+
+    /// Loads packets from the contents of a pcap that was already read into memory, skipping
+    /// any records that fail to parse
+    /// # Parameters
+    /// * `pcap_data` - Contents of the pcap
+    /// * `options` - Options controlling how packets are loaded
+    pub fn load_from_pcap_data(
+        pcap_data: Vec<u8>,
+        options: LoadOptions,
+    ) -> Result<impl Iterator<Item = Self>, Error> {
+        let pcap_reader = PcapReader::from_reader(Cursor::new(pcap_data))?;
+        Ok(Self::load_from_reader(pcap_reader, options).flatten())
+    }
+
+    /// Parses the records of a pcap reader, in batches across threads if the options ask for it
+    fn load_from_reader<T: Read>(
+        pcap_reader: PcapReader<T>,
+        options: LoadOptions,
+    ) -> impl Iterator<Item = Result<Self, ParsePacketError>> {
+        match options.parse_batch_size {
+            Some(batch_size) => Either::Left(Self::load_from_reader_parallel(
+                pcap_reader,
+                options,
+                batch_size,
+            )),
+            None => Either::Right(Self::load_from_reader_sequential(pcap_reader, options)),
+        }
+    }
+
+    /// Parses the records of a pcap reader one at a time
+    fn load_from_reader_sequential<T: Read>(
+        pcap_reader: PcapReader<T>,
+        options: LoadOptions,
+    ) -> impl Iterator<Item = Result<Self, ParsePacketError>> {
         // Extract whether the pcap is nanosecond resolution
         let is_nanosecond_res: bool = pcap_reader.is_nanosecond_res;
-        // Iterate over the pcap records
-        let packets =
-            pcap_reader.flat_map(move |record| Self::from_record(record, is_nanosecond_res));
-        Ok(packets)
+        // Only reassemble fragments if asked to
+        let mut reassembler = options.fragment_timeout.map(Ipv4Reassembler::new);
+        // Iterate over the pcap records. Fragments only yield a packet once the datagram they
+        // belong to is complete
+        pcap_reader.flat_map(move |record| {
+            let timestamp = record.header.get_time_as_nanos(is_nanosecond_res);
+            let internet_layer = match Self::parse_frame(record.data) {
+                Ok(internet_layer) => internet_layer,
+                Err(err) => return Some(Err(err)),
+            };
+            let internet_layer =
+                Self::reassemble(&mut reassembler, internet_layer, timestamp, options)?;
+            Some(Self::from_internet_layer(
+                internet_layer,
+                timestamp,
+                options.payload_prefix_length,
+            ))
+        })
     }
 
-    pub fn from_record(
-        record: PcapRecord,
-        is_nanosecond_res: bool,
-    ) -> Result<Self, ParsePacketError> {
+    /// Parses the records of a pcap reader in batches, parsing the records of each batch across
+    /// threads
+    ///
+    /// Packets are yielded in the same order as when parsing sequentially
+    /// # Parameters
+    /// * `pcap_reader` - Reader of the pcap records
+    /// * `options` - Options controlling how packets are loaded
+    /// * `batch_size` - Number of records to read and parse at a time
+    fn load_from_reader_parallel<T: Read>(
+        pcap_reader: PcapReader<T>,
+        options: LoadOptions,
+        batch_size: usize,
+    ) -> impl Iterator<Item = Result<Self, ParsePacketError>> {
+        // Extract whether the pcap is nanosecond resolution
+        let is_nanosecond_res: bool = pcap_reader.is_nanosecond_res;
+        // Only reassemble fragments if asked to
+        let mut reassembler = options.fragment_timeout.map(Ipv4Reassembler::new);
+        let mut records = pcap_reader.map(move |record| {
+            (
+                record.header.get_time_as_nanos(is_nanosecond_res),
+                record.data,
+            )
+        });
+        iter::from_fn(move || {
+            let batch: Vec<(u64, Vec<u8>)> = records.by_ref().take(batch_size.max(1)).collect();
+            if batch.is_empty() {
+                return None;
+            }
+            // Parse the frames across threads
+            let internet_layers: Vec<(u64, Result<InternetLayer, ParsePacketError>)> = batch
+                .into_par_iter()
+                .map(|(timestamp, data)| (timestamp, Self::parse_frame(data)))
+                .collect();
+            // Reassembly depends on the fragments that came before, so it's done in order
+            let internet_layers: Vec<(u64, Result<InternetLayer, ParsePacketError>)> =
+                internet_layers
+                    .into_iter()
+                    .filter_map(|(timestamp, internet_layer)| match internet_layer {
+                        Ok(internet_layer) => {
+                            Self::reassemble(&mut reassembler, internet_layer, timestamp, options)
+                                .map(|internet_layer| (timestamp, Ok(internet_layer)))
+                        }
+                        Err(err) => Some((timestamp, Err(err))),
+                    })
+                    .collect();
+            // Parse the transport layers across threads
+            let packets: Vec<Result<Self, ParsePacketError>> = internet_layers
+                .into_par_iter()
+                .map(|(timestamp, internet_layer)| {
+                    internet_layer.and_then(|internet_layer| {
+                        Self::from_internet_layer(
+                            internet_layer,
+                            timestamp,
+                            options.payload_prefix_length,
+                        )
+                    })
+                })
+                .collect();
+            Some(packets)
+        })
+        .flatten()
+    }
+
+    /// Reassembles fragments if a reassembler is given, and leaves out packets without a
+    /// transport layer if the options ask for it
+    ///
+    /// Returns None if the packet isn't complete yet, or is left out
+    /// # Parameters
+    /// * `reassembler` - Reassembler holding the fragments seen so far, if reassembling
+    /// * `internet_layer` - The parsed internet layer of the packet
+    /// * `timestamp` - Time the packet arrived
+    /// * `options` - Options controlling how packets are loaded
+    fn reassemble(
+        reassembler: &mut Option<Ipv4Reassembler>,
+        internet_layer: InternetLayer,
+        timestamp: u64,
+        options: LoadOptions,
+    ) -> Option<InternetLayer> {
+        let internet_layer = match reassembler {
+            Some(ref mut reassembler) => reassembler.process(internet_layer, timestamp)?,
+            None => internet_layer,
+        };
+        // Packets without a transport layer may be left out
+        if internet_layer.trans_protocol == IpNextHeaderProtocols::Ipv6NoNxt
+            && options.no_next_header == NoNextHeaderHandling::Skip
+        {
+            return None;
+        }
+        Some(internet_layer)
+    }
+
+    /// Parses the ethernet and internet layers of a frame
+    fn parse_frame(data: Vec<u8>) -> Result<InternetLayer, ParsePacketError> {
         // Parse out the ethernet header
-        let ethernet_header = match EthernetPacket::owned(record.data) {
+        let ethernet_header = match EthernetPacket::owned(data) {
             Some(ethernet_header) => ethernet_header.from_packet(),
             None => return Err(ParsePacketError::InvalidEthernetHeader),
         };
         // Parse out the IP header
-        let (src_ip, dst_ip, payload, trans_protocol) = match ethernet_header.ethertype {
-            EtherTypes::Ipv4 => match Ipv4Packet::owned(ethernet_header.payload) {
-                Some(ipv4_header) => {
-                    // Extract the header
-                    let ipv4_header = ipv4_header.from_packet();
-                    // Extract fields
-                    (
-                        IpAddr::V4(ipv4_header.source),
-                        IpAddr::V4(ipv4_header.destination),
-                        ipv4_header.payload,
-                        ipv4_header.next_level_protocol,
-                    )
-                }
-                None => return Err(ParsePacketError::InvalidIpv4Header),
-            },
-            EtherTypes::Ipv6 => match Ipv6Packet::owned(ethernet_header.payload) {
-                Some(ipv6_header) => {
-                    // Extract the header
-                    let ipv6_header = ipv6_header.from_packet();
-                    // Extract fields
-                    (
-                        IpAddr::V6(ipv6_header.source),
-                        IpAddr::V6(ipv6_header.destination),
-                        ipv6_header.payload,
-                        ipv6_header.next_header,
-                    )
-                }
-                None => return Err(ParsePacketError::InvalidIpv6Header),
-            },
-            _ => return Err(ParsePacketError::InvalidInternetLayer),
-        };
+        Self::parse_internet_layer(ethernet_header.ethertype, ethernet_header.payload)
+    }
+
+    /// Parses the transport layer of a packet, given its internet layer
+    /// # Parameters
+    /// * `internet_layer` - The parsed internet layer of the packet
+    /// * `timestamp` - Time the packet arrived
+    /// * `payload_prefix_length` - Number of bytes at the start of the payload to keep
+    fn from_internet_layer(
+        internet_layer: InternetLayer,
+        timestamp: u64,
+        payload_prefix_length: usize,
+    ) -> Result<Self, ParsePacketError> {
+        let InternetLayer {
+            src_ip,
+            dst_ip,
+            payload,
+            trans_protocol,
+            ..
+        } = internet_layer;
         // Parse out the TCP header
-        let (src_port, dst_port, payload) = match trans_protocol {
+        let (src_port, dst_port, tcp_flags, payload) = match trans_protocol {
             IpNextHeaderProtocols::Tcp => match TcpPacket::owned(payload) {
                 Some(tcp_header) => {
                     // Extract the TCP header
                     let tcp_header = tcp_header.from_packet();
                     // Extract fields from the TCP header
+                    // Newer versions of pnet include the NS flag, which is dropped here
                     (
                         tcp_header.source,
                         tcp_header.destination,
+                        tcp_header.flags as u8,
                         tcp_header.payload,
                     )
                 }
@@ -142,13 +366,33 @@ impl Packet {
                     (
                         udp_header.source,
                         udp_header.destination,
+                        0,
                         udp_header.payload,
                     )
                 }
                 None => return Err(ParsePacketError::InvalidUdpHeader),
             },
+            IpNextHeaderProtocols::Sctp => {
+                // pnet does not parse SCTP, so read the common header directly
+                // It consists of the source port, destination port, verification tag, and
+                // checksum, and is followed by the chunks
+                const SCTP_COMMON_HEADER_LENGTH: usize = 12;
+                if payload.len() < SCTP_COMMON_HEADER_LENGTH {
+                    return Err(ParsePacketError::InvalidSctpHeader);
+                }
+                (
+                    BigEndian::read_u16(&payload[0..2]),
+                    BigEndian::read_u16(&payload[2..4]),
+                    0,
+                    payload[SCTP_COMMON_HEADER_LENGTH..].to_vec(),
+                )
+            }
+            // There is no transport layer, and anything following the header is ignored
+            IpNextHeaderProtocols::Ipv6NoNxt => (0, 0, 0, Vec::new()),
             _ => return Err(ParsePacketError::InvalidTransportProtocol),
         };
+        // Count the payload's bytes once for both of its randomness scores
+        let histogram = ByteHistogram::new(payload.iter().cloned());
         // Construct a packet from useful features
         Ok(Packet {
             src_ip,
@@ -156,45 +400,585 @@ impl Packet {
             trans_protocol: trans_protocol.0,
             src_port,
             dst_port,
+            tcp_flags,
+            payload_prefix: payload[..payload.len().min(payload_prefix_length)].to_vec(),
             payload_length: payload.len(),
-            entropy: payload.shannon_entropy(),
-            timestamp: record.header.get_time_as_nanos(is_nanosecond_res),
+            entropy: histogram.shannon_entropy(),
+            chi_square: histogram.chi_square(),
+            timestamp,
         })
     }
 
+    /// Parses the internet layer of a frame
+    ///
+    /// VLAN tags are skipped, and the protocol they encapsulate is parsed instead
+    fn parse_internet_layer(
+        ethertype: EtherType,
+        payload: Vec<u8>,
+    ) -> Result<InternetLayer, ParsePacketError> {
+        match ethertype {
+            EtherTypes::Ipv4 => match Ipv4Packet::owned(payload) {
+                Some(ipv4_header) => {
+                    // Extract the header
+                    let ipv4_header = ipv4_header.from_packet();
+                    // The datagram is fragmented if more fragments follow, or this isn't the first
+                    let more_fragments = ipv4_header.flags & Ipv4Flags::MoreFragments != 0;
+                    let fragment = if more_fragments || ipv4_header.fragment_offset != 0 {
+                        Some(Ipv4Fragment {
+                            identification: ipv4_header.identification,
+                            // The offset is measured in units of 8 bytes
+                            offset: ipv4_header.fragment_offset as usize * 8,
+                            more_fragments,
+                        })
+                    } else {
+                        None
+                    };
+                    // Extract fields
+                    Ok(InternetLayer {
+                        src_ip: IpAddr::V4(ipv4_header.source),
+                        dst_ip: IpAddr::V4(ipv4_header.destination),
+                        payload: ipv4_header.payload,
+                        trans_protocol: ipv4_header.next_level_protocol,
+                        fragment,
+                    })
+                }
+                None => Err(ParsePacketError::InvalidIpv4Header),
+            },
+            EtherTypes::Ipv6 => match Ipv6Packet::owned(payload) {
+                Some(ipv6_header) => {
+                    // Extract the header
+                    let ipv6_header = ipv6_header.from_packet();
+                    // Find the transport layer after any extension headers
+                    let (trans_protocol, payload) = Self::skip_ipv6_extension_headers(
+                        ipv6_header.next_header,
+                        ipv6_header.payload,
+                    )?;
+                    // Extract fields
+                    Ok(InternetLayer {
+                        src_ip: IpAddr::V6(ipv6_header.source),
+                        dst_ip: IpAddr::V6(ipv6_header.destination),
+                        payload,
+                        trans_protocol,
+                        fragment: None,
+                    })
+                }
+                None => Err(ParsePacketError::InvalidIpv6Header),
+            },
+            // Skip over VLAN tags, including stacked tags
+            EtherTypes::Vlan | EtherTypes::PBridge | EtherTypes::QinQ => {
+                match VlanPacket::owned(payload) {
+                    Some(vlan_header) => {
+                        // Extract the header
+                        let vlan_header = vlan_header.from_packet();
+                        // Parse the encapsulated protocol
+                        Self::parse_internet_layer(vlan_header.ethertype, vlan_header.payload)
+                    }
+                    None => Err(ParsePacketError::InvalidVlanHeader),
+                }
+            }
+            _ => Err(ParsePacketError::InvalidInternetLayer),
+        }
+    }
+
+    /// Skips the extension headers following an IPv6 header, returning the protocol of the
+    /// header after them and the payload starting from it
+    ///
+    /// Each extension header gives the protocol of the header after it and its own length, so
+    /// the chain is followed until a header that isn't an extension header. IPv6 fragments are
+    /// not reassembled, so any fragment of a larger datagram is rejected: later fragments don't
+    /// start with a transport header, and the first one only carries part of the payload. Atomic
+    /// fragments (offset 0 with no more fragments following) hold a whole datagram and are parsed
+    /// # Parameters
+    /// * `next_header` - Protocol of the header following the IPv6 header
+    /// * `payload` - Payload of the IPv6 header
+    fn skip_ipv6_extension_headers(
+        mut next_header: IpNextHeaderProtocol,
+        mut payload: Vec<u8>,
+    ) -> Result<(IpNextHeaderProtocol, Vec<u8>), ParsePacketError> {
+        // Start of the current header in the payload
+        let mut offset = 0;
+        loop {
+            // Length (in bytes) of the current header
+            let length = match next_header {
+                // The length is given in units of 8 bytes, not counting the first 8
+                IpNextHeaderProtocols::Hopopt
+                | IpNextHeaderProtocols::Ipv6Route
+                | IpNextHeaderProtocols::Ipv6Opts
+                | IpNextHeaderProtocols::MobilityHeader
+                | IpNextHeaderProtocols::Hip
+                | IpNextHeaderProtocols::Shim6 => match payload.get(offset + 1) {
+                    Some(&length) => (length as usize + 1) * 8,
+                    None => return Err(ParsePacketError::InvalidIpv6Header),
+                },
+                // The length is given in units of 4 bytes, not counting the first 8
+                IpNextHeaderProtocols::Ah => match payload.get(offset + 1) {
+                    Some(&length) => (length as usize + 2) * 4,
+                    None => return Err(ParsePacketError::InvalidIpv6Header),
+                },
+                // The fragment header always has 8 bytes, holding the fragment's offset (in
+                // units of 8 bytes) in its upper 13 bits and the more fragments flag in its
+                // lowest bit
+                IpNextHeaderProtocols::Ipv6Frag => match payload.get(offset + 2..offset + 4) {
+                    Some(fragment) if BigEndian::read_u16(fragment) != 0 => {
+                        return Err(ParsePacketError::UnsupportedIpv6Fragment)
+                    }
+                    Some(_) => 8,
+                    None => return Err(ParsePacketError::InvalidIpv6Header),
+                },
+                _ => break,
+            };
+            if offset + length > payload.len() {
+                return Err(ParsePacketError::InvalidIpv6Header);
+            }
+            next_header = IpNextHeaderProtocol(payload[offset]);
+            offset += length;
+        }
+        payload.drain(..offset);
+        Ok((next_header, payload))
+    }
+
     /// Strip out features that are identifying and not useful for generating features
-    pub fn strip(self) -> StrippedPacket {
-        StrippedPacket::from(self)
+    ///
+    /// Fields contained in the key of the packet's flow are dropped, keeping only which end of
+    /// the flow sent the packet
+    /// # Parameters
+    /// * `key` - Key of the flow the packet belongs to
+    pub fn strip(self, key: &PacketKey) -> StrippedPacket {
+        StrippedPacket {
+            payload_length: self.payload_length,
+            entropy: self.entropy,
+            chi_square: self.chi_square,
+            timestamp: self.timestamp,
+            sent_by_a: key.is_sent_by_a(self.src_ip, self.src_port),
+            tcp_flags: self.tcp_flags,
+        }
     }
 }
 
 /// A packet stripped of identifying features, leaving only those useful for
 /// feature generation
+///
+/// Addresses, ports, and the transport protocol are kept once per flow in its `PacketKey`
 pub struct StrippedPacket {
-    /// Transport protocol
-    pub trans_protocol: u8,
     /// Length of the application layer payload
     pub payload_length: usize,
     /// Entropy of the application layer payload
     pub entropy: f64,
+    /// Chi-square statistic of the application layer payload's bytes against a uniform
+    /// distribution
+    pub chi_square: f64,
     /// Timestamp for the packet's occurrence
     pub timestamp: u64,
-    /// Source port
-    pub src_port: u16,
-    /// Destination port
-    pub dst_port: u16,
+    /// Whether the packet was sent by endpoint a of its flow's key
+    pub sent_by_a: bool,
+    /// TCP flags, or 0 if this is not a TCP packet
+    pub tcp_flags: u8,
 }
 
-impl From<Packet> for StrippedPacket {
-    /// Converts a packet into a stripped packet
-    fn from(packet: Packet) -> Self {
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::pcap::tests::build_pcap;
+    use std::fs;
+    use std::net::Ipv6Addr;
+    use tempdir::TempDir;
+
+    /// Creates an ACK without a payload from port `src_port` on 10.0.0.1 to port 443 on 10.0.0.2
+    /// at the given time (in ns)
+    pub(crate) fn packet_at(src_port: u16, timestamp: u64) -> Packet {
+        Packet {
+            src_ip: "10.0.0.1".parse().unwrap(),
+            dst_ip: "10.0.0.2".parse().unwrap(),
+            trans_protocol: 6,
+            src_port,
+            dst_port: 443,
+            tcp_flags: 0x10,
+            payload_prefix: Vec::new(),
+            payload_length: 0,
+            entropy: 0.0,
+            chi_square: 0.0,
+            timestamp,
+        }
+    }
+    /// Swaps the endpoints of a packet, turning it into a reply
+    pub(crate) fn reply(packet: Packet) -> Packet {
+        Packet {
+            src_ip: packet.dst_ip,
+            dst_ip: packet.src_ip,
+            src_port: packet.dst_port,
+            dst_port: packet.src_port,
+            ..packet
+        }
+    }
+    /// Creates a stripped ACK without a payload at the given time (in ns)
+    pub(crate) fn stripped_packet_at(sent_by_a: bool, timestamp: u64) -> StrippedPacket {
         StrippedPacket {
-            trans_protocol: packet.trans_protocol,
-            payload_length: packet.payload_length,
-            entropy: packet.entropy,
-            timestamp: packet.timestamp,
-            src_port: packet.src_port,
-            dst_port: packet.dst_port,
+            payload_length: 0,
+            entropy: 0.0,
+            chi_square: 0.0,
+            timestamp,
+            sent_by_a,
+            tcp_flags: 0x10,
+        }
+    }
+
+    /// Wraps a payload in an ethernet header
+    pub(crate) fn ethernet(ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x02, 0, 0, 0, 0, 0x01, 0x02, 0, 0, 0, 0, 0x02];
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+    /// Wraps a payload in an IPv4 header from 10.0.0.1 to 10.0.0.2
+    pub(crate) fn ipv4(protocol: u8, payload: &[u8]) -> Vec<u8> {
+        ipv4_fragment(protocol, 0, 0, false, payload)
+    }
+    /// Wraps a fragment's payload in an IPv4 header from 10.0.0.1 to 10.0.0.2
+    pub(crate) fn ipv4_fragment(
+        protocol: u8,
+        identification: u16,
+        offset: usize,
+        more_fragments: bool,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let total_length = (20 + payload.len()) as u16;
+        let flags_and_offset = (offset / 8) as u16 | if more_fragments { 0x2000 } else { 0 };
+        let mut packet = vec![0x45, 0];
+        packet.extend_from_slice(&total_length.to_be_bytes());
+        packet.extend_from_slice(&identification.to_be_bytes());
+        packet.extend_from_slice(&flags_and_offset.to_be_bytes());
+        packet.extend_from_slice(&[64, protocol, 0, 0]);
+        packet.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2]);
+        packet.extend_from_slice(payload);
+        packet
+    }
+    /// Wraps a payload in an IPv6 header from fe80::1 to fe80::2
+    pub(crate) fn ipv6(next_header: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x60, 0, 0, 0];
+        packet.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&[next_header, 64]);
+        packet.extend_from_slice(&"fe80::1".parse::<Ipv6Addr>().unwrap().octets());
+        packet.extend_from_slice(&"fe80::2".parse::<Ipv6Addr>().unwrap().octets());
+        packet.extend_from_slice(payload);
+        packet
+    }
+    /// Wraps a payload in a TCP header with the given flags
+    pub(crate) fn tcp(src_port: u16, dst_port: u16, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let mut segment = Vec::new();
+        segment.extend_from_slice(&src_port.to_be_bytes());
+        segment.extend_from_slice(&dst_port.to_be_bytes());
+        segment.extend_from_slice(&[0; 8]);
+        segment.extend_from_slice(&[0x50, flags, 0xff, 0xff, 0, 0, 0, 0]);
+        segment.extend_from_slice(payload);
+        segment
+    }
+    /// Wraps a payload in a UDP header
+    pub(crate) fn udp(src_port: u16, dst_port: u16, payload: &[u8]) -> Vec<u8> {
+        let length = (8 + payload.len()) as u16;
+        let mut datagram = Vec::new();
+        datagram.extend_from_slice(&src_port.to_be_bytes());
+        datagram.extend_from_slice(&dst_port.to_be_bytes());
+        datagram.extend_from_slice(&length.to_be_bytes());
+        datagram.extend_from_slice(&[0, 0]);
+        datagram.extend_from_slice(payload);
+        datagram
+    }
+    /// Writes the given frames to a pcap in a temporary directory and loads them
+    pub(crate) fn load_frames(frames: &[(u64, Vec<u8>)]) -> Vec<Result<Packet, ParsePacketError>> {
+        load_frames_reassembled(frames, None)
+    }
+    /// Writes the given frames to a pcap in a temporary directory and loads them, reassembling
+    /// fragments if a timeout is given
+    pub(crate) fn load_frames_reassembled(
+        frames: &[(u64, Vec<u8>)],
+        fragment_timeout: Option<u64>,
+    ) -> Vec<Result<Packet, ParsePacketError>> {
+        load_frames_with_options(
+            frames,
+            LoadOptions {
+                fragment_timeout,
+                payload_prefix_length: 4,
+                ..LoadOptions::default()
+            },
+        )
+    }
+    /// Writes the given frames to a pcap in a temporary directory and loads them with the given
+    /// options
+    pub(crate) fn load_frames_with_options(
+        frames: &[(u64, Vec<u8>)],
+        options: LoadOptions,
+    ) -> Vec<Result<Packet, ParsePacketError>> {
+        let scratch_dir = TempDir::new("data_generator").unwrap();
+        let pcap_path = scratch_dir.path().join("test.pcap");
+        fs::write(&pcap_path, build_pcap(frames)).unwrap();
+        Packet::load_from_pcap_with_errors(&pcap_path, options)
+            .unwrap()
+            .collect()
+    }
+
+    /// Tests that parse errors are reported instead of being dropped
+    #[test]
+    fn test_load_from_pcap_with_errors() {
+        let packets = load_frames(&[
+            (
+                0,
+                ethernet(0x0800, &ipv4(6, &tcp(50000, 443, 0x18, &[1; 10]))),
+            ),
+            (1, ethernet(0x0806, &[0; 28])),
+            (2, ethernet(0x0800, &ipv4(6, &[0; 4]))),
+            (3, ethernet(0x0800, &ipv4(17, &udp(50000, 443, &[2; 5])))),
+        ]);
+        assert_eq!(packets.len(), 4);
+        match packets[0] {
+            Ok(ref packet) => {
+                assert_eq!(packet.src_port, 50000);
+                assert_eq!(packet.dst_port, 443);
+                assert_eq!(packet.payload_length, 10);
+                assert_eq!(packet.tcp_flags, 0x18);
+                assert_eq!(packet.payload_prefix, vec![1; 4]);
+            }
+            Err(ref err) => panic!("Failed to parse packet: {}", err),
+        }
+        assert!(matches!(
+            packets[1],
+            Err(ParsePacketError::InvalidInternetLayer)
+        ));
+        assert!(matches!(
+            packets[2],
+            Err(ParsePacketError::InvalidTcpHeader)
+        ));
+        assert_eq!(packets[3].as_ref().unwrap().payload_length, 5);
+        assert_eq!(packets[3].as_ref().unwrap().tcp_flags, 0);
+    }
+
+    /// Tests that VLAN tagged frames are parsed, including stacked tags
+    #[test]
+    fn test_vlan() {
+        let ip_packet = ipv4(6, &tcp(50000, 443, 0x18, &[1; 10]));
+        // Single 802.1Q tag
+        let mut vlan = vec![0x00, 0x64, 0x08, 0x00];
+        vlan.extend_from_slice(&ip_packet);
+        // 802.1ad outer tag around an 802.1Q tag
+        let mut qinq = vec![0x00, 0x01, 0x81, 0x00];
+        qinq.extend_from_slice(&vlan);
+        let packets = load_frames(&[(0, ethernet(0x8100, &vlan)), (1, ethernet(0x88a8, &qinq))]);
+        for packet in packets {
+            let packet = packet.unwrap();
+            assert_eq!(packet.src_ip, "10.0.0.1".parse::<IpAddr>().unwrap());
+            assert_eq!(packet.dst_port, 443);
+            assert_eq!(packet.payload_length, 10);
+        }
+    }
+
+    /// Tests that ports and payload are extracted from SCTP packets
+    #[test]
+    fn test_sctp() {
+        let mut sctp = vec![0x0b, 0x59, 0x0b, 0x5a, 0, 0, 0, 1, 0, 0, 0, 0];
+        sctp.extend_from_slice(&[0; 16]);
+        let packets = load_frames(&[
+            (0, ethernet(0x0800, &ipv4(132, &sctp))),
+            (1, ethernet(0x0800, &ipv4(132, &sctp[..8]))),
+        ]);
+        let packet = packets[0].as_ref().unwrap();
+        assert_eq!(packet.trans_protocol, 132);
+        assert_eq!(packet.src_port, 2905);
+        assert_eq!(packet.dst_port, 2906);
+        assert_eq!(packet.payload_length, 16);
+        assert!(matches!(
+            packets[1],
+            Err(ParsePacketError::InvalidSctpHeader)
+        ));
+    }
+
+    /// Tests that IPv6 packets with no next header are recorded or skipped, but never errors
+    #[test]
+    fn test_ipv6_no_next_header() {
+        let frames = vec![
+            (0, ethernet(0x86dd, &ipv6(59, &[0; 8]))),
+            (1, ethernet(0x86dd, &ipv6(17, &udp(50000, 443, &[2; 5])))),
+        ];
+        let packets = load_frames(&frames);
+        assert_eq!(packets.len(), 2);
+        let packet = packets[0].as_ref().unwrap();
+        assert_eq!(packet.trans_protocol, 59);
+        assert_eq!(packet.src_ip, "fe80::1".parse::<IpAddr>().unwrap());
+        assert_eq!((packet.src_port, packet.dst_port), (0, 0));
+        assert_eq!(packet.payload_length, 0);
+        // Skipped packets are left out without an error
+        let options = LoadOptions {
+            no_next_header: NoNextHeaderHandling::Skip,
+            ..LoadOptions::default()
+        };
+        let packets = load_frames_with_options(&frames, options);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].as_ref().unwrap().dst_port, 443);
+    }
+
+    /// Tests that the transport layer is found after IPv6 extension headers
+    #[test]
+    fn test_ipv6_extension_headers() {
+        // Wraps a payload in an extension header padded to the given number of 8 byte units
+        let extension = |next_header: u8, units: u8, payload: &[u8]| {
+            let mut header = vec![next_header, units - 1];
+            header.resize(units as usize * 8, 0);
+            header.extend_from_slice(payload);
+            header
+        };
+        // Wraps a payload in a fragment header with the given offset (in bytes), and whether
+        // more fragments follow
+        let fragment = |next_header: u8, offset: u16, more: bool, payload: &[u8]| {
+            let mut header = vec![next_header, 0];
+            header.extend_from_slice(&(offset | more as u16).to_be_bytes());
+            header.extend_from_slice(&[0, 0, 0, 1]);
+            header.extend_from_slice(payload);
+            header
+        };
+        let segment = tcp(50000, 443, 0x18, &[1; 10]);
+        // Hop-by-hop options, routing, destination options, then a fragment header
+        let chain = extension(
+            43,
+            1,
+            &extension(60, 2, &extension(44, 1, &fragment(6, 0, false, &segment))),
+        );
+        let frames = vec![
+            (0, ethernet(0x86dd, &ipv6(0, &chain))),
+            (
+                1,
+                ethernet(
+                    0x86dd,
+                    &ipv6(44, &fragment(17, 0, false, &udp(50000, 443, &[2; 5]))),
+                ),
+            ),
+            // The first fragment only carries part of the datagram
+            (
+                2,
+                ethernet(0x86dd, &ipv6(44, &fragment(6, 0, true, &segment[..16]))),
+            ),
+            (
+                3,
+                ethernet(0x86dd, &ipv6(44, &fragment(6, 16, false, &segment[16..]))),
+            ),
+            // The routing header claims to be longer than the packet
+            (4, ethernet(0x86dd, &ipv6(43, &[6, 4, 0, 0, 0, 0, 0, 0]))),
+        ];
+        let packets = load_frames(&frames);
+        assert_eq!(packets.len(), 5);
+        let packet = packets[0].as_ref().unwrap();
+        assert_eq!(packet.trans_protocol, 6);
+        assert_eq!((packet.src_port, packet.dst_port), (50000, 443));
+        assert_eq!(packet.tcp_flags, 0x18);
+        assert_eq!(packet.payload_length, 10);
+        let packet = packets[1].as_ref().unwrap();
+        assert_eq!(packet.trans_protocol, 17);
+        assert_eq!(packet.payload_length, 5);
+        for packet in &packets[2..4] {
+            assert!(matches!(
+                packet,
+                Err(ParsePacketError::UnsupportedIpv6Fragment)
+            ));
         }
+        assert!(matches!(
+            packets[4],
+            Err(ParsePacketError::InvalidIpv6Header)
+        ));
+    }
+
+    /// Tests that fragmented datagrams are reassembled before the transport layer is parsed
+    #[test]
+    fn test_fragment_reassembly() {
+        let segment = tcp(50000, 443, 0x18, &[1; 40]);
+        let frames = vec![
+            (
+                0,
+                ethernet(0x0800, &ipv4_fragment(6, 7, 0, true, &segment[..24])),
+            ),
+            (
+                1,
+                ethernet(0x0800, &ipv4_fragment(6, 7, 24, false, &segment[24..])),
+            ),
+        ];
+        // Without reassembly the first fragment is parsed on its own
+        let packets = load_frames(&frames);
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].as_ref().unwrap().payload_length, 4);
+        // With reassembly the fragments form a single packet, timestamped by the last fragment
+        let packets = load_frames_reassembled(&frames, Some(1_000_000_000));
+        assert_eq!(packets.len(), 1);
+        let packet = packets[0].as_ref().unwrap();
+        assert_eq!(packet.dst_port, 443);
+        assert_eq!(packet.payload_length, 40);
+        assert_eq!(packet.timestamp, 1_000);
+        // Fragments that never complete are dropped
+        let packets = load_frames_reassembled(&frames[..1], Some(1_000_000_000));
+        assert!(packets.is_empty());
+    }
+
+    /// Tests that parsing in batches across threads gives the same packets, in the same order,
+    /// as parsing sequentially
+    #[test]
+    fn test_parallel_parsing() {
+        let segment = tcp(50000, 443, 0x18, &[1; 40]);
+        let mut frames: Vec<(u64, Vec<u8>)> = (0..50)
+            .map(|t| {
+                (
+                    t,
+                    ethernet(0x0800, &ipv4(6, &tcp(50000, 443, 0x18, &[t as u8; 10]))),
+                )
+            })
+            .collect();
+        // Include a parse error, and a fragmented datagram split across batches
+        frames.push((50, ethernet(0x0806, &[0; 28])));
+        frames.push((
+            51,
+            ethernet(0x0800, &ipv4_fragment(6, 7, 0, true, &segment[..24])),
+        ));
+        frames.push((
+            52,
+            ethernet(0x0800, &ipv4_fragment(6, 7, 24, false, &segment[24..])),
+        ));
+        frames.push((53, ethernet(0x0800, &ipv4(17, &udp(50000, 443, &[2; 5])))));
+        let options = LoadOptions {
+            fragment_timeout: Some(1_000_000_000),
+            payload_prefix_length: 4,
+            ..LoadOptions::default()
+        };
+        let sequential = load_frames_with_options(&frames, options);
+        assert_eq!(sequential.len(), 53);
+        for &batch_size in &[1, 7, 52, 1000] {
+            let parallel = load_frames_with_options(
+                &frames,
+                LoadOptions {
+                    parse_batch_size: Some(batch_size),
+                    ..options
+                },
+            );
+            assert_eq!(
+                format!("{:?}", parallel),
+                format!("{:?}", sequential),
+                "batch size {}",
+                batch_size
+            );
+        }
+    }
+
+    /// Tests that loading a pcap from memory gives the same packets as loading it from disk
+    #[test]
+    fn test_load_from_pcap_data() {
+        let pcap = build_pcap(&[
+            (0, ethernet(0x0800, &ipv4(6, &tcp(50000, 443, 0x02, &[])))),
+            (1, ethernet(0x0806, &[0; 28])),
+            (2, ethernet(0x0800, &ipv4(17, &udp(50000, 443, &[2; 5])))),
+        ]);
+        let scratch_dir = TempDir::new("data_generator").unwrap();
+        let pcap_path = scratch_dir.path().join("test.pcap");
+        fs::write(&pcap_path, &pcap).unwrap();
+        let from_disk: Vec<Packet> = Packet::load_from_pcap(&pcap_path, LoadOptions::default())
+            .unwrap()
+            .collect();
+        let from_memory: Vec<Packet> = Packet::load_from_pcap_data(pcap, LoadOptions::default())
+            .unwrap()
+            .collect();
+        assert_eq!(from_disk.len(), 2);
+        assert_eq!(format!("{:?}", from_disk), format!("{:?}", from_memory));
     }
 }
@@ -14,6 +14,7 @@
 // You should have received a copy of the GNU General Public License
 // along with packet_captor_sakura.  If not, see <https:// www.gnu.org/licenses/>.
 use byteorder::{BigEndian, LittleEndian, NativeEndian, ReadBytesExt};
+use log::error;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
@@ -21,22 +22,35 @@ use std::io::BufReader;
 use std::path::Path;
 use std::time::Duration;
 
+/// Default size (in bytes) of the read buffer, which is also the largest record we will read
+pub const DEFAULT_BUFFER_CAPACITY: usize = 2 << 20;
+
 #[derive(Debug)]
 pub struct PcapReader<T> {
     source: T,
     endianness: Endianness,
     pub is_nanosecond_res: bool,
     header: PcapHeader,
+    /// Maximum size (in bytes) of a single record
+    capacity: usize,
 }
 
 impl PcapReader<BufReader<File>> {
     /// Constructor from a filename
     pub fn open(path: &Path) -> Result<Self, io::Error> {
+        PcapReader::open_with_capacity(path, DEFAULT_BUFFER_CAPACITY)
+    }
+    /// Constructor from a filename, using a read buffer of the given size
+    ///
+    /// # Parameters
+    /// * `path` - path to the pcap file
+    /// * `capacity` - size of the read buffer, in bytes. Records larger than this are rejected
+    pub fn open_with_capacity(path: &Path, capacity: usize) -> Result<Self, io::Error> {
         // Open the PCAP file
         let pcap_file: File = File::open(path)?;
-        let reader = BufReader::new(pcap_file);
+        let reader = BufReader::with_capacity(capacity, pcap_file);
         // Initialize the pcap reader from the BufReader
-        PcapReader::from_reader(reader)
+        PcapReader::from_reader_with_capacity(reader, capacity)
     }
 }
 impl<T> PcapReader<T>
@@ -44,7 +58,15 @@ where
     T: Read,
 {
     /// Constructor from a reader
-    pub fn from_reader(mut source: T) -> Result<Self, io::Error> {
+    pub fn from_reader(source: T) -> Result<Self, io::Error> {
+        PcapReader::from_reader_with_capacity(source, DEFAULT_BUFFER_CAPACITY)
+    }
+    /// Constructor from a reader, with a limit on the size of a single record
+    ///
+    /// # Parameters
+    /// * `source` - reader containing pcap data
+    /// * `capacity` - maximum size of a single record, in bytes
+    pub fn from_reader_with_capacity(mut source: T, capacity: usize) -> Result<Self, io::Error> {
         // Read in magic number using the system's endianness
         let magic_number = source.read_u32::<NativeEndian>()?;
         // Determine endianness
@@ -92,6 +114,7 @@ where
             endianness,
             is_nanosecond_res,
             header,
+            capacity,
         })
     }
 }
@@ -103,7 +126,15 @@ where
     type Item = PcapRecord;
     /// Reads a record from the source
     fn next(&mut self) -> Option<Self::Item> {
-        PcapRecord::read_from(&mut self.source, &self.endianness).ok()
+        match PcapRecord::read_from(&mut self.source, &self.endianness, self.capacity) {
+            Ok(record) => Some(record),
+            // Running out of data is the normal way for a pcap to end
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => None,
+            Err(err) => {
+                error!("Failed to read pcap record: {}", err);
+                None
+            }
+        }
     }
 }
 
@@ -227,18 +258,31 @@ pub struct PcapRecord {
 
 impl PcapRecord {
     /// Reads in the pcap header from some source using the given endianness
+    ///
+    /// Records longer than `capacity` bytes are rejected with `InvalidData`
     fn read_from<'a, T: 'a>(
         mut source: &'a mut T,
         endianness: &Endianness,
+        capacity: usize,
     ) -> Result<Self, io::Error>
     where
         T: Read,
     {
         // Read the header
         let header = PcapRecordHeader::read_from(&mut source, endianness)?;
+        // Refuse to allocate for records that are larger than the buffer
+        let incl_len = header.incl_len as usize;
+        if incl_len > capacity {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "record of {} bytes is larger than the configured buffer of {} bytes",
+                    incl_len, capacity
+                ),
+            ));
+        }
         // Read the number of bytes specified in the header
-        // FIXME: conversion here is potentially unsafe
-        let mut data: Vec<u8> = vec![0; header.incl_len as usize];
+        let mut data: Vec<u8> = vec![0; incl_len];
         source.read_exact(&mut data)?;
         // Construct the header
         Ok(PcapRecord { header, data })
@@ -284,3 +328,53 @@ impl Endianness {
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+    /// Builds a little-endian, microsecond resolution pcap containing the given records
+    ///
+    /// Each record is given as a timestamp (in microseconds) and its data
+    pub(crate) fn build_pcap(records: &[(u64, Vec<u8>)]) -> Vec<u8> {
+        let mut pcap: Vec<u8> = Vec::new();
+        // Global header
+        pcap.write_u32::<LittleEndian>(0xa1b2_c3d4).unwrap();
+        pcap.write_u16::<LittleEndian>(2).unwrap();
+        pcap.write_u16::<LittleEndian>(4).unwrap();
+        pcap.write_i32::<LittleEndian>(0).unwrap();
+        pcap.write_u32::<LittleEndian>(0).unwrap();
+        pcap.write_u32::<LittleEndian>(65535).unwrap();
+        pcap.write_u32::<LittleEndian>(1).unwrap();
+        // Records
+        for (timestamp, data) in records {
+            pcap.write_u32::<LittleEndian>((timestamp / 1_000_000) as u32)
+                .unwrap();
+            pcap.write_u32::<LittleEndian>((timestamp % 1_000_000) as u32)
+                .unwrap();
+            pcap.write_u32::<LittleEndian>(data.len() as u32).unwrap();
+            pcap.write_u32::<LittleEndian>(data.len() as u32).unwrap();
+            pcap.extend_from_slice(data);
+        }
+        pcap
+    }
+    /// Tests that records fitting in the buffer are read
+    #[test]
+    fn test_read_records() {
+        let pcap = build_pcap(&[(0, vec![1; 10]), (1_000_001, vec![2; 20])]);
+        let reader = PcapReader::from_reader(&pcap[..]).unwrap();
+        let records: Vec<PcapRecord> = reader.collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].data, vec![2; 20]);
+        assert_eq!(records[1].header.get_time_as_nanos(false), 1_000_001_000);
+    }
+    /// Tests that a record larger than the buffer stops iteration instead of being read
+    #[test]
+    fn test_record_larger_than_capacity() {
+        let pcap = build_pcap(&[(0, vec![1; 10]), (1, vec![2; 100]), (2, vec![3; 10])]);
+        let reader = PcapReader::from_reader_with_capacity(&pcap[..], 64).unwrap();
+        let records: Vec<PcapRecord> = reader.collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].data, vec![1; 10]);
+    }
+}
@@ -0,0 +1,311 @@
+// Copyright 2018 Steven Sheffey
+// This file is part of packet_captor_sakura.
+//
+// packet_captor_sakura is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// packet_captor_sakura is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with packet_captor_sakura.  If not, see <https:// www.gnu.org/licenses/>.
+
+use log::warn;
+use rayon::iter::{ParallelBridge, ParallelIterator};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// State shared between the prefetch thread and its consumers
+#[derive(Debug, Default)]
+struct PrefetchState {
+    /// Contents of pcaps that have been read ahead, but not yet taken
+    buffered: HashMap<PathBuf, Vec<u8>>,
+    /// Pcaps that consumers have asked for, which no longer need to be read ahead
+    taken: HashSet<PathBuf>,
+    /// Total size (in bytes) of the buffered pcaps
+    buffered_bytes: usize,
+    /// Set when the prefetcher is dropped, to stop the prefetch thread
+    stopped: bool,
+    /// Number of pcaps that had been read ahead when they were taken
+    hits: usize,
+    /// Number of pcaps that had to be read from disk when they were taken
+    misses: usize,
+}
+
+/// Reads pcaps into memory ahead of when they are processed, so reading the next pcap overlaps
+/// with processing the current one
+///
+/// The total size of pcaps held in memory is bounded. Pcaps that are larger than the bound are
+/// never prefetched, and are read from disk as usual. Pcaps are read ahead one at a time in the
+/// order given, so they should be processed in about that order, as `par_map_in_order` does
+#[derive(Debug)]
+pub struct PcapPrefetcher {
+    /// Shared state, and a condition variable signalled whenever buffered pcaps are taken
+    state: Arc<(Mutex<PrefetchState>, Condvar)>,
+    /// Thread reading pcaps ahead
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PcapPrefetcher {
+    /// Starts prefetching pcaps
+    /// # Parameters
+    /// * `paths` - Paths to the pcaps, in the order they are expected to be processed
+    /// * `max_bytes` - Maximum total size (in bytes) of pcaps held in memory
+    pub fn new(paths: Vec<PathBuf>, max_bytes: usize) -> Self {
+        let state = Arc::new((Mutex::new(PrefetchState::default()), Condvar::new()));
+        let thread_state = Arc::clone(&state);
+        let thread = thread::spawn(move || {
+            let (ref lock, ref cvar) = *thread_state;
+            for path in paths {
+                // Skip pcaps too large to ever fit within the bound
+                let size = match fs::metadata(&path) {
+                    Ok(metadata) if metadata.len() <= max_bytes as u64 => metadata.len() as usize,
+                    _ => continue,
+                };
+                // Wait until there is room for this pcap
+                {
+                    let mut state = match lock.lock() {
+                        Ok(state) => state,
+                        Err(_) => return,
+                    };
+                    while !state.stopped
+                        && !state.taken.contains(&path)
+                        && state.buffered_bytes + size > max_bytes
+                    {
+                        state = match cvar.wait(state) {
+                            Ok(state) => state,
+                            Err(_) => return,
+                        };
+                    }
+                    if state.stopped {
+                        return;
+                    }
+                    // The consumer got here first, so it already read the pcap itself
+                    if state.taken.contains(&path) {
+                        continue;
+                    }
+                    // Reserve room for the pcap while it is being read
+                    state.buffered_bytes += size;
+                }
+                let contents = fs::read(&path);
+                let mut state = match lock.lock() {
+                    Ok(state) => state,
+                    Err(_) => return,
+                };
+                state.buffered_bytes -= size;
+                match contents {
+                    // Only keep the contents if they still fit, since the file may have grown
+                    Ok(contents)
+                        if !state.taken.contains(&path)
+                            && state.buffered_bytes + contents.len() <= max_bytes =>
+                    {
+                        state.buffered_bytes += contents.len();
+                        state.buffered.insert(path, contents);
+                    }
+                    Ok(_) => {}
+                    Err(err) => warn!("Failed to prefetch {:?}: {}", path, err),
+                }
+            }
+        });
+        PcapPrefetcher {
+            state,
+            thread: Some(thread),
+        }
+    }
+
+    /// Takes the contents of a pcap if it has already been read ahead
+    ///
+    /// Returns None if the pcap has not been read yet, in which case it will no longer be read
+    /// ahead, and should be read from disk instead
+    /// # Parameters
+    /// * `path` - Path to the pcap
+    pub fn take(&self, path: &Path) -> Option<Vec<u8>> {
+        let (ref lock, ref cvar) = *self.state;
+        let mut state = lock.lock().ok()?;
+        state.taken.insert(path.to_path_buf());
+        let contents = state.buffered.remove(path);
+        if let Some(ref contents) = contents {
+            state.buffered_bytes -= contents.len();
+            state.hits += 1;
+        } else {
+            state.misses += 1;
+        }
+        // Wake the prefetch thread, since there may be room for the next pcap, or the pcap it
+        // is waiting on may have just been taken
+        cvar.notify_all();
+        contents
+    }
+
+    /// Returns how many pcaps were taken after being read ahead, and how many weren't
+    pub fn hits_and_misses(&self) -> (usize, usize) {
+        let (ref lock, _) = *self.state;
+        lock.lock()
+            .map(|state| (state.hits, state.misses))
+            .unwrap_or((0, 0))
+    }
+}
+
+/// Maps items across threads, starting them in the order given, and returns the results in
+/// the same order
+///
+/// Unlike splitting the items into a chunk per thread, every thread works near the front of
+/// the items, so a prefetcher reading ahead in the same order stays ahead of all of them
+/// # Parameters
+/// * `items` - Items to map, in the order to start them
+/// * `map` - Function mapping each item
+pub fn par_map_in_order<T, R, F>(items: Vec<T>, map: F) -> Vec<R>
+where
+    T: Send,
+    R: Send,
+    F: Fn(T) -> R + Sync + Send,
+{
+    let mut results: Vec<(usize, R)> = items
+        .into_iter()
+        .enumerate()
+        .par_bridge()
+        .map(|(index, item)| (index, map(item)))
+        .collect();
+    results.sort_unstable_by_key(|&(index, _)| index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+impl Drop for PcapPrefetcher {
+    /// Stops the prefetch thread
+    fn drop(&mut self) {
+        let (ref lock, ref cvar) = *self.state;
+        if let Ok(mut state) = lock.lock() {
+            state.stopped = true;
+        }
+        cvar.notify_all();
+        if let Some(thread) = self.thread.take() {
+            drop(thread.join());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+    use tempdir::TempDir;
+
+    /// Waits until the pcap at the given path has been buffered
+    fn wait_for_buffered(prefetcher: &PcapPrefetcher, path: &Path) {
+        let start = Instant::now();
+        while !prefetcher
+            .state
+            .0
+            .lock()
+            .unwrap()
+            .buffered
+            .contains_key(path)
+        {
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "{:?} was never prefetched",
+                path
+            );
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Tests that prefetched pcaps match the files, and memory use stays within the bound
+    #[test]
+    fn test_prefetch_bounded() {
+        let scratch_dir = TempDir::new("data_generator").unwrap();
+        let paths: Vec<PathBuf> = (0..3u8)
+            .map(|index| {
+                let path = scratch_dir.path().join(format!("{}.pcap", index));
+                fs::write(&path, vec![index; 100]).unwrap();
+                path
+            })
+            .collect();
+        let large_path = scratch_dir.path().join("large.pcap");
+        fs::write(&large_path, vec![0xff; 200]).unwrap();
+        let mut all_paths = paths.clone();
+        all_paths.insert(1, large_path.clone());
+        let prefetcher = PcapPrefetcher::new(all_paths, 150);
+        for (index, path) in paths.iter().enumerate() {
+            wait_for_buffered(&prefetcher, path);
+            {
+                let state = prefetcher.state.0.lock().unwrap();
+                assert!(state.buffered_bytes <= 150);
+                assert_eq!(state.buffered.len(), 1);
+            }
+            assert_eq!(prefetcher.take(path).unwrap(), vec![index as u8; 100]);
+        }
+        // The large pcap never fit, so it was skipped
+        assert!(prefetcher.take(&large_path).is_none());
+    }
+
+    /// Tests that pcaps processed across threads had almost all been read ahead when taken
+    #[test]
+    fn test_prefetch_hits() {
+        let scratch_dir = TempDir::new("data_generator").unwrap();
+        let paths: Vec<PathBuf> = (0..20)
+            .map(|index| {
+                let path = scratch_dir.path().join(format!("{}.pcap", index));
+                fs::write(&path, vec![0; 100]).unwrap();
+                path
+            })
+            .collect();
+        let prefetcher = PcapPrefetcher::new(paths.clone(), 10_000);
+        wait_for_buffered(&prefetcher, &paths[1]);
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+        let taken = pool.install(|| {
+            par_map_in_order(paths.clone(), |path| {
+                // Processing a pcap takes much longer than reading it
+                thread::sleep(Duration::from_millis(5));
+                prefetcher.take(&path).is_some()
+            })
+        });
+        assert_eq!(taken.len(), paths.len());
+        let (hits, misses) = prefetcher.hits_and_misses();
+        assert_eq!(hits + misses, paths.len());
+        assert!(
+            hits >= 18,
+            "only {} of {} pcaps were prefetched",
+            hits,
+            paths.len()
+        );
+    }
+
+    /// Tests that mapping in order keeps results in the order of their items
+    #[test]
+    fn test_par_map_in_order() {
+        let items: Vec<usize> = (0..100).collect();
+        let doubled = par_map_in_order(items, |item| item * 2);
+        assert_eq!(doubled, (0..100).map(|item| item * 2).collect::<Vec<_>>());
+    }
+
+    /// Tests that pcaps taken before they were prefetched are not read ahead
+    #[test]
+    fn test_take_before_prefetch() {
+        let scratch_dir = TempDir::new("data_generator").unwrap();
+        let paths: Vec<PathBuf> = (0..2)
+            .map(|index| {
+                let path = scratch_dir.path().join(format!("{}.pcap", index));
+                fs::write(&path, vec![0; 100]).unwrap();
+                path
+            })
+            .collect();
+        let prefetcher = PcapPrefetcher::new(paths.clone(), 100);
+        wait_for_buffered(&prefetcher, &paths[0]);
+        // The second pcap doesn't fit yet, so taking it means it's read from disk
+        assert!(prefetcher.take(&paths[1]).is_none());
+        assert!(prefetcher.take(&paths[0]).is_some());
+        let state = prefetcher.state.0.lock().unwrap();
+        assert!(state.buffered.is_empty());
+        assert_eq!(state.buffered_bytes, 0);
+    }
+}
@@ -0,0 +1,195 @@
+// Copyright 2018 Steven Sheffey
+// This file is part of packet_captor_sakura.
+//
+// packet_captor_sakura is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// packet_captor_sakura is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with packet_captor_sakura.  If not, see <https:// www.gnu.org/licenses/>.
+
+use failure::Error;
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Minimum time between writes of the status file
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Status document written to the status file
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct Status {
+    /// Number of work items to process
+    pub total: usize,
+    /// Number of work items processed so far, including ones that failed
+    pub done: usize,
+    /// Number of work items that failed to process
+    pub errors: usize,
+    /// Time (in seconds) since processing started
+    pub elapsed_secs: f64,
+    /// Estimated time (in seconds) until processing finishes, once any work has been done
+    pub eta_secs: Option<f64>,
+    /// Whether processing has finished
+    pub finished: bool,
+}
+
+/// Counts work items as they're processed, and periodically writes the counts to a status file
+/// so external tools can monitor progress
+#[derive(Debug)]
+pub struct Progress {
+    /// Number of work items to process
+    total: usize,
+    /// Number of work items processed so far
+    done: AtomicUsize,
+    /// Number of work items that failed
+    errors: AtomicUsize,
+    /// Time processing started
+    start: Instant,
+    /// File to write the status to, if any
+    status_file: Option<PathBuf>,
+    /// Time the status file was last written
+    last_write: Mutex<Option<Instant>>,
+}
+
+impl Progress {
+    /// Starts tracking progress
+    /// # Parameters
+    /// * `total` - Number of work items to process
+    /// * `status_file` - File to write the status to
+    pub fn new(total: usize, status_file: Option<PathBuf>) -> Self {
+        Progress {
+            total,
+            done: AtomicUsize::new(0),
+            errors: AtomicUsize::new(0),
+            start: Instant::now(),
+            status_file,
+            last_write: Mutex::new(None),
+        }
+    }
+
+    /// Records that a work item has been processed
+    ///
+    /// The status file is rewritten if enough time has passed since it was last written
+    /// # Parameters
+    /// * `success` - Whether the work item was processed successfully
+    pub fn item_done(&self, success: bool) {
+        self.done.fetch_add(1, Ordering::SeqCst);
+        if !success {
+            self.errors.fetch_add(1, Ordering::SeqCst);
+        }
+        if self.status_file.is_none() {
+            return;
+        }
+        // Only one thread writes at a time, and the rest skip writing
+        let mut last_write = match self.last_write.try_lock() {
+            Ok(last_write) => last_write,
+            Err(_) => return,
+        };
+        let now = Instant::now();
+        if let Some(last_write) = *last_write {
+            if now - last_write < STATUS_INTERVAL {
+                return;
+            }
+        }
+        *last_write = Some(now);
+        if let Err(err) = self.write_status(false) {
+            warn!("Failed to write status file: {}", err);
+        }
+    }
+
+    /// Records that processing has finished, and writes the final status
+    pub fn finish(&self) -> Result<(), Error> {
+        // Wait for any in progress write, so it can't overwrite the final status
+        let _last_write = self.last_write.lock();
+        self.write_status(true)
+    }
+
+    /// Gets the current status
+    /// # Parameters
+    /// * `finished` - Whether processing has finished
+    pub fn status(&self, finished: bool) -> Status {
+        let done = self.done.load(Ordering::SeqCst);
+        let elapsed = self.start.elapsed();
+        let elapsed_secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
+        // Assume the remaining items take as long as the ones done so far
+        let eta_secs = if done > 0 {
+            Some(elapsed_secs / done as f64 * self.total.saturating_sub(done) as f64)
+        } else {
+            None
+        };
+        Status {
+            total: self.total,
+            done,
+            errors: self.errors.load(Ordering::SeqCst),
+            elapsed_secs,
+            eta_secs,
+            finished,
+        }
+    }
+
+    /// Writes the status to the status file, replacing it atomically so readers never see a
+    /// partial document
+    /// # Parameters
+    /// * `finished` - Whether processing has finished
+    fn write_status(&self, finished: bool) -> Result<(), Error> {
+        let status_file = match self.status_file {
+            Some(ref status_file) => status_file,
+            None => return Ok(()),
+        };
+        // Write to a temporary file next to the status file, then move it into place
+        let mut temp_file_name = status_file
+            .file_name()
+            .map(|file_name| file_name.to_os_string())
+            .unwrap_or_default();
+        temp_file_name.push(".tmp");
+        let temp_path = status_file.with_file_name(temp_file_name);
+        {
+            let mut writer = BufWriter::new(File::create(&temp_path)?);
+            serde_json::to_writer(&mut writer, &self.status(finished))?;
+            writer.flush()?;
+        }
+        fs::rename(&temp_path, status_file)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    /// Tests that the final status is written, and counts errors
+    #[test]
+    fn test_status_file() {
+        let scratch_dir = TempDir::new("data_generator").unwrap();
+        let status_path = scratch_dir.path().join("status.json");
+        let progress = Progress::new(3, Some(status_path.clone()));
+        progress.item_done(true);
+        // The first item is always written
+        let status: Status = serde_json::from_reader(File::open(&status_path).unwrap()).unwrap();
+        assert_eq!((status.done, status.finished), (1, false));
+        assert!(status.eta_secs.is_some());
+        progress.item_done(false);
+        progress.item_done(true);
+        progress.finish().unwrap();
+        let status: Status = serde_json::from_reader(File::open(&status_path).unwrap()).unwrap();
+        assert_eq!(
+            (status.total, status.done, status.errors, status.finished),
+            (3, 3, 1, true)
+        );
+        assert_eq!(status.eta_secs, Some(0.0));
+        // The temporary file was moved into place
+        assert_eq!(fs::read_dir(scratch_dir.path()).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,226 @@
+// Copyright 2018 Steven Sheffey
+// This file is part of packet_captor_sakura.
+//
+// packet_captor_sakura is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// packet_captor_sakura is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with packet_captor_sakura.  If not, see <https:// www.gnu.org/licenses/>.
+
+use crate::packet::InternetLayer;
+use log::warn;
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+
+/// Identifies the fragments belonging to a single IPv4 datagram
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+struct FragmentKey {
+    src_ip: IpAddr,
+    dst_ip: IpAddr,
+    trans_protocol: u8,
+    identification: u16,
+}
+
+/// Fragments of a datagram that have arrived so far
+#[derive(Debug)]
+struct FragmentBuffer {
+    /// Timestamp of the first fragment that arrived
+    first_timestamp: u64,
+    /// Payload of each fragment, keyed by its offset (in bytes) into the datagram
+    fragments: BTreeMap<usize, Vec<u8>>,
+    /// Length of the datagram's payload, known once the last fragment arrives
+    total_length: Option<usize>,
+}
+
+impl FragmentBuffer {
+    /// Joins the fragments into a single payload, if all of them have arrived
+    fn assemble(&self) -> Option<Vec<u8>> {
+        let total_length = self.total_length?;
+        let mut payload: Vec<u8> = Vec::with_capacity(total_length);
+        for (&offset, data) in &self.fragments {
+            // A gap means a fragment is still missing
+            if offset > payload.len() {
+                return None;
+            }
+            // Where fragments overlap, keep the data that is already there
+            let overlap = payload.len() - offset;
+            if overlap < data.len() {
+                payload.extend_from_slice(&data[overlap..]);
+            }
+        }
+        if payload.len() < total_length {
+            return None;
+        }
+        payload.truncate(total_length);
+        Some(payload)
+    }
+}
+
+/// Buffers fragments of IPv4 datagrams until they can be reassembled
+#[derive(Debug)]
+pub struct Ipv4Reassembler {
+    /// Datagrams that are waiting for more fragments
+    buffers: HashMap<FragmentKey, FragmentBuffer>,
+    /// Time (in ns) to wait for the remaining fragments of a datagram before dropping it
+    timeout: u64,
+}
+
+impl Ipv4Reassembler {
+    /// Creates a reassembler
+    /// # Parameters
+    /// * `timeout` - Time (in ns) to wait for the remaining fragments of a datagram
+    pub fn new(timeout: u64) -> Self {
+        Ipv4Reassembler {
+            buffers: HashMap::new(),
+            timeout,
+        }
+    }
+
+    /// Processes the internet layer of a packet
+    ///
+    /// Unfragmented packets are returned as is. Fragments are buffered, and once the last
+    /// missing fragment of a datagram arrives, the reassembled datagram is returned
+    /// # Parameters
+    /// * `internet_layer` - The parsed internet layer of the packet
+    /// * `timestamp` - Time the packet arrived
+    pub fn process(
+        &mut self,
+        internet_layer: InternetLayer,
+        timestamp: u64,
+    ) -> Option<InternetLayer> {
+        // Drop any datagrams that have waited too long for their fragments
+        self.expire(timestamp);
+        let fragment = match internet_layer.fragment {
+            Some(fragment) => fragment,
+            None => return Some(internet_layer),
+        };
+        let key = FragmentKey {
+            src_ip: internet_layer.src_ip,
+            dst_ip: internet_layer.dst_ip,
+            trans_protocol: internet_layer.trans_protocol.0,
+            identification: fragment.identification,
+        };
+        let buffer = self.buffers.entry(key).or_insert_with(|| FragmentBuffer {
+            first_timestamp: timestamp,
+            fragments: BTreeMap::new(),
+            total_length: None,
+        });
+        // The last fragment tells us how long the datagram is
+        if !fragment.more_fragments {
+            buffer.total_length = Some(fragment.offset + internet_layer.payload.len());
+        }
+        buffer
+            .fragments
+            .entry(fragment.offset)
+            .or_insert(internet_layer.payload);
+        let payload = buffer.assemble()?;
+        self.buffers.remove(&key);
+        Some(InternetLayer {
+            payload,
+            fragment: None,
+            ..internet_layer
+        })
+    }
+
+    /// Drops datagrams whose first fragment arrived more than the timeout before `now`
+    fn expire(&mut self, now: u64) {
+        let timeout = self.timeout;
+        self.buffers.retain(|key, buffer| {
+            let expired = now.saturating_sub(buffer.first_timestamp) > timeout;
+            if expired {
+                warn!(
+                    "Dropping incomplete fragmented datagram {} from {} to {}",
+                    key.identification, key.src_ip, key.dst_ip
+                );
+            }
+            !expired
+        });
+    }
+
+    /// Drops every datagram still waiting for fragments, warning how many there were
+    ///
+    /// Called once the capture ends, since their remaining fragments can no longer arrive.
+    /// Returns the number of datagrams dropped
+    pub fn finish(&mut self) -> usize {
+        let incomplete = self.buffers.len();
+        if incomplete > 0 {
+            warn!(
+                "Dropping {} incomplete fragmented datagrams at the end of the capture",
+                incomplete
+            );
+        }
+        self.buffers.clear();
+        incomplete
+    }
+}
+
+impl Drop for Ipv4Reassembler {
+    /// Packets are loaded lazily, so the reassembler is finished when the loader is dropped
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::Ipv4Fragment;
+    use pnet_packet::ip::IpNextHeaderProtocols;
+
+    /// Builds a fragment of datagram 1 from 10.0.0.1 to 10.0.0.2
+    fn fragment(offset: usize, more_fragments: bool, payload: &[u8]) -> InternetLayer {
+        InternetLayer {
+            src_ip: "10.0.0.1".parse().unwrap(),
+            dst_ip: "10.0.0.2".parse().unwrap(),
+            payload: payload.to_vec(),
+            trans_protocol: IpNextHeaderProtocols::Udp,
+            fragment: Some(Ipv4Fragment {
+                identification: 1,
+                offset,
+                more_fragments,
+            }),
+        }
+    }
+
+    /// Tests that fragments arriving out of order are reassembled
+    #[test]
+    fn test_reassemble() {
+        let mut reassembler = Ipv4Reassembler::new(1_000);
+        assert!(reassembler
+            .process(fragment(16, false, &[3; 4]), 0)
+            .is_none());
+        assert!(reassembler.process(fragment(0, true, &[1; 8]), 1).is_none());
+        let datagram = reassembler.process(fragment(8, true, &[2; 8]), 2).unwrap();
+        assert!(datagram.fragment.is_none());
+        assert_eq!(
+            datagram.payload,
+            [[1; 8].as_ref(), &[2; 8], &[3; 4]].concat()
+        );
+        assert!(reassembler.buffers.is_empty());
+    }
+
+    /// Tests that datagrams missing fragments are dropped after the timeout
+    #[test]
+    fn test_timeout() {
+        let mut reassembler = Ipv4Reassembler::new(1_000);
+        assert!(reassembler.process(fragment(0, true, &[1; 8]), 0).is_none());
+        assert!(reassembler
+            .process(fragment(16, false, &[3; 4]), 2_000)
+            .is_none());
+        // The first fragment expired, so the datagram can't be completed
+        assert!(reassembler
+            .process(fragment(8, true, &[2; 8]), 2_001)
+            .is_none());
+        assert_eq!(reassembler.buffers.len(), 1);
+        // Datagrams still incomplete at the end of the capture are counted and dropped
+        assert_eq!(reassembler.finish(), 1);
+        assert_eq!(reassembler.finish(), 0);
+    }
+}